@@ -0,0 +1,8 @@
+use clap::ArgMatches;
+
+use crate::errors::*;
+use crate::update::add_files;
+
+pub fn main(matches: &ArgMatches) -> Result<()> {
+    add_files(matches, false)
+}
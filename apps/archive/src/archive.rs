@@ -3,80 +3,251 @@
 //! ## Usage steps:
 //! 1. add path records, initialize the file entries
 //! 2. write files and update file entries
-//! 3. finalize: write entries to the starting part of the output file
+//! 3. finalize: write entries and the footer after the content area
+//!
+//! The output is written sequentially, so it doesn't need to be seekable.
+//!
+//! An existing archive can also be continued in place with [`Archive::resume`],
+//! or rewritten with [`Archive::copy_from`].
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::ffi::{OsStr, OsString};
 use std::fs::File;
-use std::io::{BufReader, Seek, SeekFrom, Write};
+use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
-use std::{fs, io};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::time::Duration;
+use std::{fs, io, thread};
 
 use byteorder::{LittleEndian, WriteBytesExt};
 use cfg_if::cfg_if;
 use chrono::{DateTime, Utc};
 use crc_lib::Crc;
 
-use bczhc_lib::field_size;
 use bczhc_lib::str::GenericOsStrExt;
 
-use crate::compressors::Compress;
+use crate::checkpoint::CheckpointWriter;
+use crate::chunking::compress_chunks;
+use crate::compressors::{self, CompressionPolicy};
 use crate::crc::write::CrcFilter;
-use crate::errors::Result;
+use crate::crc::DigestWriter;
+use crate::encryption;
+use crate::encryption::{Cipher, EncryptWriter, EncryptionHeader};
+use crate::errors::{Error, Result};
+use crate::extensions;
+use crate::hash;
+use crate::hash::{HashFunction, HashReader};
+use crate::incremental::Chain;
+use crate::parallel::{compress_file, Compressed, Permits};
+use crate::reader::ArchiveReader;
+use crate::sparse::{self, SparseMap};
 use crate::{
-    CalcCrcChecksum, Compression, Entry, EntryChecksum, FileType, FixedStoredSize, GetStoredSize,
-    Header, Info, OsStrExt, Timestamp, WriteTo, ENTRY_MAGIC, FILE_CRC_64, FILE_MAGIC, VERSION,
+    CalcCrcChecksum, Chunk, Compression, Entry, FileType, Footer, GetStoredSize, Header, Info,
+    OsStrExt, Timestamp, WriteTo, ENTRY_MAGIC, FILE_CRC_64, FILE_MAGIC, VERSION,
 };
 
+/// The size limit of trained zstd dictionaries, the zstd default
+pub const DICTIONARY_SIZE: usize = 110 * 1024;
+/// Files up to this size are taken as dictionary samples
+const DICTIONARY_SAMPLE_FILE_SIZE: u64 = 64 * 1024;
+/// Training takes samples of about this many times the dictionary size
+const DICTIONARY_SAMPLE_RATIO: u64 = 100;
+/// Too few samples make no useful dictionary
+const DICTIONARY_MIN_SAMPLES: usize = 16;
+
 pub struct Archive<'a, W>
 where
-    W: Write + Seek,
+    W: Write,
 {
-    writer: W,
-    compressor: Box<dyn Compress + 'a>,
+    writer: PositionWriter<W>,
+    compression: CompressionPolicy<'a>,
     /// the first tuple element in the vector, `PathBuf`,
     /// is the path to the file to be added,
     /// and should be opened in `write_files` method
     entries: Vec<(PathBuf, Entry)>,
-    content_offset: u64,
     last_content_offset: u64,
     header: Header,
     // for identifying hard links
     inode_map: HashMap<u64, OsString>,
+    /// number of compression threads
+    jobs: usize,
+    /// whether to record extended attributes
+    xattrs: bool,
+    /// whether to store contents as deduplicated chunks
+    chunked: bool,
+    /// hash function of the original content hashes to record
+    hash: Option<HashFunction>,
+    /// offsets and stored sizes of the chunks written
+    ///
+    /// Chunks are only shared among entries with the same compression method.
+    chunk_index: HashMap<(Compression, [u8; 32]), (u64, u32)>,
+    cipher: Option<Cipher>,
+    /// entries taken from an existing archive, which go before added ones
+    existing: Vec<Entry>,
+    /// the archive to copy the content of `existing` from; `None` if the
+    /// content is already in place
+    source: Option<ArchiveReader>,
+    checkpoint: Option<CheckpointWriter>,
 }
 
 impl<'a, W> Archive<'a, W>
 where
-    W: Write + Seek,
+    W: Write,
 {
-    pub fn new(
-        writer: W,
-        compressor: Box<dyn Compress + 'a>,
-        compression_type: Compression,
-    ) -> Result<Self> {
+    pub fn new(writer: W, compression: CompressionPolicy<'a>) -> Result<Self> {
         let header = Header {
             magic_number: *FILE_MAGIC,
             version: VERSION,
             content_offset: 0,
-            compression: compression_type,
+            compression: compression.method(),
             creation_time: Utc::now().timestamp_millis(),
             entry_count: 0,         /* placeholder */
             info_json_length: 2,    /* default */
             info_json: "{}".into(), /* default */
+            encryption: None,
+            dictionary: Vec::new(),
         };
 
         let archive = Self {
-            writer,
-            compressor,
+            writer: PositionWriter::new(writer),
+            compression,
             entries: Vec::new(),
-            content_offset: 0,
             last_content_offset: 0,
             header,
             inode_map: HashMap::new(),
+            jobs: 1,
+            xattrs: false,
+            chunked: false,
+            hash: None,
+            chunk_index: HashMap::new(),
+            cipher: None,
+            existing: Vec::new(),
+            source: None,
+            checkpoint: None,
+        };
+        Ok(archive)
+    }
+
+    /// Continue an existing archive in place
+    ///
+    /// `writer` writes to the archive file at `position`, after which new
+    /// content and then the entry table are written. `header` is the header of
+    /// the archive as read (decrypted), and `existing` are its entries to keep.
+    /// The header isn't written again, so its entry count needs updating
+    /// afterwards (see [`Archive::entry_count`]).
+    pub fn resume(
+        writer: W,
+        compression: CompressionPolicy<'a>,
+        header: Header,
+        existing: Vec<Entry>,
+        position: u64,
+    ) -> Result<Self> {
+        let cipher = match &header.encryption {
+            Some(e) => Some(Cipher::derive(&encryption::passphrase(false)?, e)?),
+            None => None,
         };
+        let mut archive = Self::new(writer, compression)?;
+        archive.writer.position = position;
+        archive.last_content_offset = position - header.content_offset;
+        archive.header = header;
+        archive.cipher = cipher;
+        if !archive.header.dictionary.is_empty() {
+            archive
+                .compression
+                .set_dictionary(&archive.header.dictionary);
+        }
+        archive.index_chunks(&existing);
+        archive.existing = existing;
         Ok(archive)
     }
 
+    /// Take all entries of another archive, and copy their content as stored
+    ///
+    /// The creation time, info and encryption of `reader` are kept, so its
+    /// passphrase is needed for encrypted archives.
+    pub fn copy_from(&mut self, reader: ArchiveReader, entries: Vec<Entry>) -> Result<()> {
+        let header = &reader.header;
+        self.cipher = match &header.encryption {
+            Some(e) => Some(Cipher::derive(&encryption::passphrase(false)?, e)?),
+            None => None,
+        };
+        self.header.encryption = header.encryption.clone();
+        self.header.compression = header.compression;
+        self.header.creation_time = header.creation_time;
+        self.header.info_json = header.info_json.clone();
+        self.header.info_json_length = self.header.info_json.len() as u32;
+        self.header.dictionary = header.dictionary.clone();
+        self.existing = entries;
+        self.source = Some(reader);
+        Ok(())
+    }
+
+    /// Entries kept from an existing archive
+    pub fn existing_entries_mut(&mut self) -> &mut Vec<Entry> {
+        &mut self.existing
+    }
+
+    /// Resolve added entries with the same paths as existing ones
+    ///
+    /// The existing entry is replaced, unless `keep_unchanged` is set and the
+    /// added one has the same metadata, in which case the added one is dropped.
+    pub fn merge_existing(&mut self, keep_unchanged: bool) {
+        let index = self
+            .existing
+            .iter()
+            .enumerate()
+            .map(|(i, x)| (&x.path[..], i))
+            .collect::<HashMap<_, _>>();
+        let mut replaced = HashSet::new();
+        self.entries
+            .retain(|(_, entry)| match index.get(&entry.path[..]) {
+                None => true,
+                Some(&i) if keep_unchanged && entry.same_metadata(&self.existing[i]) => false,
+                Some(&i) => {
+                    replaced.insert(i);
+                    true
+                }
+            });
+
+        let mut index = 0_usize;
+        self.existing.retain(|_| {
+            index += 1;
+            !replaced.contains(&(index - 1))
+        });
+    }
+
+    /// Resolve added entries with the entries completed before an interrupted
+    /// creation
+    ///
+    /// Completed entries are kept if unchanged, and dropped if not added again.
+    pub fn merge_resumed(&mut self) {
+        let added = self
+            .entries
+            .iter()
+            .map(|x| x.1.path.clone())
+            .collect::<HashSet<_>>();
+        self.existing.retain(|x| added.contains(&x.path));
+        self.merge_existing(true);
+    }
+
+    /// Number of entries, existing and added
+    pub fn entry_count(&self) -> u64 {
+        (self.existing.len() + self.entries.len()) as u64
+    }
+
+    /// Make chunks of `entries` available for deduplication
+    fn index_chunks(&mut self, entries: &[Entry]) {
+        for entry in entries {
+            for chunk in &entry.chunks {
+                self.chunk_index.insert(
+                    (entry.compression, chunk.hash),
+                    (chunk.offset, chunk.stored_size),
+                );
+            }
+        }
+    }
+
     /// add a path record
     ///
     /// `path` will be stored in the archive directly,
@@ -100,7 +271,7 @@ where
     /// like `tar` utility
     pub fn add_path<P: AsRef<Path>>(&mut self, path: &OsStr, file_path: P) -> Result<()> {
         #[cfg(unix)]
-        use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
 
         let file_path = file_path.as_ref();
         let metadata = file_path.symlink_metadata()?;
@@ -128,12 +299,15 @@ where
                         linked_path: linked_path_bytes,
                         stored_size: 0,
                         original_size: 0,
+                        compression: Compression::None,
                         owner_id: 0,
                         group_id: 0,
                         permission_mode: 0,
                         modification_time: Timestamp::zero(),
                         content_checksum: 0,
                         offset: 0,
+                        extensions: Vec::new(),
+                        chunks: Vec::new(),
                     };
                     self.entries.push((file_path.into(), entry));
                     return Ok(());
@@ -141,17 +315,9 @@ where
             }
         }
 
-        let file_type = metadata.file_type();
-        cfg_if! {
-            if #[cfg(unix)] {
-                if file_type.is_socket() {
-                    eprintln!("{}: socket ignored", path.escape());
-                    return Ok(());
-                }
-            }
-        }
-        let Ok(file_type) = FileType::try_from(file_type) else {
-            panic!("Unknown file type: {:?}", path);
+        let Ok(file_type) = FileType::try_from(metadata.file_type()) else {
+            eprintln!("{}: unknown file type, ignored", path.escape());
+            return Ok(());
         };
 
         cfg_if! {
@@ -161,10 +327,20 @@ where
 
                 let owner_id = metadata.uid();
                 let group_id = metadata.gid();
+
+                let extensions = if self.xattrs {
+                    extensions::read_xattrs(file_path).unwrap_or_else(|e| {
+                        eprintln!("{}: failed to read xattrs: {}", path.escape(), e);
+                        Vec::new()
+                    })
+                } else {
+                    Vec::new()
+                };
             } else {
                 let mut file_mode = 0;
                 let mut owner_id = 0;
                 let mut group_id = 0;
+                let extensions = Vec::new();
             }
         }
 
@@ -189,7 +365,7 @@ where
             vec![]
         };
 
-        let entry = Entry {
+        let mut entry = Entry {
             magic_number: *ENTRY_MAGIC,
             path_length: path_bytes.len() as u16,
             path: path_bytes,
@@ -198,114 +374,503 @@ where
             linked_path,
             stored_size: 0, /* placeholder */
             original_size: metadata.len(),
-            owner_id: owner_id as u16,
-            group_id: group_id as u16,
+            compression: Compression::None, /* placeholder */
+            owner_id,
+            group_id,
             permission_mode: file_mode,
             modification_time,
             content_checksum: 0, /* placeholder */
             offset: 0,           /* placeholder */
+            extensions,
+            chunks: Vec::new(), /* placeholder */
         };
 
+        match file_type {
+            FileType::Regular => {
+                if let Some(ranges) = sparse::find_data_ranges(&File::open(file_path)?)? {
+                    sparse::set_sparse_map(&mut entry, &ranges);
+                }
+            }
+            #[cfg(unix)]
+            FileType::CharDevice | FileType::BlockDevice => {
+                let device = metadata.rdev();
+                extensions::set_device_numbers(
+                    &mut entry,
+                    nix::sys::stat::major(device) as u32,
+                    nix::sys::stat::minor(device) as u32,
+                );
+            }
+            _ => {}
+        }
+
         self.entries.push((file_path.into(), entry));
         Ok(())
     }
 
-    pub fn set_info(&mut self, info: &Info) {
-        self.header.info_json = serde_json::to_string(info).unwrap();
-        self.header.info_json_length = self.header.info_json.len() as u32;
+    /// Add an entry not taken from the filesystem, like one converted from
+    /// another archive format
+    ///
+    /// The content of regular files is read from `content`; the storage fields
+    /// of `entry` get filled in when written.
+    pub fn add_entry<P: AsRef<Path>>(&mut self, entry: Entry, content: P) {
+        self.entries.push((content.as_ref().into(), entry));
     }
 
-    fn content_offset(&self) -> usize {
-        let entries_size_sum = self
+    /// Drop entries that are unchanged in respect with `reference`, and return
+    /// the paths existing in `reference` but not in this archive
+    ///
+    /// Entries are compared by their metadata (size, modification time etc.).
+    /// If `compare_content` is set, the contents of regular files are compared
+    /// too: by the content hash if the reference entry has one, or else by the
+    /// content checksum, and for this the reference should use the same
+    /// compression.
+    pub fn diff_with(&mut self, reference: &Chain, compare_content: bool) -> Result<Vec<Vec<u8>>> {
+        let mut unchanged = HashSet::new();
+        for (index, (path, entry)) in self.entries.iter().enumerate() {
+            let Some((_, old)) = reference.get(&entry.path) else {
+                continue;
+            };
+            if !entry.same_metadata(old) {
+                continue;
+            }
+            if compare_content
+                && entry.file_type == FileType::Regular
+                && !self.same_content(entry, path, old)?
+            {
+                continue;
+            }
+            unchanged.insert(index);
+        }
+
+        let paths = self
             .entries
             .iter()
-            .map(|x| {
-                x.1.stored_size() + EntryChecksum::SIZE /* checksum of entry, following after each entry */
-            })
-            .sum::<usize>();
-        self.header.stored_size() + entries_size_sum
+            .map(|x| &x.1.path)
+            .collect::<HashSet<_>>();
+        let deleted = reference
+            .entries()
+            .map(|x| &x.1.path)
+            .filter(|x| !paths.contains(x))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let mut index = 0_usize;
+        self.entries.retain(|_| {
+            index += 1;
+            !unchanged.contains(&(index - 1))
+        });
+        Ok(deleted)
     }
 
-    fn write_header(&mut self) -> io::Result<()> {
-        self.header.entry_count = self.entries.len() as u64;
+    fn same_content(&self, entry: &Entry, path: &Path, old: &Entry) -> Result<bool> {
+        match hash::stored_hash(old) {
+            // hashing is much cheaper than compressing
+            Some((function, digest)) => Ok(hash::hash_file(path, function)? == digest),
+            None => Ok(self.content_checksum(entry, path)? == old.content_checksum),
+        }
+    }
 
-        self.header.write_to(&mut self.writer)
+    /// Compute the checksum of the stored (compressed) content without writing it
+    fn content_checksum(&self, entry: &Entry, path: &Path) -> Result<u64> {
+        let method = self.compression.select(&entry.path, path)?;
+        let mut file_reader = sparse::open_content(path, entry)?;
+
+        let crc = Crc::<u64>::new(&FILE_CRC_64);
+        let mut digest = crc.digest();
+        let mut crc_writer = DigestWriter::<u64>::new(&mut digest);
+        let compressor = self.compression.compressor(method);
+        if self.chunked {
+            compress_chunks(compressor, &mut file_reader, &mut crc_writer)?;
+        } else {
+            compressor.compress_to(&mut file_reader, &mut crc_writer)?;
+        }
+        Ok(digest.finalize())
+    }
+
+    /// Set the number of threads compressing files concurrently
+    ///
+    /// The output is the same regardless of this.
+    pub fn set_jobs(&mut self, jobs: usize) {
+        self.jobs = jobs.max(1);
     }
 
-    fn change_content_offset(&mut self, offset: u64) -> Result<()> {
-        let position = self.writer.stream_position()?;
-        self.writer.seek(SeekFrom::Start(
-            (FILE_MAGIC.len() + field_size!(Header, version)) as u64,
-        ))?;
+    /// Record extended attributes (including ACLs) of added paths
+    pub fn set_xattrs(&mut self, xattrs: bool) {
+        self.xattrs = xattrs;
+    }
 
-        self.writer.write_u64::<LittleEndian>(offset)?;
+    /// Split file contents into chunks, and store identical chunks only once
+    ///
+    /// See [`crate::chunking`].
+    pub fn set_chunked(&mut self, chunked: bool) {
+        self.chunked = chunked;
+    }
+
+    /// Record hashes of the original content of files (see [`crate::hash`])
+    pub fn set_content_hash(&mut self, function: Option<HashFunction>) {
+        self.hash = function;
+    }
 
-        self.writer.seek(SeekFrom::Start(position))?;
+    /// Encrypt the archive with a key derived from `passphrase`
+    ///
+    /// See [`crate::encryption`].
+    pub fn set_encryption(&mut self, passphrase: &str) -> Result<()> {
+        let encryption = EncryptionHeader::generate();
+        self.cipher = Some(Cipher::derive(passphrase, &encryption)?);
+        self.header.encryption = Some(encryption);
         Ok(())
     }
 
-    pub fn write(&mut self) -> Result<()> {
-        self.writer.seek(SeekFrom::Start(0))?;
+    pub fn compression_method(&self) -> Compression {
+        self.compression.method()
+    }
+
+    /// Compress with a zstd dictionary, which is stored in the header
+    ///
+    /// Returns false if the compressor doesn't take dictionaries.
+    pub fn set_dictionary(&mut self, dictionary: Vec<u8>) -> bool {
+        if !self.compression.set_dictionary(&dictionary) {
+            return false;
+        }
+        self.header.dictionary = dictionary;
+        true
+    }
 
+    /// Train a zstd dictionary of at most `max_size` bytes from the small
+    /// files added, and compress with it
+    ///
+    /// Samples are taken evenly over the added files. Returns false if there
+    /// aren't enough small files for training.
+    pub fn train_dictionary(&mut self, max_size: usize) -> Result<bool> {
+        let candidates = self
+            .entries
+            .iter()
+            .filter(|(_, e)| {
+                e.file_type == FileType::Regular
+                    && e.original_size > 0
+                    && e.original_size <= DICTIONARY_SAMPLE_FILE_SIZE
+                    && !sparse::is_sparse(e)
+            })
+            .collect::<Vec<_>>();
+        if candidates.len() < DICTIONARY_MIN_SAMPLES {
+            return Ok(false);
+        }
+        let total = candidates.iter().map(|x| x.1.original_size).sum::<u64>();
+        let budget = max_size as u64 * DICTIONARY_SAMPLE_RATIO;
+        let step = total.div_ceil(budget).max(1) as usize;
+
+        let mut samples = Vec::new();
+        for (path, _) in candidates.iter().step_by(step) {
+            match fs::read(path) {
+                Ok(x) => samples.push(x),
+                // left for writing to report
+                Err(_) => continue,
+            }
+        }
+        if samples.len() < DICTIONARY_MIN_SAMPLES {
+            return Ok(false);
+        }
+        match compressors::train_dictionary(&samples, max_size) {
+            Ok(dictionary) => Ok(self.set_dictionary(dictionary)),
+            // the trainer rejects samples too few or too uniform
+            Err(_) => Ok(false),
+        }
+    }
+
+    /// Record checkpoints in `path` every `interval` while writing files
+    ///
+    /// The writer should write to a file; see [`crate::checkpoint`].
+    pub fn set_checkpoint(&mut self, path: PathBuf, interval: Duration) {
+        self.checkpoint = Some(CheckpointWriter::new(path, interval));
+    }
+
+    /// Remove the checkpoints, once the archive is written and flushed
+    pub fn remove_checkpoint(&mut self) -> io::Result<()> {
+        match self.checkpoint.take() {
+            Some(c) => c.remove(),
+            None => Ok(()),
+        }
+    }
+
+    fn start_checkpoint(&mut self) -> io::Result<()> {
+        let Some(checkpoint) = &mut self.checkpoint else {
+            return Ok(());
+        };
+        self.writer.flush()?;
+        checkpoint.start(
+            &self.header,
+            self.writer.position,
+            &self.existing,
+            self.cipher.as_ref(),
+        )
+    }
+
+    pub fn set_info(&mut self, info: &Info) {
+        self.header.info_json = serde_json::to_string(info).unwrap();
+        self.header.info_json_length = self.header.info_json.len() as u32;
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        self.header.entry_count = self.entry_count();
+        if let Some(cipher) = &self.cipher {
+            self.header.info_json = cipher.seal_string(&self.header.info_json);
+            self.header.info_json_length = self.header.info_json.len() as u32;
+            if !self.header.dictionary.is_empty() {
+                self.header.dictionary = cipher.seal(&self.header.dictionary);
+            }
+        }
+        // the content area follows the header directly
+        self.header.content_offset = self.header.stored_size() as u64;
+
+        self.header.write_to(&mut self.writer)
+    }
+
+    pub fn write(&mut self) -> Result<()> {
         self.write_header()?;
+        if self.writer.position != self.header.content_offset {
+            return Err(Error::Others(format!(
+                "Header written up to {}, but the content offset is {}",
+                self.writer.position, self.header.content_offset
+            )));
+        }
+        self.last_content_offset = 0;
+        self.copy_existing()?;
+        self.start_checkpoint()?;
         self.write_files()?;
         self.write_entries()?;
         Ok(())
     }
 
-    fn write_files(&mut self) -> Result<()> {
-        let content_offset = self.content_offset() as u64;
-        self.change_content_offset(content_offset)?;
-        self.writer.seek(SeekFrom::Start(content_offset))?;
+    /// Write added files and the entry table to an archive continued with
+    /// [`Archive::resume`]
+    pub fn write_appended(&mut self) -> Result<()> {
+        self.start_checkpoint()?;
+        self.write_files()?;
+        self.write_entries()?;
+        Ok(())
+    }
 
-        self.content_offset = content_offset;
-        self.last_content_offset = 0;
+    /// Copy the content of existing entries from the source archive
+    ///
+    /// Chunks shared by entries are copied once.
+    fn copy_existing(&mut self) -> Result<()> {
+        let Some(reader) = &mut self.source else {
+            return Ok(());
+        };
+        // old offsets to new ones
+        let mut chunk_offsets = HashMap::new();
+        for entry in &mut self.existing {
+            eprintln!("{}", OsStr::from_bytes(&entry.path).escape());
+            if entry.file_type != FileType::Regular {
+                continue;
+            }
+            if entry.chunks.is_empty() {
+                let mut content = reader.retrieve_content(entry.offset, entry.stored_size);
+                io::copy(&mut content, &mut self.writer)?;
+                entry.offset = self.last_content_offset;
+                self.last_content_offset += entry.stored_size;
+                continue;
+            }
+            for chunk in &mut entry.chunks {
+                let offset = match chunk_offsets.get(&chunk.offset) {
+                    Some(&offset) => offset,
+                    None => {
+                        let offset = self.last_content_offset;
+                        let mut content =
+                            reader.retrieve_content(chunk.offset, chunk.stored_size as u64);
+                        io::copy(&mut content, &mut self.writer)?;
+                        self.last_content_offset += chunk.stored_size as u64;
+                        chunk_offsets.insert(chunk.offset, offset);
+                        offset
+                    }
+                };
+                chunk.offset = offset;
+            }
+        }
+        let existing = std::mem::take(&mut self.existing);
+        self.index_chunks(&existing);
+        self.existing = existing;
+        Ok(())
+    }
+
+    fn write_files(&mut self) -> Result<()> {
+        // chunks are compressed into buffers first, and then deduplicated
+        if self.jobs > 1 || self.chunked || self.cipher.is_some() {
+            return self.write_files_parallel();
+        }
 
-        for (path, entry) in self.entries.iter_mut() {
+        for index in 0..self.entries.len() {
+            record_checkpoint(
+                &mut self.checkpoint,
+                &mut self.writer,
+                &self.entries,
+                index,
+                self.cipher.as_ref(),
+            )?;
+            let (path, entry) = &mut self.entries[index];
             let stored_path = OsStr::from_bytes(&entry.path);
-            println!("{}", stored_path.escape());
+            eprintln!("{}", stored_path.escape());
 
             if entry.file_type != FileType::Regular {
                 continue;
             }
 
-            let file = File::open(path)?;
-            let mut file_reader = BufReader::new(file);
+            let method = self.compression.select(&entry.path, path)?;
+            let file = sparse::open_content(path, entry)?;
+            let mut file_reader = HashReader::new(file, self.hash, SparseMap::of(entry)?);
 
             let crc = Crc::<u64>::new(&FILE_CRC_64);
             let mut digest = crc.digest();
             let mut crc_filter = CrcFilter::new(&mut digest, &mut self.writer);
 
             let compressed_size = self
-                .compressor
+                .compression
+                .compressor(method)
                 .compress_to(&mut file_reader, &mut crc_filter)?;
 
             crc_filter.flush()?;
             let content_checksum = digest.finalize();
 
+            entry.compression = method;
             entry.stored_size = compressed_size;
             entry.content_checksum = content_checksum;
+            if let Some((function, hash)) = file_reader.finalize()? {
+                hash::set_hash(entry, function, hash);
+            }
 
             entry.offset = self.last_content_offset;
             self.last_content_offset += entry.stored_size;
         }
 
+        self.record_all()
+    }
+
+    /// Record all added entries as complete
+    fn record_all(&mut self) -> Result<()> {
+        let Some(checkpoint) = &mut self.checkpoint else {
+            return Ok(());
+        };
+        self.writer.flush()?;
+        let entries = self.entries.iter().map(|x| &x.1);
+        checkpoint.record(
+            self.writer.position,
+            entries,
+            self.entries.len(),
+            self.cipher.as_ref(),
+        )?;
         Ok(())
     }
 
-    fn write_entries(&mut self) -> Result<()> {
-        self.writer
-            .seek(SeekFrom::Start(self.header.stored_size() as u64))?;
+    fn write_files_parallel(&mut self) -> Result<()> {
+        let tasks = self
+            .entries
+            .iter()
+            .enumerate()
+            .filter(|x| x.1 .1.file_type == FileType::Regular)
+            .map(|(i, x)| (i, x.1.clone(), x.0.clone()))
+            .collect::<Vec<_>>();
+        let next_task = AtomicUsize::new(0);
+        // bound the count of compressed files not written yet
+        let permits = Permits::new(self.jobs * 2);
+
+        let compression = &self.compression;
+        let chunked = self.chunked;
+        let hash = self.hash;
+        let entries = &mut self.entries;
+        let writer = &mut self.writer;
+        let last_content_offset = &mut self.last_content_offset;
+        let chunk_index = &mut self.chunk_index;
+        let cipher = self.cipher.as_ref();
+        let checkpoint = &mut self.checkpoint;
+
+        thread::scope(|s| {
+            let (sender, receiver) = mpsc::channel();
+            for _ in 0..self.jobs {
+                let sender = sender.clone();
+                let (tasks, next_task, permits) = (&tasks, &next_task, &permits);
+                s.spawn(move || {
+                    while permits.acquire() {
+                        let n = next_task.fetch_add(1, Ordering::SeqCst);
+                        let Some((index, entry, path)) = tasks.get(n) else {
+                            break;
+                        };
+                        let result = compress_file(compression, entry, path, chunked, hash);
+                        if sender.send((*index, result)).is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+            drop(sender);
 
-        for (_, entry) in &self.entries {
-            let checksum = entry.crc_checksum();
-            entry.write_to(&mut self.writer)?;
-            self.writer.write_u32::<LittleEndian>(checksum)?;
+            let result: Result<()> = (|| {
+                let mut pending = HashMap::new();
+                for index in 0..entries.len() {
+                    record_checkpoint(checkpoint, writer, entries, index, cipher)?;
+                    let entry = &mut entries[index].1;
+                    let stored_path = OsStr::from_bytes(&entry.path);
+                    eprintln!("{}", stored_path.escape());
+
+                    if entry.file_type != FileType::Regular {
+                        continue;
+                    }
+
+                    let compressed = loop {
+                        if let Some(c) = pending.remove(&index) {
+                            break c;
+                        }
+                        let (i, c) = receiver
+                            .recv()
+                            .expect("Compression workers exited unexpectedly");
+                        pending.insert(i, c);
+                    }?;
+                    write_compressed(
+                        writer,
+                        last_content_offset,
+                        chunk_index,
+                        cipher,
+                        entry,
+                        compressed,
+                    )?;
+                    permits.release();
+                }
+                Ok(())
+            })();
+            // stop workers in case of errors
+            permits.close();
+            result
+        })?;
+        self.record_all()
+    }
+
+    fn write_entries(&mut self) -> Result<()> {
+        let entries_offset = self.writer.position;
+        let content_end = self.header.content_offset + self.last_content_offset;
+        if entries_offset != content_end {
+            return Err(Error::Others(format!(
+                "Content written up to {}, but the entries account for {}",
+                entries_offset, content_end
+            )));
         }
 
-        let result = self.writer.stream_position();
-        assert_eq!(self.content_offset, result?);
+        // the final count, which resumed archives get written to the header
+        // afterwards
+        self.header.entry_count = self.entry_count();
+        let entries = self
+            .existing
+            .iter()
+            .chain(self.entries.iter().map(|x| &x.1));
+        match &self.cipher {
+            None => write_entry_table(&mut self.writer, entries)?,
+            Some(cipher) => {
+                let aad = self.header.table_aad();
+                let mut writer = EncryptWriter::with_aad(cipher, &mut self.writer, &aad);
+                write_entry_table(&mut writer, entries)?;
+                writer.finish()?;
+            }
+        }
 
+        Footer::new(entries_offset).write_to(&mut self.writer)?;
         Ok(())
     }
 
@@ -316,13 +881,159 @@ where
 
 impl<'a, W> Drop for Archive<'a, W>
 where
-    W: Write + Seek,
+    W: Write,
 {
     /// finalize the archive
     fn drop(&mut self) {}
 }
 
-trait ToBytes {
+/// Record a checkpoint if one is due, with the added entries before
+/// `completed` complete
+fn record_checkpoint<W: Write>(
+    checkpoint: &mut Option<CheckpointWriter>,
+    writer: &mut PositionWriter<W>,
+    entries: &[(PathBuf, Entry)],
+    completed: usize,
+    cipher: Option<&Cipher>,
+) -> io::Result<()> {
+    match checkpoint {
+        Some(c) if c.is_due() => {
+            writer.flush()?;
+            c.record(
+                writer.position,
+                entries.iter().map(|x| &x.1),
+                completed,
+                cipher,
+            )
+        }
+        _ => Ok(()),
+    }
+}
+
+fn write_entry_table<'e, W, I>(writer: &mut W, entries: I) -> io::Result<()>
+where
+    W: Write,
+    I: IntoIterator<Item = &'e Entry>,
+{
+    for entry in entries {
+        let checksum = entry.crc_checksum();
+        entry.write_to(writer)?;
+        writer.write_u32::<LittleEndian>(checksum)?;
+    }
+    Ok(())
+}
+
+/// Copy `from` to `to`, encrypted as a separate stream if `cipher` is given,
+/// and return the written size
+fn copy_content<R, W>(from: &mut R, to: &mut W, cipher: Option<&Cipher>) -> io::Result<u64>
+where
+    R: Read,
+    W: Write,
+{
+    match cipher {
+        None => io::copy(from, to),
+        Some(cipher) => {
+            let mut writer = EncryptWriter::new(cipher, to);
+            io::copy(from, &mut writer)?;
+            writer.finish()
+        }
+    }
+}
+
+/// Copy compressed content to the content area, and fill in the storage fields
+/// of `entry`
+///
+/// Chunks already written are referenced instead of copied again. With
+/// encryption, stored sizes are of the encrypted content, while the checksum
+/// still covers the compressed content.
+fn write_compressed<W: Write>(
+    writer: &mut PositionWriter<W>,
+    last_content_offset: &mut u64,
+    chunk_index: &mut HashMap<(Compression, [u8; 32]), (u64, u32)>,
+    cipher: Option<&Cipher>,
+    entry: &mut Entry,
+    compressed: Compressed,
+) -> Result<()> {
+    let mut reader = compressed.data.into_reader()?;
+
+    entry.compression = compressed.compression;
+    entry.stored_size = compressed.stored_size;
+    entry.content_checksum = compressed.content_checksum;
+    entry.chunks.clear();
+    if let Some((function, hash)) = compressed.hash {
+        hash::set_hash(entry, function, hash);
+    }
+
+    if compressed.chunks.is_empty() {
+        entry.stored_size = copy_content(&mut reader, writer, cipher)?;
+        entry.offset = *last_content_offset;
+        *last_content_offset += entry.stored_size;
+        return Ok(());
+    }
+
+    entry.offset = 0;
+    entry.stored_size = 0;
+    for chunk in compressed.chunks {
+        let mut data = (&mut reader).take(chunk.stored_size as u64);
+        let key = (compressed.compression, chunk.hash);
+        let (offset, stored_size) = match chunk_index.get(&key) {
+            Some(&(offset, stored_size)) => {
+                io::copy(&mut data, &mut io::sink())?;
+                (offset, stored_size)
+            }
+            None => {
+                let offset = *last_content_offset;
+                let stored_size = copy_content(&mut data, writer, cipher)? as u32;
+                *last_content_offset += stored_size as u64;
+                chunk_index.insert(key, (offset, stored_size));
+                (offset, stored_size)
+            }
+        };
+        entry.stored_size += stored_size as u64;
+        entry.chunks.push(Chunk {
+            offset,
+            stored_size,
+            original_size: chunk.original_size,
+            hash: chunk.hash,
+        });
+    }
+    Ok(())
+}
+
+/// Keeps track of the written size, in place of `stream_position`
+struct PositionWriter<W>
+where
+    W: Write,
+{
+    inner: W,
+    position: u64,
+}
+
+impl<W> PositionWriter<W>
+where
+    W: Write,
+{
+    fn new(inner: W) -> Self {
+        Self { inner, position: 0 }
+    }
+}
+
+impl<W> Write for PositionWriter<W>
+where
+    W: Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let size = self.inner.write(buf)?;
+        self.position += size as u64;
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub trait ToBytes {
     fn to_bytes(&self) -> Option<Vec<u8>>;
 }
 
@@ -339,3 +1050,73 @@ impl ToBytes for OsStr {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::ffi::OsStr;
+    use std::fs;
+    use std::path::Path;
+
+    use super::Archive;
+    use crate::compressors::{create_compressor, CodecOptions, CompressionPolicy, Level};
+    use crate::Compression;
+
+    fn build(src: &Path, jobs: usize, chunked: bool) -> Vec<u8> {
+        let mut output = Vec::new();
+        let compressor = create_compressor(
+            Compression::Zstd,
+            Level::Numeric(3),
+            &CodecOptions::default(),
+        );
+        let policy = CompressionPolicy::new(Compression::Zstd, compressor);
+        let mut archive = Archive::new(&mut output, policy).unwrap();
+        archive.header.creation_time = 0;
+        archive.set_jobs(jobs);
+        archive.set_chunked(chunked);
+        for entry in walkdir::WalkDir::new(src).min_depth(1).sort_by_file_name() {
+            let entry = entry.unwrap();
+            let path = entry.path().strip_prefix(src).unwrap();
+            archive.add_path(path.as_os_str(), entry.path()).unwrap();
+        }
+        // a hard link
+        archive
+            .add_path(OsStr::new("again"), src.join("1"))
+            .unwrap();
+        archive.write().unwrap();
+        archive.flush().unwrap();
+        drop(archive);
+        output
+    }
+
+    #[test]
+    fn jobs_same_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path();
+        fs::create_dir(src.join("sub")).unwrap();
+        for i in 0..40_usize {
+            // sizes from empty to a few blocks, some compressing well
+            let content = (0..i * i * 997)
+                .map(|x| {
+                    if i % 2 == 0 {
+                        (x % 7) as u8
+                    } else {
+                        ((x * x) >> 5) as u8
+                    }
+                })
+                .collect::<Vec<_>>();
+            let name = if i % 3 == 0 {
+                format!("sub/{}", i)
+            } else {
+                i.to_string()
+            };
+            fs::write(src.join(name), content).unwrap();
+        }
+        fs::write(src.join("big"), b"0123456789abcdef".repeat(300_000)).unwrap();
+
+        for chunked in [false, true] {
+            let serial = build(src, 1, chunked);
+            assert_eq!(build(src, 4, chunked), serial);
+            assert_eq!(build(src, 16, chunked), serial);
+        }
+    }
+}
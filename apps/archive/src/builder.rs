@@ -0,0 +1,339 @@
+//! Creating archives from code
+//!
+//! [`ArchiveBuilder`] collects files from the filesystem and contents given in
+//! memory, and writes them all in [`ArchiveBuilder::finish`], the same as
+//! `archive create` does. Archives are read back with
+//! [`ArchiveReader`](crate::reader::ArchiveReader).
+//!
+//! ```no_run
+//! use archive::builder::{ArchiveBuilder, EntryMetadata};
+//! use archive::compressors::Level;
+//! use archive::reader::ArchiveReader;
+//! use archive::Compression;
+//! use std::io::Read;
+//!
+//! let mut builder = ArchiveBuilder::create("out.arc", Compression::Zstd, Level::Numeric(3))?;
+//! builder.add_bytes(b"hello.txt", b"hello", &EntryMetadata::default())?;
+//! builder.add_dir_all(b"src", "./src")?;
+//! builder.finish()?;
+//!
+//! let reader = ArchiveReader::new("out.arc")?;
+//! let mut content = String::new();
+//! reader.open_entry(b"hello.txt")?.read_to_string(&mut content)?;
+//! # Ok::<(), archive::errors::Error>(())
+//! ```
+
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::time::SystemTime;
+
+use pathdiff::diff_paths;
+use tempfile::TempDir;
+
+use crate::archive::{Archive, ToBytes, DICTIONARY_SIZE};
+use crate::compressors::{create_compressor, CodecOptions, CompressionPolicy, Level};
+use crate::convert::permission_mode;
+use crate::create::{check_dictionary, compression_description};
+use crate::errors::*;
+use crate::hash::HashFunction;
+use crate::{Compression, Entry, FileType, Info, OsStrExt};
+
+/// Metadata of entries added from memory
+#[derive(Debug, Clone)]
+pub struct EntryMetadata {
+    /// permission bits, like 0o644
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: SystemTime,
+}
+
+impl Default for EntryMetadata {
+    /// Mode 0644, owned by root, modified now
+    fn default() -> Self {
+        Self {
+            mode: 0o644,
+            uid: 0,
+            gid: 0,
+            mtime: SystemTime::now(),
+        }
+    }
+}
+
+pub struct ArchiveBuilder<W: Write> {
+    archive: Archive<'static, BufWriter<W>>,
+    info: Info,
+    /// contents added from memory, created on first use
+    staging: Option<TempDir>,
+    staged_count: usize,
+    zstd_dictionary: bool,
+}
+
+impl ArchiveBuilder<File> {
+    /// Create the archive file at `path`
+    pub fn create<P: AsRef<Path>>(path: P, method: Compression, level: Level) -> Result<Self> {
+        Self::new(File::create(path)?, method, level)
+    }
+}
+
+impl<W: Write> ArchiveBuilder<W> {
+    /// `method` is one of the built-in compressors (not
+    /// [`Compression::External`])
+    pub fn new(writer: W, method: Compression, level: Level) -> Result<Self> {
+        if method == Compression::External {
+            return Err(Error::InvalidCompressor);
+        }
+        level.check(method)?;
+        let compressor = create_compressor(method, level, &CodecOptions::default());
+        let compression = CompressionPolicy::new(method, compressor);
+        let archive = Archive::new(BufWriter::new(writer), compression)?;
+        Ok(Self {
+            archive,
+            info: Info {
+                compression_method: compression_description(method, level),
+                comment: None,
+                parent: None,
+                deleted: Vec::new(),
+            },
+            staging: None,
+            staged_count: 0,
+            zstd_dictionary: false,
+        })
+    }
+
+    /// Number of compression threads
+    pub fn jobs(&mut self, jobs: usize) -> &mut Self {
+        self.archive.set_jobs(jobs);
+        self
+    }
+
+    /// Whether to record extended attributes of added files
+    pub fn xattrs(&mut self, xattrs: bool) -> &mut Self {
+        self.archive.set_xattrs(xattrs);
+        self
+    }
+
+    /// Whether to store contents as deduplicated chunks
+    pub fn chunked(&mut self, chunked: bool) -> &mut Self {
+        self.archive.set_chunked(chunked);
+        self
+    }
+
+    /// The hash function of the original content hashes to record
+    pub fn content_hash(&mut self, function: Option<HashFunction>) -> &mut Self {
+        self.archive.set_content_hash(function);
+        self
+    }
+
+    /// Whether to train a zstd dictionary from the small files added, when
+    /// finishing
+    ///
+    /// Without enough small files, the archive is written without it.
+    pub fn zstd_dictionary(&mut self, zstd_dictionary: bool) -> &mut Self {
+        self.zstd_dictionary = zstd_dictionary;
+        self
+    }
+
+    pub fn comment(&mut self, comment: &str) -> &mut Self {
+        self.info.comment = Some(comment.into());
+        self
+    }
+
+    pub fn encryption(&mut self, passphrase: &str) -> Result<&mut Self> {
+        self.archive.set_encryption(passphrase)?;
+        Ok(self)
+    }
+
+    /// Add the file at `path` as `stored_path`, with its metadata
+    ///
+    /// Directories are added without their content; see
+    /// [`ArchiveBuilder::add_dir_all`].
+    pub fn add_file<P: AsRef<Path>>(&mut self, stored_path: &[u8], path: P) -> Result<()> {
+        let path = path.as_ref();
+        let mut stored_path = stored_path.to_vec();
+        if path.symlink_metadata()?.is_dir() && !stored_path.ends_with(b"/") {
+            stored_path.push(b'/');
+        }
+        self.archive.add_path(OsStr::from_bytes(&stored_path), path)
+    }
+
+    /// Add a regular file with `data` as its content
+    pub fn add_bytes(
+        &mut self,
+        stored_path: &[u8],
+        data: &[u8],
+        metadata: &EntryMetadata,
+    ) -> Result<()> {
+        if self.staging.is_none() {
+            self.staging = Some(tempfile::tempdir()?);
+        }
+        let content = self
+            .staging
+            .as_ref()
+            .unwrap()
+            .path()
+            .join(self.staged_count.to_string());
+        self.staged_count += 1;
+        File::create(&content)?.write_all(data)?;
+
+        let mut entry = Entry::new(stored_path.to_vec(), FileType::Regular);
+        entry.original_size = data.len() as u64;
+        entry.permission_mode = permission_mode(FileType::Regular, metadata.mode);
+        entry.owner_id = metadata.uid;
+        entry.group_id = metadata.gid;
+        entry.modification_time = metadata.mtime.into();
+        self.archive.add_entry(entry, content);
+        Ok(())
+    }
+
+    /// Add the directory `dir` and everything under it, stored under
+    /// `stored_prefix`
+    ///
+    /// With an empty prefix, the content is stored at the top level, and the
+    /// directory itself isn't added.
+    pub fn add_dir_all<P: AsRef<Path>>(&mut self, stored_prefix: &[u8], dir: P) -> Result<()> {
+        let dir = dir.as_ref();
+        let mut prefix = stored_prefix.to_vec();
+        while prefix.ends_with(b"/") {
+            prefix.pop();
+        }
+
+        let min_depth = if prefix.is_empty() { 1 } else { 0 };
+        for dir_entry in walkdir::WalkDir::new(dir).min_depth(min_depth) {
+            let dir_entry = dir_entry?;
+            let relative = diff_paths(dir_entry.path(), dir).unwrap();
+            let Some(relative) = relative.as_os_str().to_bytes() else {
+                return Err(Error::Others(format!(
+                    "Invalid path name: {}",
+                    dir_entry.path().display()
+                )));
+            };
+
+            let mut stored_path = prefix.clone();
+            if !stored_path.is_empty() && !relative.is_empty() {
+                stored_path.push(b'/');
+            }
+            stored_path.extend_from_slice(&relative);
+            if dir_entry.file_type().is_dir() {
+                stored_path.push(b'/');
+            }
+            self.archive
+                .add_path(OsStr::from_bytes(&stored_path), dir_entry.path())?;
+        }
+        Ok(())
+    }
+
+    /// Write the archive
+    pub fn finish(mut self) -> Result<()> {
+        if self.zstd_dictionary {
+            check_dictionary(self.archive.compression_method())?;
+            if self.archive.train_dictionary(DICTIONARY_SIZE)? {
+                self.info.compression_method.push_str(",dictionary");
+            }
+        }
+        self.archive.set_info(&self.info);
+        self.archive.write()?;
+        self.archive.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::time::{Duration, SystemTime};
+
+    use super::{ArchiveBuilder, EntryMetadata};
+    use crate::compressors::Level;
+    use crate::reader::ArchiveReader;
+    use crate::test_utils::read_entry;
+    use crate::{Compression, FileType};
+
+    #[test]
+    fn build_and_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        fs::create_dir_all(src.join("sub")).unwrap();
+        fs::write(src.join("a.txt"), b"aaa").unwrap();
+        let big = b"0123456789".repeat(100_000);
+        fs::write(src.join("sub/big"), &big).unwrap();
+        let output = dir.path().join("out.arc");
+
+        for chunked in [false, true] {
+            let mut builder =
+                ArchiveBuilder::create(&output, Compression::Zstd, Level::Numeric(3)).unwrap();
+            builder.chunked(chunked).comment("built");
+            let metadata = EntryMetadata {
+                mode: 0o600,
+                uid: 1000,
+                gid: 100,
+                mtime: SystemTime::UNIX_EPOCH + Duration::new(1700000000, 5),
+            };
+            builder
+                .add_bytes(b"hello.txt", b"hello", &metadata)
+                .unwrap();
+            builder.add_file(b"single.txt", src.join("a.txt")).unwrap();
+            builder.add_dir_all(b"tree", &src).unwrap();
+            builder.finish().unwrap();
+
+            let reader = ArchiveReader::new(&output).unwrap();
+            assert_eq!(read_entry(&reader, b"hello.txt"), b"hello");
+            assert_eq!(read_entry(&reader, b"./single.txt"), b"aaa");
+            assert_eq!(read_entry(&reader, b"tree/a.txt"), b"aaa");
+            assert_eq!(read_entry(&reader, b"tree/sub/big"), big);
+
+            let entry = reader.find_entry(b"hello.txt").unwrap().unwrap();
+            assert_eq!(entry.file_type(), FileType::Regular);
+            assert_eq!(entry.original_size(), 5);
+            assert_eq!(entry.permission_mode(), 0o100600);
+            assert_eq!((entry.owner_id(), entry.group_id()), (1000, 100));
+            assert_eq!(entry.modification_time().seconds(), 1700000000);
+            assert_eq!(entry.modification_time().nanoseconds(), 5);
+            assert_eq!(entry.compression(), Compression::Zstd);
+
+            let dir_entry = reader.find_entry(b"tree/sub").unwrap().unwrap();
+            assert_eq!(dir_entry.path(), b"tree/sub/");
+            assert_eq!(dir_entry.file_type(), FileType::Directory);
+            assert!(reader.open_entry(b"tree/sub").is_err());
+            assert!(reader.open_entry(b"missing").is_err());
+        }
+    }
+
+    #[test]
+    fn dictionary() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("out.arc");
+        let contents = (0..200)
+            .map(|x| {
+                format!(
+                    r#"{{"id": {}, "name": "item-{}", "tags": ["a", "b"]}}"#,
+                    x,
+                    x * 7
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let mut builder =
+            ArchiveBuilder::create(&output, Compression::Zstd, Level::Numeric(19)).unwrap();
+        builder.zstd_dictionary(true);
+        for (i, x) in contents.iter().enumerate() {
+            let path = format!("{}.json", i);
+            builder
+                .add_bytes(path.as_bytes(), x.as_bytes(), &EntryMetadata::default())
+                .unwrap();
+        }
+        builder.finish().unwrap();
+
+        let reader = ArchiveReader::new(&output).unwrap();
+        assert!(reader.header.dictionary().is_some());
+        for (i, x) in contents.iter().enumerate() {
+            let path = format!("{}.json", i);
+            assert_eq!(read_entry(&reader, path.as_bytes()), x.as_bytes());
+        }
+
+        let builder = ArchiveBuilder::create(&output, Compression::Zstd, Level::Numeric(23));
+        assert!(builder.is_err());
+    }
+}
@@ -0,0 +1,484 @@
+//! Checkpoints of archive creation
+//!
+//! While `create` writes to a file, it records checkpoints every so often in
+//! `<archive>.checkpoint`: the entries whose content is completely written,
+//! and the archive size covering them. The checkpoint file is a journal, with
+//! a prelude pairing it with the archive (the creation time and content offset
+//! from the header), followed by blocks of:
+//!
+//! | position: u64 | entry count: u32 | length: u32 | entries | CRC32 |
+//!
+//! Each block holds the entries completed since the previous one, sealed in
+//! encrypted archives, and the CRC covers the whole block. A block torn by an
+//! interruption fails the check and ends the journal.
+//!
+//! A partially written archive is the header and content up to some point;
+//! [`PartialArchive`] cuts it back to the last checkpoint, and continues it
+//! like an archive modified in place. The journal is removed once the archive
+//! is complete.
+
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use crc_lib::Crc;
+
+use crate::archive::Archive;
+use crate::compressors::CompressionPolicy;
+use crate::encryption::{self, Cipher};
+use crate::errors::*;
+use crate::update::write_entry_count;
+use crate::{Entry, Header, ReadFrom, WriteTo, ENTRY_CRC_32, FILE_MAGIC, VERSION};
+
+pub const CHECKPOINT_MAGIC: &[u8; 8] = b"Chkpoint";
+
+/// The checkpoint journal of the archive at `archive`
+pub fn checkpoint_path(archive: &Path) -> PathBuf {
+    let mut path = archive.as_os_str().to_owned();
+    path.push(".checkpoint");
+    path.into()
+}
+
+/// Records checkpoints while an archive is written
+pub struct CheckpointWriter {
+    path: PathBuf,
+    interval: Duration,
+    file: Option<File>,
+    last: Instant,
+    /// count of added entries recorded
+    recorded: usize,
+}
+
+impl CheckpointWriter {
+    pub fn new(path: PathBuf, interval: Duration) -> Self {
+        Self {
+            path,
+            interval,
+            file: None,
+            last: Instant::now(),
+            recorded: 0,
+        }
+    }
+
+    /// Start the journal, with `existing` as the entries already complete at
+    /// `position`
+    ///
+    /// An old journal is replaced only after the new one is written.
+    pub fn start<'e, I>(
+        &mut self,
+        header: &Header,
+        position: u64,
+        existing: I,
+        cipher: Option<&Cipher>,
+    ) -> io::Result<()>
+    where
+        I: IntoIterator<Item = &'e Entry>,
+    {
+        let mut new_path = self.path.clone().into_os_string();
+        new_path.push(".new");
+        let mut file = File::create(&new_path)?;
+        file.write_all(CHECKPOINT_MAGIC)?;
+        file.write_i64::<LittleEndian>(header.creation_time)?;
+        file.write_u64::<LittleEndian>(header.content_offset)?;
+        file.write_all(&block(position, existing, cipher))?;
+        file.sync_data()?;
+        fs::rename(&new_path, &self.path)?;
+
+        self.file = Some(file);
+        self.last = Instant::now();
+        self.recorded = 0;
+        Ok(())
+    }
+
+    pub fn is_due(&self) -> bool {
+        self.file.is_some() && self.last.elapsed() >= self.interval
+    }
+
+    /// Record the first `completed` of `added` as complete at `position`
+    ///
+    /// The archive content up to `position` needs to be flushed first.
+    pub fn record<'e, I>(
+        &mut self,
+        position: u64,
+        added: I,
+        completed: usize,
+        cipher: Option<&Cipher>,
+    ) -> io::Result<()>
+    where
+        I: IntoIterator<Item = &'e Entry>,
+    {
+        let Some(file) = &mut self.file else {
+            return Ok(());
+        };
+        let entries = added.into_iter().take(completed).skip(self.recorded);
+        file.write_all(&block(position, entries, cipher))?;
+        self.last = Instant::now();
+        self.recorded = completed;
+        Ok(())
+    }
+
+    /// Remove the journal, once the archive is complete
+    pub fn remove(self) -> io::Result<()> {
+        if self.file.is_none() {
+            return Ok(());
+        }
+        drop(self.file);
+        fs::remove_file(self.path)
+    }
+}
+
+fn block<'e, I>(position: u64, entries: I, cipher: Option<&Cipher>) -> Vec<u8>
+where
+    I: IntoIterator<Item = &'e Entry>,
+{
+    let mut count = 0_u32;
+    let mut payload = Vec::new();
+    for entry in entries {
+        entry.write_to(&mut payload).unwrap();
+        count += 1;
+    }
+    if let Some(cipher) = cipher {
+        payload = cipher.seal(&payload);
+    }
+
+    let mut block = Vec::with_capacity(payload.len() + 20);
+    block.write_u64::<LittleEndian>(position).unwrap();
+    block.write_u32::<LittleEndian>(count).unwrap();
+    block
+        .write_u32::<LittleEndian>(payload.len() as u32)
+        .unwrap();
+    block.extend_from_slice(&payload);
+    let checksum = Crc::<u32>::new(&ENTRY_CRC_32).checksum(&block);
+    block.write_u32::<LittleEndian>(checksum).unwrap();
+    block
+}
+
+/// Read the next block; `None` at the end of the journal, including torn
+/// blocks
+fn read_block<R: Read>(reader: &mut R) -> Option<(u64, u32, Vec<u8>)> {
+    let mut fields = [0_u8; 16];
+    reader.read_exact(&mut fields).ok()?;
+    let mut cursor = &fields[..];
+    let position = cursor.read_u64::<LittleEndian>().unwrap();
+    let count = cursor.read_u32::<LittleEndian>().unwrap();
+    let length = cursor.read_u32::<LittleEndian>().unwrap();
+
+    let mut payload = Vec::new();
+    reader.take(length as u64).read_to_end(&mut payload).ok()?;
+    let checksum = reader.read_u32::<LittleEndian>().ok()?;
+    if payload.len() != length as usize {
+        return None;
+    }
+    let crc = Crc::<u32>::new(&ENTRY_CRC_32);
+    let mut digest = crc.digest();
+    digest.update(&fields);
+    digest.update(&payload);
+    (digest.finalize() == checksum).then_some((position, count, payload))
+}
+
+/// An archive whose creation got interrupted, as of its last checkpoint
+pub struct PartialArchive {
+    path: PathBuf,
+    /// with the info JSON and dictionary decrypted
+    pub header: Header,
+    /// the archive size at the checkpoint
+    pub position: u64,
+    /// the current size, including content after the checkpoint
+    pub size: u64,
+    pub entries: Vec<Entry>,
+}
+
+impl PartialArchive {
+    /// Open the archive at `path` with its checkpoint journal, asking for the
+    /// passphrase if it's encrypted
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut journal = match File::open(checkpoint_path(path)) {
+            Ok(f) => io::BufReader::new(f),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(Error::Others(format!(
+                    "No checkpoint of {} found",
+                    path.display()
+                )));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let mut file = File::open(path)?;
+        let size = file.metadata()?.len();
+        let mut header = Header::read_from(&mut file)?;
+        if &header.magic_number != FILE_MAGIC {
+            return Err(Error::InvalidFileType);
+        }
+        if header.version != VERSION {
+            return Err(Error::UnsupportedVersion(header.version));
+        }
+
+        let mut magic = [0_u8; CHECKPOINT_MAGIC.len()];
+        journal.read_exact(&mut magic)?;
+        let creation_time = journal.read_i64::<LittleEndian>()?;
+        let content_offset = journal.read_u64::<LittleEndian>()?;
+        if &magic != CHECKPOINT_MAGIC
+            || creation_time != header.creation_time
+            || content_offset != header.content_offset
+        {
+            return Err(Error::Others(format!(
+                "The checkpoint doesn't belong to {}",
+                path.display()
+            )));
+        }
+
+        let cipher = match &header.encryption {
+            Some(e) => {
+                let c = Cipher::derive(&encryption::passphrase(false)?, e)?;
+                header.info_json = c.open_string(&header.info_json)?;
+                if !header.dictionary.is_empty() {
+                    header.dictionary = c.open(&header.dictionary)?;
+                }
+                Some(c)
+            }
+            None => None,
+        };
+
+        let mut position = None;
+        let mut entries = Vec::new();
+        while let Some((block_position, count, payload)) = read_block(&mut journal) {
+            // content written after the journal gets lost
+            if block_position > size {
+                break;
+            }
+            let payload = match &cipher {
+                Some(c) => c.open(&payload)?,
+                None => payload,
+            };
+            let mut reader = &payload[..];
+            for _ in 0..count {
+                entries.push(Entry::read_from_version(&mut reader, &header)?);
+            }
+            position = Some(block_position);
+        }
+        let Some(position) = position else {
+            return Err(Error::Others(format!(
+                "The checkpoint of {} is damaged",
+                path.display()
+            )));
+        };
+
+        Ok(Self {
+            path: path.into(),
+            header,
+            position,
+            size,
+            entries,
+        })
+    }
+
+    /// Continue the archive after the checkpoint, dropping what's written
+    /// after it
+    ///
+    /// The entries of the checkpoint are the existing ones of the archive, and
+    /// the entry count in the header needs updating once it's written (see
+    /// [`write_entry_count`]).
+    pub fn resume<'a>(
+        self,
+        compression: CompressionPolicy<'a>,
+    ) -> Result<Archive<'a, BufWriter<Box<dyn Write>>>> {
+        let mut file = OpenOptions::new().write(true).open(&self.path)?;
+        file.set_len(self.position)?;
+        file.seek(SeekFrom::Start(self.position))?;
+        let writer: Box<dyn Write> = Box::new(file);
+        Archive::resume(
+            BufWriter::new(writer),
+            compression,
+            self.header,
+            self.entries,
+            self.position,
+        )
+    }
+
+    /// Finish the archive with the entries of the checkpoint, and remove the
+    /// journal
+    ///
+    /// Returns the count of entries.
+    pub fn finish(self, compression: CompressionPolicy) -> Result<u64> {
+        let path = self.path.clone();
+        let mut archive = self.resume(compression)?;
+        archive.write_appended()?;
+        archive.flush()?;
+        let entry_count = archive.entry_count();
+        drop(archive);
+
+        write_entry_count(
+            &mut OpenOptions::new().read(true).write(true).open(&path)?,
+            entry_count,
+        )?;
+        fs::remove_file(checkpoint_path(&path))?;
+        Ok(entry_count)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{block, read_block};
+    use crate::{Entry, FileType};
+
+    #[test]
+    fn blocks() {
+        let entries = [
+            Entry::new(b"a".to_vec(), FileType::Regular),
+            Entry::new(b"b/".to_vec(), FileType::Directory),
+        ];
+        let mut journal = block(100, &entries, None);
+        let second = block(200, &entries[..0], None);
+        journal.extend_from_slice(&second);
+        // torn by an interruption
+        journal.extend_from_slice(&block(300, &entries, None)[..30]);
+
+        let mut reader = &journal[..];
+        let (position, count, payload) = read_block(&mut reader).unwrap();
+        assert_eq!((position, count), (100, 2));
+        assert!(payload.starts_with(b"Entry"));
+        assert_eq!(read_block(&mut reader).unwrap().0, 200);
+        assert!(read_block(&mut reader).is_none());
+
+        let mut damaged = second.clone();
+        damaged[3] ^= 1;
+        assert!(read_block(&mut &damaged[..]).is_none());
+    }
+
+    /// Interruptions by a source file failing to read: `/proc/self/mem`
+    #[cfg(target_os = "linux")]
+    mod interrupted {
+        use std::fs;
+        use std::os::unix::ffi::OsStrExt;
+        use std::path::Path;
+
+        use crate::checkpoint::{checkpoint_path, PartialArchive};
+        use crate::reader::ArchiveReader;
+        use crate::test_utils::{read_entry, run};
+        use crate::Entry;
+
+        fn write_tree(src: &Path) {
+            fs::create_dir_all(src.join("c")).unwrap();
+            fs::write(src.join("a"), vec![b'a'; 100_000]).unwrap();
+            fs::write(src.join("b"), b"b1").unwrap();
+            fs::write(src.join("c/d"), b"d1").unwrap();
+        }
+
+        /// `src` as passed with `-C /`
+        fn relative(src: &Path) -> &str {
+            src.strip_prefix("/").unwrap().to_str().unwrap()
+        }
+
+        fn stored_path(src: &Path, name: &str) -> Vec<u8> {
+            Path::new(relative(src))
+                .join(name)
+                .as_os_str()
+                .as_bytes()
+                .to_vec()
+        }
+
+        fn sorted_paths<'e, I: IntoIterator<Item = &'e Entry>>(entries: I) -> Vec<Vec<u8>> {
+            let mut paths = entries
+                .into_iter()
+                .map(|x| x.path.clone())
+                .collect::<Vec<_>>();
+            paths.sort();
+            paths
+        }
+
+        /// Create `archive` of `src`, with a later source file failing to read
+        ///
+        /// Returns the entries of the last checkpoint.
+        fn create_interrupted(archive: &Path, src: &Path) -> Vec<Entry> {
+            // reading its first page fails
+            let unreadable = "proc/self/mem";
+            let archive = archive.to_str().unwrap();
+            let result = run(&[
+                "create",
+                archive,
+                "-C",
+                "/",
+                "--checkpoint-interval",
+                "0",
+                relative(src),
+                unreadable,
+            ]);
+            assert!(result.is_err());
+            assert!(checkpoint_path(Path::new(archive)).exists());
+            assert!(ArchiveReader::new(archive).is_err());
+
+            let partial = PartialArchive::open(Path::new(archive)).unwrap();
+            // everything before the unreadable file
+            let expected = ["", "a", "b", "c/", "c/d"]
+                .map(|x| stored_path(src, x))
+                .to_vec();
+            assert_eq!(sorted_paths(&partial.entries), expected);
+            partial.entries
+        }
+
+        #[test]
+        fn resume() {
+            let dir = tempfile::tempdir().unwrap();
+            let src = dir.path().join("src");
+            write_tree(&src);
+            let archive = dir.path().join("out.arc");
+            let completed = create_interrupted(&archive, &src);
+
+            // the unreadable file is left out, a completed file changes, and
+            // another one is new
+            fs::write(src.join("b"), b"b2, changed").unwrap();
+            fs::write(src.join("e"), b"e1").unwrap();
+            run(&[
+                "create",
+                archive.to_str().unwrap(),
+                "-C",
+                "/",
+                "--resume",
+                relative(&src),
+            ])
+            .unwrap();
+            assert!(!checkpoint_path(&archive).exists());
+
+            let reader = ArchiveReader::new(&archive).unwrap();
+            let entries = reader.entries().map(|x| x.unwrap()).collect::<Vec<_>>();
+            let expected = ["", "a", "b", "c/", "c/d", "e"]
+                .map(|x| stored_path(&src, x))
+                .to_vec();
+            assert_eq!(sorted_paths(&entries), expected);
+            for name in ["a", "b", "c/d", "e"] {
+                let content = read_entry(&reader, &stored_path(&src, name));
+                assert_eq!(content, fs::read(src.join(name)).unwrap(), "{}", name);
+            }
+
+            // unchanged completed entries are kept where they are
+            for name in ["a", "c/d"] {
+                let path = stored_path(&src, name);
+                let offset =
+                    |entries: &[Entry]| entries.iter().find(|x| x.path == path).unwrap().offset;
+                assert_eq!(offset(&entries), offset(&completed), "{}", name);
+            }
+        }
+
+        #[test]
+        fn truncate() {
+            let dir = tempfile::tempdir().unwrap();
+            let src = dir.path().join("src");
+            write_tree(&src);
+            let archive = dir.path().join("out.arc");
+            let completed = create_interrupted(&archive, &src);
+
+            run(&["test", archive.to_str().unwrap(), "--truncate"]).unwrap();
+            assert!(!checkpoint_path(&archive).exists());
+
+            let reader = ArchiveReader::new(&archive).unwrap();
+            let entries = reader.entries().map(|x| x.unwrap()).collect::<Vec<_>>();
+            assert_eq!(sorted_paths(&entries), sorted_paths(&completed));
+            for name in ["a", "b", "c/d"] {
+                let content = read_entry(&reader, &stored_path(&src, name));
+                assert_eq!(content, fs::read(src.join(name)).unwrap(), "{}", name);
+            }
+        }
+    }
+}
@@ -0,0 +1,191 @@
+//! Content-defined chunking
+//!
+//! In the chunked mode, file contents are split where a rolling (gear) hash of
+//! the last bytes hits a mask, so boundaries move along with inserted or removed
+//! data instead of shifting every chunk after it. Each chunk is compressed on its
+//! own and identified by the BLAKE3 hash of its original data; chunks already
+//! written are referenced again instead of being stored twice.
+//!
+//! The cut points are found as in FastCDC: a harder mask before the average
+//! size and an easier one after it keep chunk sizes close to the average.
+
+use std::io;
+use std::io::{Read, Write};
+
+use crate::compressors::Compress;
+use crate::errors::Result;
+
+pub const MIN_CHUNK_SIZE: usize = 16 * 1024;
+pub const AVERAGE_CHUNK_SIZE: usize = 64 * 1024;
+pub const MAX_CHUNK_SIZE: usize = 256 * 1024;
+
+/// 18 bits; used before reaching the average size
+const MASK_HARD: u64 = !0 << (64 - 18);
+/// 14 bits; used after the average size
+const MASK_EASY: u64 = !0 << (64 - 14);
+
+/// Random values for the gear hash, from a fixed seed so chunk boundaries are
+/// stable across runs
+static GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    // splitmix64
+    let mut table = [0_u64; 256];
+    let mut state = 0x6172_6368_6976_6521_u64;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// Find the length of the first chunk in `data`
+///
+/// `data` should be no longer than [`MAX_CHUNK_SIZE`], and its whole length is
+/// returned if there's no cut point.
+fn cut_point(data: &[u8]) -> usize {
+    if data.len() <= MIN_CHUNK_SIZE {
+        return data.len();
+    }
+    let normal_size = data.len().min(AVERAGE_CHUNK_SIZE);
+    let mut hash = 0_u64;
+    for (i, &b) in data.iter().enumerate().skip(MIN_CHUNK_SIZE) {
+        hash = (hash << 1).wrapping_add(GEAR[b as usize]);
+        let mask = if i < normal_size {
+            MASK_HARD
+        } else {
+            MASK_EASY
+        };
+        if hash & mask == 0 {
+            return i + 1;
+        }
+    }
+    data.len()
+}
+
+/// Splits a stream into content-defined chunks
+pub struct Chunker<R: Read> {
+    reader: R,
+    buf: Vec<u8>,
+    eof: bool,
+}
+
+impl<R: Read> Chunker<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            buf: Vec::with_capacity(MAX_CHUNK_SIZE),
+            eof: false,
+        }
+    }
+
+    /// Returns `None` at the end of the stream
+    pub fn next_chunk(&mut self) -> io::Result<Option<Vec<u8>>> {
+        self.fill()?;
+        if self.buf.is_empty() {
+            return Ok(None);
+        }
+        let size = cut_point(&self.buf);
+        let rest = self.buf.split_off(size);
+        Ok(Some(std::mem::replace(&mut self.buf, rest)))
+    }
+
+    fn fill(&mut self) -> io::Result<()> {
+        while !self.eof && self.buf.len() < MAX_CHUNK_SIZE {
+            let len = self.buf.len();
+            self.buf.resize(MAX_CHUNK_SIZE, 0);
+            match self.reader.read(&mut self.buf[len..]) {
+                Ok(0) => {
+                    self.eof = true;
+                    self.buf.truncate(len);
+                }
+                Ok(n) => self.buf.truncate(len + n),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => self.buf.truncate(len),
+                Err(e) => {
+                    self.buf.truncate(len);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A chunk compressed by [`compress_chunks`]
+pub struct ChunkInfo {
+    pub hash: [u8; 32],
+    pub original_size: u32,
+    pub stored_size: u32,
+}
+
+/// Split `from` into chunks, and write each of them compressed to `to`
+/// one after another
+pub fn compress_chunks(
+    compressor: &dyn Compress,
+    from: &mut dyn Read,
+    to: &mut dyn Write,
+) -> Result<Vec<ChunkInfo>> {
+    let mut chunker = Chunker::new(from);
+    let mut chunks = Vec::new();
+    while let Some(data) = chunker.next_chunk()? {
+        let stored_size = compressor.compress_to(&mut &data[..], to)?;
+        chunks.push(ChunkInfo {
+            hash: *blake3::hash(&data).as_bytes(),
+            original_size: data.len() as u32,
+            stored_size: stored_size as u32,
+        });
+    }
+    Ok(chunks)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use super::{Chunker, MAX_CHUNK_SIZE, MIN_CHUNK_SIZE};
+    use crate::test_utils::pseudo_random;
+
+    fn chunks(data: &[u8]) -> Vec<Vec<u8>> {
+        let mut chunker = Chunker::new(data);
+        let mut chunks = Vec::new();
+        while let Some(c) = chunker.next_chunk().unwrap() {
+            chunks.push(c);
+        }
+        chunks
+    }
+
+    #[test]
+    fn chunk_sizes() {
+        let data = pseudo_random(4 * 1024 * 1024, 1);
+        let chunks = chunks(&data);
+        assert_eq!(chunks.concat(), data);
+        let (last, others) = chunks.split_last().unwrap();
+        assert!(last.len() <= MAX_CHUNK_SIZE);
+        assert!(others
+            .iter()
+            .all(|x| (MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE).contains(&x.len())));
+    }
+
+    #[test]
+    fn insertion_resyncs() {
+        let data = pseudo_random(4 * 1024 * 1024, 1);
+        let mut modified = data.clone();
+        modified.splice(1_000_000..1_000_000, *b"inserted");
+
+        let original = chunks(&data).into_iter().collect::<HashSet<_>>();
+        let modified = chunks(&modified);
+        let changed = modified.iter().filter(|x| !original.contains(*x)).count();
+        // only chunks around the insertion differ
+        assert!(
+            changed <= 2,
+            "{} of {} chunks changed",
+            changed,
+            modified.len()
+        );
+    }
+}
@@ -1,5 +1,9 @@
-use clap::{Arg, ArgAction, Command, ValueHint};
+use bytesize::ByteSize;
+use cfg_if::cfg_if;
+use clap::{value_parser, Arg, ArgAction, Command, ValueHint};
 
+use crate::hash::HASH_FUNCTION_NAMES;
+use crate::output::FORMAT_NAMES;
 use crate::{Compression, COMPRESSIONS};
 
 pub fn build_cli() -> Command {
@@ -18,69 +22,73 @@ pub fn build_cli() -> Command {
                 .arg(
                     Arg::new("output")
                         .required(true)
+                        .help("Output path, \"-\" for stdout")
                         .value_hint(ValueHint::FilePath),
                 )
+                .args(file_args(&compression_names))
+                .args(output_args())
                 .arg(
-                    Arg::new("path")
-                        .action(ArgAction::Append)
-                        .required(true)
-                        .value_hint(ValueHint::AnyPath),
-                )
-                .arg(
-                    Arg::new("base-dir")
-                        .short('C')
-                        .long("base-directory")
-                        .default_value(".")
-                        .value_hint(ValueHint::DirPath),
-                )
-                .arg(
-                    Arg::new("comment")
-                        .short('m')
-                        .long("comment")
-                        .help("Comment for this archive")
-                        .value_hint(ValueHint::Other),
+                    Arg::new("incremental")
+                        .short('i')
+                        .long("incremental")
+                        .value_name("reference")
+                        .value_hint(ValueHint::FilePath)
+                        .help("Create an incremental archive, only storing files new or changed since the reference archive"),
                 )
                 .arg(
-                    Arg::new("compress")
-                        .short('c')
-                        .long("compress")
-                        .value_name("method")
-                        .value_parser(compression_names)
-                        .help("Compression method used for each file, \"none\" for not used")
-                        .default_value("none")
-                        .ignore_case(true)
-                        .conflicts_with("data-filter-cmd"),
+                    Arg::new("checksum")
+                        .long("checksum")
+                        .action(ArgAction::SetTrue)
+                        .requires("incremental")
+                        .help("Also compare file contents against the reference archive: by its content hashes (see --hash), or else by compressing every file again"),
                 )
                 .arg(
-                    Arg::new("level")
-                        .short('l')
-                        .long("level")
-                        .help("Compression level")
-                        .default_value("best")
-                        .conflicts_with("data-filter-cmd")
-                        .value_hint(ValueHint::Other),
+                    Arg::new("resume")
+                        .long("resume")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("volume-size")
+                        .help("Continue an interrupted creation of the output from its last checkpoint, with the same arguments"),
                 )
                 .arg(
-                    Arg::new("data-filter-cmd")
-                        .long("data-filter")
-                        .action(ArgAction::Append)
-                        .num_args(1..)
-                        .allow_hyphen_values(true)
-                        .value_hint(ValueHint::Other)
-                        .help(
-                            "External data filter command line (for compression and decompression)",
-                        ),
+                    Arg::new("checkpoint-interval")
+                        .long("checkpoint-interval")
+                        .value_name("seconds")
+                        .value_parser(value_parser!(u64))
+                        .default_value("60")
+                        .help("Seconds between checkpoints recorded in OUTPUT.checkpoint, for --resume (0: after every file)"),
                 ),
         )
         .subcommand(
             Command::new("list")
                 .about("List archive content")
                 .alias("l")
+                .arg(format_arg())
                 .arg(
                     Arg::new("archive")
                         .help("Archive file path")
                         .required(true)
                         .value_hint(ValueHint::FilePath),
+                )
+                .arg(
+                    Arg::new("paths")
+                        .action(ArgAction::Append)
+                        .help("Paths or glob patterns to select; a directory selects everything under it")
+                        .value_hint(ValueHint::Other),
+                )
+                .arg(
+                    Arg::new("regex")
+                        .short('r')
+                        .long("regex")
+                        .action(ArgAction::SetTrue)
+                        .help("Treat <paths> as regular expressions searched in entry paths"),
+                )
+                .arg(
+                    Arg::new("exclude")
+                        .long("exclude")
+                        .value_name("pattern")
+                        .action(ArgAction::Append)
+                        .value_hint(ValueHint::Other)
+                        .help("Skip entries matching the glob pattern"),
                 ),
         )
         .subcommand(
@@ -103,9 +111,24 @@ pub fn build_cli() -> Command {
                 .arg(
                     Arg::new("paths")
                         .action(ArgAction::Append)
-                        .help("File paths to be extracted")
+                        .help("Paths or glob patterns to select; a directory selects everything under it")
                         .value_hint(ValueHint::Other),
                 )
+                .arg(
+                    Arg::new("regex")
+                        .short('r')
+                        .long("regex")
+                        .action(ArgAction::SetTrue)
+                        .help("Treat <paths> as regular expressions searched in entry paths"),
+                )
+                .arg(
+                    Arg::new("exclude")
+                        .long("exclude")
+                        .value_name("pattern")
+                        .action(ArgAction::Append)
+                        .value_hint(ValueHint::Other)
+                        .help("Skip entries matching the glob pattern"),
+                )
                 .arg(
                     Arg::new("pipe")
                         .short('p')
@@ -113,6 +136,18 @@ pub fn build_cli() -> Command {
                         .action(ArgAction::SetTrue)
                         .help("Pipe the data to stdout"),
                 )
+                .arg(
+                    Arg::new("unsafe")
+                        .long("unsafe")
+                        .action(ArgAction::SetTrue)
+                        .help("Allow absolute paths, \"..\" and writing through symlinks when extracting"),
+                )
+                .arg(
+                    Arg::new("no-xattrs")
+                        .long("no-xattrs")
+                        .action(ArgAction::SetTrue)
+                        .help("Don't restore recorded extended attributes"),
+                )
                 .arg(
                     Arg::new("data-filter-cmd")
                         .long("data-filter")
@@ -128,17 +163,25 @@ pub fn build_cli() -> Command {
         .subcommand(
             Command::new("test")
                 .alias("t")
+                .arg(format_arg())
                 .arg(
                     Arg::new("archive")
                         .help("Archive file path")
                         .required(true)
                         .value_hint(ValueHint::FilePath),
                 )
+                .arg(
+                    Arg::new("truncate")
+                        .long("truncate")
+                        .action(ArgAction::SetTrue)
+                        .help("Finish a partially written archive with the entries completed by its last checkpoint, dropping the rest"),
+                )
                 .about("Check the archive integrity"),
         )
         .subcommand(
             Command::new("info")
                 .alias("i")
+                .arg(format_arg())
                 .arg(
                     Arg::new("archive")
                         .help("Archive file path")
@@ -147,6 +190,319 @@ pub fn build_cli() -> Command {
                 )
                 .about("Show the information of archive"),
         )
+        .subcommand(
+            Command::new("diff")
+                .about("Compare an archive with a directory, printing A (added), D (removed), M (modified) or m (metadata changed) for each differing path; exits with 1 if any")
+                .arg(archive_arg())
+                .arg(
+                    Arg::new("dir")
+                        .required(true)
+                        .help("Directory to compare with, as the base directory the archive was created in")
+                        .value_hint(ValueHint::DirPath),
+                )
+                .arg(
+                    Arg::new("data-filter-cmd")
+                        .long("data-filter")
+                        .action(ArgAction::Append)
+                        .num_args(1..)
+                        .allow_hyphen_values(true)
+                        .value_hint(ValueHint::Other)
+                        .help("External data filter command line (for decompression)"),
+                ),
+        )
+        .subcommand(
+            Command::new("add")
+                .about("Add files to an existing archive in place, replacing entries with the same paths")
+                .arg(archive_arg())
+                .args(file_args(&compression_names)),
+        )
+        .subcommand(
+            Command::new("update")
+                .about("Like `add`, but keep entries whose files are unchanged (by size, modification time etc.)")
+                .alias("u")
+                .arg(archive_arg())
+                .args(file_args(&compression_names)),
+        )
+        .subcommand(
+            Command::new("delete")
+                .about("Delete entries from an archive in place; `compact` reclaims the space")
+                .arg(archive_arg())
+                .arg(
+                    Arg::new("paths")
+                        .action(ArgAction::Append)
+                        .required(true)
+                        .help("Paths or glob patterns to delete; a directory selects everything under it")
+                        .value_hint(ValueHint::Other),
+                )
+                .arg(
+                    Arg::new("regex")
+                        .short('r')
+                        .long("regex")
+                        .action(ArgAction::SetTrue)
+                        .help("Treat <paths> as regular expressions searched in entry paths"),
+                )
+                .arg(
+                    Arg::new("exclude")
+                        .long("exclude")
+                        .value_name("pattern")
+                        .action(ArgAction::Append)
+                        .value_hint(ValueHint::Other)
+                        .help("Keep entries matching the glob pattern"),
+                ),
+        )
+        .subcommand(
+            Command::new("compact")
+                .about("Rewrite an archive without content no longer referenced, in the current format")
+                .arg(archive_arg()),
+        )
+        .subcommand(
+            Command::new("convert")
+                .about("Convert a tar, zip or cpio archive to an archive, or an archive to tar")
+                .arg(
+                    Arg::new("input")
+                        .required(true)
+                        .help("Input path, \"-\" for stdin (except zip); tar and cpio may be compressed with gzip, xz, zstd or bzip2")
+                        .value_hint(ValueHint::FilePath),
+                )
+                .arg(
+                    Arg::new("output")
+                        .required(true)
+                        .help("Output path, \"-\" for stdout")
+                        .value_hint(ValueHint::FilePath),
+                )
+                .arg(
+                    Arg::new("from")
+                        .long("from")
+                        .value_name("format")
+                        .value_parser(["tar", "zip", "cpio"])
+                        .help("Format of the input, detected from its content by default"),
+                )
+                .args(compression_args(&compression_names))
+                .args(output_args()),
+        )
+        .subcommand(
+            Command::new("repair")
+                .arg(
+                    Arg::new("archive")
+                        .help("Archive file path")
+                        .required(true)
+                        .value_hint(ValueHint::FilePath),
+                )
+                .about("Repair a damaged archive in place with its recovery records"),
+        )
+        .subcommands(mount_subcommand())
         .subcommand_required(true)
         .about("An archive format for data backups with indexing and compression capabilities")
 }
+
+fn archive_arg() -> Arg {
+    Arg::new("archive")
+        .help("Archive file path")
+        .required(true)
+        .value_hint(ValueHint::FilePath)
+}
+
+fn format_arg() -> Arg {
+    Arg::new("format")
+        .long("format")
+        .value_name("format")
+        .value_parser(FORMAT_NAMES)
+        .default_value("text")
+        .help(
+            "Output format; records in the other formats have fixed fields, and errors exit with 2",
+        )
+}
+
+/// Arguments of commands adding files
+fn file_args(compression_names: &[&'static str]) -> Vec<Arg> {
+    let mut args = vec![
+        Arg::new("path")
+            .action(ArgAction::Append)
+            .required(true)
+            .value_hint(ValueHint::AnyPath),
+        Arg::new("base-dir")
+            .short('C')
+            .long("base-directory")
+            .default_value(".")
+            .value_hint(ValueHint::DirPath),
+        Arg::new("include")
+            .long("include")
+            .value_name("pattern")
+            .action(ArgAction::Append)
+            .value_hint(ValueHint::Other)
+            .help("Only add files matching the glob pattern (or under a matching directory)"),
+        Arg::new("exclude")
+            .long("exclude")
+            .value_name("pattern")
+            .action(ArgAction::Append)
+            .value_hint(ValueHint::Other)
+            .help("Skip files and directories matching the glob pattern"),
+        Arg::new("exclude-from")
+            .long("exclude-from")
+            .value_name("file")
+            .action(ArgAction::Append)
+            .value_hint(ValueHint::FilePath)
+            .help(
+                "Read exclude patterns from a gitignore-style file, relative to the base directory",
+            ),
+        Arg::new("xattrs")
+            .long("xattrs")
+            .action(ArgAction::SetTrue)
+            .help("Record extended attributes, including POSIX ACLs"),
+    ];
+    args.extend(compression_args(compression_names));
+    args
+}
+
+/// Arguments of commands compressing file contents
+fn compression_args(compression_names: &[&'static str]) -> Vec<Arg> {
+    vec![
+        Arg::new("compress")
+            .short('c')
+            .long("compress")
+            .value_name("method")
+            .value_parser(compression_names.to_vec())
+            .help("Compression method used for each file, \"none\" for not used")
+            .default_value("none")
+            .ignore_case(true)
+            .conflicts_with("data-filter-cmd"),
+        Arg::new("level")
+            .short('l')
+            .long("level")
+            .help("Compression level")
+            .default_value("best")
+            .conflicts_with("data-filter-cmd")
+            .value_hint(ValueHint::Other),
+        Arg::new("zstd-long")
+            .long("zstd-long")
+            .action(ArgAction::SetTrue)
+            .help("zstd: long-distance matching, for repetitions far apart in a file"),
+        Arg::new("zstd-window-log")
+            .long("zstd-window-log")
+            .value_name("n")
+            .value_parser(value_parser!(u32).range(10..=31))
+            .help("zstd: base-2 log of the match window size; over 27 needs as much memory to extract"),
+        Arg::new("xz-extreme")
+            .long("xz-extreme")
+            .action(ArgAction::SetTrue)
+            .help("xz: extreme presets, slower for slightly smaller output"),
+        Arg::new("brotli-window")
+            .long("brotli-window")
+            .value_name("n")
+            .value_parser(value_parser!(u32).range(10..=24))
+            .help("brotli: base-2 log of the window size [default: 21]"),
+        Arg::new("data-filter-cmd")
+            .long("data-filter")
+            .action(ArgAction::Append)
+            .num_args(1..)
+            .allow_hyphen_values(true)
+            .value_hint(ValueHint::Other)
+            .help("External data filter command line (for compression and decompression)"),
+        Arg::new("store")
+            .long("store")
+            .value_name("pattern")
+            .action(ArgAction::Append)
+            .value_hint(ValueHint::Other)
+            .help("Store files matching the glob pattern without compression"),
+        Arg::new("auto-store")
+            .long("auto-store")
+            .action(ArgAction::SetTrue)
+            .help("Store files without compression if a sample of them compresses poorly"),
+        Arg::new("hash")
+            .long("hash")
+            .value_name("function")
+            .value_parser(HASH_FUNCTION_NAMES)
+            .help(
+                "Record a hash of the original content of each file, checked by `test` and `diff`",
+            ),
+        Arg::new("chunked")
+            .long("chunked")
+            .action(ArgAction::SetTrue)
+            .help("Split files into content-defined chunks, and store identical chunks only once"),
+        Arg::new("jobs")
+            .short('j')
+            .long("jobs")
+            .value_parser(value_parser!(usize))
+            .default_value("1")
+            .help("Number of threads for compressing files"),
+    ]
+}
+
+/// Arguments of commands writing new archives
+fn output_args() -> Vec<Arg> {
+    vec![
+        Arg::new("comment")
+            .short('m')
+            .long("comment")
+            .help("Comment for this archive")
+            .value_hint(ValueHint::Other),
+        Arg::new("recovery")
+            .long("recovery")
+            .value_name("percent")
+            .value_parser(value_parser!(u8).range(1..=100))
+            .help("Append Reed-Solomon recovery records of about the given percent of the archive size"),
+        Arg::new("volume-size")
+            .long("volume-size")
+            .value_name("size")
+            .value_parser(parse_volume_size)
+            .conflicts_with("recovery")
+            .help("Split the archive into volumes NAME.001, NAME.002... of the given size, like 4G or 700MiB"),
+        Arg::new("zstd-dict")
+            .long("zstd-dict")
+            .action(ArgAction::SetTrue)
+            .help("zstd: train a dictionary from a sample of the small files, stored in the archive header"),
+        Arg::new("encrypt")
+            .long("encrypt")
+            .action(ArgAction::SetTrue)
+            .help("Encrypt the archive with a passphrase, prompted or taken from the ARCHIVE_PASSPHRASE environment variable"),
+    ]
+}
+
+fn parse_volume_size(size: &str) -> Result<u64, String> {
+    match size.parse::<ByteSize>()?.0 {
+        0 => Err("The volume size can't be zero".into()),
+        size => Ok(size),
+    }
+}
+
+/// Only available with the `mount` feature
+fn mount_subcommand() -> Option<Command> {
+    cfg_if! {
+        if #[cfg(all(unix, feature = "mount"))] {
+            Some(
+                Command::new("mount")
+                    .about("Mount the archive read-only via FUSE")
+                    .arg(
+                        Arg::new("archive")
+                            .help("Archive file path")
+                            .required(true)
+                            .value_hint(ValueHint::FilePath),
+                    )
+                    .arg(
+                        Arg::new("mount-point")
+                            .required(true)
+                            .value_hint(ValueHint::DirPath),
+                    )
+                    .arg(
+                        Arg::new("cache-size")
+                            .long("cache-size")
+                            .value_name("MiB")
+                            .value_parser(value_parser!(u64))
+                            .default_value("64")
+                            .help("Size limit of decompressed content kept in memory"),
+                    )
+                    .arg(
+                        Arg::new("data-filter-cmd")
+                            .long("data-filter")
+                            .action(ArgAction::Append)
+                            .num_args(1..)
+                            .allow_hyphen_values(true)
+                            .value_hint(ValueHint::Other)
+                            .help("External data filter command line (for decompression)"),
+                    ),
+            )
+        } else {
+            None
+        }
+    }
+}
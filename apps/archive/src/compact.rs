@@ -0,0 +1,51 @@
+use std::fs;
+use std::io::BufWriter;
+use std::path::Path;
+
+use clap::ArgMatches;
+
+use crate::archive::Archive;
+use crate::compressors::{CompressionPolicy, NoCompressor};
+use crate::errors::*;
+use crate::reader::ArchiveReader;
+use crate::{recovery, Compression};
+
+pub fn main(matches: &ArgMatches) -> Result<()> {
+    let path = matches.get_one::<String>("archive").unwrap();
+
+    let reader = ArchiveReader::new(path)?;
+    if reader.is_multi_volume() {
+        return Err("Multi-volume archives can't be compacted in place".into());
+    }
+    eprintln!("Collecting entries...");
+    let entries = reader.entries().collect::<Result<Vec<_>>>()?;
+    let recovery = recovery::recovery_percent(&mut fs::File::open(path)?)?;
+    let metadata = fs::metadata(path)?;
+
+    // written next to the archive, and then renamed over it
+    let dir = match Path::new(path).parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    let temp = tempfile::NamedTempFile::new_in(dir)?;
+
+    let compression = CompressionPolicy::new(Compression::None, Box::new(NoCompressor));
+    let mut archive = Archive::new(BufWriter::new(temp.as_file()), compression)?;
+    archive.copy_from(reader, entries)?;
+    eprintln!("Copying content...");
+    archive.write()?;
+    archive.flush()?;
+    drop(archive);
+
+    fs::set_permissions(temp.path(), metadata.permissions())?;
+    temp.persist(path).map_err(|e| e.error)?;
+
+    if let Some(percent) = recovery {
+        eprintln!("Writing recovery records...");
+        recovery::add_recovery(path, percent)?;
+    }
+
+    println!("Original size: {}", metadata.len());
+    println!("Compacted size: {}", fs::metadata(path)?.len());
+    Ok(())
+}
@@ -1,14 +1,20 @@
+use std::ffi::OsStr;
+use std::fs::File;
 use std::io;
-use std::io::{Read, Write};
+use std::io::{BufReader, Read, Write};
 use std::mem::transmute_copy;
+use std::path::Path;
 use std::process::{Command, Stdio};
 use std::str::FromStr;
+use std::sync::Arc;
 use std::thread::spawn;
 
 use bytesize::ByteSize;
+use globset::GlobSet;
+use zstd::dict::{DecoderDictionary, EncoderDictionary};
 
 use crate::errors::Result;
-use crate::{Compression, Error};
+use crate::{Compression, Error, OsStrExt};
 
 #[derive(Copy, Clone)]
 pub enum Level {
@@ -23,6 +29,76 @@ impl Level {
             Level::Numeric(n) => *n,
         }
     }
+
+    /// Check the level is within the range of `compression`
+    pub fn check(&self, compression: Compression) -> Result<()> {
+        let range = compression.level_range();
+        let level = self.to_numeric(compression);
+        if !range.contains(&level) {
+            return Err(Error::Others(format!(
+                "Level {} is out of the range of {} ({}..={})",
+                level,
+                compression.as_str(),
+                range.start(),
+                range.end()
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Tuning of specific codecs besides the level
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct CodecOptions {
+    /// zstd long-distance matching, for repetitions far apart
+    pub zstd_long: bool,
+    /// zstd window log, the base-2 logarithm of the match window size
+    pub zstd_window_log: Option<u32>,
+    /// xz extreme presets: slower, for slightly smaller output
+    pub xz_extreme: bool,
+    /// brotli window log (21 if not set)
+    pub brotli_window: Option<u32>,
+}
+
+impl CodecOptions {
+    /// The options set, like "long,window-log=27", as recorded in the info
+    /// JSON after the level
+    pub fn describe(&self) -> Vec<String> {
+        let mut options = Vec::new();
+        if self.zstd_long {
+            options.push(String::from("long"));
+        }
+        if let Some(n) = self.zstd_window_log {
+            options.push(format!("window-log={}", n));
+        }
+        if self.xz_extreme {
+            options.push(String::from("extreme"));
+        }
+        if let Some(n) = self.brotli_window {
+            options.push(format!("window={}", n));
+        }
+        options
+    }
+
+    /// Check all the options set apply to `method`
+    pub fn check(&self, method: Compression) -> Result<()> {
+        let zstd = self.zstd_long || self.zstd_window_log.is_some();
+        let misplaced = [
+            (zstd, Compression::Zstd),
+            (self.xz_extreme, Compression::Xz),
+            (self.brotli_window.is_some(), Compression::Brotli),
+        ]
+        .into_iter()
+        .find(|(set, x)| *set && *x != method);
+        match misplaced {
+            Some((_, x)) => Err(Error::Others(format!(
+                "{} options don't apply to {}",
+                x.as_str(),
+                method.as_str()
+            ))),
+            None => Ok(()),
+        }
+    }
 }
 
 impl FromStr for Level {
@@ -42,16 +118,29 @@ impl FromStr for Level {
     }
 }
 
-pub fn create_compressor(method: Compression, level: Level) -> Box<dyn Compress> {
+pub fn create_compressor(
+    method: Compression,
+    level: Level,
+    options: &CodecOptions,
+) -> Box<dyn Compress> {
     let level = level.to_numeric(method);
 
     match method {
         Compression::Gzip => Box::new(GzipCompressor::new(level)),
-        Compression::Xz => Box::new(XzCompressor::new(level)),
-        Compression::Zstd => Box::new(ZstdCompressor::new(level)),
+        Compression::Xz => Box::new(XzCompressor::new(level, options.xz_extreme)),
+        Compression::Zstd => Box::new(ZstdCompressor::new(
+            level,
+            options.zstd_long,
+            options.zstd_window_log,
+        )),
         Compression::Bzip2 => Box::new(Bzip2Compressor::new(level)),
         Compression::None => Box::new(NoCompressor::new()),
-        Compression::Brotli => Box::new(BrotliCompressor::new(level)),
+        Compression::Brotli => Box::new(BrotliCompressor::new(
+            level,
+            options
+                .brotli_window
+                .unwrap_or(BrotliCompressor::DEFAULT_WINDOW),
+        )),
         Compression::Bzip3 => Box::new(Bzip3Compressor::new(Bzip3Compressor::level_to_block_size(
             level,
         ))),
@@ -61,11 +150,15 @@ pub fn create_compressor(method: Compression, level: Level) -> Box<dyn Compress>
     }
 }
 
-pub fn create_decompressor(method: Compression) -> Box<dyn Decompress> {
+/// `dictionary` is the zstd dictionary of the archive, if any
+pub fn create_decompressor(
+    method: Compression,
+    dictionary: Option<Dictionary>,
+) -> Box<dyn Decompress> {
     match method {
         Compression::Gzip => Box::new(GzipDecompressor),
         Compression::Xz => Box::new(XzDecompressor),
-        Compression::Zstd => Box::new(ZstdDecompressor),
+        Compression::Zstd => Box::new(ZstdDecompressor { dictionary }),
         Compression::Bzip2 => Box::new(Bzip2Decompressor),
         Compression::None => Box::new(NoDecompressor),
         Compression::Brotli => Box::new(BrotliDecompressor),
@@ -76,9 +169,140 @@ pub fn create_decompressor(method: Compression) -> Box<dyn Decompress> {
     }
 }
 
-pub trait Compress {
+/// Create the decompressor for data compressed with `method`
+///
+/// `external_cmd` is needed only for externally filtered data, and
+/// `dictionary` is the zstd dictionary of the archive.
+pub fn entry_decompressor(
+    method: Compression,
+    external_cmd: Option<&Vec<String>>,
+    dictionary: Option<Dictionary>,
+) -> Result<Box<dyn Decompress + '_>> {
+    match method {
+        Compression::External => match external_cmd {
+            None => Err(Error::MissingDecompressor),
+            Some(cmd) => Ok(Box::new(ExternalFilter::new(cmd))),
+        },
+        _ => Ok(create_decompressor(method, dictionary)),
+    }
+}
+
+/// Wrap `from` to read the data decompressed with `method`
+///
+/// Externally filtered data can't be read this way.
+pub fn decompress_reader<'a>(
+    method: Compression,
+    from: Box<dyn Read + 'a>,
+    dictionary: Option<&'a Dictionary>,
+) -> Result<Box<dyn Read + 'a>> {
+    Ok(match method {
+        Compression::Gzip => Box::new(flate2::read::GzDecoder::new(from)),
+        Compression::Xz => Box::new(xz2::read::XzDecoder::new(from)),
+        Compression::Zstd => Box::new(zstd_decoder(from, dictionary)?),
+        Compression::Bzip2 => Box::new(bzip2::read::BzDecoder::new(from)),
+        Compression::None => from,
+        Compression::Brotli => Box::new(brotli::Decompressor::new(from, 4096)),
+        Compression::Bzip3 => match bzip3::read::Bz3Decoder::new(from) {
+            Ok(d) => Box::new(d),
+            Err(e) => return Err(Error::DecompressorError(format!("{}", e))),
+        },
+        Compression::External => return Err(Error::MissingDecompressor),
+    })
+}
+
+/// Decides the compression method for each file
+///
+/// Files matching the store rules, or looking incompressible in auto mode,
+/// are stored without compression.
+pub struct CompressionPolicy<'a> {
+    method: Compression,
+    compressor: Box<dyn Compress + 'a>,
+    store_rules: GlobSet,
+    auto_store: bool,
+}
+
+impl<'a> CompressionPolicy<'a> {
+    /// Sample size for detecting incompressible data
+    const SAMPLE_SIZE: u64 = 64 * 1024;
+    /// Stored/original size ratio of the sample above which a file is considered incompressible
+    const POOR_RATIO: f64 = 0.95;
+
+    pub fn new(method: Compression, compressor: Box<dyn Compress + 'a>) -> Self {
+        Self {
+            method,
+            compressor,
+            store_rules: GlobSet::empty(),
+            auto_store: false,
+        }
+    }
+
+    pub fn set_store_rules(&mut self, rules: GlobSet) {
+        self.store_rules = rules;
+    }
+
+    pub fn set_auto_store(&mut self, auto: bool) {
+        self.auto_store = auto;
+    }
+
+    pub fn method(&self) -> Compression {
+        self.method
+    }
+
+    /// Use a zstd dictionary; returns false if the compressor doesn't take
+    /// dictionaries
+    pub fn set_dictionary(&mut self, dictionary: &[u8]) -> bool {
+        self.compressor.set_dictionary(dictionary)
+    }
+
+    /// `path`: the path stored in the archive
+    ///
+    /// `file_path`: the actual path of the file
+    pub fn select(&self, path: &[u8], file_path: &Path) -> Result<Compression> {
+        if self.method == Compression::None {
+            return Ok(Compression::None);
+        }
+        if self.store_rules.is_match(OsStr::from_bytes(path)) {
+            return Ok(Compression::None);
+        }
+        if self.auto_store && self.is_incompressible(file_path)? {
+            return Ok(Compression::None);
+        }
+        Ok(self.method)
+    }
+
+    pub fn compressor(&self, method: Compression) -> &dyn Compress {
+        if method == Compression::None {
+            &NoCompressor
+        } else {
+            assert_eq!(method, self.method);
+            &*self.compressor
+        }
+    }
+
+    /// Compress a sample from the file start, and see if the ratio is poor
+    fn is_incompressible(&self, file_path: &Path) -> Result<bool> {
+        let mut sample = Vec::new();
+        File::open(file_path)?
+            .take(Self::SAMPLE_SIZE)
+            .read_to_end(&mut sample)?;
+        if sample.is_empty() {
+            return Ok(false);
+        }
+        let compressed_size = self
+            .compressor
+            .compress_to(&mut &sample[..], &mut io::sink())?;
+        Ok(compressed_size as f64 / sample.len() as f64 > Self::POOR_RATIO)
+    }
+}
+
+pub trait Compress: Send + Sync {
     /// Returns the size after compression
     fn compress_to(&self, from: &mut dyn Read, to: &mut dyn Write) -> Result<u64>;
+
+    /// Compress with a dictionary from now on; returns false if not supported
+    fn set_dictionary(&mut self, _dictionary: &[u8]) -> bool {
+        false
+    }
 }
 
 pub trait Decompress {
@@ -128,36 +352,106 @@ impl NoCompressor {
 
 pub struct XzCompressor {
     level: u32,
+    extreme: bool,
 }
 
 impl XzCompressor {
-    pub fn new(level: u32) -> XzCompressor {
-        Self { level }
+    /// `LZMA_PRESET_EXTREME`
+    const PRESET_EXTREME: u32 = 1 << 31;
+
+    pub fn new(level: u32, extreme: bool) -> XzCompressor {
+        Self { level, extreme }
     }
 }
 
 impl Compress for XzCompressor {
     fn compress_to(&self, from: &mut dyn Read, to: &mut dyn Write) -> Result<u64> {
-        let mut encoder = xz2::read::XzEncoder::new(from, self.level);
+        let preset = if self.extreme {
+            self.level | Self::PRESET_EXTREME
+        } else {
+            self.level
+        };
+        let stream = xz2::stream::Stream::new_easy_encoder(preset, xz2::stream::Check::Crc64)
+            .map_err(|e| Error::CompressorError(e.to_string()))?;
+        let mut encoder = xz2::read::XzEncoder::new_stream(from, stream);
         Ok(io::copy(&mut encoder, to)?)
     }
 }
 
 pub struct ZstdCompressor {
     level: u32,
+    long: bool,
+    window_log: Option<u32>,
+    dictionary: Option<EncoderDictionary<'static>>,
 }
 
 impl ZstdCompressor {
-    pub fn new(level: u32) -> ZstdCompressor {
-        Self { level }
+    pub fn new(level: u32, long: bool, window_log: Option<u32>) -> ZstdCompressor {
+        Self {
+            level,
+            long,
+            window_log,
+            dictionary: None,
+        }
     }
 }
 
 impl Compress for ZstdCompressor {
     fn compress_to(&self, from: &mut dyn Read, to: &mut dyn Write) -> Result<u64> {
-        let mut encoder = zstd::stream::read::Encoder::new(from, self.level as i32)?;
+        let mut encoder = match &self.dictionary {
+            Some(d) => {
+                zstd::stream::read::Encoder::with_prepared_dictionary(BufReader::new(from), d)?
+            }
+            None => zstd::stream::read::Encoder::new(from, self.level as i32)?,
+        };
+        if self.long {
+            encoder.long_distance_matching(true)?;
+        }
+        if let Some(n) = self.window_log {
+            encoder.window_log(n)?;
+        }
         Ok(io::copy(&mut encoder, to)?)
     }
+
+    fn set_dictionary(&mut self, dictionary: &[u8]) -> bool {
+        self.dictionary = Some(EncoderDictionary::copy(dictionary, self.level as i32));
+        true
+    }
+}
+
+/// A zstd dictionary prepared for decompression, shared by decompressors
+#[derive(Clone)]
+pub struct Dictionary(Arc<DecoderDictionary<'static>>);
+
+impl Dictionary {
+    pub fn new(dictionary: &[u8]) -> Self {
+        Self(Arc::new(DecoderDictionary::copy(dictionary)))
+    }
+}
+
+/// Train a zstd dictionary of at most `max_size` bytes from samples of the
+/// content to be compressed
+///
+/// Fails if there are too few samples.
+pub fn train_dictionary<S: AsRef<[u8]>>(samples: &[S], max_size: usize) -> Result<Vec<u8>> {
+    zstd::dict::from_samples(samples, max_size)
+        .map_err(|e| Error::CompressorError(format!("Training the dictionary failed: {}", e)))
+}
+
+/// Windows up to 2 GiB are accepted, for content compressed with large window
+/// logs
+fn zstd_decoder<'a, R: Read + 'a>(
+    from: R,
+    dictionary: Option<&'a Dictionary>,
+) -> io::Result<zstd::stream::read::Decoder<'a, BufReader<R>>> {
+    let mut decoder = match dictionary {
+        Some(d) => {
+            zstd::stream::read::Decoder::with_prepared_dictionary(BufReader::new(from), &d.0)?
+        }
+        None => zstd::stream::read::Decoder::new(from)?,
+    };
+    decoder.window_log_max(31)?;
+    Ok(decoder)
 }
 
 pub struct Bzip2Compressor {
@@ -179,17 +473,21 @@ impl Compress for Bzip2Compressor {
 
 struct BrotliCompressor {
     quality: u32,
+    /// window log
+    window: u32,
 }
 
 impl BrotliCompressor {
-    pub fn new(quality: u32) -> BrotliCompressor {
-        Self { quality }
+    const DEFAULT_WINDOW: u32 = 21;
+
+    pub fn new(quality: u32, window: u32) -> BrotliCompressor {
+        Self { quality, window }
     }
 }
 
 impl Compress for BrotliCompressor {
     fn compress_to(&self, from: &mut dyn Read, to: &mut dyn Write) -> Result<u64> {
-        let mut reader = brotli::CompressorReader::new(from, 4096, self.quality, 21);
+        let mut reader = brotli::CompressorReader::new(from, 4096, self.quality, self.window);
         Ok(io::copy(&mut reader, to)?)
     }
 }
@@ -230,7 +528,9 @@ impl Bzip3Compressor {
 
 pub struct GzipDecompressor;
 pub struct XzDecompressor;
-pub struct ZstdDecompressor;
+pub struct ZstdDecompressor {
+    dictionary: Option<Dictionary>,
+}
 pub struct Bzip2Decompressor;
 pub struct NoDecompressor;
 pub struct BrotliDecompressor;
@@ -250,7 +550,8 @@ impl Decompress for XzDecompressor {
 
 impl Decompress for ZstdDecompressor {
     fn decompress_to(&self, from: &mut dyn Read, to: &mut dyn Write) -> Result<u64> {
-        Ok(io::copy(&mut zstd::stream::read::Decoder::new(from)?, to)?)
+        let mut decoder = zstd_decoder(from, self.dictionary.as_ref())?;
+        Ok(io::copy(&mut decoder, to)?)
     }
 }
 
@@ -344,3 +645,123 @@ impl Decompress for Bzip3Decompressor {
         Ok(io::copy(&mut decoder, to)?)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::path::Path;
+
+    use super::{create_compressor, CodecOptions, CompressionPolicy, Level};
+    use crate::filter::build_glob_set;
+    use crate::reader::ArchiveReader;
+    use crate::test_utils::{pseudo_random, read_entry, run};
+    use crate::Compression;
+
+    fn zstd_policy() -> CompressionPolicy<'static> {
+        let compressor = create_compressor(
+            Compression::Zstd,
+            Level::Numeric(3),
+            &CodecOptions::default(),
+        );
+        CompressionPolicy::new(Compression::Zstd, compressor)
+    }
+
+    #[test]
+    fn store_rules() {
+        let mut policy = zstd_policy();
+        policy.set_store_rules(build_glob_set(["*.jpg", "media/*"]).unwrap());
+        // the file isn't read without auto mode
+        let missing = Path::new("/nonexistent");
+        assert_eq!(policy.select(b"a.jpg", missing).unwrap(), Compression::None);
+        assert_eq!(
+            policy.select(b"dir/a.jpg", missing).unwrap(),
+            Compression::None
+        );
+        assert_eq!(
+            policy.select(b"media/a", missing).unwrap(),
+            Compression::None
+        );
+        assert_eq!(policy.select(b"a.txt", missing).unwrap(), Compression::Zstd);
+        assert_eq!(
+            policy.select(b"a.jpg.txt", missing).unwrap(),
+            Compression::Zstd
+        );
+    }
+
+    #[test]
+    fn auto_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let noisy = dir.path().join("noisy");
+        let text = dir.path().join("text");
+        let empty = dir.path().join("empty");
+        fs::write(&noisy, pseudo_random(100_000, 5)).unwrap();
+        fs::write(&text, "some text ".repeat(10_000)).unwrap();
+        fs::write(&empty, "").unwrap();
+
+        let mut policy = zstd_policy();
+        assert_eq!(policy.select(b"noisy", &noisy).unwrap(), Compression::Zstd);
+        policy.set_auto_store(true);
+        assert_eq!(policy.select(b"noisy", &noisy).unwrap(), Compression::None);
+        assert_eq!(policy.select(b"text", &text).unwrap(), Compression::Zstd);
+        assert_eq!(policy.select(b"empty", &empty).unwrap(), Compression::Zstd);
+        assert!(policy
+            .select(b"missing", &dir.path().join("missing"))
+            .is_err());
+    }
+
+    #[test]
+    fn per_entry_compression() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        fs::create_dir(&src).unwrap();
+        let noisy = pseudo_random(100_000, 5);
+        let text = "some text ".repeat(10_000);
+        fs::write(src.join("noisy"), &noisy).unwrap();
+        fs::write(src.join("text"), &text).unwrap();
+        fs::write(src.join("text.jpg"), &text).unwrap();
+        let output = dir.path().join("out.arc");
+        run(&[
+            "create".as_ref(),
+            output.as_os_str(),
+            "-C".as_ref(),
+            src.as_os_str(),
+            "-c".as_ref(),
+            "xz".as_ref(),
+            "--store".as_ref(),
+            "*.jpg".as_ref(),
+            "--auto-store".as_ref(),
+            "noisy".as_ref(),
+            "text".as_ref(),
+            "text.jpg".as_ref(),
+        ])
+        .unwrap();
+
+        let reader = ArchiveReader::new(&output).unwrap();
+        assert_eq!(reader.header.compression, Compression::Xz);
+        let compression = |path: &[u8]| reader.find_entry(path).unwrap().unwrap().compression();
+        assert_eq!(compression(b"noisy"), Compression::None);
+        assert_eq!(compression(b"text"), Compression::Xz);
+        assert_eq!(compression(b"text.jpg"), Compression::None);
+        assert_eq!(read_entry(&reader, b"noisy"), noisy);
+        assert_eq!(read_entry(&reader, b"text"), text.as_bytes());
+        assert_eq!(read_entry(&reader, b"text.jpg"), text.as_bytes());
+        let entry = reader.find_entry(b"text").unwrap().unwrap();
+        assert!(entry.stored_size() < entry.original_size() / 10);
+
+        run(&["test".as_ref(), output.as_os_str()]).unwrap();
+        let extracted = dir.path().join("extracted");
+        fs::create_dir(&extracted).unwrap();
+        run(&[
+            "extract".as_ref(),
+            "-C".as_ref(),
+            extracted.as_os_str(),
+            output.as_os_str(),
+        ])
+        .unwrap();
+        assert_eq!(fs::read(extracted.join("noisy")).unwrap(), noisy);
+        assert_eq!(
+            fs::read(extracted.join("text.jpg")).unwrap(),
+            text.as_bytes()
+        );
+    }
+}
@@ -0,0 +1,1345 @@
+//! Conversion from tar, zip and cpio archives, and to tar
+//!
+//! Imported files are staged in a temporary directory first, since the entry
+//! count goes into the header before all the content. Whatever one side can't
+//! represent is skipped or approximated, and summarized at the end (see
+//! [`LossReport`]).
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io;
+use std::io::{stdin, stdout, BufReader, BufWriter, Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+
+use bczhc_lib::str::{escape_utf8_bytes, GenericOsStrExt};
+use byteorder::{LittleEndian, ReadBytesExt};
+use chrono::{Local, TimeZone};
+use clap::ArgMatches;
+use tar::EntryType;
+use tempfile::TempDir;
+
+use crate::compressors::entry_decompressor;
+use crate::create::{check_dictionary, compression_from_matches, create_archive, train_dictionary};
+use crate::encryption;
+use crate::errors::*;
+use crate::extensions::{self, DEVICE_KEY, SPARSE_KEY, XATTR_PREFIX};
+use crate::hash::HashFunction;
+use crate::parallel::SpillBuffer;
+use crate::reader::ArchiveReader;
+use crate::recovery;
+use crate::sparse::{Expand, SparseMap, ZeroFill};
+use crate::{Entry, Extension, FileType, Info, OsStrExt, Timestamp, FILE_MAGIC};
+
+const S_IFMT: u32 = 0o170000;
+const S_IFSOCK: u32 = 0o140000;
+const S_IFLNK: u32 = 0o120000;
+const S_IFREG: u32 = 0o100000;
+const S_IFBLK: u32 = 0o060000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFCHR: u32 = 0o020000;
+const S_IFIFO: u32 = 0o010000;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Format {
+    Tar,
+    Zip,
+    Cpio,
+}
+
+pub fn main(matches: &ArgMatches) -> Result<()> {
+    let input = matches.get_one::<String>("input").unwrap();
+    let output = matches.get_one::<String>("output").unwrap();
+    let data_filter_cmd = matches
+        .get_many::<String>("data-filter-cmd")
+        .map(|values| values.map(|x| x.to_owned()).collect::<Vec<_>>());
+
+    let mut report = LossReport::default();
+    if input != "-" && is_archive(input)? {
+        if matches.get_flag("encrypt") || matches.contains_id("recovery") {
+            return Err("--encrypt and --recovery only apply when converting to an archive".into());
+        }
+        export_tar(input, output, data_filter_cmd.as_ref(), &mut report)?;
+    } else {
+        import(
+            matches,
+            input,
+            output,
+            data_filter_cmd.as_ref(),
+            &mut report,
+        )?;
+    }
+    report.print();
+    Ok(())
+}
+
+fn is_archive(path: &str) -> Result<bool> {
+    let mut magic = Vec::new();
+    File::open(path)?
+        .take(FILE_MAGIC.len() as u64)
+        .read_to_end(&mut magic)?;
+    Ok(magic == FILE_MAGIC)
+}
+
+/// Fields lost in a conversion, with the paths of the entries concerned
+#[derive(Default)]
+struct LossReport(BTreeMap<String, BTreeSet<Vec<u8>>>);
+
+impl LossReport {
+    fn add<S: Into<String>>(&mut self, field: S, path: &[u8]) {
+        self.0
+            .entry(field.into())
+            .or_default()
+            .insert(path.to_vec());
+    }
+
+    /// Record a loss not specific to entries
+    fn note<S: Into<String>>(&mut self, field: S) {
+        self.0.entry(field.into()).or_default();
+    }
+
+    fn print(&self) {
+        if self.0.is_empty() {
+            return;
+        }
+        eprintln!("Not converted:");
+        for (field, paths) in &self.0 {
+            match paths.first() {
+                None => eprintln!("  {}", field),
+                Some(path) => eprintln!(
+                    "  {}: {} entries, e.g. {}",
+                    field,
+                    paths.len(),
+                    escape_utf8_bytes(path)
+                ),
+            }
+        }
+    }
+}
+
+fn import(
+    matches: &ArgMatches,
+    input: &str,
+    output: &str,
+    data_filter_cmd: Option<&Vec<String>>,
+    report: &mut LossReport,
+) -> Result<()> {
+    let from = matches.get_one::<String>("from").map(|x| match x.as_str() {
+        "zip" => Format::Zip,
+        "cpio" => Format::Cpio,
+        _ => Format::Tar,
+    });
+    let comment = matches.get_one::<String>("comment");
+    let jobs = *matches.get_one::<usize>("jobs").unwrap();
+    let chunked = matches.get_flag("chunked");
+    let hash = matches
+        .get_one::<String>("hash")
+        .map(|x| x.parse::<HashFunction>().unwrap());
+    let encrypt = matches.get_flag("encrypt");
+    let recovery = matches.get_one::<u8>("recovery").copied();
+    let volume_size = matches.get_one::<u64>("volume-size").copied();
+
+    let zstd_dict = matches.get_flag("zstd-dict");
+
+    let (compression, mut compression_info) = compression_from_matches(matches, data_filter_cmd)?;
+    if zstd_dict {
+        check_dictionary(compression.method())?;
+    }
+
+    if recovery.is_some() && output == "-" {
+        return Err("Recovery records can't be written to stdout".into());
+    }
+    if volume_size.is_some() && output == "-" {
+        return Err("Volumes can't be written to stdout".into());
+    }
+
+    // stage next to the output, which is about to take the same space anyway
+    let staging_dir = match Path::new(output).parent() {
+        _ if output == "-" => tempfile::tempdir()?,
+        Some(dir) if dir != Path::new("") => tempfile::tempdir_in(dir)?,
+        _ => tempfile::tempdir_in(".")?,
+    };
+    let mut staging = Staging::new(staging_dir);
+
+    eprintln!("Reading entries...");
+    let (format, reader) = open_input(input, from)?;
+    match format {
+        Format::Tar => read_tar(reader, &mut staging, report)?,
+        Format::Cpio => read_cpio(reader, &mut staging, report)?,
+        Format::Zip => {
+            drop(reader);
+            if input == "-" {
+                return Err("Zip archives can't be read from stdin".into());
+            }
+            read_zip(File::open(input)?, &mut staging, report)?;
+        }
+    }
+
+    let passphrase = if encrypt {
+        Some(encryption::passphrase(true)?)
+    } else {
+        None
+    };
+
+    let mut archive = create_archive(output, compression, volume_size)?;
+    archive.set_jobs(jobs);
+    archive.set_chunked(chunked);
+    archive.set_content_hash(hash);
+    if let Some(p) = passphrase {
+        archive.set_encryption(&p)?;
+    }
+    for (content, entry) in staging.entries {
+        archive.add_entry(entry, content);
+    }
+    if zstd_dict {
+        train_dictionary(&mut archive, &mut compression_info)?;
+    }
+    archive.set_info(&Info {
+        compression_method: compression_info,
+        comment: comment.map(|x| x.to_owned()),
+        parent: None,
+        deleted: Vec::new(),
+    });
+
+    eprintln!("Writing files...");
+    archive.write()?;
+
+    archive.flush()?;
+    drop(archive);
+
+    if let Some(percent) = recovery {
+        eprintln!("Writing recovery records...");
+        recovery::add_recovery(output, percent)?;
+    }
+    Ok(())
+}
+
+/// Open the input, decompressing gzip, xz, zstd and bzip2 streams, and detect
+/// its format from the content unless given
+fn open_input(input: &str, from: Option<Format>) -> Result<(Format, Box<dyn Read>)> {
+    let reader: Box<dyn Read> = if input == "-" {
+        Box::new(stdin().lock())
+    } else {
+        Box::new(File::open(input)?)
+    };
+
+    let (magic, reader) = peek(reader, 6)?;
+    let reader: Box<dyn Read> = if magic.starts_with(b"\x1f\x8b") {
+        Box::new(flate2::read::MultiGzDecoder::new(reader))
+    } else if magic.starts_with(b"\xfd7zXZ\x00") {
+        Box::new(xz2::read::XzDecoder::new_multi_decoder(reader))
+    } else if magic.starts_with(b"\x28\xb5\x2f\xfd") {
+        Box::new(zstd::stream::read::Decoder::new(reader)?)
+    } else if magic.starts_with(b"BZh") {
+        Box::new(bzip2::read::MultiBzDecoder::new(reader))
+    } else {
+        reader
+    };
+
+    let (head, reader) = peek(reader, 512)?;
+    let detected = if head.starts_with(b"PK\x03\x04") || head.starts_with(b"PK\x05\x06") {
+        Some(Format::Zip)
+    } else if head.starts_with(b"0707") {
+        Some(Format::Cpio)
+    } else if head.get(257..262) == Some(b"ustar") {
+        Some(Format::Tar)
+    } else {
+        None
+    };
+    let format = from
+        .or(detected)
+        .ok_or("Unknown input format; specify it with --from")?;
+    Ok((format, reader))
+}
+
+/// Read up to `size` bytes ahead, and return them with a reader still starting
+/// from the beginning
+fn peek(mut reader: Box<dyn Read>, size: usize) -> io::Result<(Vec<u8>, Box<dyn Read>)> {
+    let mut head = Vec::with_capacity(size);
+    (&mut reader).take(size as u64).read_to_end(&mut head)?;
+    let reader = Box::new(Cursor::new(head.clone()).chain(reader));
+    Ok((head, reader))
+}
+
+/// Entries read from the input, with their content in a temporary directory
+struct Staging {
+    dir: TempDir,
+    /// content paths and entries
+    entries: Vec<(PathBuf, Entry)>,
+    /// indices of the first entries of files with multiple links, by device
+    /// and inode numbers (cpio)
+    links: HashMap<(u64, u64), usize>,
+}
+
+impl Staging {
+    fn new(dir: TempDir) -> Self {
+        Self {
+            dir,
+            entries: Vec::new(),
+            links: HashMap::new(),
+        }
+    }
+
+    /// `content` is required for regular files
+    fn push(&mut self, mut entry: Entry, content: Option<&mut dyn Read>) -> io::Result<()> {
+        let path = self.dir.path().join(self.entries.len().to_string());
+        if let Some(content) = content {
+            entry.original_size = io::copy(content, &mut File::create(&path)?)?;
+        }
+        self.entries.push((path, entry));
+        Ok(())
+    }
+
+    /// Replace the content of an entry pushed before
+    fn restage(&mut self, index: usize, content: &mut dyn Read) -> io::Result<()> {
+        let (path, entry) = &mut self.entries[index];
+        entry.original_size = io::copy(content, &mut File::create(path)?)?;
+        Ok(())
+    }
+}
+
+/// Make a path relative, with a trailing slash only for directories
+///
+/// Returns `None` for the root directory.
+fn stored_path(path: &[u8], file_type: FileType, report: &mut LossReport) -> Option<Vec<u8>> {
+    let mut relative = path;
+    while let Some(x) = relative.strip_prefix(b"/") {
+        relative = x;
+    }
+    if relative.len() != path.len() {
+        report.add("leading \"/\" of paths (removed)", path);
+    }
+    while let Some(x) = relative.strip_suffix(b"/") {
+        relative = x;
+    }
+    if relative.is_empty() {
+        return None;
+    }
+
+    let mut stored = relative.to_vec();
+    if file_type == FileType::Directory {
+        stored.push(b'/');
+    }
+    Some(stored)
+}
+
+/// The permission mode as recorded by `create`, with the file type bits
+pub(crate) fn permission_mode(file_type: FileType, mode: u32) -> u16 {
+    let type_bits = match file_type {
+        FileType::Regular | FileType::Link => S_IFREG,
+        FileType::Symlink => S_IFLNK,
+        FileType::Fifo => S_IFIFO,
+        FileType::Directory => S_IFDIR,
+        FileType::CharDevice => S_IFCHR,
+        FileType::BlockDevice => S_IFBLK,
+        FileType::Socket => S_IFSOCK,
+    };
+    (type_bits | mode & 0o7777) as u16
+}
+
+/// Parse a PAX timestamp, like "1700000000.123456789"
+fn parse_pax_time(time: &str) -> Option<Timestamp> {
+    let (seconds, fraction) = time.split_once('.').unwrap_or((time, ""));
+    let mut seconds = seconds.parse::<i64>().ok()?;
+    let digits = &fraction[..fraction.len().min(9)];
+    if !digits.bytes().all(|x| x.is_ascii_digit()) {
+        return None;
+    }
+    let mut nanoseconds = format!("{:0<9}", digits).parse::<u32>().ok()?;
+    // the fraction goes in the same direction as the sign
+    if time.starts_with('-') && nanoseconds != 0 {
+        seconds -= 1;
+        nanoseconds = 1_000_000_000 - nanoseconds;
+    }
+    Some(Timestamp::new(seconds, nanoseconds))
+}
+
+const NAMES_LOST: &str = "user and group names (only numeric ids are kept)";
+
+fn read_tar<R: Read>(reader: R, staging: &mut Staging, report: &mut LossReport) -> Result<()> {
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let header = entry.header().clone();
+        let path = entry.path_bytes().into_owned();
+        let linked_path = entry.link_name_bytes().map(|x| x.into_owned());
+
+        let file_type = match header.entry_type() {
+            EntryType::Regular | EntryType::Continuous | EntryType::GNUSparse => FileType::Regular,
+            EntryType::Link => FileType::Link,
+            EntryType::Symlink => FileType::Symlink,
+            EntryType::Directory => FileType::Directory,
+            EntryType::Fifo => FileType::Fifo,
+            EntryType::Char => FileType::CharDevice,
+            EntryType::Block => FileType::BlockDevice,
+            EntryType::XGlobalHeader => {
+                report.add("PAX global headers (ignored)", &path);
+                continue;
+            }
+            t => {
+                report.add(
+                    format!("tar entries of type '{}' (skipped)", t.as_byte() as char),
+                    &path,
+                );
+                continue;
+            }
+        };
+        let Some(stored) = stored_path(&path, file_type, report) else {
+            continue;
+        };
+        let mut converted = Entry::new(stored, file_type);
+
+        if file_type == FileType::Link {
+            // hard links only refer to stored paths
+            let target = linked_path
+                .and_then(|x| stored_path(&x, FileType::Regular, report))
+                .ok_or_else(|| {
+                    Error::Others(format!(
+                        "{}: hard link without a target",
+                        escape_utf8_bytes(&path)
+                    ))
+                })?;
+            converted.set_linked_path(target);
+            staging.push(converted, None)?;
+            continue;
+        }
+        if file_type == FileType::Symlink {
+            converted.set_linked_path(linked_path.unwrap_or_default());
+        }
+
+        converted.permission_mode = permission_mode(file_type, header.mode()?);
+        converted.owner_id = header.uid()? as u32;
+        converted.group_id = header.gid()? as u32;
+        converted.modification_time = Timestamp::new(header.mtime()? as i64, 0);
+        if matches!(file_type, FileType::CharDevice | FileType::BlockDevice) {
+            let major = header.device_major()?.unwrap_or(0);
+            let minor = header.device_minor()?.unwrap_or(0);
+            extensions::set_device_numbers(&mut converted, major, minor);
+        }
+        let has_names = [header.username_bytes(), header.groupname_bytes()]
+            .iter()
+            .any(|x| x.is_some_and(|x| !x.is_empty()));
+        if has_names {
+            report.add(NAMES_LOST, &path);
+        }
+
+        if let Some(extensions) = entry.pax_extensions()? {
+            for x in extensions {
+                let x = x?;
+                apply_pax_record(&mut converted, x.key_bytes(), x.value_bytes(), report);
+            }
+        }
+
+        match file_type {
+            FileType::Regular => staging.push(converted, Some(&mut entry))?,
+            _ => staging.push(converted, None)?,
+        }
+    }
+    Ok(())
+}
+
+fn apply_pax_record(entry: &mut Entry, key: &[u8], value: &[u8], report: &mut LossReport) {
+    let value_str = std::str::from_utf8(value).ok();
+    match key {
+        // already applied by the tar reader
+        b"path" | b"linkpath" | b"size" => {}
+        b"mtime" => {
+            if let Some(time) = value_str.and_then(parse_pax_time) {
+                entry.modification_time = time;
+            }
+        }
+        b"uid" => {
+            if let Some(id) = value_str.and_then(|x| x.parse().ok()) {
+                entry.owner_id = id;
+            }
+        }
+        b"gid" => {
+            if let Some(id) = value_str.and_then(|x| x.parse().ok()) {
+                entry.group_id = id;
+            }
+        }
+        b"uname" | b"gname" => report.add(NAMES_LOST, &entry.path),
+        b"atime" | b"ctime" => report.add("access and change times", &entry.path),
+        _ => match key.strip_prefix(b"SCHILY.xattr.") {
+            Some(name) => {
+                let mut key = Vec::from(XATTR_PREFIX);
+                key.extend_from_slice(name);
+                entry.extensions.push(Extension::new(key, value.to_vec()));
+            }
+            None => report.add(
+                format!("PAX records \"{}\"", escape_utf8_bytes(key)),
+                &entry.path,
+            ),
+        },
+    }
+}
+
+/// Unix metadata from zip extra fields
+#[derive(Default)]
+struct ZipExtra {
+    /// from the extended timestamp field
+    modification_time: Option<i64>,
+    /// from the Info-ZIP Unix field
+    ids: Option<(u32, u32)>,
+}
+
+impl ZipExtra {
+    fn parse(mut data: &[u8]) -> Self {
+        let mut extra = Self::default();
+        while data.len() >= 4 {
+            let id = u16::from_le_bytes([data[0], data[1]]);
+            let size = u16::from_le_bytes([data[2], data[3]]) as usize;
+            let Some(mut field) = data.get(4..4 + size) else {
+                break;
+            };
+            match id {
+                // flags, then the modification time if flagged
+                0x5455 => {
+                    if field.read_u8().is_ok_and(|x| x & 1 != 0) {
+                        if let Ok(time) = field.read_i32::<LittleEndian>() {
+                            extra.modification_time = Some(time as i64);
+                        }
+                    }
+                }
+                // version, then the sizes and values of the ids
+                0x7875 => {
+                    let version = field.read_u8();
+                    let uid = read_sized_id(&mut field);
+                    let gid = read_sized_id(&mut field);
+                    if let (Ok(1), Ok(uid), Ok(gid)) = (version, uid, gid) {
+                        extra.ids = Some((uid, gid));
+                    }
+                }
+                _ => {}
+            }
+            data = &data[4 + size..];
+        }
+        extra
+    }
+}
+
+fn read_sized_id(field: &mut &[u8]) -> io::Result<u32> {
+    let size = field.read_u8()? as usize;
+    Ok(field.read_uint::<LittleEndian>(size)? as u32)
+}
+
+fn read_zip(file: File, staging: &mut Staging, report: &mut LossReport) -> Result<()> {
+    let mut zip = zip::ZipArchive::new(BufReader::new(file))?;
+    if !zip.comment().is_empty() {
+        report.note("archive comment");
+    }
+
+    for index in 0..zip.len() {
+        let mut file = zip.by_index(index)?;
+        let path = file.name_raw().to_vec();
+        let mode = file.unix_mode();
+
+        let file_type = match mode.map(|x| x & S_IFMT) {
+            Some(S_IFLNK) => FileType::Symlink,
+            Some(S_IFDIR) => FileType::Directory,
+            Some(S_IFIFO) => FileType::Fifo,
+            Some(S_IFSOCK) => FileType::Socket,
+            Some(S_IFCHR | S_IFBLK) => {
+                // zip has no device numbers
+                report.add("device files (skipped)", &path);
+                continue;
+            }
+            _ if file.is_dir() => FileType::Directory,
+            _ => FileType::Regular,
+        };
+        let Some(stored) = stored_path(&path, file_type, report) else {
+            continue;
+        };
+        let mut converted = Entry::new(stored, file_type);
+
+        let default_mode = match file_type {
+            FileType::Directory => 0o755,
+            _ => 0o644,
+        };
+        if mode.is_none() {
+            report.add("permissions (not recorded, defaults used)", &path);
+        }
+        converted.permission_mode = permission_mode(file_type, mode.unwrap_or(default_mode));
+
+        let extra = ZipExtra::parse(file.extra_data());
+        converted.modification_time = match extra.modification_time {
+            Some(seconds) => Timestamp::new(seconds, 0),
+            None => {
+                report.add("modification times (DOS time of 2-second precision)", &path);
+                let time = file.last_modified();
+                Local
+                    .with_ymd_and_hms(
+                        time.year() as i32,
+                        time.month() as u32,
+                        time.day() as u32,
+                        time.hour() as u32,
+                        time.minute() as u32,
+                        time.second() as u32,
+                    )
+                    .earliest()
+                    .map(|x| Timestamp::new(x.timestamp(), 0))
+                    .unwrap_or_else(Timestamp::zero)
+            }
+        };
+        match extra.ids {
+            Some((uid, gid)) => {
+                converted.owner_id = uid;
+                converted.group_id = gid;
+            }
+            None => report.add("owners (not recorded)", &path),
+        }
+        if !file.comment().is_empty() {
+            report.add("file comments", &path);
+        }
+
+        match file_type {
+            FileType::Regular => staging.push(converted, Some(&mut file))?,
+            FileType::Symlink => {
+                let mut target = Vec::new();
+                file.read_to_end(&mut target)?;
+                converted.set_linked_path(target);
+                staging.push(converted, None)?;
+            }
+            _ => staging.push(converted, None)?,
+        }
+    }
+    Ok(())
+}
+
+/// Header of a cpio entry in the "newc" (SVR4) or "odc" (POSIX.1) format
+struct CpioHeader {
+    newc: bool,
+    device: u64,
+    inode: u64,
+    mode: u32,
+    owner_id: u32,
+    group_id: u32,
+    link_count: u32,
+    modification_time: i64,
+    /// major and minor numbers of device files
+    device_numbers: (u32, u32),
+    size: u64,
+    name: Vec<u8>,
+}
+
+fn parse_cpio_number(field: &[u8], radix: u32) -> Result<u64> {
+    std::str::from_utf8(field)
+        .ok()
+        .and_then(|x| u64::from_str_radix(x, radix).ok())
+        .ok_or_else(|| "Invalid cpio header".into())
+}
+
+fn read_cpio_header<R: Read>(reader: &mut R) -> Result<CpioHeader> {
+    let mut magic = [0_u8; 6];
+    reader.read_exact(&mut magic)?;
+
+    let (mut header, name_size) = match &magic {
+        b"070701" | b"070702" => {
+            let mut fields = [0_u8; 13 * 8];
+            reader.read_exact(&mut fields)?;
+            let field = |i: usize| parse_cpio_number(&fields[i * 8..(i + 1) * 8], 16);
+            let header = CpioHeader {
+                newc: true,
+                device: field(7)? << 32 | field(8)?,
+                inode: field(0)?,
+                mode: field(1)? as u32,
+                owner_id: field(2)? as u32,
+                group_id: field(3)? as u32,
+                link_count: field(4)? as u32,
+                modification_time: field(5)? as i64,
+                device_numbers: (field(9)? as u32, field(10)? as u32),
+                size: field(6)?,
+                name: Vec::new(),
+            };
+            (header, field(11)?)
+        }
+        b"070707" => {
+            let mut fields = [0_u8; 70];
+            reader.read_exact(&mut fields)?;
+            let field = |range: std::ops::Range<usize>| parse_cpio_number(&fields[range], 8);
+            let header = CpioHeader {
+                newc: false,
+                device: field(0..6)?,
+                inode: field(6..12)?,
+                mode: field(12..18)? as u32,
+                owner_id: field(18..24)? as u32,
+                group_id: field(24..30)? as u32,
+                link_count: field(30..36)? as u32,
+                modification_time: field(42..53)? as i64,
+                // the traditional 8-bit major and minor numbers
+                device_numbers: {
+                    let device = field(36..42)? as u32;
+                    (device >> 8, device & 0xff)
+                },
+                size: field(59..70)?,
+                name: Vec::new(),
+            };
+            (header, field(53..59)?)
+        }
+        _ => return Err("Unsupported cpio format; only \"newc\" and \"odc\" are supported".into()),
+    };
+
+    (&mut *reader)
+        .take(name_size)
+        .read_to_end(&mut header.name)?;
+    if header.name.pop() != Some(0) {
+        return Err("Invalid cpio header".into());
+    }
+    if header.newc {
+        // the header and the name are padded to a multiple of four bytes
+        skip(reader, (4 - (110 + name_size) % 4) % 4)?;
+    }
+    Ok(header)
+}
+
+fn skip<R: Read>(reader: &mut R, size: u64) -> io::Result<()> {
+    io::copy(&mut reader.take(size), &mut io::sink())?;
+    Ok(())
+}
+
+fn read_cpio<R: Read>(mut reader: R, staging: &mut Staging, report: &mut LossReport) -> Result<()> {
+    loop {
+        let header = read_cpio_header(&mut reader)?;
+        if header.name == b"TRAILER!!!" {
+            break;
+        }
+        let mut content = (&mut reader).take(header.size);
+        convert_cpio_entry(&header, &mut content, staging, report)?;
+        // the rest of unused content
+        io::copy(&mut content, &mut io::sink())?;
+        if header.newc {
+            skip(&mut reader, (4 - header.size % 4) % 4)?;
+        }
+    }
+    Ok(())
+}
+
+fn convert_cpio_entry(
+    header: &CpioHeader,
+    content: &mut dyn Read,
+    staging: &mut Staging,
+    report: &mut LossReport,
+) -> Result<()> {
+    let path = &header.name;
+    let file_type = match header.mode & S_IFMT {
+        S_IFREG => FileType::Regular,
+        S_IFDIR => FileType::Directory,
+        S_IFLNK => FileType::Symlink,
+        S_IFIFO => FileType::Fifo,
+        S_IFCHR => FileType::CharDevice,
+        S_IFBLK => FileType::BlockDevice,
+        S_IFSOCK => FileType::Socket,
+        _ => {
+            report.add("entries of unknown types (skipped)", path);
+            return Ok(());
+        }
+    };
+    let Some(stored) = stored_path(path, file_type, report) else {
+        return Ok(());
+    };
+    let mut converted = Entry::new(stored, file_type);
+
+    if file_type == FileType::Regular && header.link_count > 1 {
+        let key = (header.device, header.inode);
+        if let Some(&first) = staging.links.get(&key) {
+            // the content may only come with the last link
+            if header.size != 0 && staging.entries[first].1.original_size == 0 {
+                staging.restage(first, content)?;
+            }
+            converted.file_type = FileType::Link;
+            let target = staging.entries[first].1.path.clone();
+            converted.set_linked_path(target);
+            staging.push(converted, None)?;
+            return Ok(());
+        }
+        staging.links.insert(key, staging.entries.len());
+    }
+
+    converted.permission_mode = permission_mode(file_type, header.mode);
+    converted.owner_id = header.owner_id;
+    converted.group_id = header.group_id;
+    converted.modification_time = Timestamp::new(header.modification_time, 0);
+    if matches!(file_type, FileType::CharDevice | FileType::BlockDevice) {
+        let (major, minor) = header.device_numbers;
+        extensions::set_device_numbers(&mut converted, major, minor);
+    }
+
+    match file_type {
+        FileType::Regular => staging.push(converted, Some(content))?,
+        FileType::Symlink => {
+            let mut target = Vec::new();
+            content.read_to_end(&mut target)?;
+            converted.set_linked_path(target);
+            staging.push(converted, None)?;
+        }
+        _ => staging.push(converted, None)?,
+    }
+    Ok(())
+}
+
+fn export_tar(
+    input: &str,
+    output: &str,
+    data_filter_cmd: Option<&Vec<String>>,
+    report: &mut LossReport,
+) -> Result<()> {
+    let mut reader = ArchiveReader::new(input)?;
+    let info: Info =
+        serde_json::from_str(&reader.header.info_json).map_err(|_| Error::InvalidInfoJson)?;
+    if info.comment.is_some() {
+        report.note("archive comment");
+    }
+    if info.parent.is_some() {
+        report.note("parent archives (only this incremental layer is converted)");
+    }
+    let entries = reader.entries().collect::<Result<Vec<_>>>()?;
+
+    let writer: Box<dyn Write> = if output == "-" {
+        Box::new(stdout().lock())
+    } else {
+        Box::new(File::create(output)?)
+    };
+    let mut builder = tar::Builder::new(BufWriter::new(writer));
+
+    for entry in &entries {
+        let path = Path::new(OsStr::from_bytes(&entry.path));
+        eprintln!("{}", path.as_os_str().escape());
+        if entry.file_type == FileType::Socket {
+            // like what `tar` does
+            report.add("sockets (skipped)", &entry.path);
+            continue;
+        }
+
+        let mut header = tar::Header::new_gnu();
+        header.set_size(0);
+        header.set_mode(entry.permission_mode as u32 & 0o7777);
+        header.set_uid(entry.owner_id as u64);
+        header.set_gid(entry.group_id as u64);
+
+        let mut records = Vec::new();
+        let time = &entry.modification_time;
+        if time.seconds < 0 {
+            report.add("modification times before 1970", &entry.path);
+            header.set_mtime(0);
+        } else {
+            header.set_mtime(time.seconds as u64);
+            if time.nanoseconds != 0 {
+                let value = format!("{}.{:09}", time.seconds, time.nanoseconds);
+                records.push((String::from("mtime"), value.into_bytes()));
+            }
+        }
+        for x in &entry.extensions {
+            // represented by the header, or as the expanded content
+            if x.key() == DEVICE_KEY || x.key() == SPARSE_KEY {
+                continue;
+            }
+            let name = x.key().strip_prefix(XATTR_PREFIX);
+            match name.and_then(|x| std::str::from_utf8(x).ok()) {
+                Some(name) => records.push((format!("SCHILY.xattr.{}", name), x.value().to_vec())),
+                None => report.add(
+                    format!("extension \"{}\"", escape_utf8_bytes(x.key())),
+                    &entry.path,
+                ),
+            }
+        }
+        if !records.is_empty() {
+            append_pax_header(&mut builder, &records)?;
+        }
+
+        match entry.file_type {
+            FileType::Regular => {
+                let decompressor =
+                    entry_decompressor(entry.compression, data_filter_cmd, reader.dictionary())?;
+                let map = SparseMap::of(entry)?;
+                let sparse = map.is_some();
+                if sparse {
+                    report.add("holes of sparse files (stored as zeros)", &entry.path);
+                }
+                let mut content = Expand::new(ZeroFill(SpillBuffer::new()), map);
+                let size = reader.decompress_entry(entry, &*decompressor, &mut content)?;
+                let content = content.finish()?.0;
+                header.set_entry_type(EntryType::Regular);
+                header.set_size(if sparse { entry.original_size } else { size });
+                builder.append_data(&mut header, path, content.into_reader()?)?;
+            }
+            FileType::Link | FileType::Symlink => {
+                let entry_type = match entry.file_type {
+                    FileType::Link => EntryType::Link,
+                    _ => EntryType::Symlink,
+                };
+                header.set_entry_type(entry_type);
+                let target = Path::new(OsStr::from_bytes(&entry.linked_path));
+                builder.append_link(&mut header, path, target)?;
+            }
+            FileType::Fifo | FileType::Directory => {
+                let entry_type = match entry.file_type {
+                    FileType::Fifo => EntryType::Fifo,
+                    _ => EntryType::Directory,
+                };
+                header.set_entry_type(entry_type);
+                builder.append_data(&mut header, path, io::empty())?;
+            }
+            FileType::CharDevice | FileType::BlockDevice => {
+                let entry_type = match entry.file_type {
+                    FileType::CharDevice => EntryType::Char,
+                    _ => EntryType::Block,
+                };
+                header.set_entry_type(entry_type);
+                let (major, minor) = extensions::device_numbers(entry).unwrap_or((0, 0));
+                header.set_device_major(major)?;
+                header.set_device_minor(minor)?;
+                builder.append_data(&mut header, path, io::empty())?;
+            }
+            FileType::Socket => unreachable!(),
+        }
+    }
+
+    builder.into_inner()?.flush()?;
+    Ok(())
+}
+
+/// Append a PAX extended header, which applies to the entry next
+fn append_pax_header<W: Write>(
+    builder: &mut tar::Builder<W>,
+    records: &[(String, Vec<u8>)],
+) -> io::Result<()> {
+    let mut data = Vec::new();
+    for (key, value) in records {
+        // "<length> <key>=<value>\n", the length including its own digits
+        let rest = key.len() + value.len() + 3;
+        let mut length = rest;
+        loop {
+            let new_length = rest + length.to_string().len();
+            if new_length == length {
+                break;
+            }
+            length = new_length;
+        }
+        write!(data, "{} {}=", length, key)?;
+        data.extend_from_slice(value);
+        data.push(b'\n');
+    }
+
+    let mut header = tar::Header::new_ustar();
+    header.set_path("././@PaxHeader")?;
+    header.set_entry_type(EntryType::XHeader);
+    header.set_mode(0o644);
+    header.set_size(data.len() as u64);
+    header.set_cksum();
+    builder.append(&header, &data[..])
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use std::fs::{self, File};
+    use std::io::{Cursor, Write};
+    use std::os::unix::fs::{symlink, PermissionsExt};
+    use std::path::Path;
+
+    use filetime::FileTime;
+    use tar::{EntryType, Header};
+
+    use super::{
+        append_pax_header, parse_pax_time, read_cpio, read_cpio_header, read_tar, read_zip,
+        LossReport, Staging, ZipExtra,
+    };
+    use crate::reader::ArchiveReader;
+    use crate::test_utils::{read_entry, run};
+    use crate::{extensions, Entry, FileType, Timestamp};
+
+    /// A "newc" cpio entry, owned by 1000:100
+    fn newc(inode: u64, mode: u32, link_count: u32, name: &str, data: &[u8]) -> Vec<u8> {
+        let fields = [
+            inode,
+            mode as u64,
+            1000,
+            100,
+            link_count as u64,
+            1700000000,
+            data.len() as u64,
+            0,
+            1,
+            4,
+            1,
+            name.len() as u64 + 1,
+            0,
+        ];
+        let mut entry = b"070701".to_vec();
+        for x in fields {
+            write!(entry, "{:08x}", x).unwrap();
+        }
+        entry.extend_from_slice(name.as_bytes());
+        entry.push(0);
+        entry.resize(entry.len().next_multiple_of(4), 0);
+        entry.extend_from_slice(data);
+        entry.resize(entry.len().next_multiple_of(4), 0);
+        entry
+    }
+
+    /// An "odc" cpio entry, owned by 1000:100
+    fn odc(inode: u64, mode: u32, name: &str, data: &[u8]) -> Vec<u8> {
+        let mut entry = b"070707".to_vec();
+        write!(
+            entry,
+            "{:06o}{:06o}{:06o}{:06o}{:06o}{:06o}{:06o}{:011o}{:06o}{:011o}",
+            1,
+            inode,
+            mode,
+            1000,
+            100,
+            1,
+            4 << 8 | 1,
+            1700000000,
+            name.len() + 1,
+            data.len()
+        )
+        .unwrap();
+        entry.extend_from_slice(name.as_bytes());
+        entry.push(0);
+        entry.extend_from_slice(data);
+        entry
+    }
+
+    fn sorted_entries(archive: &Path) -> Vec<Entry> {
+        let reader = ArchiveReader::new(archive).unwrap();
+        let mut entries = reader.entries().map(|x| x.unwrap()).collect::<Vec<_>>();
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        entries
+    }
+
+    fn tar_header(entry_type: EntryType, mode: u32, size: u64) -> Header {
+        let mut header = Header::new_gnu();
+        header.set_entry_type(entry_type);
+        header.set_mode(mode);
+        header.set_uid(1000);
+        header.set_gid(1000);
+        header.set_mtime(1700000000);
+        header.set_size(size);
+        header
+    }
+
+    #[test]
+    fn tar_entries() {
+        let long_name = format!("dir/{}", "x".repeat(200));
+        let mut builder = tar::Builder::new(Vec::new());
+        builder.preserve_absolute(true);
+
+        let mut header = tar_header(EntryType::Directory, 0o755, 0);
+        builder.append_data(&mut header, "/dir", &b""[..]).unwrap();
+        let mut header = tar_header(EntryType::Regular, 0o640, 5);
+        builder
+            .append_data(&mut header, &long_name, &b"hello"[..])
+            .unwrap();
+        let mut header = tar_header(EntryType::Link, 0o640, 0);
+        builder
+            .append_link(&mut header, "dir/link", &long_name)
+            .unwrap();
+        let mut header = tar_header(EntryType::Char, 0o620, 0);
+        header.set_device_major(4).unwrap();
+        header.set_device_minor(1).unwrap();
+        builder.append_data(&mut header, "tty", &b""[..]).unwrap();
+
+        let data = builder.into_inner().unwrap();
+        let mut staging = Staging::new(tempfile::tempdir().unwrap());
+        let mut report = LossReport::default();
+        read_tar(&data[..], &mut staging, &mut report).unwrap();
+
+        let entries = staging.entries.iter().map(|x| &x.1).collect::<Vec<_>>();
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[0].path, b"dir/");
+        assert_eq!(entries[0].file_type, FileType::Directory);
+        assert_eq!(entries[1].path, long_name.as_bytes());
+        assert_eq!(entries[1].permission_mode, 0o100640);
+        assert_eq!(entries[1].owner_id, 1000);
+        assert_eq!(entries[1].original_size, 5);
+        assert_eq!(entries[1].modification_time, Timestamp::new(1700000000, 0));
+        assert_eq!(std::fs::read(&staging.entries[1].0).unwrap(), b"hello");
+        assert_eq!(entries[2].file_type, FileType::Link);
+        assert_eq!(entries[2].linked_path, long_name.as_bytes());
+        assert_eq!(entries[3].file_type, FileType::CharDevice);
+        assert_eq!(entries[3].permission_mode, 0o020620);
+        assert_eq!(extensions::device_numbers(entries[3]), Some((4, 1)));
+
+        let fields = report.0.keys().collect::<Vec<_>>();
+        assert_eq!(fields, ["leading \"/\" of paths (removed)"]);
+    }
+
+    #[test]
+    fn pax_time() {
+        assert_eq!(
+            parse_pax_time("1700000000"),
+            Some(Timestamp::new(1700000000, 0))
+        );
+        assert_eq!(
+            parse_pax_time("1700000000.5"),
+            Some(Timestamp::new(1700000000, 500_000_000))
+        );
+        assert_eq!(
+            parse_pax_time("1.1234567891"),
+            Some(Timestamp::new(1, 123_456_789))
+        );
+        assert_eq!(
+            parse_pax_time("-1.25"),
+            Some(Timestamp::new(-2, 750_000_000))
+        );
+        assert_eq!(parse_pax_time("1.x"), None);
+    }
+
+    #[test]
+    fn zip_extra() {
+        let mut data = Vec::new();
+        // unknown
+        data.extend_from_slice(&[0x01, 0x00, 2, 0, 0xff, 0xff]);
+        // extended timestamp, with the modification time flagged
+        data.extend_from_slice(&[0x55, 0x54, 5, 0, 1]);
+        data.extend_from_slice(&1700000000_i32.to_le_bytes());
+        // Info-ZIP Unix, version 1, with a 4-byte uid and a 2-byte gid
+        data.extend_from_slice(&[0x75, 0x78, 9, 0, 1, 4]);
+        data.extend_from_slice(&1000_u32.to_le_bytes());
+        data.extend_from_slice(&[2, 100, 0]);
+        let extra = ZipExtra::parse(&data);
+        assert_eq!(extra.modification_time, Some(1700000000));
+        assert_eq!(extra.ids, Some((1000, 100)));
+
+        // the access time only, an unknown version, and a truncated field
+        let mut data = vec![0x55, 0x54, 5, 0, 2, 0, 0, 0, 0];
+        data.extend_from_slice(&[0x75, 0x78, 5, 0, 2, 1, 1, 1, 1]);
+        data.extend_from_slice(&[0x75, 0x78, 9, 0, 1]);
+        let extra = ZipExtra::parse(&data);
+        assert_eq!(extra.modification_time, None);
+        assert_eq!(extra.ids, None);
+    }
+
+    #[test]
+    fn cpio_headers() {
+        let data = newc(7, 0o020620, 1, "tty", b"");
+        let header = read_cpio_header(&mut &data[..]).unwrap();
+        assert!(header.newc);
+        assert_eq!((header.device, header.inode), (1, 7));
+        assert_eq!(header.mode, 0o020620);
+        assert_eq!((header.owner_id, header.group_id), (1000, 100));
+        assert_eq!(header.modification_time, 1700000000);
+        assert_eq!(header.device_numbers, (4, 1));
+        assert_eq!(header.name, b"tty");
+
+        // the name is padded, and the content follows
+        let data = newc(8, 0o100644, 1, "file", b"hello");
+        let mut reader = &data[..];
+        let header = read_cpio_header(&mut reader).unwrap();
+        assert_eq!((header.name.as_slice(), header.size), (&b"file"[..], 5));
+        assert!(reader.starts_with(b"hello"));
+
+        let data = odc(9, 0o100640, "file", b"hello");
+        let mut reader = &data[..];
+        let header = read_cpio_header(&mut reader).unwrap();
+        assert!(!header.newc);
+        assert_eq!((header.device, header.inode), (1, 9));
+        assert_eq!(header.mode, 0o100640);
+        assert_eq!((header.owner_id, header.group_id), (1000, 100));
+        assert_eq!(header.modification_time, 1700000000);
+        assert_eq!(header.device_numbers, (4, 1));
+        assert_eq!((header.name.as_slice(), header.size), (&b"file"[..], 5));
+        assert_eq!(reader, b"hello");
+
+        assert!(read_cpio_header(&mut &b"070703"[..]).is_err());
+        let mut data = newc(1, 0o100644, 1, "a", b"");
+        data[6] = b'x';
+        assert!(read_cpio_header(&mut &data[..]).is_err());
+    }
+
+    #[test]
+    fn cpio_hard_links() {
+        // the content comes with the last link only
+        let mut data = newc(10, 0o100644, 3, "a", b"");
+        data.extend(newc(10, 0o100644, 3, "b", b""));
+        data.extend(newc(10, 0o100644, 3, "c", b"hello"));
+        data.extend(newc(11, 0o100644, 1, "d", b"single"));
+        data.extend(newc(0, 0, 1, "TRAILER!!!", b""));
+
+        let mut staging = Staging::new(tempfile::tempdir().unwrap());
+        read_cpio(&data[..], &mut staging, &mut LossReport::default()).unwrap();
+        let entries = staging.entries.iter().map(|x| &x.1).collect::<Vec<_>>();
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[0].file_type, FileType::Regular);
+        assert_eq!(entries[0].original_size, 5);
+        assert_eq!(fs::read(&staging.entries[0].0).unwrap(), b"hello");
+        for entry in &entries[1..3] {
+            assert_eq!(entry.file_type, FileType::Link);
+            assert_eq!(entry.linked_path, b"a");
+        }
+        assert_eq!(entries[3].file_type, FileType::Regular);
+        assert_eq!(fs::read(&staging.entries[3].0).unwrap(), b"single");
+    }
+
+    #[test]
+    fn zip_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("in.zip");
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        let options =
+            zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+        zip.add_directory("dir", options.unix_permissions(0o750))
+            .unwrap();
+        // the writer refuses the IDs of the Unix fields, which are put in
+        // afterwards
+        let mut extra = vec![0x55, 0xf0, 5, 0, 1];
+        extra.extend_from_slice(&1700000000_i32.to_le_bytes());
+        extra.extend_from_slice(&[0x78, 0xf0, 11, 0, 1, 4]);
+        extra.extend_from_slice(&1000_u32.to_le_bytes());
+        extra.push(4);
+        extra.extend_from_slice(&100_u32.to_le_bytes());
+        zip.start_file_with_extra_data("dir/file", options.unix_permissions(0o640))
+            .unwrap();
+        zip.write_all(&extra).unwrap();
+        zip.end_extra_data().unwrap();
+        zip.write_all(b"hello").unwrap();
+        zip.add_symlink("link", "dir/file", options).unwrap();
+        zip.start_file("/plain", options).unwrap();
+        zip.write_all(b"plain").unwrap();
+        let mut data = zip.finish().unwrap().into_inner();
+        for (placeholder, id) in [
+            ([0x55, 0xf0, 5], [0x55, 0x54]),
+            ([0x78, 0xf0, 11], [0x75, 0x78]),
+        ] {
+            for i in 0..(data.len() - 3) {
+                if data[i..(i + 3)] == placeholder {
+                    data[i..(i + 2)].copy_from_slice(&id);
+                }
+            }
+        }
+        fs::write(&path, data).unwrap();
+
+        let mut staging = Staging::new(tempfile::tempdir().unwrap());
+        let mut report = LossReport::default();
+        read_zip(File::open(&path).unwrap(), &mut staging, &mut report).unwrap();
+        let entries = staging.entries.iter().map(|x| &x.1).collect::<Vec<_>>();
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[0].path, b"dir/");
+        assert_eq!(entries[0].permission_mode, 0o040750);
+        assert_eq!(entries[1].path, b"dir/file");
+        assert_eq!(entries[1].permission_mode, 0o100640);
+        assert_eq!((entries[1].owner_id, entries[1].group_id), (1000, 100));
+        assert_eq!(entries[1].modification_time, Timestamp::new(1700000000, 0));
+        assert_eq!(fs::read(&staging.entries[1].0).unwrap(), b"hello");
+        assert_eq!(entries[2].file_type, FileType::Symlink);
+        assert_eq!(entries[2].linked_path, b"dir/file");
+        assert_eq!(entries[3].path, b"plain");
+        assert_eq!(fs::read(&staging.entries[3].0).unwrap(), b"plain");
+
+        let plain = |field: &str| report.0[field].contains(&b"/plain"[..]);
+        assert!(plain("leading \"/\" of paths (removed)"));
+        assert!(plain("owners (not recorded)"));
+        assert!(plain("modification times (DOS time of 2-second precision)"));
+        assert!(!report.0["owners (not recorded)"].contains(&b"dir/file"[..]));
+    }
+
+    #[test]
+    fn cpio_import() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut data = odc(1, 0o040755, "dir", b"");
+        data.extend(odc(2, 0o100600, "dir/file", b"hello"));
+        data.extend(odc(3, 0o120777, "link", b"dir/file"));
+        data.extend(odc(0, 0, "TRAILER!!!", b""));
+        let input = dir.path().join("in.cpio.gz");
+        let mut encoder = flate2::write::GzEncoder::new(
+            File::create(&input).unwrap(),
+            flate2::Compression::default(),
+        );
+        encoder.write_all(&data).unwrap();
+        encoder.finish().unwrap();
+
+        let output = dir.path().join("out.arc");
+        run(&["convert".as_ref(), input.as_os_str(), output.as_os_str()]).unwrap();
+
+        let entries = sorted_entries(&output);
+        let paths = entries.iter().map(|x| &x.path[..]).collect::<Vec<_>>();
+        assert_eq!(paths, [&b"dir/"[..], b"dir/file", b"link"]);
+        assert_eq!(entries[1].permission_mode, 0o100600);
+        assert_eq!((entries[1].owner_id, entries[1].group_id), (1000, 100));
+        assert_eq!(entries[1].modification_time, Timestamp::new(1700000000, 0));
+        assert_eq!(entries[2].file_type, FileType::Symlink);
+        assert_eq!(entries[2].linked_path, b"dir/file");
+        let reader = ArchiveReader::new(&output).unwrap();
+        assert_eq!(read_entry(&reader, b"dir/file"), b"hello");
+    }
+
+    #[test]
+    fn pax_header_length() {
+        // lengths crossing a number of digits
+        for size in [0, 5, 90, 94, 95, 96, 990, 994, 995, 996] {
+            let records = [
+                (String::from("k"), vec![b'v'; size]),
+                (String::from("mtime"), b"1.5".to_vec()),
+            ];
+            let mut builder = tar::Builder::new(Vec::new());
+            append_pax_header(&mut builder, &records).unwrap();
+            let data = builder.into_inner().unwrap();
+
+            let header = Header::from_byte_slice(&data[..512]);
+            assert_eq!(header.entry_type(), EntryType::XHeader);
+            let size = header.size().unwrap() as usize;
+            let mut content = &data[512..(512 + size)];
+            for (key, value) in &records {
+                let space = content.iter().position(|&x| x == b' ').unwrap();
+                let length = std::str::from_utf8(&content[..space])
+                    .unwrap()
+                    .parse::<usize>()
+                    .unwrap();
+                let mut expected = format!("{} {}=", length, key).into_bytes();
+                expected.extend_from_slice(value);
+                expected.push(b'\n');
+                assert_eq!(&content[..length], expected);
+                content = &content[length..];
+            }
+            assert!(content.is_empty());
+        }
+    }
+
+    #[test]
+    fn tar_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        fs::create_dir_all(src.join("sub")).unwrap();
+        fs::write(src.join("file"), b"hello").unwrap();
+        fs::set_permissions(src.join("file"), fs::Permissions::from_mode(0o640)).unwrap();
+        let time = FileTime::from_unix_time(1700000000, 123456789);
+        filetime::set_file_mtime(src.join("file"), time).unwrap();
+        fs::write(src.join("sub/empty"), b"").unwrap();
+        fs::hard_link(src.join("file"), src.join("hard")).unwrap();
+        symlink("sub/empty", src.join("link")).unwrap();
+        filetime::set_file_mtime(src.join("sub"), time).unwrap();
+
+        let first = dir.path().join("first.arc");
+        let tar = dir.path().join("out.tar");
+        let second = dir.path().join("second.arc");
+        let src = src.to_str().unwrap();
+        let paths = ["file", "hard", "link", "sub"];
+        let mut args = vec!["create", first.to_str().unwrap(), "-C", src];
+        args.extend(paths);
+        run(&args).unwrap();
+        run(&["convert", first.to_str().unwrap(), tar.to_str().unwrap()]).unwrap();
+        run(&["convert", tar.to_str().unwrap(), second.to_str().unwrap()]).unwrap();
+
+        let expected = sorted_entries(&first);
+        let entries = sorted_entries(&second);
+        assert_eq!(entries.len(), 5);
+        for (a, b) in expected.iter().zip(&entries) {
+            assert_eq!(a.path, b.path);
+            assert_eq!(a.file_type, b.file_type);
+            assert_eq!(a.permission_mode, b.permission_mode);
+            assert_eq!((a.owner_id, a.group_id), (b.owner_id, b.group_id));
+            assert_eq!(a.modification_time, b.modification_time);
+            assert_eq!(a.linked_path, b.linked_path);
+            if a.file_type == FileType::Regular {
+                assert_eq!(a.original_size, b.original_size);
+            }
+        }
+        assert_eq!(
+            entries[0].modification_time,
+            Timestamp::new(1700000000, 123456789)
+        );
+        let reader = ArchiveReader::new(&second).unwrap();
+        assert_eq!(read_entry(&reader, b"file"), b"hello");
+        assert_eq!(read_entry(&reader, b"hard"), b"hello");
+    }
+}
@@ -80,3 +80,37 @@ pub mod write {
         }
     }
 }
+
+pub mod read {
+    use crc_lib::{Digest, Width};
+    use std::io::Read;
+
+    pub struct CrcFilter<'a, 'b, W, R>
+    where
+        W: Width,
+        R: Read,
+    {
+        digest: &'a mut Digest<'b, W>,
+        reader: &'a mut R,
+    }
+
+    impl<'a, 'b, R> CrcFilter<'a, 'b, u32, R>
+    where
+        R: Read,
+    {
+        pub fn new(digest: &'a mut Digest<'b, u32>, reader: &'a mut R) -> Self {
+            Self { digest, reader }
+        }
+    }
+
+    impl<'a, 'b, R> Read for CrcFilter<'a, 'b, u32, R>
+    where
+        R: Read,
+    {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let read_size = self.reader.read(buf)?;
+            self.digest.update(&buf[..read_size]);
+            Ok(read_size)
+        }
+    }
+}
@@ -1,6 +1,17 @@
 use crate::archive::Archive;
-use crate::compressors::{create_compressor, Compress, ExternalFilter, Level};
-use crate::{Compression, Configs, Info};
+use crate::archive::DICTIONARY_SIZE;
+use crate::checkpoint::{checkpoint_path, PartialArchive};
+use crate::compressors::{
+    create_compressor, CodecOptions, Compress, CompressionPolicy, ExternalFilter, Level,
+};
+use crate::encryption;
+use crate::filter::{build_glob_set, FileFilter};
+use crate::incremental::Chain;
+use crate::info::parse_info;
+use crate::recovery;
+use crate::update::write_entry_count;
+use crate::volume::VolumeWriter;
+use crate::{Compression, Configs, FileType, Info, StoredPath};
 use bczhc_lib::mutex_lock;
 use std::ffi::OsString;
 
@@ -8,13 +19,15 @@ use clap::ArgMatches;
 use once_cell::sync::Lazy;
 
 use pathdiff::diff_paths;
-use std::fs::File;
-use std::io::{BufWriter, Seek, Write};
+use std::fs::{File, OpenOptions};
+use std::io::{stdout, BufWriter, Write};
 use std::path::{Component, Path, PathBuf};
 use std::str::FromStr;
 use std::sync::Mutex;
+use std::time::Duration;
 
 use crate::errors::*;
+use crate::hash::{self, HashFunction};
 
 static CONFIGS: Lazy<Mutex<Configs>> = Lazy::new(|| Mutex::new(Configs::default()));
 
@@ -23,13 +36,181 @@ pub fn main(matches: &ArgMatches) -> Result<()> {
     let output = matches.get_one::<String>("output").unwrap();
     let base_dir = matches.get_one::<String>("base-dir").unwrap();
     let comment = matches.get_one::<String>("comment");
+    let reference = matches.get_one::<String>("incremental");
+    let compare_content = matches.get_flag("checksum");
+    let jobs = *matches.get_one::<usize>("jobs").unwrap();
+    let xattrs = matches.get_flag("xattrs");
+    let chunked = matches.get_flag("chunked");
+    let hash = matches
+        .get_one::<String>("hash")
+        .map(|x| x.parse::<HashFunction>().unwrap());
+    let encrypt = matches.get_flag("encrypt");
+    let recovery = matches.get_one::<u8>("recovery").copied();
+    let volume_size = matches.get_one::<u64>("volume-size").copied();
+    let zstd_dict = matches.get_flag("zstd-dict");
+    let resume = matches.get_flag("resume");
+    let checkpoint_interval = *matches.get_one::<u64>("checkpoint-interval").unwrap();
+    let filter = FileFilter::from_matches(matches, Path::new(base_dir))?;
 
     let data_filter_cmd = matches
         .get_many::<String>("data-filter-cmd")
         .map(|values| values.map(|x| x.to_owned()).collect::<Vec<_>>());
 
+    let (compression, compression_info) =
+        compression_from_matches(matches, data_filter_cmd.as_ref())?;
+    if zstd_dict {
+        check_dictionary(compression.method())?;
+        if compare_content {
+            return Err("Comparing checksums doesn't work with trained dictionaries".into());
+        }
+    }
+
+    // open the reference chain before the output gets truncated
+    let reference = match reference {
+        None => None,
+        Some(r) => {
+            if same_file(r, output) {
+                return Err("The reference archive can't be the output".into());
+            }
+            let chain = Chain::open(r)?;
+            // entries without content hashes are compared by recompressing them
+            if compare_content
+                && chain.entries().any(|(layer, entry)| {
+                    entry.file_type == FileType::Regular
+                        && hash::stored_hash(entry).is_none()
+                        && chain.layers()[layer].info.compression_method != compression_info
+                })
+            {
+                return Err(
+                    "Comparing checksums needs the same compression as the reference archive"
+                        .into(),
+                );
+            }
+            Some(chain)
+        }
+    };
+
+    if recovery.is_some() && output == "-" {
+        return Err("Recovery records can't be written to stdout".into());
+    }
+    if volume_size.is_some() && output == "-" {
+        return Err("Volumes can't be written to stdout".into());
+    }
+
+    if resume && output == "-" {
+        return Err("Writing to stdout can't be resumed".into());
+    }
+    let partial = match resume {
+        true => Some(PartialArchive::open(Path::new(output))?),
+        false => None,
+    };
+    if let Some(partial) = &partial {
+        check_resumable(partial, &compression_info, encrypt)?;
+    }
+
+    let passphrase = if encrypt && partial.is_none() {
+        Some(encryption::passphrase(true)?)
+    } else {
+        None
+    };
+
+    let mut archive = match partial {
+        None => create_archive(output, compression, volume_size)?,
+        Some(partial) => {
+            eprintln!(
+                "Resuming after {} entries ({} of {} bytes kept)...",
+                partial.entries.len(),
+                partial.position,
+                partial.size
+            );
+            partial.resume(compression)?
+        }
+    };
+    archive.set_jobs(jobs);
+    archive.set_xattrs(xattrs);
+    archive.set_chunked(chunked);
+    archive.set_content_hash(hash);
+    if let Some(p) = passphrase {
+        archive.set_encryption(&p)?;
+    }
+    // volumes are written on the go, and aren't resumable
+    let checkpoints = output != "-" && volume_size.is_none();
+    if checkpoints {
+        archive.set_checkpoint(
+            checkpoint_path(Path::new(output)),
+            Duration::from_secs(checkpoint_interval),
+        );
+    }
+
+    let mut info = Info {
+        compression_method: compression_info,
+        comment: comment.map(|x| x.to_owned()),
+        parent: None,
+        deleted: Vec::new(),
+    };
+
+    eprintln!("Indexing...");
+    for path in paths {
+        if Path::new(path).is_absolute() {
+            return Err(Error::AbsolutePath);
+        }
+        add_path(&mut archive, &filter, base_dir, path)?;
+    }
+    // resumed archives keep the dictionary they have
+    if zstd_dict && !resume {
+        train_dictionary(&mut archive, &mut info.compression_method)?;
+    }
+    if let Some(ref reference) = reference {
+        eprintln!("Comparing with the reference archive...");
+        let deleted = archive.diff_with(reference, compare_content)?;
+        info.parent = Some(reference.parent_info(output));
+        info.deleted = deleted.iter().map(|x| StoredPath::from(&x[..])).collect();
+    }
+
+    eprintln!("Writing files...");
+    let result = if resume {
+        archive.merge_resumed();
+        archive.write_appended()
+    } else {
+        archive.set_info(&info);
+        archive.write()
+    };
+    if result.is_err() && checkpoints {
+        eprintln!("Interrupted; run again with --resume to continue from the last checkpoint");
+    }
+    result?;
+
+    archive.flush()?;
+    let entry_count = archive.entry_count();
+    if resume {
+        let mut file = OpenOptions::new().read(true).write(true).open(output)?;
+        write_entry_count(&mut file, entry_count)?;
+    }
+    archive.remove_checkpoint()?;
+    drop(archive);
+
+    if let Some(percent) = recovery {
+        eprintln!("Writing recovery records...");
+        recovery::add_recovery(output, percent)?;
+    }
+
+    Ok(())
+}
+
+/// Build the compression policy from the command line, and return it with the
+/// description recorded in the info JSON
+pub fn compression_from_matches<'a>(
+    matches: &ArgMatches,
+    data_filter_cmd: Option<&'a Vec<String>>,
+) -> Result<(CompressionPolicy<'a>, String)> {
+    let store_patterns = matches
+        .get_many::<String>("store")
+        .map(|x| x.collect::<Vec<_>>())
+        .unwrap_or_default();
+    let auto_store = matches.get_flag("auto-store");
+
     let compressor_type;
-    let compression_info;
+    let mut compression_info;
     let compressor: Box<dyn Compress> = match data_filter_cmd {
         None => {
             // use built-in compressors
@@ -41,21 +222,20 @@ pub fn main(matches: &ArgMatches) -> Result<()> {
                 .map_err(|_| Error::InvalidCompressor)?;
             let compress_level =
                 Level::from_str(compress_level).map_err(|_| Error::InvalidCompressor)?;
+            compress_level.check(compressor_name)?;
+            let options = codec_options_from_matches(matches);
+            options.check(compressor_name)?;
 
             compressor_type = compressor_name;
-            compression_info = if compressor_name == Compression::None {
-                String::from(compressor_name.as_str())
-            } else {
-                format!(
-                    "{}:{}",
-                    compressor_name.as_str(),
-                    compress_level.to_numeric(compressor_name)
-                )
-            };
+            compression_info = compression_description(compressor_name, compress_level);
+            for option in options.describe() {
+                compression_info.push(',');
+                compression_info.push_str(&option);
+            }
 
-            create_compressor(compressor_name, compress_level)
+            create_compressor(compressor_name, compress_level, &options)
         }
-        Some(ref cmd) => {
+        Some(cmd) => {
             // external compressor
             compressor_type = Compression::External;
             compression_info = format!("External({:?})", cmd);
@@ -68,46 +248,100 @@ pub fn main(matches: &ArgMatches) -> Result<()> {
         .compressor_type
         .replace(compressor_type);
 
-    let mut archive = create_archive(output, compressor, compressor_type)?;
+    let mut compression = CompressionPolicy::new(compressor_type, compressor);
+    compression.set_store_rules(build_glob_set(store_patterns)?);
+    compression.set_auto_store(auto_store);
+    Ok((compression, compression_info))
+}
 
-    let info = Info {
-        compression_method: compression_info,
-        comment: comment.map(|x| x.to_owned()),
-    };
-    archive.set_info(&info);
+fn codec_options_from_matches(matches: &ArgMatches) -> CodecOptions {
+    CodecOptions {
+        zstd_long: matches.get_flag("zstd-long"),
+        zstd_window_log: matches.get_one::<u32>("zstd-window-log").copied(),
+        xz_extreme: matches.get_flag("xz-extreme"),
+        brotli_window: matches.get_one::<u32>("brotli-window").copied(),
+    }
+}
 
-    println!("Indexing...");
-    for path in paths {
-        if Path::new(path).is_absolute() {
-            return Err(Error::AbsolutePath);
-        }
-        add_path(&mut archive, base_dir, path)?;
+/// Check an interrupted archive was being created with the same compression and
+/// encryption
+fn check_resumable(partial: &PartialArchive, compression_info: &str, encrypt: bool) -> Result<()> {
+    let info = parse_info(&partial.header)?;
+    let method = &info.compression_method;
+    let method = method.strip_suffix(",dictionary").unwrap_or(method);
+    if method != compression_info {
+        return Err(Error::Others(format!(
+            "The compression differs from the interrupted run ({})",
+            method
+        )));
+    }
+    if encrypt != partial.header.is_encrypted() {
+        return Err("The encryption differs from the interrupted run".into());
     }
-    println!("Writing files...");
-    archive.write()?;
+    Ok(())
+}
 
-    archive.flush()?;
+/// Dictionaries are only trained for zstd
+pub fn check_dictionary(method: Compression) -> Result<()> {
+    if method != Compression::Zstd {
+        return Err(Error::Others(format!(
+            "Dictionaries don't apply to {}",
+            method.as_str()
+        )));
+    }
+    Ok(())
+}
 
+/// Train a zstd dictionary from the files added to `archive`, and note it in
+/// the compression description
+pub fn train_dictionary<W: Write>(
+    archive: &mut Archive<W>,
+    compression_info: &mut String,
+) -> Result<()> {
+    eprintln!("Training the dictionary...");
+    if archive.train_dictionary(DICTIONARY_SIZE)? {
+        compression_info.push_str(",dictionary");
+    } else {
+        eprintln!("Not enough small files for a dictionary; compressing without it");
+    }
     Ok(())
 }
 
-fn create_archive<'a, P: AsRef<Path>>(
+/// The compression method as recorded in the info JSON, like "zstd:19"
+pub fn compression_description(method: Compression, level: Level) -> String {
+    if method == Compression::None {
+        String::from(method.as_str())
+    } else {
+        format!("{}:{}", method.as_str(), level.to_numeric(method))
+    }
+}
+
+fn same_file<P: AsRef<Path>>(a: P, b: P) -> bool {
+    match (a.as_ref().canonicalize(), b.as_ref().canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Create the output archive, as volumes of `volume_size` bytes if given
+pub fn create_archive<'a, P: AsRef<Path>>(
     path: P,
-    compressor: Box<dyn Compress + 'a>,
-    compression_type: Compression,
-) -> Result<Archive<impl Write + Seek>> {
-    let file = File::options()
-        .truncate(true)
-        .create(true)
-        .read(true)
-        .write(true)
-        .open(path)?;
-    let writer = BufWriter::new(file);
-    Archive::new(writer, compressor, compression_type)
+    compression: CompressionPolicy<'a>,
+    volume_size: Option<u64>,
+) -> Result<Archive<'a, BufWriter<Box<dyn Write>>>> {
+    let path = path.as_ref();
+    let writer: Box<dyn Write> = match volume_size {
+        _ if path == Path::new("-") => Box::new(stdout().lock()),
+        Some(size) => Box::new(VolumeWriter::create(path, size)?),
+        None => Box::new(File::create(path)?),
+    };
+    let writer = BufWriter::new(writer);
+    Archive::new(writer, compression)
 }
 
-fn add_path<P: AsRef<Path>>(
-    archive: &mut Archive<impl Write + Seek>,
+pub fn add_path<P: AsRef<Path>>(
+    archive: &mut Archive<impl Write>,
+    filter: &FileFilter,
     base_dir: P,
     path: P,
 ) -> Result<()> {
@@ -125,12 +359,20 @@ fn add_path<P: AsRef<Path>>(
         components.next() == Some(Component::CurDir) && components.next().is_none()
     };
 
-    let entries = walkdir::WalkDir::new(walk_dir_path);
+    let entries = walkdir::WalkDir::new(walk_dir_path)
+        .into_iter()
+        .filter_entry(|e| {
+            let relative_path = diff_paths(e.path(), base_dir).unwrap();
+            !filter.is_excluded(&relative_path, e.file_type().is_dir())
+        });
     for entry in entries {
         let entry = entry?;
         let path = entry.path();
 
         let relative_path = diff_paths(path, base_dir).unwrap();
+        if !filter.is_included(&relative_path) {
+            continue;
+        }
 
         let stored_path = {
             let mut buf = if path_is_current {
@@ -0,0 +1,64 @@
+use std::ffi::OsStr;
+
+use bczhc_lib::str::GenericOsStrExt;
+use clap::ArgMatches;
+
+use crate::compressors::{CompressionPolicy, NoCompressor};
+use crate::errors::*;
+use crate::filter::EntryFilter;
+use crate::update::modify;
+use crate::{Compression, Entry, FileType, OsStrExt};
+
+pub fn main(matches: &ArgMatches) -> Result<()> {
+    let path = matches.get_one::<String>("archive").unwrap();
+    let filter = EntryFilter::from_matches(matches)?;
+
+    let compression = CompressionPolicy::new(Compression::None, Box::new(NoCompressor));
+    let mut deleted_count = 0;
+    modify(path, compression, |archive| {
+        let entries = archive.existing_entries_mut();
+        let (deleted, mut kept): (Vec<_>, Vec<_>) = std::mem::take(entries)
+            .into_iter()
+            .partition(|x| filter.is_selected(&x.path));
+        for entry in &deleted {
+            eprintln!("{}", OsStr::from_bytes(&entry.path).escape());
+        }
+        relink(&mut kept, &deleted);
+        deleted_count = deleted.len();
+        *entries = kept;
+        Ok(())
+    })?;
+
+    println!("Deleted entries: {}", deleted_count);
+    Ok(())
+}
+
+/// Keep hard links to deleted files valid
+///
+/// The first hard link to a deleted file takes over its content, and the other
+/// ones link to it instead.
+fn relink(entries: &mut [Entry], deleted: &[Entry]) {
+    for target in deleted.iter().filter(|x| x.file_type == FileType::Regular) {
+        let mut new_target: Option<Vec<u8>> = None;
+        for entry in entries.iter_mut() {
+            if entry.file_type != FileType::Link || entry.linked_path != target.path {
+                continue;
+            }
+            match &new_target {
+                None => {
+                    let path = std::mem::take(&mut entry.path);
+                    new_target = Some(path.clone());
+                    *entry = Entry {
+                        path_length: path.len() as u16,
+                        path,
+                        ..target.clone()
+                    };
+                }
+                Some(p) => {
+                    entry.linked_path_length = p.len() as u16;
+                    entry.linked_path = p.clone();
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,331 @@
+//! Comparison between an archive and a directory tree
+//!
+//! Entries are matched by their paths relative to the directory, ignoring
+//! leading "./". Contents are compared through the recorded hashes if present
+//! (see [`crate::hash`]), or else by decompressing the archived content.
+
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::{File, Metadata};
+use std::io::{BufReader, Read, Write};
+use std::path::Path;
+use std::process::exit;
+use std::{fs, io};
+
+use bczhc_lib::str::GenericOsStrExt;
+use clap::ArgMatches;
+use pathdiff::diff_paths;
+
+use crate::archive::ToBytes;
+use crate::compressors::entry_decompressor;
+use crate::errors::*;
+use crate::extensions;
+use crate::hash::{hash_file, stored_hash};
+use crate::incremental::Chain;
+use crate::sparse::{Expand, SparseMap, ZeroFill};
+use crate::{normalized_path, Entry, FileType, OsStrExt, Timestamp};
+
+#[derive(Debug, PartialEq)]
+enum Difference {
+    Added,
+    Removed,
+    /// content, type or link target changed, along with the fields
+    Modified(Vec<&'static str>),
+    /// only metadata changed, along with the fields
+    MetadataChanged(Vec<&'static str>),
+}
+
+pub fn main(matches: &ArgMatches) -> Result<()> {
+    let archive_path = matches.get_one::<String>("archive").unwrap();
+    let dir = Path::new(matches.get_one::<String>("dir").unwrap());
+    let external_filter_cmd = matches
+        .get_many::<String>("data-filter-cmd")
+        .map(|values| values.map(|x| x.to_owned()).collect::<Vec<_>>());
+
+    let differences = differences(archive_path, dir, external_filter_cmd.as_ref())?;
+    for (key, difference) in &differences {
+        let path = OsStr::from_bytes(key).escape();
+        match difference {
+            Difference::Added => println!("A {}", path),
+            Difference::Removed => println!("D {}", path),
+            Difference::Modified(fields) => println!("M {} ({})", path, fields.join(", ")),
+            Difference::MetadataChanged(fields) => println!("m {} ({})", path, fields.join(", ")),
+        }
+    }
+
+    if differences.is_empty() {
+        Ok(())
+    } else {
+        exit(1)
+    }
+}
+
+/// Differences between the archive and `dir`, sorted by path
+fn differences(
+    archive_path: &str,
+    dir: &Path,
+    external_filter_cmd: Option<&Vec<String>>,
+) -> Result<Vec<(Vec<u8>, Difference)>> {
+    // an incremental archive is compared along with all its ancestors
+    let mut chain = Chain::open(archive_path)?;
+    let entries = chain
+        .entries()
+        .map(|(l, e)| (l, e.clone()))
+        .collect::<Vec<_>>();
+    let mut archived = entries
+        .iter()
+        .enumerate()
+        .filter_map(|(i, (_, e))| Some((match_key(&e.path)?, i)))
+        .collect::<HashMap<_, _>>();
+
+    let mut differences = Vec::new();
+    for dir_entry in walkdir::WalkDir::new(dir).min_depth(1) {
+        let dir_entry = dir_entry?;
+        let path = dir_entry.path();
+        let relative_path = diff_paths(path, dir).unwrap();
+        let Some(key) = relative_path.as_os_str().to_bytes() else {
+            return Err(Error::Others(format!(
+                "Invalid path name: {}",
+                path.display()
+            )));
+        };
+
+        let Some(index) = archived.remove(&key) else {
+            differences.push((key, Difference::Added));
+            continue;
+        };
+        let (layer, entry) = &entries[index];
+        let metadata = path.symlink_metadata()?;
+
+        let changed = if entry.file_type == FileType::Link {
+            compare_link(dir, entry, &metadata)?
+        } else {
+            compare_type(entry, path, &metadata)?
+        };
+        if !changed.is_empty() {
+            differences.push((key, Difference::Modified(changed)));
+            continue;
+        }
+        if entry.file_type == FileType::Link {
+            continue;
+        }
+
+        let mut changed = compare_metadata(entry, &metadata);
+        if entry.file_type == FileType::Regular {
+            let reader = &mut chain.layer_mut(*layer).reader;
+            let same_content = match stored_hash(entry) {
+                _ if metadata.len() != entry.original_size => false,
+                Some((function, hash)) => hash_file(path, function)? == hash,
+                None => {
+                    let decompressor = entry_decompressor(
+                        entry.compression,
+                        external_filter_cmd,
+                        reader.dictionary(),
+                    )?;
+                    let file = BufReader::new(File::open(path)?);
+                    let compare = CompareWriter::new(file);
+                    let mut compare = Expand::new(ZeroFill(compare), SparseMap::of(entry)?);
+                    reader.decompress_entry(entry, &*decompressor, &mut compare)?;
+                    compare.finish()?.0.finish()?
+                }
+            };
+            if !same_content {
+                changed.insert(0, "content");
+                differences.push((key, Difference::Modified(changed)));
+                continue;
+            }
+        }
+        if !changed.is_empty() {
+            differences.push((key, Difference::MetadataChanged(changed)));
+        }
+    }
+    for (key, _) in archived {
+        differences.push((key, Difference::Removed));
+    }
+
+    differences.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(differences)
+}
+
+/// The path to match with the relative path on disk
+///
+/// Returns `None` for the base directory itself.
+fn match_key(stored_path: &[u8]) -> Option<Vec<u8>> {
+    normalized_path(stored_path).map(|x| x.to_vec())
+}
+
+/// Compare the file type, the target of symlinks and device numbers
+fn compare_type(entry: &Entry, path: &Path, metadata: &Metadata) -> Result<Vec<&'static str>> {
+    if FileType::try_from(metadata.file_type()) != Ok(entry.file_type) {
+        return Ok(vec!["type"]);
+    }
+    #[cfg(unix)]
+    if matches!(
+        entry.file_type,
+        FileType::CharDevice | FileType::BlockDevice
+    ) {
+        use std::os::unix::fs::MetadataExt;
+        let device = metadata.rdev();
+        let numbers = (
+            nix::sys::stat::major(device) as u32,
+            nix::sys::stat::minor(device) as u32,
+        );
+        if extensions::device_numbers(entry) != Some(numbers) {
+            return Ok(vec!["device"]);
+        }
+    }
+    if entry.file_type == FileType::Symlink {
+        let target = fs::read_link(path)?;
+        if target.as_os_str().to_bytes().as_ref() != Some(&entry.linked_path) {
+            return Ok(vec!["target"]);
+        }
+    }
+    Ok(Vec::new())
+}
+
+/// Check a hard link entry is still a hard link to the same file
+fn compare_link(dir: &Path, entry: &Entry, metadata: &Metadata) -> Result<Vec<&'static str>> {
+    let linked = match_key(&entry.linked_path)
+        .map(|x| dir.join(OsStr::from_bytes(&x)))
+        .and_then(|x| x.symlink_metadata().ok());
+    let same_file = match linked {
+        Some(linked) => metadata.is_file() && file_id(&linked) == file_id(metadata),
+        None => false,
+    };
+    Ok(if same_file { Vec::new() } else { vec!["link"] })
+}
+
+fn file_id(metadata: &Metadata) -> (u64, u64) {
+    cfg_if::cfg_if! {
+        if #[cfg(unix)] {
+            use std::os::unix::fs::MetadataExt;
+            (metadata.dev(), metadata.ino())
+        } else {
+            let _ = metadata;
+            (0, 0)
+        }
+    }
+}
+
+fn compare_metadata(entry: &Entry, metadata: &Metadata) -> Vec<&'static str> {
+    let mut changed = Vec::new();
+    cfg_if::cfg_if! {
+        if #[cfg(unix)] {
+            use std::os::unix::fs::MetadataExt;
+            if metadata.mode() as u16 != entry.permission_mode {
+                changed.push("mode");
+            }
+            if metadata.uid() != entry.owner_id || metadata.gid() != entry.group_id {
+                changed.push("owner");
+            }
+        }
+    }
+    let modification_time = metadata.modified().ok().map(Timestamp::from);
+    if modification_time.as_ref() != Some(&entry.modification_time) {
+        changed.push("mtime");
+    }
+    changed
+}
+
+/// Compares the data written with what's read from `reader`
+struct CompareWriter<R: Read> {
+    reader: R,
+    buffer: Vec<u8>,
+    equal: bool,
+}
+
+impl<R: Read> CompareWriter<R> {
+    fn new(reader: R) -> Self {
+        Self {
+            reader,
+            buffer: Vec::new(),
+            equal: true,
+        }
+    }
+
+    /// Returns if the data written is the same as all of the reader
+    fn finish(mut self) -> io::Result<bool> {
+        Ok(self.equal && self.reader.read(&mut [0])? == 0)
+    }
+}
+
+impl<R: Read> Write for CompareWriter<R> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.equal {
+            self.buffer.resize(buf.len(), 0);
+            match self.reader.read_exact(&mut self.buffer) {
+                Ok(_) => self.equal = self.buffer == buf,
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => self.equal = false,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use std::fs;
+    use std::os::unix::fs::{symlink, PermissionsExt};
+
+    use filetime::FileTime;
+
+    use super::{differences, Difference};
+    use crate::test_utils::run;
+
+    #[test]
+    fn changes() {
+        for hash in [None, Some("blake3")] {
+            let dir = tempfile::tempdir().unwrap();
+            let src = dir.path().join("src");
+            fs::create_dir_all(src.join("sub")).unwrap();
+            fs::write(src.join("a"), b"aaa").unwrap();
+            fs::write(src.join("b"), b"bbb").unwrap();
+            fs::write(src.join("sub/c"), b"ccc").unwrap();
+            symlink("a", src.join("link")).unwrap();
+            let archive = dir.path().join("out.arc");
+            let archive_str = archive.to_str().unwrap();
+
+            let mut args = vec!["create", archive_str, "-C", src.to_str().unwrap(), "."];
+            if let Some(hash) = hash {
+                args.extend(["--hash", hash]);
+            }
+            run(&args).unwrap();
+            assert_eq!(differences(archive_str, &src, None).unwrap(), []);
+
+            // the same size and modification time
+            let mtime =
+                FileTime::from_last_modification_time(&fs::metadata(src.join("a")).unwrap());
+            fs::write(src.join("a"), b"abc").unwrap();
+            filetime::set_file_mtime(src.join("a"), mtime).unwrap();
+            fs::remove_file(src.join("b")).unwrap();
+            fs::write(src.join("new"), b"").unwrap();
+            fs::set_permissions(src.join("sub/c"), fs::Permissions::from_mode(0o600)).unwrap();
+            fs::remove_file(src.join("link")).unwrap();
+            symlink("sub/c", src.join("link")).unwrap();
+
+            let expected = [
+                (&b"a"[..], Difference::Modified(vec!["content"])),
+                (b"b", Difference::Removed),
+                (b"link", Difference::Modified(vec!["target"])),
+                (b"new", Difference::Added),
+                (b"sub/c", Difference::MetadataChanged(vec!["mode"])),
+            ]
+            .map(|(k, d)| (k.to_vec(), d));
+            assert_eq!(differences(archive_str, &src, None).unwrap(), expected);
+
+            // a directory in place of a file
+            fs::remove_file(src.join("a")).unwrap();
+            fs::create_dir(src.join("a")).unwrap();
+            let differences = differences(archive_str, &src, None).unwrap();
+            assert_eq!(
+                differences[0],
+                (b"a".to_vec(), Difference::Modified(vec!["type"]))
+            );
+        }
+    }
+}
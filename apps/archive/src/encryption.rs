@@ -0,0 +1,493 @@
+//! Authenticated encryption of archives
+//!
+//! The key is derived from a passphrase with Argon2id, whose salt and cost
+//! parameters are stored in the header. Encrypted with XChaCha20-Poly1305 are:
+//! - the info JSON, as a hex string in place
+//! - the stored content of each entry (or each chunk), as a separate stream
+//! - the entry table, as one stream
+//!
+//! A stream starts with a random nonce prefix, followed by sealed segments of
+//! [`SEGMENT_SIZE`] bytes, and the last segment (which can be empty) is marked
+//! in its nonce, so truncated or reordered segments fail to open. Each entry can
+//! still be read alone. The entry table stream also authenticates the header
+//! fields it depends on (see `Header::table_aad`), so they can't be changed
+//! without the passphrase.
+//!
+//! The costs are read before anything can be authenticated, so they're capped
+//! to keep a crafted header from making key derivation exhaust the memory or
+//! run for hours.
+
+use std::io;
+use std::io::{Read, Write};
+use std::sync::Mutex;
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, KeyInit, OsRng, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use once_cell::sync::Lazy;
+
+use crate::errors::{Error, Result};
+use crate::{FixedStoredSize, ReadFrom, WriteTo};
+
+pub const SALT_SIZE: usize = 16;
+/// Plaintext size of each segment in a stream
+pub const SEGMENT_SIZE: usize = 64 * 1024;
+const TAG_SIZE: usize = 16;
+const NONCE_SIZE: usize = 24;
+const NONCE_PREFIX_SIZE: usize = 16;
+
+/// Caps of the Argon2 costs accepted from headers: 1 GiB of memory, 16
+/// passes, 16 lanes
+pub const MAX_M_COST: u32 = 1024 * 1024;
+pub const MAX_T_COST: u32 = 16;
+pub const MAX_P_COST: u32 = 16;
+
+/// Environment variable to take the passphrase from, instead of prompting
+pub const PASSPHRASE_ENV: &str = "ARCHIVE_PASSPHRASE";
+
+/// Key derivation parameters stored in the header
+#[derive(Clone, Debug)]
+pub struct EncryptionHeader {
+    salt: [u8; SALT_SIZE],
+    /// Argon2 memory cost in KiB
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+}
+
+impl EncryptionHeader {
+    /// Random salt with the default costs
+    pub fn generate() -> Self {
+        let mut salt = [0_u8; SALT_SIZE];
+        OsRng.fill_bytes(&mut salt);
+        Self {
+            salt,
+            m_cost: Params::DEFAULT_M_COST,
+            t_cost: Params::DEFAULT_T_COST,
+            p_cost: Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+impl FixedStoredSize for EncryptionHeader {
+    const SIZE: usize = SALT_SIZE + 3 * std::mem::size_of::<u32>();
+}
+
+impl WriteTo for EncryptionHeader {
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&self.salt)?;
+        writer.write_u32::<LittleEndian>(self.m_cost)?;
+        writer.write_u32::<LittleEndian>(self.t_cost)?;
+        writer.write_u32::<LittleEndian>(self.p_cost)?;
+        Ok(())
+    }
+}
+
+impl ReadFrom for EncryptionHeader {
+    type Item = Self;
+
+    fn read_from<R: Read>(reader: &mut R) -> Result<Self::Item> {
+        let mut salt = [0_u8; SALT_SIZE];
+        reader.read_exact(&mut salt)?;
+        Ok(Self {
+            salt,
+            m_cost: reader.read_u32::<LittleEndian>()?,
+            t_cost: reader.read_u32::<LittleEndian>()?,
+            p_cost: reader.read_u32::<LittleEndian>()?,
+        })
+    }
+}
+
+static PASSPHRASE: Lazy<Mutex<Option<String>>> = Lazy::new(|| Mutex::new(None));
+
+/// Get the passphrase from [`PASSPHRASE_ENV`] or the terminal
+///
+/// It's asked only once per process, so all archives of an incremental chain
+/// share it. `confirm` asks for it twice when prompting.
+pub fn passphrase(confirm: bool) -> Result<String> {
+    let mut guard = PASSPHRASE.lock().unwrap();
+    if let Some(p) = &*guard {
+        return Ok(p.clone());
+    }
+    let passphrase = match std::env::var(PASSPHRASE_ENV) {
+        Ok(p) => p,
+        Err(_) => {
+            let p = rpassword::prompt_password("Passphrase: ")?;
+            if confirm && rpassword::prompt_password("Confirm passphrase: ")? != p {
+                return Err("Passphrases don't match".into());
+            }
+            p
+        }
+    };
+    if passphrase.is_empty() {
+        return Err("Empty passphrase".into());
+    }
+    guard.replace(passphrase.clone());
+    Ok(passphrase)
+}
+
+pub struct Cipher {
+    aead: XChaCha20Poly1305,
+}
+
+impl Cipher {
+    pub fn derive(passphrase: &str, header: &EncryptionHeader) -> Result<Self> {
+        if header.m_cost > MAX_M_COST || header.t_cost > MAX_T_COST || header.p_cost > MAX_P_COST {
+            return Err(Error::Others(format!(
+                "Key derivation costs too high: m={} t={} p={}",
+                header.m_cost, header.t_cost, header.p_cost
+            )));
+        }
+        let params = Params::new(header.m_cost, header.t_cost, header.p_cost, Some(32))
+            .map_err(|e| Error::Others(format!("Invalid key derivation parameters: {}", e)))?;
+        let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
+        let mut key = [0_u8; 32];
+        argon2
+            .hash_password_into(passphrase.as_bytes(), &header.salt, &mut key)
+            .map_err(|e| Error::Others(format!("Key derivation failed: {}", e)))?;
+        Ok(Self {
+            aead: XChaCha20Poly1305::new(Key::from_slice(&key)),
+        })
+    }
+
+    /// Encrypt a small message: random nonce followed by the ciphertext
+    pub fn seal(&self, plaintext: &[u8]) -> Vec<u8> {
+        let mut nonce = [0_u8; NONCE_SIZE];
+        OsRng.fill_bytes(&mut nonce);
+        let mut sealed = Vec::from(nonce);
+        sealed.extend(
+            self.aead
+                .encrypt(XNonce::from_slice(&nonce), plaintext)
+                .unwrap(),
+        );
+        sealed
+    }
+
+    pub fn open(&self, sealed: &[u8]) -> Result<Vec<u8>> {
+        if sealed.len() < NONCE_SIZE {
+            return Err(Error::Decryption);
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE);
+        self.aead
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .map_err(|_| Error::Decryption)
+    }
+
+    /// [`Cipher::seal`] a string into hex, to be stored in place of it
+    pub fn seal_string(&self, plaintext: &str) -> String {
+        self.seal(plaintext.as_bytes())
+            .iter()
+            .map(|x| format!("{:02x}", x))
+            .collect()
+    }
+
+    pub fn open_string(&self, sealed: &str) -> Result<String> {
+        let sealed = sealed
+            .as_bytes()
+            .chunks(2)
+            .map(|x| u8::from_str_radix(std::str::from_utf8(x).ok()?, 16).ok())
+            .collect::<Option<Vec<_>>>()
+            .ok_or(Error::Decryption)?;
+        String::from_utf8(self.open(&sealed)?).map_err(|_| Error::Decryption)
+    }
+}
+
+fn segment_nonce(prefix: &[u8; NONCE_PREFIX_SIZE], counter: u32, last: bool) -> XNonce {
+    let mut nonce = [0_u8; NONCE_SIZE];
+    nonce[..NONCE_PREFIX_SIZE].copy_from_slice(prefix);
+    nonce[NONCE_PREFIX_SIZE..(NONCE_PREFIX_SIZE + 4)].copy_from_slice(&counter.to_be_bytes());
+    nonce[NONCE_PREFIX_SIZE + 4] = last as u8;
+    *XNonce::from_slice(&nonce)
+}
+
+/// Encrypts a stream; [`EncryptWriter::finish`] must be called at the end
+pub struct EncryptWriter<'a, W: Write> {
+    cipher: &'a Cipher,
+    inner: W,
+    prefix: [u8; NONCE_PREFIX_SIZE],
+    /// associated data authenticated with every segment
+    aad: Vec<u8>,
+    counter: u32,
+    buf: Vec<u8>,
+    written: u64,
+}
+
+impl<'a, W: Write> EncryptWriter<'a, W> {
+    pub fn new(cipher: &'a Cipher, inner: W) -> Self {
+        Self::with_aad(cipher, inner, &[])
+    }
+
+    /// Also authenticate `aad`, which [`DecryptReader::with_aad`] needs to be
+    /// given the same
+    pub fn with_aad(cipher: &'a Cipher, inner: W, aad: &[u8]) -> Self {
+        let mut prefix = [0_u8; NONCE_PREFIX_SIZE];
+        OsRng.fill_bytes(&mut prefix);
+        Self {
+            cipher,
+            inner,
+            prefix,
+            aad: aad.to_vec(),
+            counter: 0,
+            buf: Vec::with_capacity(SEGMENT_SIZE),
+            written: 0,
+        }
+    }
+
+    fn write_segment(&mut self, last: bool) -> io::Result<()> {
+        if self.counter == 0 {
+            self.inner.write_all(&self.prefix)?;
+            self.written += NONCE_PREFIX_SIZE as u64;
+        }
+        let nonce = segment_nonce(&self.prefix, self.counter, last);
+        let payload = Payload {
+            msg: &self.buf,
+            aad: &self.aad,
+        };
+        let sealed = self.cipher.aead.encrypt(&nonce, payload).unwrap();
+        self.inner.write_all(&sealed)?;
+        self.written += sealed.len() as u64;
+        self.counter = self
+            .counter
+            .checked_add(1)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Stream too long"))?;
+        self.buf.clear();
+        Ok(())
+    }
+
+    /// Seal the last segment, and return the total written size
+    pub fn finish(mut self) -> io::Result<u64> {
+        self.write_segment(true)?;
+        Ok(self.written)
+    }
+}
+
+impl<'a, W: Write> Write for EncryptWriter<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let size = buf.len().min(SEGMENT_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&buf[..size]);
+        if self.buf.len() == SEGMENT_SIZE {
+            self.write_segment(false)?;
+        }
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Decrypts a stream written by [`EncryptWriter`]
+///
+/// `inner` should end where the stream ends. Failing to authenticate gives
+/// [`io::ErrorKind::InvalidData`] errors.
+pub struct DecryptReader<'a, R: Read> {
+    cipher: &'a Cipher,
+    inner: R,
+    prefix: Option<[u8; NONCE_PREFIX_SIZE]>,
+    aad: Vec<u8>,
+    counter: u32,
+    /// decrypted segment and the read position in it
+    buf: Vec<u8>,
+    position: usize,
+    finished: bool,
+}
+
+impl<'a, R: Read> DecryptReader<'a, R> {
+    pub fn new(cipher: &'a Cipher, inner: R) -> Self {
+        Self::with_aad(cipher, inner, &[])
+    }
+
+    pub fn with_aad(cipher: &'a Cipher, inner: R, aad: &[u8]) -> Self {
+        Self {
+            cipher,
+            inner,
+            prefix: None,
+            aad: aad.to_vec(),
+            counter: 0,
+            buf: Vec::new(),
+            position: 0,
+            finished: false,
+        }
+    }
+
+    fn read_segment(&mut self) -> io::Result<()> {
+        let prefix = match self.prefix {
+            Some(p) => p,
+            None => {
+                let mut prefix = [0_u8; NONCE_PREFIX_SIZE];
+                self.inner.read_exact(&mut prefix).map_err(invalid_data)?;
+                self.prefix = Some(prefix);
+                prefix
+            }
+        };
+        let mut sealed = vec![0_u8; SEGMENT_SIZE + TAG_SIZE];
+        let size = read_fully(&mut self.inner, &mut sealed)?;
+        // only the last segment is shorter
+        let last = size < sealed.len();
+        if size == 0 {
+            // the last segment is missing
+            return Err(invalid_data(Error::Decryption));
+        }
+        let nonce = segment_nonce(&prefix, self.counter, last);
+        let payload = Payload {
+            msg: &sealed[..size],
+            aad: &self.aad,
+        };
+        self.buf = self
+            .cipher
+            .aead
+            .decrypt(&nonce, payload)
+            .map_err(|_| invalid_data(Error::Decryption))?;
+        self.position = 0;
+        self.counter = self.counter.wrapping_add(1);
+        self.finished = last;
+        Ok(())
+    }
+}
+
+impl<'a, R: Read> Read for DecryptReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.buf.len() {
+            if self.finished {
+                return Ok(0);
+            }
+            self.read_segment()?;
+        }
+        let size = buf.len().min(self.buf.len() - self.position);
+        buf[..size].copy_from_slice(&self.buf[self.position..(self.position + size)]);
+        self.position += size;
+        Ok(size)
+    }
+}
+
+fn invalid_data<E>(e: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+fn read_fully<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut size = 0;
+    while size < buf.len() {
+        match reader.read(&mut buf[size..]) {
+            Ok(0) => break,
+            Ok(n) => size += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(size)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+
+    use super::{
+        Cipher, DecryptReader, EncryptWriter, EncryptionHeader, MAX_M_COST, MAX_P_COST, MAX_T_COST,
+        SEGMENT_SIZE,
+    };
+
+    fn cipher() -> Cipher {
+        let header = EncryptionHeader {
+            m_cost: 8,
+            t_cost: 1,
+            p_cost: 1,
+            ..EncryptionHeader::generate()
+        };
+        Cipher::derive("passphrase", &header).unwrap()
+    }
+
+    fn encrypt(cipher: &Cipher, data: &[u8]) -> Vec<u8> {
+        let mut encrypted = Vec::new();
+        let mut writer = EncryptWriter::new(cipher, &mut encrypted);
+        writer.write_all(data).unwrap();
+        let size = writer.finish().unwrap();
+        assert_eq!(size, encrypted.len() as u64);
+        encrypted
+    }
+
+    fn decrypt(cipher: &Cipher, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        let mut decrypted = Vec::new();
+        DecryptReader::new(cipher, data).read_to_end(&mut decrypted)?;
+        Ok(decrypted)
+    }
+
+    #[test]
+    fn stream_round_trip() {
+        let cipher = cipher();
+        for size in [0, 1, SEGMENT_SIZE, SEGMENT_SIZE + 1, 3 * SEGMENT_SIZE] {
+            let data = (0..size).map(|x| x as u8).collect::<Vec<_>>();
+            let encrypted = encrypt(&cipher, &data);
+            assert_eq!(decrypt(&cipher, &encrypted).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn stream_tampering() {
+        let cipher = cipher();
+        let data = vec![0_u8; 2 * SEGMENT_SIZE + 100];
+        let encrypted = encrypt(&cipher, &data);
+
+        let mut modified = encrypted.clone();
+        modified[SEGMENT_SIZE + 50] ^= 1;
+        assert!(decrypt(&cipher, &modified).is_err());
+
+        // dropping whole segments from the end
+        let segment = SEGMENT_SIZE + 16;
+        assert!(decrypt(&cipher, &encrypted[..(encrypted.len() - 116)]).is_err());
+        assert!(decrypt(&cipher, &encrypted[..(16 + segment)]).is_err());
+
+        assert!(decrypt(&self::cipher(), &encrypted).is_err());
+    }
+
+    #[test]
+    fn sealed_string() {
+        let cipher = cipher();
+        let sealed = cipher.seal_string("{}");
+        assert_eq!(cipher.open_string(&sealed).unwrap(), "{}");
+        assert!(self::cipher().open_string(&sealed).is_err());
+        assert!(cipher.open_string("{}").is_err());
+    }
+
+    #[test]
+    fn stream_aad() {
+        let cipher = cipher();
+        let mut encrypted = Vec::new();
+        let mut writer = EncryptWriter::with_aad(&cipher, &mut encrypted, b"header");
+        writer.write_all(b"table").unwrap();
+        writer.finish().unwrap();
+
+        let decrypt = |aad: &[u8]| {
+            let mut decrypted = Vec::new();
+            DecryptReader::with_aad(&cipher, &encrypted[..], aad)
+                .read_to_end(&mut decrypted)
+                .map(|_| decrypted)
+        };
+        assert_eq!(decrypt(b"header").unwrap(), b"table");
+        assert!(decrypt(b"headex").is_err());
+        assert!(decrypt(b"").is_err());
+    }
+
+    #[test]
+    fn cost_caps() {
+        let header = EncryptionHeader::generate();
+        for (m_cost, t_cost, p_cost) in [
+            (MAX_M_COST + 1, 1, 1),
+            (8, MAX_T_COST + 1, 1),
+            (8, 1, MAX_P_COST + 1),
+            (u32::MAX, u32::MAX, u32::MAX),
+        ] {
+            let header = EncryptionHeader {
+                m_cost,
+                t_cost,
+                p_cost,
+                ..header.clone()
+            };
+            assert!(Cipher::derive("passphrase", &header).is_err());
+        }
+    }
+}
@@ -1,5 +1,6 @@
 use std::fmt::Debug;
 use std::io;
+use std::path::PathBuf;
 use std::string::FromUtf8Error;
 
 use thiserror::Error;
@@ -16,10 +17,20 @@ pub enum Error {
     InvalidBaseDir,
     #[error("{0}")]
     WalkDir(#[from] walkdir::Error),
+    #[error("{0}")]
+    Glob(#[from] globset::Error),
+    #[error("{0}")]
+    Regex(#[from] regex::Error),
+    #[error("{0}")]
+    Ignore(#[from] ignore::Error),
     #[error("Unknown compressor (name or level)")]
     InvalidCompressor,
     #[error("Invalid file type")]
     InvalidFileType,
+    #[error("Invalid archive footer")]
+    InvalidFooter,
+    #[error("Unsupported archive version: {0}")]
+    UnsupportedVersion(u16),
     #[error("Invalid entry header")]
     InvalidEntryHeader,
     #[error("Unknown file type")]
@@ -27,7 +38,7 @@ pub enum Error {
     #[error("Unknown compression method")]
     UnknownCompressionMethod,
     #[error("Checksum error for entry: {0:?}")]
-    Checksum(Entry),
+    Checksum(Box<Entry>),
     #[cfg(unix)]
     #[error("Unix errno: {0}")]
     Errno(#[from] nix::Error),
@@ -47,6 +58,16 @@ pub enum Error {
     CompressorError(String),
     #[error("Error from decompressor: {0}")]
     DecompressorError(String),
+    #[error("{0} unsafe entries skipped; use --unsafe to extract them anyway")]
+    UnsafeEntries(u64),
+    #[error("Decryption failed: wrong passphrase or corrupted data")]
+    Decryption,
+    #[error("{0}")]
+    Zip(#[from] zip::result::ZipError),
+    #[error("Parent archive mismatch: {0:?}")]
+    ParentMismatch(PathBuf),
+    #[error("Missing volume: {0:?}")]
+    MissingVolume(PathBuf),
     #[error("{0}")]
     Others(String),
 }
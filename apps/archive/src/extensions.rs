@@ -0,0 +1,95 @@
+//! Known keys of the entry extension area
+//!
+//! Extended attributes are stored as `xattr.<name>`, for example
+//! `xattr.user.comment`. POSIX ACLs are kept this way too, as Linux exposes them
+//! as the `system.posix_acl_access` and `system.posix_acl_default` attributes.
+//!
+//! Hashes of the original content are stored as `hash.<function>` (see
+//! [`crate::hash`]).
+//!
+//! Device files have their major and minor numbers as `device`, two
+//! little-endian u32, and sparse files their data ranges as `sparse` (see
+//! [`crate::sparse`]).
+
+use std::ffi::OsStr;
+use std::io;
+use std::path::Path;
+
+use crate::{Entry, Extension, OsStrExt};
+
+pub const XATTR_PREFIX: &[u8] = b"xattr.";
+pub const HASH_PREFIX: &[u8] = b"hash.";
+pub const DEVICE_KEY: &[u8] = b"device";
+pub const SPARSE_KEY: &[u8] = b"sparse";
+
+/// Returns the major and minor numbers of a device file entry
+pub fn device_numbers(entry: &Entry) -> Option<(u32, u32)> {
+    let value = find(entry, DEVICE_KEY)?;
+    let (major, minor) = value.split_at_checked(4)?;
+    Some((
+        u32::from_le_bytes(major.try_into().ok()?),
+        u32::from_le_bytes(minor.try_into().ok()?),
+    ))
+}
+
+pub fn set_device_numbers(entry: &mut Entry, major: u32, minor: u32) {
+    let mut value = Vec::from(major.to_le_bytes());
+    value.extend_from_slice(&minor.to_le_bytes());
+    entry.extensions.retain(|x| x.key() != DEVICE_KEY);
+    entry
+        .extensions
+        .push(Extension::new(DEVICE_KEY.into(), value));
+}
+
+pub(crate) fn find<'a>(entry: &'a Entry, key: &[u8]) -> Option<&'a [u8]> {
+    entry
+        .extensions
+        .iter()
+        .find(|x| x.key() == key)
+        .map(|x| x.value())
+}
+
+/// Read all extended attributes of `path`, not following symlinks
+///
+/// Filesystems without xattr support give an empty list.
+#[cfg(unix)]
+pub fn read_xattrs(path: &Path) -> io::Result<Vec<Extension>> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let names = match xattr::list(path) {
+        Ok(names) => names,
+        Err(e) if e.raw_os_error() == Some(nix::libc::ENOTSUP) => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut extensions = Vec::new();
+    for name in names {
+        let Some(value) = xattr::get(path, &name)? else {
+            // removed in the meantime
+            continue;
+        };
+        let mut key = Vec::from(XATTR_PREFIX);
+        key.extend_from_slice(name.as_bytes());
+        extensions.push(Extension::new(key, value));
+    }
+    Ok(extensions)
+}
+
+/// Restore extended attributes of `path`, not following symlinks
+///
+/// Returns the attribute names failed to restore along with the errors; some
+/// namespaces (like `security.*` and `trusted.*`) need privileges.
+#[cfg(unix)]
+pub fn restore_xattrs(path: &Path, extensions: &[Extension]) -> Vec<(String, io::Error)> {
+    let mut errors = Vec::new();
+    for x in extensions {
+        let Some(name) = x.key().strip_prefix(XATTR_PREFIX) else {
+            continue;
+        };
+        let name = OsStr::from_bytes(name);
+        if let Err(e) = xattr::set(path, name, x.value()) {
+            errors.push((name.to_string_lossy().into_owned(), e));
+        }
+    }
+    errors
+}
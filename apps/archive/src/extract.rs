@@ -1,9 +1,9 @@
-use std::ffi::{OsStr, OsString};
+use std::ffi::OsStr;
 use std::fs::{File, Permissions};
 use std::io::stdout;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 use std::time::SystemTime;
-use std::{fs, os};
+use std::{fs, io, os};
 
 use cfg_if::cfg_if;
 use chrono::{TimeZone, Utc};
@@ -13,10 +13,13 @@ use filetime::FileTime;
 use bczhc_lib::io::OpenOrCreate;
 use bczhc_lib::str::GenericOsStrExt;
 
-use crate::compressors::{create_decompressor, Decompress, ExternalFilter};
+use crate::compressors::entry_decompressor;
 use crate::errors::*;
-use crate::reader::ArchiveReader;
-use crate::{Compression, FileType, LocalResultExt, OsStrExt};
+use crate::extensions;
+use crate::filter::EntryFilter;
+use crate::incremental::Chain;
+use crate::sparse::{Expand, SparseMap};
+use crate::{Entry, FileType, LocalResultExt, OsStrExt};
 
 pub fn main(matches: &ArgMatches) -> Result<()> {
     let archive_path = matches.get_one::<String>("archive").unwrap();
@@ -24,113 +27,99 @@ pub fn main(matches: &ArgMatches) -> Result<()> {
     let external_filter_cmd = matches
         .get_many::<String>("data-filter-cmd")
         .map(|values| values.map(|x| x.to_owned()).collect::<Vec<_>>());
-    let paths = matches
-        .get_many::<String>("paths")
-        .map(|x| x.map(OsString::from).collect::<Vec<_>>());
+    let filter = EntryFilter::from_matches(matches)?;
     let pipe_mode = matches.get_flag("pipe");
+    let restore_xattrs = !matches.get_flag("no-xattrs");
+    let unsafe_mode = matches.get_flag("unsafe");
+    let base_dir = Path::new(base_dir);
 
-    let mut archive = ArchiveReader::new(archive_path)?;
+    // an incremental archive is restored along with all its ancestors
+    let mut chain = Chain::open(archive_path)?;
 
-    let header = archive.header.clone();
-    let entries = archive.entries();
+    let entries = chain
+        .entries()
+        .map(|(l, e)| (l, e.clone()))
+        .collect::<Vec<_>>();
 
-    if pipe_mode && paths.is_none() && header.entry_count >= 2 {
+    if pipe_mode && !filter.has_selection() && entries.len() >= 2 {
         return Err("When in pipe mode, at least one <path> argument should be present".into());
     }
 
-    for entry in entries {
-        let entry = entry?;
+    // directory metadata is restored after all their children get extracted
+    let mut directories = Vec::new();
+    let mut unsafe_count = 0_u64;
+
+    for (layer, entry) in entries {
         let path = Path::new(OsStr::from_bytes(&entry.path));
-        let target_path = &{
-            let mut p = PathBuf::from(base_dir);
-            p.push(path);
-            p
-        };
 
-        // extract specified paths if present
-        if let Some(ref paths) = paths {
-            if !paths.iter().any(|x| Path::new(&x) == path) {
-                continue;
-            }
+        if !filter.is_selected(&entry.path) {
+            continue;
         }
 
-        let decompressor: Box<dyn Decompress> = match &header.compression {
-            Compression::External => match external_filter_cmd {
-                None => {
-                    return Err(Error::MissingDecompressor);
-                }
-                Some(ref cmd) => Box::new(ExternalFilter::new(cmd)),
-            },
-            _ => create_decompressor(header.compression),
-        };
+        let archive = &mut chain.layer_mut(layer).reader;
+        let decompressor = entry_decompressor(
+            entry.compression,
+            external_filter_cmd.as_ref(),
+            archive.dictionary(),
+        )?;
 
         if pipe_mode {
             // only support regular file
             // TODO: decompress if the data is compressed
             if entry.file_type == FileType::Regular {
                 let mut stdout = stdout();
-                let mut reader = archive.retrieve_content(entry.offset, entry.stored_size);
-                decompressor.decompress_to(&mut reader, &mut stdout)?;
+                archive.decompress_entry(&entry, &*decompressor, &mut stdout)?;
             }
-            drop(decompressor);
             continue;
         }
 
         // write to files ↓↓
+        let Some(target_path) = &resolve_target(base_dir, path, unsafe_mode) else {
+            eprintln!("{}: unsafe path, skipped", path.escape());
+            unsafe_count += 1;
+            continue;
+        };
+        if !unsafe_mode && entry.file_type != FileType::Directory {
+            // never write through an existing symlink
+            remove_symlink(target_path)?;
+        }
         println!("{}", path.escape());
 
         match entry.file_type {
             FileType::Regular => {
-                let stored_size = entry.stored_size;
-
                 if let Some(prefix) = target_path.parent() {
                     if !prefix.exists() {
                         fs::create_dir_all(prefix)?;
                     }
                 }
-                let mut file = File::open_or_create(target_path)?;
+                let file = File::open_or_create(target_path)?;
+                let mut file = Expand::new(file, SparseMap::of(&entry)?);
 
-                let mut content_reader = archive.retrieve_content(entry.offset, stored_size);
-                decompressor.decompress_to(&mut content_reader, &mut file)?;
-                drop(decompressor);
+                archive.decompress_entry(&entry, &*decompressor, &mut file)?;
+                drop(file.finish()?);
 
-                cfg_if! {
-                    if #[cfg(unix)] {
-                        use os::unix::fs::PermissionsExt;
-                        use nix::unistd::*;
-                        if nix::unistd::getuid().is_root() {
-                            fs::set_permissions(target_path, Permissions::from_mode(entry.permission_mode as u32))?;
-                            chown(
-                                target_path,
-                                Some(Uid::from(entry.owner_id as u32)),
-                                Some(Gid::from(entry.group_id as u32)),
-                            )?;
-                        }
-                    }
-                }
-                let time = Utc
-                    .timestamp_opt(
-                        entry.modification_time.seconds,
-                        entry.modification_time.nanoseconds,
-                    )
-                    .check()?;
-                filetime::set_file_mtime(target_path, FileTime::from(SystemTime::from(time)))?;
+                restore_metadata(target_path, &entry, restore_xattrs)?;
             }
             FileType::Link => {
-                let path = Path::new(OsStr::from_bytes(&entry.path));
+                // the linked path is also a stored path
                 let linked_path = Path::new(OsStr::from_bytes(&entry.linked_path));
-                fs::hard_link(linked_path, path)?;
+                let Some(linked_target) = resolve_target(base_dir, linked_path, unsafe_mode) else {
+                    eprintln!("{}: unsafe hard link target, skipped", path.escape());
+                    unsafe_count += 1;
+                    continue;
+                };
+                fs::hard_link(linked_target, target_path)?;
             }
             FileType::Symlink => {
-                let path = Path::new(OsStr::from_bytes(&entry.path));
                 let linked_path = Path::new(OsStr::from_bytes(&entry.linked_path));
                 cfg_if! {
                     if #[cfg(windows)] {
                         todo!();
                     } else {
-                        os::unix::fs::symlink(linked_path, path)?;
+                        os::unix::fs::symlink(linked_path, target_path)?;
                     }
                 }
+                restore_metadata(target_path, &entry, restore_xattrs)?;
             }
             FileType::Fifo => {
                 cfg_if! {
@@ -138,27 +127,433 @@ pub fn main(matches: &ArgMatches) -> Result<()> {
                         use nix::sys::stat::Mode;
                         nix::unistd::mkfifo(
                             target_path,
-                            Mode::from_bits(entry.permission_mode as nix::libc::mode_t).unwrap(),
+                            Mode::from_bits_truncate(entry.permission_mode as nix::libc::mode_t),
                         )?;
                     } else {
                         panic!("Not supported")
                     }
                 }
+                restore_metadata(target_path, &entry, restore_xattrs)?;
+            }
+            FileType::CharDevice | FileType::BlockDevice | FileType::Socket => {
+                cfg_if! {
+                    if #[cfg(unix)] {
+                        use nix::sys::stat::{makedev, mknod, Mode, SFlag};
+                        let kind = match entry.file_type {
+                            FileType::CharDevice => SFlag::S_IFCHR,
+                            FileType::BlockDevice => SFlag::S_IFBLK,
+                            _ => SFlag::S_IFSOCK,
+                        };
+                        let (major, minor) = extensions::device_numbers(&entry).unwrap_or((0, 0));
+                        let result = mknod(
+                            target_path,
+                            kind,
+                            Mode::from_bits_truncate(entry.permission_mode as nix::libc::mode_t),
+                            makedev(major as u64, minor as u64),
+                        );
+                        // device files need privileges
+                        if let Err(e) = result {
+                            eprintln!("{}: failed to create: {}", path.escape(), e);
+                            continue;
+                        }
+                    } else {
+                        panic!("Not supported")
+                    }
+                }
+                restore_metadata(target_path, &entry, restore_xattrs)?;
             }
             FileType::Directory => {
-                if target_path.exists() {
-                    if !(target_path.is_dir()) {
+                // don't follow symlinks here (unless in unsafe mode)
+                let existing_type = if unsafe_mode {
+                    fs::metadata(target_path)
+                } else {
+                    fs::symlink_metadata(target_path)
+                }
+                .map(|x| x.file_type());
+                match existing_type {
+                    Ok(t) if t.is_dir() => {}
+                    Ok(_) => {
                         // overwrite completely using directory
                         // same default action as `tar` utility
                         fs::remove_file(target_path)?;
                         fs::create_dir(target_path)?;
                     }
-                } else {
-                    fs::create_dir(target_path)?;
+                    Err(_) => {
+                        fs::create_dir_all(target_path)?;
+                    }
+                }
+                directories.push((target_path.clone(), entry));
+            }
+        }
+    }
+
+    // children first
+    for (path, entry) in directories.iter().rev() {
+        restore_metadata(path, entry, restore_xattrs)?;
+    }
+
+    if unsafe_count != 0 {
+        return Err(Error::UnsafeEntries(unsafe_count));
+    }
+    Ok(())
+}
+
+/// Map a stored path to its extraction target under `base_dir`
+///
+/// Unless in unsafe mode, returns `None` for absolute paths, paths containing `..`,
+/// and paths going through symlinks under `base_dir` (which may be created by the
+/// archive itself).
+fn resolve_target(base_dir: &Path, path: &Path, unsafe_mode: bool) -> Option<PathBuf> {
+    if unsafe_mode {
+        return Some(base_dir.join(path));
+    }
+
+    let mut target = base_dir.to_path_buf();
+    let mut components = path
+        .components()
+        .filter(|x| *x != Component::CurDir)
+        .peekable();
+    while let Some(c) = components.next() {
+        let Component::Normal(c) = c else {
+            // `..`, root directory or prefix
+            return None;
+        };
+        target.push(c);
+        let is_parent = components.peek().is_some();
+        if is_parent && is_symlink(&target) {
+            return None;
+        }
+    }
+    Some(target)
+}
+
+fn is_symlink(path: &Path) -> bool {
+    fs::symlink_metadata(path)
+        .map(|x| x.file_type().is_symlink())
+        .unwrap_or(false)
+}
+
+fn remove_symlink(path: &Path) -> io::Result<()> {
+    if is_symlink(path) {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// Restore ownership, permissions, extended attributes and modification time
+///
+/// Symlinks themselves are changed, instead of the files they point to.
+fn restore_metadata(path: &Path, entry: &Entry, restore_xattrs: bool) -> Result<()> {
+    let is_symlink = entry.file_type == FileType::Symlink;
+
+    cfg_if! {
+        if #[cfg(unix)] {
+            use os::unix::fs::PermissionsExt;
+            use nix::unistd::*;
+            if getuid().is_root() {
+                // chown clears some permission bits and file capabilities,
+                // so it goes first
+                fchownat(
+                    None,
+                    path,
+                    Some(Uid::from(entry.owner_id)),
+                    Some(Gid::from(entry.group_id)),
+                    FchownatFlags::NoFollowSymlink,
+                )?;
+                if !is_symlink {
+                    fs::set_permissions(path, Permissions::from_mode(entry.permission_mode as u32))?;
+                }
+            }
+            if restore_xattrs {
+                for (name, e) in extensions::restore_xattrs(path, &entry.extensions) {
+                    eprintln!("{}: failed to restore xattr {}: {}", path.escape(), name, e);
                 }
             }
         }
     }
 
+    let time = Utc
+        .timestamp_opt(
+            entry.modification_time.seconds,
+            entry.modification_time.nanoseconds,
+        )
+        .check()?;
+    let time = FileTime::from(SystemTime::from(time));
+    // setting only the modification time may open the file, which blocks for
+    // FIFOs and fails or has side effects for device files and sockets
+    if !matches!(entry.file_type, FileType::Regular | FileType::Directory) {
+        filetime::set_symlink_file_times(path, time, time)?;
+    } else {
+        filetime::set_file_mtime(path, time)?;
+    }
     Ok(())
 }
+
+#[cfg(all(test, unix))]
+mod test {
+    use std::ffi::OsStr;
+    use std::fs;
+    use std::fs::{File, Permissions};
+    use std::io::BufWriter;
+    use std::os::unix::fs::{symlink, PermissionsExt};
+    use std::path::{Path, PathBuf};
+
+    use filetime::FileTime;
+
+    use crate::archive::Archive;
+    use crate::compressors::{CompressionPolicy, NoCompressor};
+    use crate::errors::{Error, Result};
+    use crate::test_utils::run;
+    use crate::{build_cli, Compression, Info};
+
+    /// Craft an archive with arbitrary stored paths
+    ///
+    /// `entries`: (stored path, actual file path)
+    fn craft_archive(output: &Path, entries: &[(&OsStr, &Path)]) {
+        let writer = BufWriter::new(File::create(output).unwrap());
+        let policy = CompressionPolicy::new(Compression::None, Box::new(NoCompressor));
+        let mut archive = Archive::new(writer, policy).unwrap();
+        archive.set_info(&Info {
+            compression_method: "none".into(),
+            comment: None,
+            parent: None,
+            deleted: Vec::new(),
+        });
+        for (path, file_path) in entries {
+            archive.add_path(path, file_path).unwrap();
+        }
+        archive.write().unwrap();
+        archive.flush().unwrap();
+    }
+
+    /// Create `count` distinct files under `dir`
+    ///
+    /// The same file added twice would be recorded as a hard link.
+    fn source_files(dir: &Path, count: usize) -> Vec<PathBuf> {
+        (0..count)
+            .map(|i| {
+                let path = dir.join(format!("file{}", i));
+                fs::write(&path, "pwned").unwrap();
+                path
+            })
+            .collect()
+    }
+
+    fn extract(archive: &Path, base_dir: &Path) -> Result<()> {
+        let matches = build_cli()
+            .try_get_matches_from([
+                OsStr::new("archive"),
+                OsStr::new("extract"),
+                OsStr::new("-C"),
+                base_dir.as_os_str(),
+                archive.as_os_str(),
+            ])
+            .unwrap();
+        super::main(matches.subcommand_matches("extract").unwrap())
+    }
+
+    fn assert_unsafe(result: Result<()>, count: u64) {
+        match result {
+            Err(Error::UnsafeEntries(c)) => assert_eq!(c, count),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn parent_dir_traversal() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("base");
+        fs::create_dir_all(base.join("a")).unwrap();
+        let files = source_files(dir.path(), 3);
+
+        let archive = dir.path().join("archive");
+        craft_archive(
+            &archive,
+            &[
+                (OsStr::new("../evil"), &files[0]),
+                (OsStr::new("a/../../evil2"), &files[1]),
+                (OsStr::new("./ok"), &files[2]),
+            ],
+        );
+        assert_unsafe(extract(&archive, &base), 2);
+        assert!(!dir.path().join("evil").exists());
+        assert!(!dir.path().join("evil2").exists());
+        assert_eq!(fs::read_to_string(base.join("ok")).unwrap(), "pwned");
+    }
+
+    #[test]
+    fn absolute_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("base");
+        fs::create_dir(&base).unwrap();
+        let file = dir.path().join("file");
+        fs::write(&file, "pwned").unwrap();
+        let evil = dir.path().join("evil");
+
+        let archive = dir.path().join("archive");
+        craft_archive(&archive, &[(evil.as_os_str(), &file)]);
+        assert_unsafe(extract(&archive, &base), 1);
+        assert!(!evil.exists());
+    }
+
+    #[test]
+    fn write_through_symlink() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("base");
+        fs::create_dir(&base).unwrap();
+        let outside = dir.path().join("outside");
+        fs::create_dir(&outside).unwrap();
+        let files = source_files(dir.path(), 2);
+        let link = dir.path().join("link");
+        symlink(&outside, &link).unwrap();
+        let file_link = dir.path().join("file-link");
+        symlink(outside.join("target"), &file_link).unwrap();
+
+        let archive = dir.path().join("archive");
+        craft_archive(
+            &archive,
+            &[
+                // a symlink to the outside directory, and a file through it
+                (OsStr::new("a"), &link),
+                (OsStr::new("a/pwned"), &files[0]),
+                // a symlink to an outside file, and then a file replacing it
+                (OsStr::new("b"), &file_link),
+                (OsStr::new("b"), &files[1]),
+            ],
+        );
+        assert_unsafe(extract(&archive, &base), 1);
+        assert!(!outside.join("pwned").exists());
+        assert!(!outside.join("target").exists());
+        assert!(base.join("a").symlink_metadata().unwrap().is_symlink());
+        assert_eq!(fs::read_to_string(base.join("b")).unwrap(), "pwned");
+    }
+
+    #[test]
+    fn hard_link_outside() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("base");
+        fs::create_dir(&base).unwrap();
+        let victim = dir.path().join("victim");
+        fs::write(&victim, "original").unwrap();
+
+        let archive = dir.path().join("archive");
+        craft_archive(
+            &archive,
+            &[
+                // the second one becomes a hard link to the absolute path
+                (victim.as_os_str(), &victim),
+                (OsStr::new("evil"), &victim),
+            ],
+        );
+        assert_unsafe(extract(&archive, &base), 2);
+        assert_eq!(fs::read_to_string(&victim).unwrap(), "original");
+        assert!(!base.join("evil").exists());
+    }
+
+    #[test]
+    fn links_under_base_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        fs::create_dir(&src).unwrap();
+        fs::write(src.join("file"), "content").unwrap();
+        fs::hard_link(src.join("file"), src.join("hard")).unwrap();
+        symlink("file", src.join("sym")).unwrap();
+        let base = dir.path().join("base");
+        fs::create_dir(&base).unwrap();
+
+        let archive = dir.path().join("archive");
+        craft_archive(
+            &archive,
+            &[
+                (OsStr::new("file"), &src.join("file")),
+                (OsStr::new("hard"), &src.join("hard")),
+                (OsStr::new("sym"), &src.join("sym")),
+            ],
+        );
+        extract(&archive, &base).unwrap();
+        assert_eq!(fs::read_to_string(base.join("hard")).unwrap(), "content");
+        assert_eq!(fs::read_link(base.join("sym")).unwrap(), Path::new("file"));
+    }
+
+    #[test]
+    fn xattrs() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        fs::create_dir(&src).unwrap();
+        fs::write(src.join("file"), "content").unwrap();
+        match xattr::set(src.join("file"), "user.comment", b"hi") {
+            Err(e) if e.raw_os_error() == Some(nix::libc::ENOTSUP) => return,
+            r => r.unwrap(),
+        }
+        xattr::set(src.join("file"), "user.empty", b"").unwrap();
+
+        let archive = dir.path().join("archive");
+        let create = |xattrs: bool| {
+            let mut args = vec!["create".as_ref(), archive.as_os_str()];
+            args.extend(["-C".as_ref(), src.as_os_str(), "file".as_ref()]);
+            if xattrs {
+                args.push("--xattrs".as_ref());
+            }
+            run(&args).unwrap();
+        };
+        let extract_to = |name: &str, args: &[&str]| {
+            let base = dir.path().join(name);
+            fs::create_dir(&base).unwrap();
+            let mut all = vec!["extract".as_ref(), "-C".as_ref(), base.as_os_str()];
+            all.extend(args.iter().map(OsStr::new));
+            all.push(archive.as_os_str());
+            run(&all).unwrap();
+            base.join("file")
+        };
+
+        create(true);
+        let file = extract_to("restored", &[]);
+        assert_eq!(xattr::get(&file, "user.comment").unwrap().unwrap(), b"hi");
+        assert_eq!(xattr::get(&file, "user.empty").unwrap().unwrap(), b"");
+        let file = extract_to("skipped", &["--no-xattrs"]);
+        assert_eq!(xattr::get(&file, "user.comment").unwrap(), None);
+
+        create(false);
+        let file = extract_to("unrecorded", &[]);
+        assert_eq!(xattr::get(&file, "user.comment").unwrap(), None);
+    }
+
+    #[test]
+    fn deferred_directory_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        fs::create_dir_all(src.join("d/e")).unwrap();
+        fs::write(src.join("d/e/file"), "content").unwrap();
+        let time = FileTime::from_unix_time(1_000_000_000, 0);
+        for x in ["d/e", "d"] {
+            filetime::set_file_mtime(src.join(x), time).unwrap();
+        }
+        // read-only directories only get filled before their mode is restored
+        fs::set_permissions(src.join("d/e"), Permissions::from_mode(0o555)).unwrap();
+
+        let archive = dir.path().join("archive");
+        let mut args = vec!["create".as_ref(), archive.as_os_str()];
+        args.extend(["-C".as_ref(), src.as_os_str(), "d".as_ref()]);
+        run(&args).unwrap();
+        let base = dir.path().join("base");
+        fs::create_dir(&base).unwrap();
+        extract(&archive, &base).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(base.join("d/e/file")).unwrap(),
+            "content"
+        );
+        for x in ["d/e", "d"] {
+            let metadata = fs::metadata(base.join(x)).unwrap();
+            assert_eq!(FileTime::from_last_modification_time(&metadata), time);
+        }
+        if nix::unistd::getuid().is_root() {
+            let mode = fs::metadata(base.join("d/e")).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o555);
+        }
+        // let the temporary directory be removed
+        for x in [&src, &base] {
+            fs::set_permissions(x.join("d/e"), Permissions::from_mode(0o755)).unwrap();
+        }
+    }
+}
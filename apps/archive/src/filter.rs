@@ -0,0 +1,284 @@
+//! Selecting paths by patterns
+//!
+//! `create` filters the files being walked with [`FileFilter`], while `list`
+//! and `extract` pick entries out of an archive with [`EntryFilter`].
+
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::path::{Component, Path, PathBuf};
+
+use clap::ArgMatches;
+use globset::{Glob, GlobBuilder, GlobSet, GlobSetBuilder};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use regex::bytes::RegexSet;
+
+use crate::errors::Result;
+use crate::OsStrExt;
+
+pub fn build_glob_set<I, S>(patterns: I) -> Result<GlobSet>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    let mut builder = GlobSetBuilder::new();
+    for p in patterns {
+        builder.add(Glob::new(p.as_ref())?);
+    }
+    Ok(builder.build()?)
+}
+
+/// Glob patterns matched against a path and all its parents
+///
+/// A path matches if itself or any of its parent directories matches, so a
+/// pattern naming a directory covers everything under it. `*` and `?` don't
+/// match `/`.
+pub struct PatternSet {
+    globs: GlobSet,
+    /// patterns also taken as exact paths, so paths with glob metacharacters
+    /// (like `a[1].txt`) can be given as they are
+    literals: HashSet<PathBuf>,
+}
+
+impl PatternSet {
+    /// Patterns matched against the whole path, either exactly or as globs
+    ///
+    /// Patterns that aren't valid globs are only taken as exact paths.
+    pub fn anchored<I, S>(patterns: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let mut globs = GlobSetBuilder::new();
+        let mut literals = HashSet::new();
+        for p in patterns {
+            let p = p.as_ref();
+            if let Ok(glob) = GlobBuilder::new(p).literal_separator(true).build() {
+                globs.add(glob);
+            }
+            literals.insert(normalize(Path::new(p)));
+        }
+        Ok(Self {
+            globs: globs.build()?,
+            literals,
+        })
+    }
+
+    /// Like gitignore files, a pattern without `/` matches a name at any depth
+    pub fn unanchored<I, S>(patterns: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let mut globs = GlobSetBuilder::new();
+        for p in patterns {
+            let p = p.as_ref();
+            let p = if p.contains('/') {
+                String::from(p.trim_start_matches('/'))
+            } else {
+                format!("**/{}", p)
+            };
+            globs.add(GlobBuilder::new(&p).literal_separator(true).build()?);
+        }
+        Ok(Self {
+            globs: globs.build()?,
+            literals: HashSet::new(),
+        })
+    }
+
+    pub fn is_match(&self, path: &Path) -> bool {
+        normalize(path)
+            .ancestors()
+            .filter(|x| !x.as_os_str().is_empty())
+            .any(|x| self.literals.contains(x) || self.globs.is_match(x))
+    }
+}
+
+/// Strip `.` components and the trailing slash
+fn normalize(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|x| *x != Component::CurDir)
+        .collect()
+}
+
+/// Filter for files added by `create`
+///
+/// Paths are relative to the base directory.
+pub struct FileFilter {
+    include: Option<PatternSet>,
+    exclude: PatternSet,
+    exclude_file: Option<Gitignore>,
+}
+
+impl FileFilter {
+    pub fn from_matches(matches: &ArgMatches, base_dir: &Path) -> Result<Self> {
+        let include = match matches.get_many::<String>("include") {
+            None => None,
+            Some(x) => Some(PatternSet::unanchored(x)?),
+        };
+        let exclude =
+            PatternSet::unanchored(matches.get_many::<String>("exclude").into_iter().flatten())?;
+        let exclude_file = match matches.get_many::<String>("exclude-from") {
+            None => None,
+            Some(files) => {
+                let mut builder = GitignoreBuilder::new(base_dir);
+                for f in files {
+                    if let Some(e) = builder.add(f) {
+                        return Err(e.into());
+                    }
+                }
+                Some(builder.build()?)
+            }
+        };
+        Ok(Self {
+            include,
+            exclude,
+            exclude_file,
+        })
+    }
+
+    /// Excluded directories are not walked into
+    pub fn is_excluded(&self, path: &Path, is_dir: bool) -> bool {
+        if self.exclude.is_match(path) {
+            return true;
+        }
+        match &self.exclude_file {
+            Some(ignore) => ignore
+                .matched_path_or_any_parents(normalize(path), is_dir)
+                .is_ignore(),
+            None => false,
+        }
+    }
+
+    /// With include patterns present, only matching paths are added, but all
+    /// directories are still walked
+    pub fn is_included(&self, path: &Path) -> bool {
+        match &self.include {
+            Some(include) => include.is_match(path),
+            None => true,
+        }
+    }
+}
+
+enum Selection {
+    Globs(PatternSet),
+    Regex(RegexSet),
+}
+
+/// Filter for entries handled by `list` and `extract`
+///
+/// A pattern selects the path equal to it or matching it as a glob, and
+/// everything under it, so a plain directory path works as a prefix. Regular
+/// expressions are searched in the stored path instead.
+pub struct EntryFilter {
+    selection: Option<Selection>,
+    exclude: PatternSet,
+}
+
+impl EntryFilter {
+    pub fn from_matches(matches: &ArgMatches) -> Result<Self> {
+        let selection = match matches.get_many::<String>("paths") {
+            None => None,
+            Some(patterns) if matches.get_flag("regex") => {
+                Some(Selection::Regex(RegexSet::new(patterns)?))
+            }
+            Some(patterns) => Some(Selection::Globs(PatternSet::anchored(
+                patterns.map(|x| x.trim_start_matches("./")),
+            )?)),
+        };
+        let exclude =
+            PatternSet::unanchored(matches.get_many::<String>("exclude").into_iter().flatten())?;
+        Ok(Self { selection, exclude })
+    }
+
+    /// Whether entries are selected by patterns, rather than all taken
+    pub fn has_selection(&self) -> bool {
+        self.selection.is_some()
+    }
+
+    pub fn is_selected(&self, stored_path: &[u8]) -> bool {
+        let path = Path::new(OsStr::from_bytes(stored_path));
+        if self.exclude.is_match(path) {
+            return false;
+        }
+        match &self.selection {
+            None => true,
+            Some(Selection::Globs(globs)) => globs.is_match(path),
+            Some(Selection::Regex(regex)) => regex.is_match(trim_path(stored_path)),
+        }
+    }
+}
+
+/// Strip leading `./` and the trailing slash of a stored path
+fn trim_path(mut path: &[u8]) -> &[u8] {
+    while let Some(p) = path.strip_prefix(b"./") {
+        path = p;
+    }
+    path.strip_suffix(b"/").unwrap_or(path)
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::{EntryFilter, PatternSet};
+    use crate::build_cli;
+
+    fn entry_filter(args: &[&str]) -> EntryFilter {
+        let matches = build_cli()
+            .try_get_matches_from(["archive", "list", "archive"].iter().chain(args))
+            .unwrap();
+        EntryFilter::from_matches(matches.subcommand_matches("list").unwrap()).unwrap()
+    }
+
+    #[test]
+    fn anchored() {
+        let set = PatternSet::anchored(["src/*.rs", "./docs", "a[1].txt", "b[", "c?"]).unwrap();
+        let is_match = |x: &str| set.is_match(Path::new(x));
+        assert!(is_match("src/main.rs"));
+        assert!(is_match("./src/main.rs"));
+        assert!(!is_match("src/bin/main.rs"));
+        assert!(!is_match("main.rs"));
+        assert!(is_match("docs/"));
+        assert!(is_match("docs/a/b"));
+        // exact paths with glob metacharacters
+        assert!(is_match("a[1].txt"));
+        assert!(is_match("b["));
+        assert!(is_match("b[/c"));
+        assert!(is_match("c?"));
+        assert!(is_match("cx"));
+        assert!(!is_match("c/"));
+    }
+
+    #[test]
+    fn unanchored() {
+        let set = PatternSet::unanchored(["*.o", "/build/*", "tmp"]).unwrap();
+        let is_match = |x: &str| set.is_match(Path::new(x));
+        assert!(is_match("a.o"));
+        assert!(is_match("src/a.o"));
+        assert!(is_match("build/x/y"));
+        assert!(!is_match("src/build/x"));
+        assert!(is_match("src/tmp/x"));
+        assert!(!is_match("src/tmpx"));
+        assert!(PatternSet::unanchored(["b["]).is_err());
+    }
+
+    #[test]
+    fn entries() {
+        let filter = entry_filter(&["dir", "f[1]", "--exclude", "*.tmp"]);
+        assert!(filter.has_selection());
+        assert!(filter.is_selected(b"./dir/"));
+        assert!(filter.is_selected(b"dir/a"));
+        assert!(!filter.is_selected(b"dir/a.tmp"));
+        assert!(filter.is_selected(b"f[1]"));
+        assert!(filter.is_selected(b"f1"));
+        assert!(!filter.is_selected(b"dir2"));
+
+        let filter = entry_filter(&["-r", "^dir/.*\\.rs$"]);
+        assert!(filter.is_selected(b"./dir/a.rs"));
+        assert!(!filter.is_selected(b"dir/a.rsx"));
+
+        let filter = entry_filter(&[]);
+        assert!(!filter.has_selection());
+        assert!(filter.is_selected(b"anything"));
+    }
+}
@@ -0,0 +1,186 @@
+//! Cryptographic hashes of original file contents
+//!
+//! Unlike content checksums, which are CRC-64 of the stored bytes, these are
+//! over the original data, so extracted files or files on disk can be checked
+//! against them. They're stored as the `hash.<function>` extension of regular
+//! file entries, like `hash.blake3`.
+
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use sha2::Digest;
+
+use crate::extensions::HASH_PREFIX;
+use crate::sparse::{Expand, SparseMap, ZeroFill};
+use crate::{Entry, Extension};
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum HashFunction {
+    Blake3,
+    Sha256,
+}
+
+pub static HASH_FUNCTION_NAMES: [&str; 2] = ["blake3", "sha256"];
+
+impl HashFunction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HashFunction::Blake3 => "blake3",
+            HashFunction::Sha256 => "sha256",
+        }
+    }
+
+    pub fn hasher(&self) -> Hasher {
+        match self {
+            HashFunction::Blake3 => Hasher::Blake3(Box::default()),
+            HashFunction::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+        }
+    }
+
+    /// The extension key
+    fn key(&self) -> Vec<u8> {
+        let mut key = Vec::from(HASH_PREFIX);
+        key.extend_from_slice(self.as_str().as_bytes());
+        key
+    }
+}
+
+impl FromStr for HashFunction {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "blake3" => Ok(HashFunction::Blake3),
+            "sha256" => Ok(HashFunction::Sha256),
+            _ => Err(()),
+        }
+    }
+}
+
+pub enum Hasher {
+    Blake3(Box<blake3::Hasher>),
+    Sha256(sha2::Sha256),
+}
+
+impl Hasher {
+    pub fn finalize(self) -> Vec<u8> {
+        match self {
+            // not `Digest::finalize`, which blake3 has with the
+            // `traits-preview` feature other crates of the workspace enable
+            Hasher::Blake3(h) => blake3::Hasher::finalize(&h).as_bytes().to_vec(),
+            Hasher::Sha256(h) => h.finalize().to_vec(),
+        }
+    }
+}
+
+impl Write for Hasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Hasher::Blake3(h) => {
+                h.update(buf);
+            }
+            Hasher::Sha256(h) => h.update(buf),
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Hashes all the data read through, if a hash function is given
+///
+/// For sparse files, the data read are the data ranges of `map`, and the hash
+/// covers the holes too.
+pub struct HashReader<R: Read> {
+    inner: R,
+    hasher: Option<(HashFunction, Expand<ZeroFill<Hasher>>)>,
+}
+
+impl<R: Read> HashReader<R> {
+    pub fn new(inner: R, function: Option<HashFunction>, map: Option<SparseMap>) -> Self {
+        Self {
+            inner,
+            hasher: function.map(|x| (x, Expand::new(ZeroFill(x.hasher()), map))),
+        }
+    }
+
+    pub fn finalize(self) -> io::Result<Option<(HashFunction, Vec<u8>)>> {
+        let Some((function, hasher)) = self.hasher else {
+            return Ok(None);
+        };
+        Ok(Some((function, hasher.finish()?.0.finalize())))
+    }
+}
+
+impl<R: Read> Read for HashReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = self.inner.read(buf)?;
+        if let Some((_, hasher)) = &mut self.hasher {
+            hasher.write_all(&buf[..size])?;
+        }
+        Ok(size)
+    }
+}
+
+pub fn hash_file<P: AsRef<Path>>(path: P, function: HashFunction) -> io::Result<Vec<u8>> {
+    let mut hasher = function.hasher();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hasher.finalize())
+}
+
+/// Returns the first recorded hash of the original content, if any
+pub fn stored_hash(entry: &Entry) -> Option<(HashFunction, &[u8])> {
+    entry.extensions.iter().find_map(|x| {
+        let name = x.key().strip_prefix(HASH_PREFIX)?;
+        let function = std::str::from_utf8(name).ok()?.parse().ok()?;
+        Some((function, x.value()))
+    })
+}
+
+/// Record the hash of the original content, replacing the old one
+pub fn set_hash(entry: &mut Entry, function: HashFunction, hash: Vec<u8>) {
+    entry
+        .extensions
+        .retain(|x| !x.key().starts_with(HASH_PREFIX));
+    entry.extensions.push(Extension::new(function.key(), hash));
+}
+
+#[cfg(test)]
+mod test {
+    use super::{HashFunction, HashReader};
+    use std::io::{self, Read};
+
+    #[test]
+    fn known_hashes() {
+        let mut reader = HashReader::new(&b"abc"[..], Some(HashFunction::Sha256), None);
+        io::copy(&mut reader, &mut io::sink()).unwrap();
+        let (_, hash) = reader.finalize().unwrap().unwrap();
+        let hex = hash
+            .iter()
+            .map(|x| format!("{:02x}", x))
+            .collect::<String>();
+        assert_eq!(
+            hex,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+
+        let mut reader = HashReader::new(&b"abc"[..], Some(HashFunction::Blake3), None);
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"abc");
+        let hash = blake3::hash(b"abc").as_bytes().to_vec();
+        assert_eq!(
+            reader.finalize().unwrap(),
+            Some((HashFunction::Blake3, hash))
+        );
+
+        let mut reader = HashReader::new(&b"abc"[..], None, None);
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!(reader.finalize().unwrap(), None);
+    }
+}
@@ -0,0 +1,317 @@
+//! # Incremental archives
+//!
+//! An incremental archive only stores entries that are new or changed in respect
+//! with its parent archive. The parent archive is recorded in the info JSON,
+//! along with the paths deleted since then.
+//!
+//! A chain is made up of a base (full) archive and several incremental ones on top.
+//! To restore an incremental archive, every layer from the base is overlaid in order.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use pathdiff::diff_paths;
+
+use crate::errors::*;
+use crate::reader::ArchiveReader;
+use crate::{Entry, Info, ParentInfo};
+
+pub struct Layer {
+    pub path: PathBuf,
+    pub reader: ArchiveReader,
+    pub info: Info,
+}
+
+pub struct Chain {
+    /// from the base archive to the tip
+    layers: Vec<Layer>,
+    /// the merged view: (layer index, entry)
+    ///
+    /// `None` stands for entries deleted by later layers
+    entries: Vec<Option<(usize, Entry)>>,
+    index: HashMap<Vec<u8>, usize>,
+}
+
+impl Chain {
+    /// Open `tip` and all its ancestors
+    pub fn open<P: AsRef<Path>>(tip: P) -> Result<Self> {
+        let mut layers = Vec::new();
+        let mut path = tip.as_ref().to_path_buf();
+        let mut expected_creation_time = None;
+        loop {
+            let reader = ArchiveReader::new(&path)?;
+            let info: Info = serde_json::from_str(&reader.header.info_json)
+                .map_err(|_| Error::InvalidInfoJson)?;
+            if let Some(time) = expected_creation_time {
+                if reader.header.creation_time != time {
+                    return Err(Error::ParentMismatch(path));
+                }
+            }
+
+            let parent = info.parent.clone();
+            layers.push(Layer {
+                path: path.clone(),
+                reader,
+                info,
+            });
+            let Some(parent) = parent else {
+                break;
+            };
+            if layers.len() > MAX_CHAIN_LENGTH {
+                return Err(Error::Others("Archive chain is too long".into()));
+            }
+            expected_creation_time = Some(parent.creation_time);
+            path = resolve_parent_path(&path, &parent.path);
+        }
+        layers.reverse();
+
+        let mut chain = Self {
+            layers,
+            entries: Vec::new(),
+            index: HashMap::new(),
+        };
+        chain.merge()?;
+        Ok(chain)
+    }
+
+    fn merge(&mut self) -> Result<()> {
+        for (layer_index, layer) in self.layers.iter().enumerate() {
+            for path in &layer.info.deleted {
+                if let Some(i) = self.index.remove(path.as_bytes()) {
+                    self.entries[i] = None;
+                }
+            }
+            for entry in layer.reader.entries() {
+                let entry = entry?;
+                match self.index.get(&entry.path) {
+                    Some(&i) => {
+                        // replace in place, keeping the original order
+                        self.entries[i] = Some((layer_index, entry));
+                    }
+                    None => {
+                        self.index.insert(entry.path.clone(), self.entries.len());
+                        self.entries.push(Some((layer_index, entry)));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Merged entries with their layer indices
+    pub fn entries(&self) -> impl Iterator<Item = (usize, &Entry)> {
+        self.entries.iter().flatten().map(|(l, e)| (*l, e))
+    }
+
+    pub fn get(&self, path: &[u8]) -> Option<(usize, &Entry)> {
+        self.index
+            .get(path)
+            .and_then(|&i| self.entries[i].as_ref())
+            .map(|(l, e)| (*l, e))
+    }
+
+    pub fn layers(&self) -> &[Layer] {
+        &self.layers
+    }
+
+    pub fn layer_mut(&mut self, index: usize) -> &mut Layer {
+        &mut self.layers[index]
+    }
+
+    pub fn tip(&self) -> &Layer {
+        self.layers.last().unwrap()
+    }
+
+    /// Build the parent record of an archive to be created at `output`,
+    /// pointing to the tip of this chain
+    pub fn parent_info<P: AsRef<Path>>(&self, output: P) -> ParentInfo {
+        let tip = self.tip();
+        let output_dir = output.as_ref().parent().and_then(|x| {
+            // `Path::parent` gives an empty path for a bare file name
+            let dir = if x.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                x
+            };
+            dir.canonicalize().ok()
+        });
+        let path = match (tip.path.canonicalize(), output_dir) {
+            (Ok(p), Some(d)) => diff_paths(&p, d).unwrap_or(p),
+            _ => tip.path.clone(),
+        };
+        ParentInfo {
+            path: path.to_string_lossy().into_owned(),
+            creation_time: tip.reader.header.creation_time,
+        }
+    }
+}
+
+const MAX_CHAIN_LENGTH: usize = 65536;
+
+/// The parent path is relative to the directory of the child archive
+fn resolve_parent_path(child: &Path, parent: &str) -> PathBuf {
+    let parent = Path::new(parent);
+    if parent.is_absolute() {
+        return parent.into();
+    }
+    match child.parent() {
+        Some(dir) => dir.join(parent),
+        None => parent.into(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::ffi::OsStr;
+    use std::fs;
+    use std::path::Path;
+
+    use super::Chain;
+    use crate::errors::Error;
+    use crate::test_utils::{read_entry, run};
+    use crate::FileType;
+
+    fn create(output: &Path, src: &Path, reference: Option<&Path>) {
+        let mut args = vec![
+            "create".as_ref(),
+            output.as_os_str(),
+            "-C".as_ref(),
+            src.as_os_str(),
+            ".".as_ref(),
+        ];
+        if let Some(r) = reference {
+            args.extend(["-i".as_ref(), r.as_os_str()]);
+        }
+        run(&args).unwrap();
+    }
+
+    /// Files with their layer indices; the top directory changes in every layer
+    fn files(chain: &Chain) -> Vec<(usize, &[u8])> {
+        chain
+            .entries()
+            .filter(|(_, e)| e.file_type == FileType::Regular)
+            .map(|(l, e)| (l, &e.path[..]))
+            .collect()
+    }
+
+    #[test]
+    fn overlay() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        fs::create_dir(&src).unwrap();
+        for name in ["a", "b", "c"] {
+            fs::write(src.join(name), name).unwrap();
+        }
+        let archives = dir.path().join("archives");
+        fs::create_dir(&archives).unwrap();
+        create(&archives.join("0"), &src, None);
+
+        fs::write(src.join("b"), "b, changed").unwrap();
+        fs::remove_file(src.join("c")).unwrap();
+        fs::write(src.join("d"), "d").unwrap();
+        create(&archives.join("1"), &src, Some(&archives.join("0")));
+
+        fs::remove_file(src.join("a")).unwrap();
+        fs::write(src.join("c"), "c, again").unwrap();
+        create(&archives.join("2"), &src, Some(&archives.join("1")));
+
+        let chain = Chain::open(archives.join("2")).unwrap();
+        assert_eq!(chain.layers().len(), 3);
+        let deleted = &chain.tip().info.deleted;
+        assert_eq!(
+            deleted.iter().map(|x| x.as_bytes()).collect::<Vec<_>>(),
+            [b"./a"]
+        );
+        // the base order is kept, and files new in later layers are appended
+        assert_eq!(files(&chain), [(1, &b"./b"[..]), (1, b"./d"), (2, b"./c")]);
+        assert!(chain.get(b"./a").is_none());
+        let reader = &chain.layers()[1].reader;
+        assert_eq!(read_entry(reader, b"./b"), b"b, changed");
+        let reader = &chain.tip().reader;
+        assert_eq!(read_entry(reader, b"./c"), b"c, again");
+        assert!(reader.find_entry(b"./d").unwrap().is_none());
+
+        // a middle layer sees the chain up to itself
+        let chain = Chain::open(archives.join("1")).unwrap();
+        assert_eq!(chain.get(b"./a").unwrap().0, 0);
+        assert!(chain.get(b"./c").is_none());
+    }
+
+    #[test]
+    fn parent_resolution() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        fs::create_dir(&src).unwrap();
+        fs::write(src.join("a"), "a").unwrap();
+        let archives = dir.path().join("archives");
+        fs::create_dir_all(archives.join("sub")).unwrap();
+        create(&archives.join("base"), &src, None);
+        fs::write(src.join("a"), "a, changed").unwrap();
+        // creation times are in milliseconds, and need to differ
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        create(
+            &archives.join("sub/tip"),
+            &src,
+            Some(&archives.join("base")),
+        );
+
+        let chain = Chain::open(archives.join("sub/tip")).unwrap();
+        assert_eq!(chain.tip().info.parent.as_ref().unwrap().path, "../base");
+
+        // parent paths are relative to the child, so the chain can be moved
+        let moved = dir.path().join("moved");
+        fs::rename(&archives, &moved).unwrap();
+        let chain = Chain::open(moved.join("sub/tip")).unwrap();
+        assert_eq!(chain.layers()[0].path, moved.join("sub/../base"));
+
+        // the parent is checked by its creation time
+        fs::copy(moved.join("sub/tip"), moved.join("base")).unwrap();
+        match Chain::open(moved.join("sub/tip")) {
+            Err(Error::ParentMismatch(p)) => assert_eq!(p, moved.join("sub/../base")),
+            r => panic!("Unexpected result: {:?}", r.map(|_| ())),
+        }
+        fs::remove_file(moved.join("base")).unwrap();
+        assert!(Chain::open(moved.join("sub/tip")).is_err());
+    }
+
+    #[test]
+    fn content_comparison() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        fs::create_dir(&src).unwrap();
+        fs::write(src.join("a"), "old").unwrap();
+        let base = dir.path().join("base");
+        let tip = dir.path().join("tip");
+        let create = |output: &Path, args: &[&str]| {
+            let mut all = vec!["create".as_ref(), output.as_os_str()];
+            all.extend(["-C".as_ref(), src.as_os_str(), ".".as_ref()]);
+            all.extend(args.iter().map(OsStr::new));
+            run(&all)
+        };
+        create(&base, &["-c", "zstd", "--hash", "blake3"]).unwrap();
+
+        // same size and modification time
+        let mtime =
+            filetime::FileTime::from_last_modification_time(&fs::metadata(src.join("a")).unwrap());
+        fs::write(src.join("a"), "new").unwrap();
+        filetime::set_file_mtime(src.join("a"), mtime).unwrap();
+
+        let base = base.to_str().unwrap();
+        create(&tip, &["-i", base]).unwrap();
+        let chain = Chain::open(&tip).unwrap();
+        assert_eq!(chain.get(b"./a").unwrap().0, 0);
+
+        // compared by the content hashes, so the compression doesn't matter
+        create(&tip, &["-c", "none", "-i", base, "--checksum"]).unwrap();
+        let chain = Chain::open(&tip).unwrap();
+        assert_eq!(chain.get(b"./a").unwrap().0, 1);
+        assert_eq!(read_entry(&chain.tip().reader, b"./a"), b"new");
+
+        // without them, by checksums of the compressed contents
+        create(dir.path().join("base").as_path(), &["-c", "zstd"]).unwrap();
+        assert!(create(&tip, &["-c", "none", "-i", base, "--checksum"]).is_err());
+        create(&tip, &["-c", "zstd", "-i", base, "--checksum"]).unwrap();
+        let chain = Chain::open(&tip).unwrap();
+        assert_eq!(chain.get(b"./a").unwrap().0, 0);
+    }
+}
@@ -1,19 +1,33 @@
 use crate::errors::*;
+use crate::output::{write_single, Format, InfoRecord};
 use crate::reader::ArchiveReader;
 use crate::{Header, Info};
 use clap::ArgMatches;
+use std::io::stdout;
 
 pub fn main(matches: &ArgMatches) -> Result<()> {
     let path = matches.get_one::<String>("archive").unwrap();
+    let format = Format::from_matches(matches);
     let archive = ArchiveReader::new(path)?;
 
-    print_info(&archive.header)?;
+    match format {
+        Format::Text => print_info(&archive.header)?,
+        Format::Records(format) => {
+            let info = parse_info(&archive.header)?;
+            let record = InfoRecord::new(&archive.header, &info);
+            write_single(format, stdout().lock(), &record)?;
+        }
+    }
 
     Ok(())
 }
 
 pub fn print_info(header: &Header) -> Result<()> {
-    let info: Info = serde_json::from_str(&header.info_json).map_err(|_| Error::InvalidInfoJson)?;
+    let info = parse_info(header)?;
     println!("{}\n{}", header, info);
     Ok(())
 }
+
+pub fn parse_info(header: &Header) -> Result<Info> {
+    serde_json::from_str(&header.info_json).map_err(|_| Error::InvalidInfoJson)
+}
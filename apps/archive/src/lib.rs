@@ -7,12 +7,15 @@ extern crate crc as crc_lib;
 use std::ffi::OsStr;
 use std::fmt::{Debug, Display, Formatter};
 use std::io::{Read, Write};
+use std::mem::size_of;
+use std::ops::RangeInclusive;
 use std::str::FromStr;
+use std::time::SystemTime;
 use std::{io, mem};
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use cfg_if::cfg_if;
-use chrono::{Local, LocalResult, TimeZone};
+use chrono::{DateTime, Local, LocalResult, TimeZone, Utc};
 use crc_lib::{Algorithm, Crc, Width};
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
@@ -24,20 +27,45 @@ pub use cli::build_cli;
 use errors::Result;
 
 use crate::crc::DigestWriter;
+use crate::encryption::EncryptionHeader;
 use crate::errors::{Error, TimeError};
 
+pub mod add;
 pub mod archive;
+pub mod builder;
+pub mod checkpoint;
+pub mod chunking;
 pub mod cli;
+pub mod compact;
 pub mod compressors;
+pub mod convert;
 pub mod crc;
 pub mod create;
+pub mod delete;
+pub mod diff;
+pub mod encryption;
 pub mod errors;
+pub mod extensions;
 pub mod extract;
+pub mod filter;
+pub mod hash;
+pub mod incremental;
 pub mod info;
 pub mod line_progress;
 pub mod list;
+#[cfg(all(unix, feature = "mount"))]
+pub mod mount;
+pub mod output;
+pub mod parallel;
 pub mod reader;
+pub mod recovery;
+pub mod repair;
+pub mod sparse;
 pub mod test;
+#[cfg(test)]
+mod test_utils;
+pub mod update;
+pub mod volume;
 
 #[derive(Debug, Clone)]
 pub struct Entry {
@@ -49,13 +77,122 @@ pub struct Entry {
     linked_path: Vec<u8>,
     stored_size: u64,
     original_size: u64,
-    owner_id: u16,
-    group_id: u16,
+    /// compression method of the stored content (since version 2)
+    compression: Compression,
+    /// 16-bit before version 3
+    owner_id: u32,
+    /// 16-bit before version 3
+    group_id: u32,
     permission_mode: u16,
     modification_time: Timestamp,
     content_checksum: u64,
     // the offset in respect with the content area (0 for the first file)
     offset: u64,
+    /// key/value records like extended attributes (since version 3)
+    extensions: Vec<Extension>,
+    /// content chunks in the chunked mode (since version 4)
+    ///
+    /// If present, the content is made up of these chunks, each compressed
+    /// individually, and `offset` isn't used.
+    chunks: Vec<Chunk>,
+}
+
+/// A key/value record in the entry extension area
+///
+/// See [`extensions`] for the known keys.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Extension {
+    key: Vec<u8>,
+    value: Vec<u8>,
+}
+
+impl Extension {
+    pub fn new(key: Vec<u8>, value: Vec<u8>) -> Self {
+        Self { key, value }
+    }
+
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    pub fn value(&self) -> &[u8] {
+        &self.value
+    }
+}
+
+impl GetStoredSize for Extension {
+    fn stored_size(&self) -> usize {
+        size_of::<u16>() + self.key.len() + size_of::<u32>() + self.value.len()
+    }
+}
+
+impl WriteTo for Extension {
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_u16::<LittleEndian>(self.key.len() as u16)?;
+        writer.write_all(&self.key)?;
+        writer.write_u32::<LittleEndian>(self.value.len() as u32)?;
+        writer.write_all(&self.value)?;
+        Ok(())
+    }
+}
+
+impl ReadFrom for Extension {
+    type Item = Self;
+
+    fn read_from<R: Read>(reader: &mut R) -> Result<Self::Item> {
+        let key_length = reader.read_u16::<LittleEndian>()?;
+        let mut key = vec![0_u8; key_length as usize];
+        reader.read_exact(&mut key)?;
+        let value_length = reader.read_u32::<LittleEndian>()?;
+        let mut value = vec![0_u8; value_length as usize];
+        reader.read_exact(&mut value)?;
+        Ok(Self { key, value })
+    }
+}
+
+/// A piece of file content, shared by all entries containing the same data
+///
+/// See [`chunking`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chunk {
+    /// offset in respect with the content area
+    offset: u64,
+    stored_size: u32,
+    original_size: u32,
+    /// BLAKE3 hash of the original data
+    hash: [u8; 32],
+}
+
+impl FixedStoredSize for Chunk {
+    const SIZE: usize = field_size!(Self, offset, stored_size, original_size, hash);
+}
+
+impl WriteTo for Chunk {
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_u64::<LittleEndian>(self.offset)?;
+        writer.write_u32::<LittleEndian>(self.stored_size)?;
+        writer.write_u32::<LittleEndian>(self.original_size)?;
+        writer.write_all(&self.hash)?;
+        Ok(())
+    }
+}
+
+impl ReadFrom for Chunk {
+    type Item = Self;
+
+    fn read_from<R: Read>(reader: &mut R) -> Result<Self::Item> {
+        let offset = reader.read_u64::<LittleEndian>()?;
+        let stored_size = reader.read_u32::<LittleEndian>()?;
+        let original_size = reader.read_u32::<LittleEndian>()?;
+        let mut hash = [0_u8; 32];
+        reader.read_exact(&mut hash)?;
+        Ok(Self {
+            offset,
+            stored_size,
+            original_size,
+            hash,
+        })
+    }
 }
 
 #[repr(transparent)]
@@ -72,7 +209,7 @@ impl FixedStoredSize for ContentChecksum {
     const SIZE: usize = field_size!(Self, 0);
 }
 
-#[derive(Copy, Clone, FromPrimitive, Debug, Eq, PartialEq)]
+#[derive(Copy, Clone, FromPrimitive, Debug, Eq, PartialEq, Hash)]
 #[repr(u8)]
 pub enum Compression {
     External = 0,
@@ -117,6 +254,25 @@ pub enum FileType {
     Symlink = 2,
     Fifo = 3,
     Directory = 4,
+    /// device numbers are stored as an extension (see [`extensions`])
+    CharDevice = 5,
+    BlockDevice = 6,
+    Socket = 7,
+}
+
+impl FileType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FileType::Regular => "regular",
+            FileType::Link => "link",
+            FileType::Symlink => "symlink",
+            FileType::Fifo => "fifo",
+            FileType::Directory => "directory",
+            FileType::CharDevice => "char-device",
+            FileType::BlockDevice => "block-device",
+            FileType::Socket => "socket",
+        }
+    }
 }
 
 trait FixedStoredSize {
@@ -127,6 +283,12 @@ trait FixedStoredSize {
 pub struct Info {
     compression_method: String,
     comment: Option<String>,
+    /// present if this is an incremental archive
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    parent: Option<ParentInfo>,
+    /// paths in the parent archive that no longer exist
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    deleted: Vec<StoredPath>,
 }
 
 impl Display for Info {
@@ -136,10 +298,53 @@ impl Display for Info {
             writeln!(f)?;
             write!(f, "Comment: {}", s)?;
         }
+        if let Some(ref p) = self.parent {
+            writeln!(f)?;
+            write!(f, "Parent archive: {}", p.path)?;
+            writeln!(f)?;
+            write!(f, "Deleted entries: {}", self.deleted.len())?;
+        }
         Ok(())
     }
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ParentInfo {
+    /// path to the parent archive, relative to the directory of this archive
+    /// if possible
+    path: String,
+    /// creation time of the parent archive, for checking it's the expected one
+    creation_time: i64,
+}
+
+/// Path stored in the info JSON
+///
+/// Stored as a plain string when it's valid UTF-8; raw bytes otherwise.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum StoredPath {
+    Utf8(String),
+    Bytes(Vec<u8>),
+}
+
+impl From<&[u8]> for StoredPath {
+    fn from(bytes: &[u8]) -> Self {
+        match std::str::from_utf8(bytes) {
+            Ok(s) => Self::Utf8(s.into()),
+            Err(_) => Self::Bytes(bytes.into()),
+        }
+    }
+}
+
+impl StoredPath {
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            StoredPath::Utf8(s) => s.as_bytes(),
+            StoredPath::Bytes(b) => b,
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Header {
     magic_number: [u8; FILE_MAGIC.len()],
@@ -149,7 +354,45 @@ pub struct Header {
     creation_time: i64,
     entry_count: u64,
     info_json_length: u32,
+    /// hex-encoded ciphertext in encrypted archives, and replaced with the
+    /// plaintext once decrypted
     info_json: String,
+    /// present if encrypted (since version 5)
+    encryption: Option<EncryptionHeader>,
+    /// zstd dictionary of all zstd-compressed content, empty if not used
+    /// (since version 6)
+    ///
+    /// Sealed in encrypted archives, and replaced with the plaintext once
+    /// decrypted.
+    dictionary: Vec<u8>,
+}
+
+impl Header {
+    pub fn is_encrypted(&self) -> bool {
+        self.encryption.is_some()
+    }
+
+    /// The zstd dictionary, if any
+    pub fn dictionary(&self) -> Option<&[u8]> {
+        (!self.dictionary.is_empty()).then_some(&self.dictionary[..])
+    }
+
+    /// The header fields authenticated with the encrypted entry table
+    ///
+    /// The info JSON and the dictionary are sealed themselves.
+    pub(crate) fn table_aad(&self) -> Vec<u8> {
+        let mut aad = Vec::new();
+        aad.extend_from_slice(&self.magic_number);
+        aad.extend_from_slice(&self.version.to_le_bytes());
+        aad.extend_from_slice(&self.content_offset.to_le_bytes());
+        aad.push(self.compression as u8);
+        aad.extend_from_slice(&self.creation_time.to_le_bytes());
+        aad.extend_from_slice(&self.entry_count.to_le_bytes());
+        if let Some(e) = &self.encryption {
+            e.write_to(&mut aad).unwrap();
+        }
+        aad
+    }
 }
 
 impl Display for Header {
@@ -162,6 +405,14 @@ impl Display for Header {
             Local.timestamp_millis_opt(self.creation_time).unwrap(),
         )?;
         write!(f, "Entry count: {}", self.entry_count)?;
+        if self.is_encrypted() {
+            writeln!(f)?;
+            write!(f, "Encryption: XChaCha20-Poly1305, Argon2id")?;
+        }
+        if let Some(dictionary) = self.dictionary() {
+            writeln!(f)?;
+            write!(f, "Zstd dictionary: {} bytes", dictionary.len())?;
+        }
 
         Ok(())
     }
@@ -179,6 +430,66 @@ impl GetStoredSize for Header {
             entry_count,
             info_json_length
         ) + self.info_json.len()
+            + if self.version >= 5 {
+                size_of::<u8>() /* encryption method */
+                    + self.encryption.as_ref().map_or(0, GetStoredSize::stored_size)
+            } else {
+                0
+            }
+            + if self.version >= 6 {
+                size_of::<u32>() + self.dictionary.len()
+            } else {
+                0
+            }
+    }
+}
+
+/// Trailing locator of the entry table
+///
+/// Since version 1, entries are written after the content area, so an archive
+/// can be created in one pass on non-seekable outputs. This fixed-size footer
+/// at the end of the file tells where the entry table starts.
+#[derive(Clone, Debug)]
+pub struct Footer {
+    entries_offset: u64,
+    magic_number: [u8; FOOTER_MAGIC.len()],
+}
+
+impl FixedStoredSize for Footer {
+    const SIZE: usize = field_size!(Self, entries_offset, magic_number);
+}
+
+impl Footer {
+    fn new(entries_offset: u64) -> Self {
+        Self {
+            entries_offset,
+            magic_number: *FOOTER_MAGIC,
+        }
+    }
+}
+
+impl ReadFrom for Footer {
+    type Item = Self;
+
+    fn read_from<R: Read>(reader: &mut R) -> Result<Self::Item> {
+        let entries_offset = reader.read_u64::<LittleEndian>()?;
+        let mut magic_number = [0_u8; FOOTER_MAGIC.len()];
+        reader.read_exact(&mut magic_number)?;
+        if &magic_number != FOOTER_MAGIC {
+            return Err(Error::InvalidFooter);
+        }
+        Ok(Self {
+            entries_offset,
+            magic_number,
+        })
+    }
+}
+
+impl WriteTo for Footer {
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_u64::<LittleEndian>(self.entries_offset)?;
+        writer.write_all(&self.magic_number)?;
+        Ok(())
     }
 }
 
@@ -207,6 +518,21 @@ impl ReadFrom for Header {
         let mut info_json_buf = vec![0_u8; info_json_length as usize];
         reader.read_exact(&mut info_json_buf)?;
         let info_json = String::from_utf8(info_json_buf)?;
+        let encryption = if version >= 5 {
+            match reader.read_u8()? {
+                0 => None,
+                1 => Some(EncryptionHeader::read_from(reader)?),
+                _ => return Err("Unknown encryption method".into()),
+            }
+        } else {
+            None
+        };
+        let mut dictionary = Vec::new();
+        if version >= 6 {
+            let length = reader.read_u32::<LittleEndian>()?;
+            dictionary = vec![0_u8; length as usize];
+            reader.read_exact(&mut dictionary)?;
+        }
 
         // checks
         let compression =
@@ -221,6 +547,8 @@ impl ReadFrom for Header {
             entry_count,
             info_json_length,
             info_json,
+            encryption,
+            dictionary,
         })
     }
 }
@@ -235,6 +563,19 @@ impl WriteTo for Header {
         writer.write_u64::<LittleEndian>(self.entry_count)?;
         writer.write_u32::<LittleEndian>(self.info_json_length)?;
         writer.write_all(self.info_json.as_bytes())?;
+        if self.version >= 5 {
+            match &self.encryption {
+                None => writer.write_u8(0)?,
+                Some(e) => {
+                    writer.write_u8(1)?;
+                    e.write_to(writer)?;
+                }
+            }
+        }
+        if self.version >= 6 {
+            writer.write_u32::<LittleEndian>(self.dictionary.len() as u32)?;
+            writer.write_all(&self.dictionary)?;
+        }
         Ok(())
     }
 }
@@ -255,16 +596,20 @@ impl FromStr for Compression {
 
 impl Compression {
     pub fn best_level(&self) -> u32 {
-        // TODO: maybe as a property in the trait
+        *self.level_range().end()
+    }
+
+    /// The valid levels
+    pub fn level_range(&self) -> RangeInclusive<u32> {
         match self {
-            Compression::Gzip => flate2::Compression::best().level(),
-            Compression::Xz => 9,
-            Compression::Zstd => 22,
-            Compression::None => 0,
-            Compression::Bzip2 => bzip2::Compression::best().level(),
-            Compression::Brotli => 11,
+            Compression::Gzip => 0..=flate2::Compression::best().level(),
+            Compression::Xz => 0..=9,
+            Compression::Zstd => 1..=22,
+            Compression::None => 0..=0,
+            Compression::Bzip2 => 1..=bzip2::Compression::best().level(),
+            Compression::Brotli => 0..=11,
             Compression::External => panic!("Unexpected method"),
-            Compression::Bzip3 => 9,
+            Compression::Bzip3 => 1..=9,
         }
     }
 
@@ -292,20 +637,32 @@ impl WriteTo for Entry {
         writer.write_all(&self.linked_path)?;
         writer.write_u64::<LittleEndian>(self.stored_size)?;
         writer.write_u64::<LittleEndian>(self.original_size)?;
-        writer.write_u16::<LittleEndian>(self.owner_id)?;
-        writer.write_u16::<LittleEndian>(self.group_id)?;
+        writer.write_u8(self.compression as u8)?;
+        writer.write_u32::<LittleEndian>(self.owner_id)?;
+        writer.write_u32::<LittleEndian>(self.group_id)?;
         writer.write_u16::<LittleEndian>(self.permission_mode)?;
         self.modification_time.write_to(writer)?;
         writer.write_u64::<LittleEndian>(self.content_checksum)?;
         writer.write_u64::<LittleEndian>(self.offset)?;
+        writer.write_u16::<LittleEndian>(self.extensions.len() as u16)?;
+        for x in &self.extensions {
+            x.write_to(writer)?;
+        }
+        writer.write_u32::<LittleEndian>(self.chunks.len() as u32)?;
+        for x in &self.chunks {
+            x.write_to(writer)?;
+        }
         Ok(())
     }
 }
 
-impl ReadFrom for Entry {
-    type Item = Self;
+impl Entry {
+    /// Read an entry in the layout of the archive described by `header`
+    ///
+    /// Fields absent in older versions are filled from the header.
+    fn read_from_version<R: Read>(reader: &mut R, header: &Header) -> Result<Self> {
+        let version = header.version;
 
-    fn read_from<R: Read>(reader: &mut R) -> Result<Self::Item> {
         let mut magic_buf = [0_u8; ENTRY_MAGIC.len()];
         reader.read_exact(&mut magic_buf)?;
 
@@ -321,18 +678,48 @@ impl ReadFrom for Entry {
 
         let stored_size = reader.read_u64::<LittleEndian>()?;
         let original_size = reader.read_u64::<LittleEndian>()?;
-        let owner_id = reader.read_u16::<LittleEndian>()?;
-        let group_id = reader.read_u16::<LittleEndian>()?;
+        let compression = if version >= 2 {
+            reader.read_u8()?
+        } else {
+            header.compression as u8
+        };
+        let (owner_id, group_id) = if version >= 3 {
+            (
+                reader.read_u32::<LittleEndian>()?,
+                reader.read_u32::<LittleEndian>()?,
+            )
+        } else {
+            (
+                reader.read_u16::<LittleEndian>()? as u32,
+                reader.read_u16::<LittleEndian>()? as u32,
+            )
+        };
         let permission_mode = reader.read_u16::<LittleEndian>()?;
         let modification_time = Timestamp::read_from(reader)?;
         let content_checksum = reader.read_u64::<LittleEndian>()?;
         let offset = reader.read_u64::<LittleEndian>()?;
+        let mut extensions = Vec::new();
+        if version >= 3 {
+            let count = reader.read_u16::<LittleEndian>()?;
+            for _ in 0..count {
+                extensions.push(Extension::read_from(reader)?);
+            }
+        }
+        let mut chunks = Vec::new();
+        if version >= 4 {
+            let count = reader.read_u32::<LittleEndian>()?;
+            for _ in 0..count {
+                chunks.push(Chunk::read_from(reader)?);
+            }
+        }
 
         // checks
         if &magic_buf != ENTRY_MAGIC {
             return Err(Error::InvalidEntryHeader);
         }
         let file_type = FromPrimitive::from_u8(file_type).ok_or(Error::UnknownFileType)?;
+        let compression =
+            FromPrimitive::from_u8(compression).ok_or(Error::UnknownCompressionMethod)?;
 
         Ok(Self {
             magic_number: magic_buf,
@@ -343,16 +730,130 @@ impl ReadFrom for Entry {
             linked_path,
             stored_size,
             original_size,
+            compression,
             owner_id,
             group_id,
             permission_mode,
             modification_time,
             content_checksum,
             offset,
+            extensions,
+            chunks,
         })
     }
 }
 
+impl Entry {
+    /// An entry with only the path and the file type set
+    pub(crate) fn new(path: Vec<u8>, file_type: FileType) -> Self {
+        Self {
+            magic_number: *ENTRY_MAGIC,
+            path_length: path.len() as u16,
+            path,
+            file_type,
+            linked_path_length: 0,
+            linked_path: Vec::new(),
+            stored_size: 0,
+            original_size: 0,
+            compression: Compression::None,
+            owner_id: 0,
+            group_id: 0,
+            permission_mode: 0,
+            modification_time: Timestamp::zero(),
+            content_checksum: 0,
+            offset: 0,
+            extensions: Vec::new(),
+            chunks: Vec::new(),
+        }
+    }
+
+    pub(crate) fn set_linked_path(&mut self, linked_path: Vec<u8>) {
+        self.linked_path_length = linked_path.len() as u16;
+        self.linked_path = linked_path;
+    }
+
+    pub fn path(&self) -> &[u8] {
+        &self.path
+    }
+
+    pub fn file_type(&self) -> FileType {
+        self.file_type
+    }
+
+    /// The target of symlinks and hard links; empty for other types
+    pub fn linked_path(&self) -> &[u8] {
+        &self.linked_path
+    }
+
+    /// The size of the file content, including holes of sparse files
+    pub fn original_size(&self) -> u64 {
+        self.original_size
+    }
+
+    /// The size of the content in the archive, compressed and encrypted
+    pub fn stored_size(&self) -> u64 {
+        self.stored_size
+    }
+
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    pub fn owner_id(&self) -> u32 {
+        self.owner_id
+    }
+
+    pub fn group_id(&self) -> u32 {
+        self.group_id
+    }
+
+    /// The file mode, with the file type bits
+    pub fn permission_mode(&self) -> u16 {
+        self.permission_mode
+    }
+
+    pub fn modification_time(&self) -> &Timestamp {
+        &self.modification_time
+    }
+
+    /// CRC-64 of the stored content
+    pub fn content_checksum(&self) -> u64 {
+        self.content_checksum
+    }
+
+    pub fn extensions(&self) -> &[Extension] {
+        &self.extensions
+    }
+
+    pub fn is_chunked(&self) -> bool {
+        !self.chunks.is_empty()
+    }
+}
+
+impl Entry {
+    /// Compares all the metadata fields except those related to the storage
+    /// (stored size, content checksum and offset) and content hashes
+    pub fn same_metadata(&self, other: &Entry) -> bool {
+        let metadata_extensions = |entry: &'_ Entry| {
+            entry
+                .extensions
+                .iter()
+                .filter(|x| !x.key().starts_with(extensions::HASH_PREFIX))
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        self.path == other.path
+            && self.file_type == other.file_type
+            && self.linked_path == other.linked_path
+            && self.original_size == other.original_size
+            && self.owner_id == other.owner_id
+            && self.group_id == other.group_id
+            && self.permission_mode == other.permission_mode
+            && self.modification_time == other.modification_time
+            && metadata_extensions(self) == metadata_extensions(other)
+    }
+}
+
 impl TryFrom<std::fs::FileType> for FileType {
     type Error = ();
 
@@ -372,6 +873,15 @@ impl TryFrom<std::fs::FileType> for FileType {
                     if t.is_fifo() {
                         return Ok(FileType::Fifo);
                     }
+                    if t.is_char_device() {
+                        return Ok(FileType::CharDevice);
+                    }
+                    if t.is_block_device() {
+                        return Ok(FileType::BlockDevice);
+                    }
+                    if t.is_socket() {
+                        return Ok(FileType::Socket);
+                    }
                 }
             }
             Err(())
@@ -399,6 +909,7 @@ impl GetStoredSize for Entry {
                 file_type,
                 stored_size,
                 original_size,
+                compression,
                 owner_id,
                 group_id,
                 permission_mode,
@@ -408,6 +919,14 @@ impl GetStoredSize for Entry {
             + Timestamp::SIZE
             + field_size!(Self, linked_path_length)
             + self.linked_path.len()
+            + size_of::<u16>() /* extension count */
+            + self
+                .extensions
+                .iter()
+                .map(GetStoredSize::stored_size)
+                .sum::<usize>()
+            + size_of::<u32>() /* chunk count */
+            + self.chunks.len() * Chunk::SIZE
     }
 }
 
@@ -466,7 +985,7 @@ where
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Timestamp {
     seconds: i64,
     nanoseconds: u32,
@@ -487,6 +1006,36 @@ impl Timestamp {
     fn zero() -> Timestamp {
         Timestamp::new(0, 0)
     }
+
+    /// Seconds since the Unix epoch
+    pub fn seconds(&self) -> i64 {
+        self.seconds
+    }
+
+    pub fn nanoseconds(&self) -> u32 {
+        self.nanoseconds
+    }
+}
+
+impl From<SystemTime> for Timestamp {
+    fn from(time: SystemTime) -> Self {
+        let time = DateTime::<Utc>::from(time);
+        Timestamp::new(time.timestamp(), time.timestamp_subsec_nanos())
+    }
+}
+
+/// The stored path without leading "./" and trailing "/", for comparing paths
+///
+/// Returns `None` for the archive root.
+pub fn normalized_path(stored_path: &[u8]) -> Option<&[u8]> {
+    let mut path = stored_path;
+    while let Some(x) = path.strip_prefix(b"./") {
+        path = x;
+    }
+    while let Some(x) = path.strip_suffix(b"/") {
+        path = x;
+    }
+    (!path.is_empty() && path != b".").then_some(path)
 }
 
 impl ReadFrom for Timestamp {
@@ -512,7 +1061,21 @@ impl WriteTo for Timestamp {
 
 pub const FILE_MAGIC: &[u8; 13] = b"bczhc archive";
 pub const ENTRY_MAGIC: &[u8; 5] = b"Entry";
-pub const VERSION: u16 = 0;
+pub const FOOTER_MAGIC: &[u8; 6] = b"Footer";
+/// Version 0: entries follow the header, and the content area follows entries
+///
+/// Version 1: the content area follows the header, then the entries and a [`Footer`]
+///
+/// Version 2: each entry records its own compression method
+///
+/// Version 3: 32-bit owner/group IDs, and the entry extension area
+///
+/// Version 4: content chunks of entries
+///
+/// Version 5: encryption
+///
+/// Version 6: zstd dictionary
+pub const VERSION: u16 = 6;
 
 pub const FILE_CRC_64: Algorithm<u64> = crc_lib::CRC_64_XZ;
 pub const ENTRY_CRC_32: Algorithm<u32> = crc_lib::CRC_32_CKSUM;
@@ -521,9 +1084,10 @@ pub const ENTRY_CRC_32: Algorithm<u32> = crc_lib::CRC_32_CKSUM;
 pub mod unit_test {
     use std::io::{Cursor, Seek};
 
+    use crate::encryption::EncryptionHeader;
     use crate::{
-        Compression, Entry, FileType, GetStoredSize, Header, Timestamp, WriteTo, ENTRY_MAGIC,
-        FILE_MAGIC,
+        Chunk, Compression, Entry, Extension, FileType, Footer, GetStoredSize, Header, Timestamp,
+        WriteTo, ENTRY_MAGIC, FILE_MAGIC, VERSION,
     };
 
     fn test_size<T>(x: &T)
@@ -546,6 +1110,16 @@ pub mod unit_test {
             entry_count: 0,
             info_json_length: 2,
             info_json: "{}".to_string(),
+            encryption: None,
+            dictionary: Vec::new(),
+        };
+        test_size(&header);
+
+        let header = Header {
+            version: VERSION,
+            encryption: Some(EncryptionHeader::generate()),
+            dictionary: vec![1, 2, 3],
+            ..header
         };
         test_size(&header);
     }
@@ -560,6 +1134,7 @@ pub mod unit_test {
             linked_path: (*b"test").into(),
             stored_size: 0,
             original_size: 0,
+            compression: Compression::None,
             owner_id: 0,
             group_id: 0,
             permission_mode: 0,
@@ -569,9 +1144,80 @@ pub mod unit_test {
             },
             content_checksum: 0,
             offset: 0,
+            extensions: vec![Extension::new(b"key".to_vec(), b"value".to_vec())],
+            chunks: vec![Chunk {
+                offset: 0,
+                stored_size: 0,
+                original_size: 0,
+                hash: [0; 32],
+            }],
         };
         test_size(&entry);
     }
+
+    #[test]
+    pub fn footer_size() {
+        test_size(&Footer::new(0));
+    }
+
+    fn header(version: u16) -> Header {
+        Header {
+            magic_number: *FILE_MAGIC,
+            version,
+            content_offset: 0,
+            compression: Compression::Zstd,
+            creation_time: 0,
+            entry_count: 0,
+            info_json_length: 2,
+            info_json: "{}".to_string(),
+            encryption: None,
+            dictionary: Vec::new(),
+        }
+    }
+
+    #[test]
+    pub fn entry_round_trip() {
+        let mut entry = Entry::new(b"a".to_vec(), FileType::Regular);
+        entry.owner_id = 100_000;
+        entry.group_id = u32::MAX;
+        entry.extensions = vec![
+            Extension::new(b"xattr.user.comment".to_vec(), b"hi".to_vec()),
+            Extension::new(b"xattr.user.empty".to_vec(), Vec::new()),
+            Extension::new(b"xattr.user.binary".to_vec(), vec![0, 255, 0]),
+        ];
+        let mut buf = Vec::new();
+        entry.write_to(&mut buf).unwrap();
+        let read = Entry::read_from_version(&mut &buf[..], &header(VERSION)).unwrap();
+        assert_eq!((read.owner_id, read.group_id), (100_000, u32::MAX));
+        assert_eq!(read.extensions.len(), 3);
+        for (a, b) in read.extensions.iter().zip(&entry.extensions) {
+            assert_eq!((a.key(), a.value()), (b.key(), b.value()));
+        }
+        assert!(read.same_metadata(&entry));
+    }
+
+    #[test]
+    pub fn version_2_entry() {
+        // 16-bit owner IDs, no extension area and no chunks
+        let mut buf = Vec::new();
+        buf.extend_from_slice(ENTRY_MAGIC);
+        buf.extend_from_slice(&1_u16.to_le_bytes());
+        buf.push(b'a');
+        buf.push(FileType::Regular as u8);
+        buf.extend_from_slice(&0_u16.to_le_bytes());
+        buf.extend_from_slice(&[0; 16]);
+        buf.push(Compression::None as u8);
+        buf.extend_from_slice(&1000_u16.to_le_bytes());
+        buf.extend_from_slice(&65535_u16.to_le_bytes());
+        buf.extend_from_slice(&0o644_u16.to_le_bytes());
+        buf.extend_from_slice(&[0; 12 + 16]);
+        let mut reader = &buf[..];
+        let entry = Entry::read_from_version(&mut reader, &header(2)).unwrap();
+        assert!(reader.is_empty());
+        assert_eq!((entry.owner_id, entry.group_id), (1000, 65535));
+        assert_eq!(entry.permission_mode, 0o644);
+        assert!(entry.extensions.is_empty());
+    }
 }
 
 pub trait LocalResultExt<T>
@@ -1,35 +1,63 @@
+use std::collections::HashSet;
 use std::ffi::OsStr;
+use std::io::stdout;
 
 use bczhc_lib::str::GenericOsStrExt;
 use clap::ArgMatches;
 use prettytable::{format, row, Table};
 
 use crate::errors::Result;
+use crate::filter::EntryFilter;
 use crate::info::print_info;
+use crate::output::{EntryRecord, Format, RecordWriter};
 use crate::reader::ArchiveReader;
 use crate::{FileType, OsStrExt};
 
 pub fn main(matches: &ArgMatches) -> Result<()> {
     let path = matches.get_one::<String>("archive").unwrap();
+    let filter = EntryFilter::from_matches(matches)?;
+    let format = Format::from_matches(matches);
     let archive = ArchiveReader::new(path)?;
     let header = &archive.header;
 
+    if let Format::Records(format) = format {
+        let mut records = RecordWriter::new(format, stdout().lock());
+        for entry in archive.entries() {
+            let entry = entry?;
+            if filter.is_selected(&entry.path) {
+                records.write(&EntryRecord::from(&entry))?;
+            }
+        }
+        return records.finish();
+    }
+
     print_info(header)?;
     println!();
 
     let mut original_size_sum = 0_u64;
     let mut stored_size_sum = 0_u64;
     let mut entry_count = 0_u64;
+    let mut total_count = 0_u64;
+    // chunks referenced more than once are only counted once
+    let mut chunk_offsets = HashSet::new();
+    let mut has_chunks = false;
+    let mut dedup_size = 0_u64;
 
     let mut table = Table::new();
     table.set_format(*format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR);
 
-    table.set_titles(row!["Type", "Original", "Stored", "Ratio", "Path"]);
+    table.set_titles(row![
+        "Type", "Method", "Original", "Stored", "Ratio", "Path"
+    ]);
 
     println!("Collecting entries...");
     let entries = archive.entries();
     for entry in entries {
         let entry = entry?;
+        total_count += 1;
+        if !filter.is_selected(&entry.path) {
+            continue;
+        }
         let path_bytes = &entry.path[..];
         let path = OsStr::from_bytes(path_bytes);
         let path_string = path.escape();
@@ -38,6 +66,7 @@ pub fn main(matches: &ArgMatches) -> Result<()> {
             let compression_ratio = entry.original_size as f64 / entry.stored_size as f64;
             table.add_row(row![
                 format!("{:?}", entry.file_type),
+                entry.compression,
                 entry.original_size,
                 entry.stored_size,
                 if compression_ratio.is_finite() {
@@ -50,9 +79,16 @@ pub fn main(matches: &ArgMatches) -> Result<()> {
 
             stored_size_sum += entry.stored_size;
             original_size_sum += entry.original_size;
+            for chunk in &entry.chunks {
+                has_chunks = true;
+                if !chunk_offsets.insert(chunk.offset) {
+                    dedup_size += chunk.stored_size as u64;
+                }
+            }
         } else {
             table.add_row(row![
                 format!("{:?}", entry.file_type),
+                "-",
                 entry.original_size,
                 "-",
                 "-",
@@ -65,18 +101,30 @@ pub fn main(matches: &ArgMatches) -> Result<()> {
     table.printstd();
     println!();
     println!("Entries: {}", entry_count);
-    assert_eq!(entry_count, header.entry_count);
+    assert_eq!(total_count, header.entry_count);
     println!("Total original size: {}", original_size_sum);
     println!("Total stored size: {}", stored_size_sum);
-    let space_saving = (1.0 - stored_size_sum as f64 / original_size_sum as f64) * 100.0;
     println!(
         "Space saving: {:.3}%",
-        if space_saving.is_finite() {
-            space_saving
-        } else {
-            0.0
-        }
+        space_saving(stored_size_sum, original_size_sum)
     );
+    if has_chunks {
+        let deduplicated_size = stored_size_sum - dedup_size;
+        println!("Deduplicated stored size: {}", deduplicated_size);
+        println!(
+            "Space saving with deduplication: {:.3}%",
+            space_saving(deduplicated_size, original_size_sum)
+        );
+    }
 
     Ok(())
 }
+
+fn space_saving(stored_size: u64, original_size: u64) -> f64 {
+    let space_saving = (1.0 - stored_size as f64 / original_size as f64) * 100.0;
+    if space_saving.is_finite() {
+        space_saving
+    } else {
+        0.0
+    }
+}
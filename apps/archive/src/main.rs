@@ -1,8 +1,24 @@
+use std::process::exit;
+
 use archive::cli::build_cli;
 
-fn main() -> anyhow::Result<()> {
+fn main() {
+    // exit code 1 is left for results like differences or test errors
+    if let Err(e) = run() {
+        eprintln!("Error: {:?}", e);
+        exit(2);
+    }
+}
+
+fn run() -> anyhow::Result<()> {
     let matches = build_cli().get_matches();
 
+    #[cfg(all(unix, feature = "mount"))]
+    if let Some(matches) = matches.subcommand_matches("mount") {
+        archive::mount::main(matches)?;
+        return Ok(());
+    }
+
     if let Some(matches) = matches.subcommand_matches("create") {
         archive::create::main(matches)
     } else if let Some(matches) = matches.subcommand_matches("list") {
@@ -13,6 +29,20 @@ fn main() -> anyhow::Result<()> {
         archive::test::main(matches)
     } else if let Some(matches) = matches.subcommand_matches("info") {
         archive::info::main(matches)
+    } else if let Some(matches) = matches.subcommand_matches("diff") {
+        archive::diff::main(matches)
+    } else if let Some(matches) = matches.subcommand_matches("add") {
+        archive::add::main(matches)
+    } else if let Some(matches) = matches.subcommand_matches("update") {
+        archive::update::main(matches)
+    } else if let Some(matches) = matches.subcommand_matches("delete") {
+        archive::delete::main(matches)
+    } else if let Some(matches) = matches.subcommand_matches("compact") {
+        archive::compact::main(matches)
+    } else if let Some(matches) = matches.subcommand_matches("convert") {
+        archive::convert::main(matches)
+    } else if let Some(matches) = matches.subcommand_matches("repair") {
+        archive::repair::main(matches)
     } else {
         unreachable!()
     }?;
@@ -0,0 +1,759 @@
+//! Read-only FUSE mount of an archive
+//!
+//! The directory tree is built from the entry table when mounting. Stored
+//! entries are read straight from the archive, while compressed ones are
+//! decompressed as a whole when opened. Recently used decompressed content is
+//! kept in a small cache; entries larger than the cache go to temporary files,
+//! which live as long as they are open.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Component, Path};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bczhc_lib::str::GenericOsStrExt;
+use clap::ArgMatches;
+use fuser::{
+    FileAttr, Filesystem, MountOption, ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty,
+    ReplyEntry, ReplyOpen, ReplyXattr, Request,
+};
+use nix::libc::{EIO, EISDIR, ENODATA, ENOENT, ENOTDIR, ERANGE};
+
+use crate::compressors::entry_decompressor;
+use crate::errors::*;
+use crate::extensions::{self, XATTR_PREFIX};
+use crate::incremental::Chain;
+use crate::sparse::{Expand, SparseMap, ZeroFill};
+use crate::{Compression, Entry, FileType, OsStrExt};
+
+const ROOT_INODE: u64 = 1;
+/// The archive never changes while mounted
+const TTL: Duration = Duration::from_secs(3600);
+const BLOCK_SIZE: u32 = 512;
+
+pub fn main(matches: &ArgMatches) -> Result<()> {
+    let archive_path = matches.get_one::<String>("archive").unwrap();
+    let mount_point = matches.get_one::<String>("mount-point").unwrap();
+    let cache_size = *matches.get_one::<u64>("cache-size").unwrap();
+    let external_filter_cmd = matches
+        .get_many::<String>("data-filter-cmd")
+        .map(|values| values.map(|x| x.to_owned()).collect::<Vec<_>>());
+
+    let chain = Chain::open(archive_path)?;
+    let fs = ArchiveFs::new(chain, cache_size * 1024 * 1024, external_filter_cmd);
+
+    let options = [
+        MountOption::RO,
+        MountOption::DefaultPermissions,
+        MountOption::FSName(archive_path.clone()),
+        MountOption::Subtype(String::from("archive")),
+    ];
+    eprintln!("Mounted at {}; unmount it to exit", mount_point);
+    fuser::mount2(fs, mount_point, &options)?;
+    Ok(())
+}
+
+struct Node {
+    parent: u64,
+    kind: fuser::FileType,
+    /// `None` for directories not recorded in the archive
+    entry: Option<(usize, Entry)>,
+    children: BTreeMap<OsString, u64>,
+    nlink: u32,
+}
+
+impl Node {
+    fn directory(parent: u64) -> Self {
+        Self {
+            parent,
+            kind: fuser::FileType::Directory,
+            entry: None,
+            children: BTreeMap::new(),
+            nlink: 2,
+        }
+    }
+}
+
+/// Decompressed content of an open file
+enum Content {
+    /// Not compressed, read from the archive directly
+    Stored,
+    Memory(Arc<Vec<u8>>),
+    File(File),
+}
+
+/// Decompressed content of recently opened files, within a total size
+struct ContentCache {
+    capacity: u64,
+    size: u64,
+    /// the most recently used at the back
+    items: VecDeque<(u64, Arc<Vec<u8>>)>,
+}
+
+impl ContentCache {
+    fn new(capacity: u64) -> Self {
+        Self {
+            capacity,
+            size: 0,
+            items: VecDeque::new(),
+        }
+    }
+
+    fn get(&mut self, ino: u64) -> Option<Arc<Vec<u8>>> {
+        let index = self.items.iter().position(|(i, _)| *i == ino)?;
+        let item = self.items.remove(index).unwrap();
+        let data = Arc::clone(&item.1);
+        self.items.push_back(item);
+        Some(data)
+    }
+
+    fn put(&mut self, ino: u64, data: Arc<Vec<u8>>) {
+        self.size += data.len() as u64;
+        self.items.push_back((ino, data));
+        while self.size > self.capacity {
+            let Some((_, evicted)) = self.items.pop_front() else {
+                break;
+            };
+            self.size -= evicted.len() as u64;
+        }
+    }
+}
+
+struct ArchiveFs {
+    chain: Chain,
+    external_filter_cmd: Option<Vec<String>>,
+    /// indexed by `inode - 1`
+    nodes: Vec<Node>,
+    cache: ContentCache,
+    handles: HashMap<u64, Content>,
+    next_handle: u64,
+    creation_time: SystemTime,
+}
+
+impl ArchiveFs {
+    fn new(chain: Chain, cache_size: u64, external_filter_cmd: Option<Vec<String>>) -> Self {
+        let creation_time = UNIX_EPOCH
+            + Duration::from_millis(chain.tip().reader.header.creation_time.max(0) as u64);
+        let mut fs = Self {
+            chain,
+            external_filter_cmd,
+            nodes: vec![Node::directory(ROOT_INODE)],
+            cache: ContentCache::new(cache_size),
+            handles: HashMap::new(),
+            next_handle: 0,
+            creation_time,
+        };
+        fs.build_tree();
+        fs
+    }
+
+    fn build_tree(&mut self) {
+        let entries = self
+            .chain
+            .entries()
+            .map(|(l, e)| (l, e.clone()))
+            .collect::<Vec<_>>();
+
+        // hard links point to other entries, so add them at last
+        let (links, entries): (Vec<_>, Vec<_>) = entries
+            .into_iter()
+            .partition(|(_, e)| e.file_type == FileType::Link);
+
+        for (layer, entry) in entries {
+            let Some(names) = split_path(&entry.path) else {
+                continue;
+            };
+            let Some((name, parents)) = names.split_last() else {
+                // the base directory itself
+                self.nodes[0].entry = Some((layer, entry));
+                continue;
+            };
+            let parent = self.make_directories(parents);
+            let existing = self.node(parent).children.get(name).copied();
+            match (existing, entry.file_type) {
+                (Some(ino), FileType::Directory) if self.node(ino).entry.is_none() => {
+                    // recorded after its children
+                    self.node_mut(ino).entry = Some((layer, entry));
+                }
+                (Some(_), _) => {
+                    eprintln!("{}: duplicated path, skipped", escape(&entry.path));
+                }
+                (None, file_type) => {
+                    let kind = match file_type {
+                        FileType::Regular => fuser::FileType::RegularFile,
+                        FileType::Symlink => fuser::FileType::Symlink,
+                        FileType::Fifo => fuser::FileType::NamedPipe,
+                        FileType::Directory => fuser::FileType::Directory,
+                        FileType::CharDevice => fuser::FileType::CharDevice,
+                        FileType::BlockDevice => fuser::FileType::BlockDevice,
+                        FileType::Socket => fuser::FileType::Socket,
+                        FileType::Link => unreachable!(),
+                    };
+                    let mut node = Node::directory(parent);
+                    node.kind = kind;
+                    node.entry = Some((layer, entry));
+                    if kind != fuser::FileType::Directory {
+                        node.nlink = 1;
+                    }
+                    self.add_child(parent, name, node);
+                }
+            }
+        }
+
+        for (_, entry) in links {
+            let target = split_path(&entry.linked_path).and_then(|x| self.find(&x));
+            let (Some(names), Some(target)) = (split_path(&entry.path), target) else {
+                eprintln!("{}: dangling hard link, skipped", escape(&entry.path));
+                continue;
+            };
+            let Some((name, parents)) = names.split_last() else {
+                continue;
+            };
+            if self.node(target).kind == fuser::FileType::Directory {
+                continue;
+            }
+            let parent = self.make_directories(parents);
+            if self.node(parent).children.contains_key(name) {
+                eprintln!("{}: duplicated path, skipped", escape(&entry.path));
+                continue;
+            }
+            self.node_mut(parent).children.insert(name.clone(), target);
+            self.node_mut(target).nlink += 1;
+        }
+    }
+
+    /// Returns the inode of the innermost directory
+    fn make_directories(&mut self, names: &[OsString]) -> u64 {
+        let mut ino = ROOT_INODE;
+        for name in names {
+            ino = match self.node(ino).children.get(name) {
+                Some(&child) if self.node(child).kind == fuser::FileType::Directory => child,
+                // a non-directory is in the way; shadow it
+                _ => self.add_child(ino, name, Node::directory(ino)),
+            };
+        }
+        ino
+    }
+
+    fn add_child(&mut self, parent: u64, name: &OsStr, node: Node) -> u64 {
+        let is_directory = node.kind == fuser::FileType::Directory;
+        self.nodes.push(node);
+        let ino = self.nodes.len() as u64;
+        let parent_node = self.node_mut(parent);
+        parent_node.children.insert(name.into(), ino);
+        if is_directory {
+            parent_node.nlink += 1;
+        }
+        ino
+    }
+
+    fn find(&self, names: &[OsString]) -> Option<u64> {
+        let mut ino = ROOT_INODE;
+        for name in names {
+            ino = *self.node(ino).children.get(name)?;
+        }
+        Some(ino)
+    }
+
+    fn node(&self, ino: u64) -> &Node {
+        &self.nodes[(ino - 1) as usize]
+    }
+
+    fn node_mut(&mut self, ino: u64) -> &mut Node {
+        &mut self.nodes[(ino - 1) as usize]
+    }
+
+    fn get_node(&self, ino: u64) -> Option<&Node> {
+        ino.checked_sub(1).and_then(|i| self.nodes.get(i as usize))
+    }
+
+    fn attr(&self, ino: u64) -> FileAttr {
+        let node = self.node(ino);
+        let (size, perm, uid, gid, mtime, rdev) = match &node.entry {
+            Some((_, entry)) => {
+                let size = match entry.file_type {
+                    FileType::Symlink => entry.linked_path.len() as u64,
+                    _ => entry.original_size,
+                };
+                (
+                    size,
+                    entry.permission_mode & 0o7777,
+                    entry.owner_id,
+                    entry.group_id,
+                    system_time(
+                        entry.modification_time.seconds,
+                        entry.modification_time.nanoseconds,
+                    ),
+                    extensions::device_numbers(entry).map_or(0, encode_device),
+                )
+            }
+            None => (0, 0o755, 0, 0, self.creation_time, 0),
+        };
+        FileAttr {
+            ino,
+            size,
+            blocks: size.div_ceil(BLOCK_SIZE as u64),
+            atime: mtime,
+            mtime,
+            ctime: mtime,
+            crtime: mtime,
+            kind: node.kind,
+            perm,
+            nlink: node.nlink,
+            uid,
+            gid,
+            rdev,
+            blksize: BLOCK_SIZE,
+            flags: 0,
+        }
+    }
+
+    fn open_content(&mut self, ino: u64) -> Result<Content> {
+        let (layer, entry) = self.node(ino).entry.clone().unwrap();
+        let map = SparseMap::of(&entry)?;
+        // uncompressed plaintext is read in place
+        if entry.compression == Compression::None
+            && entry.chunks.is_empty()
+            && map.is_none()
+            && !self.chain.layers()[layer].reader.header.is_encrypted()
+        {
+            return Ok(Content::Stored);
+        }
+        if let Some(data) = self.cache.get(ino) {
+            return Ok(Content::Memory(data));
+        }
+
+        let reader = &mut self.chain.layer_mut(layer).reader;
+        let decompressor = entry_decompressor(
+            entry.compression,
+            self.external_filter_cmd.as_ref(),
+            reader.dictionary(),
+        )?;
+        if entry.original_size <= self.cache.capacity {
+            let mut data = Expand::new(
+                ZeroFill(Vec::with_capacity(entry.original_size as usize)),
+                map,
+            );
+            reader.decompress_entry(&entry, &*decompressor, &mut data)?;
+            let data = Arc::new(data.finish()?.0);
+            self.cache.put(ino, Arc::clone(&data));
+            Ok(Content::Memory(data))
+        } else {
+            let mut file = Expand::new(tempfile::tempfile()?, map);
+            reader.decompress_entry(&entry, &*decompressor, &mut file)?;
+            Ok(Content::File(file.finish()?))
+        }
+    }
+
+    fn read_content(&mut self, ino: u64, fh: u64, offset: u64, size: u32) -> Result<Vec<u8>> {
+        let (layer, entry) = self.node(ino).entry.as_ref().unwrap();
+        let (layer, entry_offset, file_size) = (*layer, entry.offset, entry.original_size);
+        let size = (size as u64).min(file_size.saturating_sub(offset));
+        if size == 0 {
+            return Ok(Vec::new());
+        }
+        let mut buf = vec![0_u8; size as usize];
+
+        let read_size = match self.handles.get_mut(&fh) {
+            Some(Content::Memory(data)) => {
+                let data = usize::try_from(offset)
+                    .ok()
+                    .and_then(|x| data.get(x..))
+                    .unwrap_or_default();
+                let read_size = buf.len().min(data.len());
+                buf[..read_size].copy_from_slice(&data[..read_size]);
+                read_size
+            }
+            Some(Content::File(file)) => {
+                file.seek(SeekFrom::Start(offset))?;
+                read_fully(file, &mut buf)?
+            }
+            Some(Content::Stored) => {
+                let mut reader = self
+                    .chain
+                    .layer_mut(layer)
+                    .reader
+                    .retrieve_content(entry_offset + offset, size);
+                read_fully(&mut reader, &mut buf)?
+            }
+            None => return Err(Error::Others("Invalid file handle".into())),
+        };
+        buf.truncate(read_size);
+        Ok(buf)
+    }
+
+    fn path_of(&self, ino: u64) -> String {
+        match &self.node(ino).entry {
+            Some((_, entry)) => escape(&entry.path),
+            None => String::from("<directory>"),
+        }
+    }
+
+    fn xattr(&self, ino: u64, name: &OsStr) -> Option<&[u8]> {
+        let (_, entry) = self.node(ino).entry.as_ref()?;
+        entry
+            .extensions
+            .iter()
+            .find(|x| {
+                x.key()
+                    .strip_prefix(XATTR_PREFIX)
+                    .map(|x| OsStr::from_bytes(x) == name)
+                    .unwrap_or(false)
+            })
+            .map(|x| x.value())
+    }
+}
+
+impl Filesystem for ArchiveFs {
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let Some(parent) = self.get_node(parent) else {
+            reply.error(ENOENT);
+            return;
+        };
+        match parent.children.get(name) {
+            Some(&ino) => reply.entry(&TTL, &self.attr(ino), 0),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+        match self.get_node(ino) {
+            Some(_) => reply.attr(&TTL, &self.attr(ino)),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
+        match self.get_node(ino).and_then(|x| x.entry.as_ref()) {
+            Some((_, entry)) if entry.file_type == FileType::Symlink => {
+                reply.data(&entry.linked_path)
+            }
+            _ => reply.error(ENOENT),
+        }
+    }
+
+    fn open(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
+        match self.get_node(ino).map(|x| x.kind) {
+            Some(fuser::FileType::RegularFile) => {}
+            Some(fuser::FileType::Directory) => return reply.error(EISDIR),
+            Some(_) => return reply.error(EIO),
+            None => return reply.error(ENOENT),
+        }
+        match self.open_content(ino) {
+            Ok(content) => {
+                let fh = self.next_handle;
+                self.next_handle += 1;
+                self.handles.insert(fh, content);
+                reply.opened(fh, 0);
+            }
+            Err(e) => {
+                eprintln!("{}: {}", self.path_of(ino), e);
+                reply.error(EIO);
+            }
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        match self.read_content(ino, fh, offset.max(0) as u64, size) {
+            Ok(data) => reply.data(&data),
+            Err(e) => {
+                eprintln!("{}: {}", self.path_of(ino), e);
+                reply.error(EIO);
+            }
+        }
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.handles.remove(&fh);
+        reply.ok();
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let Some(node) = self.get_node(ino) else {
+            reply.error(ENOENT);
+            return;
+        };
+        if node.kind != fuser::FileType::Directory {
+            reply.error(ENOTDIR);
+            return;
+        }
+        let dots = [
+            (ino, fuser::FileType::Directory, OsStr::new(".")),
+            (node.parent, fuser::FileType::Directory, OsStr::new("..")),
+        ];
+        let children = node
+            .children
+            .iter()
+            .map(|(name, &child)| (child, self.node(child).kind, name.as_os_str()));
+        for (i, (child, kind, name)) in dots
+            .into_iter()
+            .chain(children)
+            .enumerate()
+            .skip(offset.max(0) as usize)
+        {
+            // the offset is of the next entry
+            if reply.add(child, (i + 1) as i64, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn getxattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        size: u32,
+        reply: ReplyXattr,
+    ) {
+        match self.get_node(ino) {
+            Some(_) => match self.xattr(ino, name) {
+                Some(value) => reply_xattr(reply, size, value),
+                None => reply.error(ENODATA),
+            },
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn listxattr(&mut self, _req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+        let Some(node) = self.get_node(ino) else {
+            reply.error(ENOENT);
+            return;
+        };
+        // null-terminated names
+        let mut names = Vec::new();
+        if let Some((_, entry)) = &node.entry {
+            for x in &entry.extensions {
+                if let Some(name) = x.key().strip_prefix(XATTR_PREFIX) {
+                    names.extend_from_slice(name);
+                    names.push(0);
+                }
+            }
+        }
+        reply_xattr(reply, size, &names);
+    }
+}
+
+/// `size` being zero asks for the size only
+fn reply_xattr(reply: ReplyXattr, size: u32, data: &[u8]) {
+    if size == 0 {
+        reply.size(data.len() as u32);
+    } else if (size as usize) < data.len() {
+        reply.error(ERANGE);
+    } else {
+        reply.data(data);
+    }
+}
+
+fn escape(path: &[u8]) -> String {
+    OsStr::from_bytes(path).escape()
+}
+
+/// Split a stored path into names; `None` if it's unsafe
+fn split_path(path: &[u8]) -> Option<Vec<OsString>> {
+    let mut names = Vec::new();
+    for c in Path::new(OsStr::from_bytes(path)).components() {
+        match c {
+            Component::CurDir => {}
+            Component::Normal(name) => names.push(name.to_owned()),
+            _ => {
+                eprintln!("{}: unsafe path, skipped", escape(path));
+                return None;
+            }
+        }
+    }
+    Some(names)
+}
+
+fn system_time(seconds: i64, nanoseconds: u32) -> SystemTime {
+    if seconds >= 0 {
+        UNIX_EPOCH + Duration::new(seconds as u64, nanoseconds)
+    } else {
+        UNIX_EPOCH - Duration::from_secs(seconds.unsigned_abs())
+            + Duration::from_nanos(nanoseconds as u64)
+    }
+}
+
+/// Encode device numbers as the 32-bit `rdev` of the FUSE protocol
+fn encode_device((major, minor): (u32, u32)) -> u32 {
+    (minor & 0xff) | (major & 0xfff) << 8 | (minor & !0xff) << 12
+}
+
+fn read_fully<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<usize> {
+    let mut read_size = 0;
+    while read_size < buf.len() {
+        match reader.read(&mut buf[read_size..])? {
+            0 => break,
+            n => read_size += n,
+        }
+    }
+    Ok(read_size)
+}
+
+#[cfg(test)]
+mod test {
+    use std::ffi::OsStr;
+    use std::fs;
+    use std::fs::File;
+    use std::io::BufWriter;
+    use std::os::unix::fs::symlink;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use super::{ArchiveFs, Content, ROOT_INODE};
+    use crate::archive::Archive;
+    use crate::compressors::{create_compressor, CodecOptions, CompressionPolicy, Level};
+    use crate::incremental::Chain;
+    use crate::{Compression, Info};
+
+    /// `entries`: (stored path, actual file path)
+    fn craft_archive(output: &Path, method: Compression, entries: &[(&str, &Path)]) {
+        let writer = BufWriter::new(File::create(output).unwrap());
+        let compressor = create_compressor(method, Level::Numeric(3), &CodecOptions::default());
+        let mut archive = Archive::new(writer, CompressionPolicy::new(method, compressor)).unwrap();
+        archive.set_info(&Info {
+            compression_method: method.as_str().into(),
+            comment: None,
+            parent: None,
+            deleted: Vec::new(),
+        });
+        for (path, file_path) in entries {
+            archive.add_path(OsStr::new(path), file_path).unwrap();
+        }
+        archive.write().unwrap();
+        archive.flush().unwrap();
+    }
+
+    fn lookup(fs: &ArchiveFs, path: &str) -> Option<u64> {
+        let names = path.split('/').map(|x| x.into()).collect::<Vec<_>>();
+        fs.find(&names)
+    }
+
+    fn open(fs: &mut ArchiveFs, ino: u64) -> u64 {
+        let content = fs.open_content(ino).unwrap();
+        fs.handles.insert(fs.next_handle, content);
+        fs.next_handle += 1;
+        fs.next_handle - 1
+    }
+
+    #[test]
+    fn tree() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        fs::create_dir(&src).unwrap();
+        fs::create_dir(src.join("d")).unwrap();
+        fs::write(src.join("file"), "content").unwrap();
+        symlink("file", src.join("sym")).unwrap();
+        let output = dir.path().join("archive");
+        craft_archive(
+            &output,
+            Compression::None,
+            &[
+                ("./", &src),
+                ("a/b/file", &src.join("file")),
+                // a hard link to the previous one
+                ("a/hard", &src.join("file")),
+                ("a/sym", &src.join("sym")),
+                // recorded after its children
+                ("a/", &src.join("d")),
+            ],
+        );
+        let fs = ArchiveFs::new(Chain::open(&output).unwrap(), 1024, None);
+
+        assert!(fs.node(ROOT_INODE).entry.is_some());
+        let a = lookup(&fs, "a").unwrap();
+        let b = lookup(&fs, "a/b").unwrap();
+        let file = lookup(&fs, "a/b/file").unwrap();
+        assert!(fs.node(a).entry.is_some());
+        assert!(fs.node(b).entry.is_none());
+        assert_eq!(fs.node(b).parent, a);
+        assert_eq!(fs.node(a).nlink, 3);
+        assert_eq!(fs.node(ROOT_INODE).nlink, 3);
+        assert_eq!(fs.attr(file).kind, fuser::FileType::RegularFile);
+        assert_eq!(fs.attr(file).size, 7);
+        assert_eq!(lookup(&fs, "a/hard"), Some(file));
+        assert_eq!(fs.node(file).nlink, 2);
+        let sym = lookup(&fs, "a/sym").unwrap();
+        assert_eq!(fs.attr(sym).kind, fuser::FileType::Symlink);
+        assert_eq!(fs.attr(sym).size, 4);
+        assert!(lookup(&fs, "missing").is_none());
+        assert!(fs.get_node(0).is_none());
+        assert!(fs.get_node(100).is_none());
+    }
+
+    #[test]
+    fn read_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let content = b"0123456789".repeat(1000);
+        let file = dir.path().join("file");
+        fs::write(&file, &content).unwrap();
+
+        for (method, cache_size) in [
+            (Compression::None, 0),
+            (Compression::Zstd, 1 << 20),
+            (Compression::Zstd, 0),
+        ] {
+            let output = dir.path().join("archive");
+            craft_archive(&output, method, &[("file", &file)]);
+            let mut fs = ArchiveFs::new(Chain::open(&output).unwrap(), cache_size, None);
+            let ino = lookup(&fs, "file").unwrap();
+            let fh = open(&mut fs, ino);
+            let expected = match method {
+                Compression::None => "Stored",
+                _ if cache_size == 0 => "File",
+                _ => "Memory",
+            };
+            let kind = match fs.handles[&fh] {
+                Content::Stored => "Stored",
+                Content::Memory(_) => "Memory",
+                Content::File(_) => "File",
+            };
+            assert_eq!(kind, expected);
+
+            assert_eq!(fs.read_content(ino, fh, 0, 10).unwrap(), b"0123456789");
+            assert_eq!(fs.read_content(ino, fh, 9995, 100).unwrap(), b"56789");
+            assert!(fs.read_content(ino, fh, 10000, 100).unwrap().is_empty());
+            assert!(fs.read_content(ino, fh, u64::MAX, 100).unwrap().is_empty());
+            assert!(fs.read_content(ino, fh + 1, 0, 10).is_err());
+        }
+
+        // content shorter than recorded
+        let output = dir.path().join("archive");
+        let mut fs = ArchiveFs::new(Chain::open(&output).unwrap(), 0, None);
+        let ino = lookup(&fs, "file").unwrap();
+        fs.handles
+            .insert(0, Content::Memory(Arc::new(b"0123".to_vec())));
+        assert_eq!(fs.read_content(ino, 0, 2, 10).unwrap(), b"23");
+        assert!(fs.read_content(ino, 0, 8, 10).unwrap().is_empty());
+    }
+}
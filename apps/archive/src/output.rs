@@ -0,0 +1,334 @@
+//! Machine-readable output of `list`, `info` and `test`
+//!
+//! With `--format json`, a command prints a single JSON document: an array of
+//! records, or an object for `info`. `jsonl` prints one record per line, and
+//! `csv` one row per record after a header row. The fields of each record type
+//! are fixed; fields not applicable to an entry are `null` (empty in CSV).
+//!
+//! Paths are escaped the same as in the text output: backslashes are doubled,
+//! and bytes of invalid UTF-8 are written as `\xNN`.
+//!
+//! Exit codes are 0 on success, 1 if `test` finds any error, and 2 if the
+//! command fails (like an unreadable archive).
+
+use std::io;
+use std::io::Write;
+use std::str::FromStr;
+
+use bczhc_lib::str::escape_utf8_bytes;
+use serde::Serialize;
+
+use crate::errors::*;
+use crate::extensions;
+use crate::hash::stored_hash;
+use crate::sparse;
+use crate::{Entry, FileType, Header, Info};
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Format {
+    Text,
+    Records(RecordFormat),
+}
+
+/// The machine-readable formats
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RecordFormat {
+    Json,
+    Jsonl,
+    Csv,
+}
+
+pub static FORMAT_NAMES: [&str; 4] = ["text", "json", "jsonl", "csv"];
+
+impl FromStr for Format {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Records(RecordFormat::Json)),
+            "jsonl" => Ok(Format::Records(RecordFormat::Jsonl)),
+            "csv" => Ok(Format::Records(RecordFormat::Csv)),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Format {
+    pub fn from_matches(matches: &clap::ArgMatches) -> Self {
+        matches
+            .get_one::<String>("format")
+            .map(|x| x.parse().unwrap())
+            .unwrap_or(Format::Text)
+    }
+}
+
+/// Writes records in one of the machine-readable formats
+pub struct RecordWriter<W: Write> {
+    sink: Sink<W>,
+    count: usize,
+}
+
+enum Sink<W: Write> {
+    Json(W),
+    Jsonl(W),
+    Csv(Box<csv::Writer<W>>),
+}
+
+impl<W: Write> RecordWriter<W> {
+    pub fn new(format: RecordFormat, writer: W) -> Self {
+        let sink = match format {
+            RecordFormat::Json => Sink::Json(writer),
+            RecordFormat::Jsonl => Sink::Jsonl(writer),
+            RecordFormat::Csv => Sink::Csv(Box::new(csv::Writer::from_writer(writer))),
+        };
+        Self { sink, count: 0 }
+    }
+
+    pub fn write<T: Serialize>(&mut self, record: &T) -> Result<()> {
+        match &mut self.sink {
+            Sink::Json(writer) => {
+                writer.write_all(if self.count == 0 { b"[\n" } else { b",\n" })?;
+                serde_json::to_writer(&mut *writer, record).map_err(io::Error::from)?;
+            }
+            Sink::Jsonl(writer) => {
+                serde_json::to_writer(&mut *writer, record).map_err(io::Error::from)?;
+                writer.write_all(b"\n")?;
+            }
+            Sink::Csv(writer) => writer.serialize(record).map_err(io::Error::from)?,
+        }
+        self.count += 1;
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<()> {
+        match self.sink {
+            Sink::Json(mut writer) => {
+                writer.write_all(if self.count == 0 { b"[]\n" } else { b"\n]\n" })?;
+                writer.flush()?;
+            }
+            Sink::Jsonl(mut writer) => writer.flush()?,
+            Sink::Csv(mut writer) => writer.flush()?,
+        }
+        Ok(())
+    }
+}
+
+/// Write a single record, as an object instead of an array in JSON
+pub fn write_single<T: Serialize, W: Write>(
+    format: RecordFormat,
+    mut writer: W,
+    record: &T,
+) -> Result<()> {
+    match format {
+        RecordFormat::Json => {
+            serde_json::to_writer_pretty(&mut writer, record).map_err(io::Error::from)?;
+            writer.write_all(b"\n")?;
+            writer.flush()?;
+            Ok(())
+        }
+        _ => {
+            let mut records = RecordWriter::new(format, writer);
+            records.write(record)?;
+            records.finish()
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct EntryRecord {
+    pub path: String,
+    #[serde(rename = "type")]
+    pub file_type: &'static str,
+    /// target of symlinks and hard links
+    pub linked_path: Option<String>,
+    pub compression: Option<&'static str>,
+    /// the apparent size for sparse files
+    pub original_size: u64,
+    pub stored_size: u64,
+    /// permission bits in octal, like "0644"
+    pub mode: String,
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: i64,
+    pub mtime_nsec: u32,
+    /// CRC-64 of the stored content in hex
+    pub content_checksum: Option<String>,
+    /// offset in the content area, absent for chunked files
+    pub offset: Option<u64>,
+    pub chunks: usize,
+    pub sparse: bool,
+    pub device_major: Option<u32>,
+    pub device_minor: Option<u32>,
+    /// like "blake3:<hex>"
+    pub hash: Option<String>,
+}
+
+impl From<&Entry> for EntryRecord {
+    fn from(entry: &Entry) -> Self {
+        let is_regular = entry.file_type == FileType::Regular;
+        let has_link = matches!(entry.file_type, FileType::Link | FileType::Symlink);
+        let device = extensions::device_numbers(entry);
+        Self {
+            path: escape_utf8_bytes(&entry.path),
+            file_type: entry.file_type.as_str(),
+            linked_path: has_link.then(|| escape_utf8_bytes(&entry.linked_path)),
+            compression: is_regular.then(|| entry.compression.as_str()),
+            original_size: entry.original_size,
+            stored_size: entry.stored_size,
+            mode: format!("{:04o}", entry.permission_mode & 0o7777),
+            uid: entry.owner_id,
+            gid: entry.group_id,
+            mtime: entry.modification_time.seconds,
+            mtime_nsec: entry.modification_time.nanoseconds,
+            content_checksum: is_regular.then(|| format!("{:016x}", entry.content_checksum)),
+            offset: (is_regular && entry.chunks.is_empty()).then_some(entry.offset),
+            chunks: entry.chunks.len(),
+            sparse: sparse::is_sparse(entry),
+            device_major: device.map(|x| x.0),
+            device_minor: device.map(|x| x.1),
+            hash: stored_hash(entry).map(|(function, hash)| {
+                let hex = hash
+                    .iter()
+                    .map(|x| format!("{:02x}", x))
+                    .collect::<String>();
+                format!("{}:{}", function.as_str(), hex)
+            }),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct InfoRecord {
+    pub version: u16,
+    pub content_offset: u64,
+    /// milliseconds since the Unix epoch
+    pub creation_time: i64,
+    pub entry_count: u64,
+    pub encrypted: bool,
+    pub compression_method: String,
+    /// size of the zstd dictionary, 0 if none
+    pub dictionary_size: usize,
+    pub comment: Option<String>,
+    /// path of the parent archive of an incremental one
+    pub parent: Option<String>,
+    /// count of paths marked deleted from the parent
+    pub deleted: usize,
+}
+
+impl InfoRecord {
+    pub fn new(header: &Header, info: &Info) -> Self {
+        Self {
+            version: header.version,
+            content_offset: header.content_offset,
+            creation_time: header.creation_time,
+            entry_count: header.entry_count,
+            encrypted: header.is_encrypted(),
+            compression_method: info.compression_method.clone(),
+            dictionary_size: header.dictionary.len(),
+            comment: info.comment.clone(),
+            parent: info.parent.as_ref().map(|x| x.path.clone()),
+            deleted: info.deleted.len(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{write_single, EntryRecord, InfoRecord, RecordFormat, RecordWriter};
+    use crate::{Compression, Entry, FileType, Header, Info, Timestamp, FILE_MAGIC};
+
+    const ENTRY_FIELDS: &str = "path,type,linked_path,compression,original_size,stored_size,\
+        mode,uid,gid,mtime,mtime_nsec,content_checksum,offset,chunks,sparse,device_major,\
+        device_minor,hash";
+
+    fn entry_record() -> EntryRecord {
+        let mut entry = Entry::new(b"dir/a\xff".to_vec(), FileType::Regular);
+        entry.compression = Compression::Zstd;
+        entry.original_size = 5;
+        entry.stored_size = 3;
+        entry.permission_mode = 0o100644;
+        entry.owner_id = 1000;
+        entry.group_id = 100;
+        entry.modification_time = Timestamp::new(1700000000, 5);
+        entry.content_checksum = 0xabc;
+        entry.offset = 7;
+        EntryRecord::from(&entry)
+    }
+
+    fn output(format: RecordFormat, records: &[EntryRecord]) -> String {
+        let mut output = Vec::new();
+        let mut writer = RecordWriter::new(format, &mut output);
+        for x in records {
+            writer.write(x).unwrap();
+        }
+        writer.finish().unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn entry_fields() {
+        let json = r#"{"path":"dir/a\\xFF","type":"regular","linked_path":null,"compression":"zstd","original_size":5,"stored_size":3,"mode":"0644","uid":1000,"gid":100,"mtime":1700000000,"mtime_nsec":5,"content_checksum":"0000000000000abc","offset":7,"chunks":0,"sparse":false,"device_major":null,"device_minor":null,"hash":null}"#;
+        assert_eq!(
+            output(RecordFormat::Jsonl, &[entry_record(), entry_record()]),
+            format!("{}\n{}\n", json, json)
+        );
+        assert_eq!(
+            output(RecordFormat::Json, &[entry_record(), entry_record()]),
+            format!("[\n{},\n{}\n]\n", json, json)
+        );
+        let row =
+            r"dir/a\xFF,regular,,zstd,5,3,0644,1000,100,1700000000,5,0000000000000abc,7,0,false,,,";
+        assert_eq!(
+            output(RecordFormat::Csv, &[entry_record()]),
+            format!("{}\n{}\n", ENTRY_FIELDS, row)
+        );
+    }
+
+    #[test]
+    fn no_records() {
+        assert_eq!(output(RecordFormat::Json, &[]), "[]\n");
+        assert_eq!(output(RecordFormat::Jsonl, &[]), "");
+        assert_eq!(output(RecordFormat::Csv, &[]), "");
+    }
+
+    #[test]
+    fn info_fields() {
+        let header = Header {
+            magic_number: *FILE_MAGIC,
+            version: 6,
+            content_offset: 100,
+            compression: Compression::Zstd,
+            creation_time: 1700000000000,
+            entry_count: 3,
+            info_json_length: 0,
+            info_json: String::new(),
+            encryption: None,
+            dictionary: vec![0; 10],
+        };
+        let info: Info =
+            serde_json::from_str(r#"{"compression_method":"zstd 3","comment":"hi"}"#).unwrap();
+        let record = InfoRecord::new(&header, &info);
+
+        let json = r#"{"version":6,"content_offset":100,"creation_time":1700000000000,"entry_count":3,"encrypted":false,"compression_method":"zstd 3","dictionary_size":10,"comment":"hi","parent":null,"deleted":0}"#;
+        let single = |format| {
+            let mut output = Vec::new();
+            write_single(format, &mut output, &record).unwrap();
+            String::from_utf8(output).unwrap()
+        };
+        // an object, pretty-printed
+        let pretty = json
+            .replace('{', "{\n  ")
+            .replace(",\"", ",\n  \"")
+            .replace('}', "\n}\n")
+            .replace("\":", "\": ");
+        assert_eq!(single(RecordFormat::Json), pretty);
+        assert_eq!(single(RecordFormat::Jsonl), format!("{}\n", json));
+        assert_eq!(
+            single(RecordFormat::Csv),
+            "version,content_offset,creation_time,entry_count,encrypted,compression_method,\
+             dictionary_size,comment,parent,deleted\n\
+             6,100,1700000000000,3,false,zstd 3,10,hi,,0\n"
+        );
+    }
+}
@@ -0,0 +1,173 @@
+//! Helpers for compressing files concurrently
+//!
+//! Workers compress whole files into [`SpillBuffer`]s, and the writer thread
+//! copies them to the content area in the entry order, so the output is the same
+//! as the serial one. At most a fixed number of compressed files are held at once
+//! (see [`Permits`]), and each of them is moved to a temporary file when it grows large.
+
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::{Condvar, Mutex};
+
+use crc_lib::Crc;
+
+use crate::chunking::{compress_chunks, ChunkInfo};
+use crate::compressors::CompressionPolicy;
+use crate::crc::write::CrcFilter;
+use crate::errors::Result;
+use crate::hash::{HashFunction, HashReader};
+use crate::sparse::{self, SparseMap};
+use crate::{Compression, Entry, FILE_CRC_64};
+
+/// Compressed data larger than this will be spilled to a temporary file
+const SPILL_THRESHOLD: usize = 16 * 1024 * 1024;
+
+pub enum SpillBuffer {
+    Memory(Vec<u8>),
+    File(File),
+}
+
+impl SpillBuffer {
+    pub fn new() -> Self {
+        Self::Memory(Vec::new())
+    }
+
+    /// Returns a reader of the written data
+    pub fn into_reader(self) -> io::Result<Box<dyn Read>> {
+        Ok(match self {
+            SpillBuffer::Memory(vec) => Box::new(Cursor::new(vec)),
+            SpillBuffer::File(mut file) => {
+                file.seek(SeekFrom::Start(0))?;
+                Box::new(BufReader::new(file))
+            }
+        })
+    }
+}
+
+impl Default for SpillBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Write for SpillBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            SpillBuffer::Memory(vec) => {
+                if vec.len() + buf.len() > SPILL_THRESHOLD {
+                    let mut file = tempfile::tempfile()?;
+                    file.write_all(vec)?;
+                    let size = file.write(buf)?;
+                    *self = SpillBuffer::File(file);
+                    Ok(size)
+                } else {
+                    vec.extend_from_slice(buf);
+                    Ok(buf.len())
+                }
+            }
+            SpillBuffer::File(file) => file.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            SpillBuffer::Memory(_) => Ok(()),
+            SpillBuffer::File(file) => file.flush(),
+        }
+    }
+}
+
+pub struct Compressed {
+    pub compression: Compression,
+    pub data: SpillBuffer,
+    pub stored_size: u64,
+    pub content_checksum: u64,
+    /// chunks of `data` in the chunked mode
+    pub chunks: Vec<ChunkInfo>,
+    /// hash of the original content
+    pub hash: Option<(HashFunction, Vec<u8>)>,
+}
+
+/// Compress a file as what `Archive::write_files` does, but into a buffer
+///
+/// In the chunked mode, all the chunks are compressed into the buffer, including
+/// those duplicated.
+pub fn compress_file(
+    policy: &CompressionPolicy,
+    entry: &Entry,
+    path: &Path,
+    chunked: bool,
+    hash: Option<HashFunction>,
+) -> Result<Compressed> {
+    let compression = policy.select(&entry.path, path)?;
+    let file = sparse::open_content(path, entry)?;
+    let mut file_reader = HashReader::new(file, hash, SparseMap::of(entry)?);
+
+    let mut buffer = SpillBuffer::new();
+    let crc = Crc::<u64>::new(&FILE_CRC_64);
+    let mut digest = crc.digest();
+    let mut crc_filter = CrcFilter::new(&mut digest, &mut buffer);
+
+    let compressor = policy.compressor(compression);
+    let (stored_size, chunks) = if chunked {
+        let chunks = compress_chunks(compressor, &mut file_reader, &mut crc_filter)?;
+        let size = chunks.iter().map(|x| x.stored_size as u64).sum();
+        (size, chunks)
+    } else {
+        let size = compressor.compress_to(&mut file_reader, &mut crc_filter)?;
+        (size, Vec::new())
+    };
+    crc_filter.flush()?;
+
+    Ok(Compressed {
+        compression,
+        data: buffer,
+        stored_size,
+        content_checksum: digest.finalize(),
+        chunks,
+        hash: file_reader.finalize()?,
+    })
+}
+
+/// A counting semaphore which can be closed
+pub struct Permits {
+    state: Mutex<(usize, bool)>,
+    condvar: Condvar,
+}
+
+impl Permits {
+    pub fn new(count: usize) -> Self {
+        Self {
+            state: Mutex::new((count, false)),
+            condvar: Condvar::new(),
+        }
+    }
+
+    /// Returns `false` if closed
+    pub fn acquire(&self) -> bool {
+        let mut guard = self.state.lock().unwrap();
+        loop {
+            if guard.1 {
+                return false;
+            }
+            if guard.0 > 0 {
+                guard.0 -= 1;
+                return true;
+            }
+            guard = self.condvar.wait(guard).unwrap();
+        }
+    }
+
+    pub fn release(&self) {
+        self.state.lock().unwrap().0 += 1;
+        self.condvar.notify_one();
+    }
+
+    /// Wake up all the waiting threads, and make further acquisitions fail
+    pub fn close(&self) {
+        self.state.lock().unwrap().1 = true;
+        self.condvar.notify_all();
+    }
+}
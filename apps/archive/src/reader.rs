@@ -1,51 +1,280 @@
+use crate::compressors::{decompress_reader, Decompress, Dictionary};
+use crate::crc::read::CrcFilter;
+use crate::encryption::{passphrase as read_passphrase, Cipher, DecryptReader};
 use crate::errors::*;
-use crate::{CalcCrcChecksum, Entry, GetStoredSize, Header, ReadFrom, FILE_MAGIC};
+use crate::recovery::archive_size;
+use crate::sparse::{ExpandReader, SparseMap};
+use crate::volume::{VolumeFile, VolumeProblem};
+use crate::{
+    normalized_path, Compression, Entry, FileType, FixedStoredSize, Footer, GetStoredSize, Header,
+    ReadFrom, ENTRY_CRC_32, FILE_MAGIC, VERSION,
+};
+use bczhc_lib::str::escape_utf8_bytes;
 use byteorder::{LittleEndian, ReadBytesExt};
+use crc_lib::Crc;
 
-use std::fs::File;
 use std::io;
-use std::io::{Read, Seek, SeekFrom};
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::Path;
+use std::sync::Arc;
 
 pub struct ArchiveReader {
-    file: File,
+    file: VolumeFile,
     pub header: Header,
+    /// absolute offset of the entry table
+    entries_offset: u64,
+    /// where the entry table (decrypted, if encrypted) ends, unknown for
+    /// version 0
+    entries_end: Option<u64>,
+    cipher: Option<Cipher>,
+    /// the decrypted entry table of encrypted archives
+    entry_table: Option<Arc<[u8]>>,
+    /// the zstd dictionary from the header, prepared once
+    dictionary: Option<Dictionary>,
 }
 
 impl ArchiveReader {
+    /// Open an archive, asking for the passphrase if it's encrypted
     pub fn new<P: AsRef<Path>>(archive: P) -> Result<Self> {
-        let mut file = File::open(archive)?;
+        Self::open(archive.as_ref(), None)
+    }
+
+    /// Open an archive, using `passphrase` if it's encrypted
+    pub fn with_passphrase<P: AsRef<Path>>(archive: P, passphrase: &str) -> Result<Self> {
+        Self::open(archive.as_ref(), Some(passphrase))
+    }
 
-        let header = Header::read_from(&mut file)?;
+    fn open(archive: &Path, passphrase: Option<&str>) -> Result<Self> {
+        let mut file = VolumeFile::open(archive)?;
+
+        let mut header = Header::read_from(&mut file)?;
         if &header.magic_number != FILE_MAGIC {
             return Err(Error::InvalidFileType);
         }
-        Ok(Self { file, header })
+        let (entries_offset, mut entries_end) = match header.version {
+            0 => (header.stored_size() as u64, None),
+            1..=VERSION => {
+                let footer_offset = archive_size(&mut file)?
+                    .checked_sub(Footer::SIZE as u64)
+                    .ok_or(Error::InvalidFooter)?;
+                file.seek(SeekFrom::Start(footer_offset))?;
+                match Footer::read_from(&mut file) {
+                    Ok(footer) => (footer.entries_offset, Some(footer_offset)),
+                    // the volume with the footer may be missing
+                    Err(e) => match file.next_volume() {
+                        Some(path) if !path.exists() => return Err(Error::MissingVolume(path)),
+                        _ => return Err(e),
+                    },
+                }
+            }
+            v => return Err(Error::UnsupportedVersion(v)),
+        };
+
+        let mut cipher = None;
+        let mut entry_table = None;
+        if let Some(encryption) = &header.encryption {
+            let passphrase = match passphrase {
+                Some(p) => p.to_owned(),
+                None => read_passphrase(false)?,
+            };
+            let c = Cipher::derive(&passphrase, encryption)?;
+            header.info_json = c.open_string(&header.info_json)?;
+            if !header.dictionary.is_empty() {
+                header.dictionary = c.open(&header.dictionary)?;
+            }
+
+            let table_end = archive_size(&mut file)? - Footer::SIZE as u64;
+            file.seek(SeekFrom::Start(entries_offset))?;
+            let table_reader = (&mut file).take(table_end.saturating_sub(entries_offset));
+            let mut table = Vec::new();
+            DecryptReader::with_aad(&c, table_reader, &header.table_aad())
+                .read_to_end(&mut table)
+                .map_err(|_| Error::Decryption)?;
+            entries_end = Some(table.len() as u64);
+            entry_table = Some(table.into());
+            cipher = Some(c);
+        }
+
+        let dictionary = header.dictionary().map(Dictionary::new);
+        Ok(Self {
+            file,
+            header,
+            entries_offset,
+            entries_end,
+            cipher,
+            entry_table,
+            dictionary,
+        })
     }
 
     pub fn entries(&self) -> Entries {
         Entries::new(self)
     }
 
+    pub fn is_multi_volume(&self) -> bool {
+        self.file.is_multi_volume()
+    }
+
+    /// The zstd dictionary of the archive, for decompressors
+    pub fn dictionary(&self) -> Option<Dictionary> {
+        self.dictionary.clone()
+    }
+
+    /// Missing volumes, and volumes with wrong sizes
+    pub fn volume_problems(&self) -> Vec<VolumeProblem> {
+        self.file.problems()
+    }
+
+    /// Paths of the volumes holding the stored content at `offset`, for
+    /// multi-volume archives
+    pub fn content_volumes(&self, offset: u64, size: u64) -> Vec<&Path> {
+        self.file
+            .volumes_of(self.header.content_offset + offset, size)
+    }
+
     /// `offset`: offset to `content_offset`
     /// absolute offset = `offset` + `content_offset`
-    pub fn retrieve_content(&mut self, offset: u64, size: u64) -> ContentReader {
+    pub fn retrieve_content(&mut self, offset: u64, size: u64) -> ContentReader<&mut VolumeFile> {
         ContentReader::new(&mut self.file, self.header.content_offset, offset, size)
     }
+
+    /// Like [`ArchiveReader::retrieve_content`], but decrypts the content of
+    /// encrypted archives
+    ///
+    /// `offset` and `size` are of what's stored, and authentication failures
+    /// show up as [`io::ErrorKind::InvalidData`] errors when reading.
+    pub fn content(&mut self, offset: u64, size: u64) -> Box<dyn Read + '_> {
+        let reader = ContentReader::new(&mut self.file, self.header.content_offset, offset, size);
+        match &self.cipher {
+            Some(cipher) => Box::new(DecryptReader::new(cipher, reader)),
+            None => Box::new(reader),
+        }
+    }
+
+    /// Decompress the content of a regular file entry, chunked or not
+    ///
+    /// Returns the decompressed size.
+    pub fn decompress_entry(
+        &mut self,
+        entry: &Entry,
+        decompressor: &dyn Decompress,
+        to: &mut dyn Write,
+    ) -> Result<u64> {
+        if entry.chunks.is_empty() {
+            let mut reader = self.content(entry.offset, entry.stored_size);
+            return decompressor.decompress_to(&mut reader, to);
+        }
+        let mut size = 0;
+        for chunk in &entry.chunks {
+            let mut reader = self.content(chunk.offset, chunk.stored_size as u64);
+            size += decompressor.decompress_to(&mut reader, to)?;
+        }
+        Ok(size)
+    }
+
+    /// Find an entry by its path
+    ///
+    /// Leading "./" and trailing "/" are ignored when comparing paths.
+    pub fn find_entry(&self, path: &[u8]) -> Result<Option<Entry>> {
+        let key = normalized_path(path);
+        for entry in self.entries() {
+            let entry = entry?;
+            if normalized_path(&entry.path) == key {
+                return Ok(Some(entry));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Open the content of a regular file (or of the target of a hard link)
+    /// by its path, decompressed and decrypted as it's read
+    ///
+    /// Holes of sparse files read as zeros. Entries compressed with external
+    /// filters can't be opened this way; see [`ArchiveReader::decompress_entry`].
+    pub fn open_entry(&self, path: &[u8]) -> Result<impl Read + '_> {
+        let not_found = || Error::Others(format!("No such entry: {}", escape_utf8_bytes(path)));
+        let mut entry = self.find_entry(path)?.ok_or_else(not_found)?;
+        if entry.file_type == FileType::Link {
+            entry = self.find_entry(&entry.linked_path)?.ok_or_else(not_found)?;
+        }
+        if entry.file_type != FileType::Regular {
+            return Err(Error::Others(format!(
+                "Not a regular file: {}",
+                escape_utf8_bytes(path)
+            )));
+        }
+        if entry.compression == Compression::External {
+            return Err(Error::MissingDecompressor);
+        }
+
+        let segments = if entry.chunks.is_empty() {
+            vec![(entry.offset, entry.stored_size)]
+        } else {
+            entry
+                .chunks
+                .iter()
+                .map(|x| (x.offset, x.stored_size as u64))
+                .collect()
+        };
+        let reader = EntryReader {
+            archive: self,
+            compression: entry.compression,
+            segments: segments.into_iter(),
+            current: None,
+        };
+        Ok(ExpandReader::new(reader, SparseMap::of(&entry)?))
+    }
 }
 
-pub struct ContentReader<'a> {
-    file: &'a mut File,
+/// Reads and decompresses the stored content of an entry, chunk by chunk
+struct EntryReader<'a> {
+    archive: &'a ArchiveReader,
+    compression: Compression,
+    /// offsets and sizes of the stored content (or chunks) left
+    segments: std::vec::IntoIter<(u64, u64)>,
+    current: Option<Box<dyn Read + 'a>>,
+}
+
+impl<'a> Read for EntryReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.current.is_none() {
+                let Some((offset, size)) = self.segments.next() else {
+                    return Ok(0);
+                };
+                let file = self.archive.file.try_clone()?;
+                let content_offset = self.archive.header.content_offset;
+                let content = ContentReader::new(file, content_offset, offset, size);
+                let content: Box<dyn Read> = match &self.archive.cipher {
+                    Some(cipher) => Box::new(DecryptReader::new(cipher, content)),
+                    None => Box::new(content),
+                };
+                let dictionary = self.archive.dictionary.as_ref();
+                let reader = decompress_reader(self.compression, content, dictionary)
+                    .map_err(|e| io::Error::other(e.to_string()))?;
+                self.current = Some(reader);
+            }
+            let size = self.current.as_mut().unwrap().read(buf)?;
+            if size != 0 || buf.is_empty() {
+                return Ok(size);
+            }
+            self.current = None;
+        }
+    }
+}
+
+pub struct ContentReader<F: Read + Seek> {
+    file: F,
     left_size: u64,
     position: u64,
 }
 
-impl<'a> Read for ContentReader<'a> {
+impl<F: Read + Seek> Read for ContentReader<F> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         if self.file.stream_position()? != self.position {
             self.file.seek(SeekFrom::Start(self.position))?;
         }
-        let read_size = self.file.take(self.left_size).read(buf)?;
+        let read_size = (&mut self.file).take(self.left_size).read(buf)?;
         self.left_size -= read_size as u64;
         self.position += read_size as u64;
 
@@ -53,8 +282,8 @@ impl<'a> Read for ContentReader<'a> {
     }
 }
 
-impl<'a> ContentReader<'a> {
-    fn new(file: &'a mut File, content_offset: u64, offset: u64, size: u64) -> Self {
+impl<F: Read + Seek> ContentReader<F> {
+    fn new(file: F, content_offset: u64, offset: u64, size: u64) -> Self {
         Self {
             file,
             left_size: size,
@@ -63,23 +292,66 @@ impl<'a> ContentReader<'a> {
     }
 }
 
+/// Where entries are read from
+enum EntrySource {
+    File(VolumeFile),
+    Decrypted(io::Cursor<Arc<[u8]>>),
+}
+
+impl Read for EntrySource {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            EntrySource::File(f) => f.read(buf),
+            EntrySource::Decrypted(c) => c.read(buf),
+        }
+    }
+}
+
+impl Seek for EntrySource {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            EntrySource::File(f) => f.seek(pos),
+            EntrySource::Decrypted(c) => c.seek(pos),
+        }
+    }
+}
+
+/// Iterates the entry table
+///
+/// The table needs to end right after the last of the entries counted in the
+/// header; an error is given otherwise, after the entries read.
 pub struct Entries {
     position: u64,
+    end: Option<u64>,
     count: u64,
     total_count: u64,
-    file: File,
+    file: EntrySource,
+    header: Header,
+    /// the table size has been checked
+    checked: bool,
 }
 
 impl Entries {
     fn new(outer: &ArchiveReader) -> Self {
-        let entries_start_pos = outer.header.stored_size() as u64;
-        let file = outer.file.try_clone().unwrap();
+        let (file, position) = match &outer.entry_table {
+            Some(table) => (
+                EntrySource::Decrypted(io::Cursor::new(Arc::clone(table))),
+                0,
+            ),
+            None => (
+                EntrySource::File(outer.file.try_clone().unwrap()),
+                outer.entries_offset,
+            ),
+        };
 
         Self {
-            position: entries_start_pos,
+            position,
+            end: outer.entries_end,
             count: 0,
             total_count: outer.header.entry_count,
             file,
+            header: outer.header.clone(),
+            checked: false,
         }
     }
 }
@@ -89,27 +361,159 @@ impl Iterator for Entries {
 
     fn next(&mut self) -> Option<Self::Item> {
         fn try_next(s: &mut Entries) -> Result<Entry> {
+            if s.end.is_some_and(|end| s.position >= end) {
+                return Err(Error::Others(format!(
+                    "Entry table ends after {} of {} entries",
+                    s.count, s.total_count
+                )));
+            }
             if s.file.stream_position()? != s.position {
                 s.file.seek(SeekFrom::Start(s.position))?;
             }
-            let result = Entry::read_from(&mut s.file);
+            // the checksum is over the stored bytes, which vary with versions
+            let crc = Crc::<u32>::new(&ENTRY_CRC_32);
+            let mut digest = crc.digest();
+            let mut crc_filter = CrcFilter::new(&mut digest, &mut s.file);
+            let result = Entry::read_from_version(&mut crc_filter, &s.header);
+            let computed_checksum = digest.finalize();
             let checksum = s.file.read_u32::<LittleEndian>()?;
             s.position = s.file.stream_position()?;
 
             let entry = result?;
-            if entry.crc_checksum() != checksum {
-                return Err(Error::Checksum(entry));
+            if s.end.is_some_and(|end| s.position > end) {
+                return Err(Error::Others(format!(
+                    "Entry {} overruns the entry table",
+                    s.count
+                )));
+            }
+            if computed_checksum != checksum {
+                return Err(Error::Checksum(Box::new(entry)));
             }
 
             Ok(entry)
         }
 
         if self.count == self.total_count {
-            return None;
+            if self.checked {
+                return None;
+            }
+            self.checked = true;
+            return match self.end {
+                Some(end) if end != self.position => Some(Err(Error::Others(format!(
+                    "{} bytes left in the entry table after {} entries",
+                    end.saturating_sub(self.position),
+                    self.count
+                )))),
+                _ => None,
+            };
         }
 
         let result = try_next(self);
         self.count += 1;
+        if result.is_err() && self.end.is_some_and(|end| self.position >= end) {
+            // nothing more to read
+            self.count = self.total_count;
+            self.checked = true;
+        }
         Some(result)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::fs::{self, OpenOptions};
+    use std::path::Path;
+
+    use super::ArchiveReader;
+    use crate::builder::{ArchiveBuilder, EntryMetadata};
+    use crate::compressors::Level;
+    use crate::errors::{Error, Result};
+    use crate::update::write_entry_count;
+    use crate::{Compression, Entry, Header, ReadFrom, WriteTo};
+
+    const PASSPHRASE: &str = "passphrase";
+
+    fn build(output: &Path, encrypted: bool) {
+        let mut builder =
+            ArchiveBuilder::create(output, Compression::Zstd, Level::Numeric(3)).unwrap();
+        if encrypted {
+            builder.encryption(PASSPHRASE).unwrap();
+        }
+        for path in ["a", "b", "c"] {
+            builder
+                .add_bytes(path.as_bytes(), path.as_bytes(), &EntryMetadata::default())
+                .unwrap();
+        }
+        builder.finish().unwrap();
+    }
+
+    fn entries(archive: &Path) -> Result<Vec<Entry>> {
+        ArchiveReader::with_passphrase(archive, PASSPHRASE)?
+            .entries()
+            .collect()
+    }
+
+    /// Rewrite the header of `archive` as changed by `change`
+    fn patch_header(archive: &Path, change: impl FnOnce(&mut Header)) {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(archive)
+            .unwrap();
+        let mut header = Header::read_from(&mut file).unwrap();
+        change(&mut header);
+        let mut patched = Vec::new();
+        header.write_to(&mut patched).unwrap();
+        let mut content = fs::read(archive).unwrap();
+        content[..patched.len()].copy_from_slice(&patched);
+        fs::write(archive, content).unwrap();
+    }
+
+    #[test]
+    fn table_bounds() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("out.arc");
+        build(&archive, false);
+        assert_eq!(entries(&archive).unwrap().len(), 3);
+
+        for count in [0, 2, 4, u64::MAX] {
+            let modified = dir.path().join(format!("{}.arc", count));
+            fs::copy(&archive, &modified).unwrap();
+            let mut file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&modified)
+                .unwrap();
+            write_entry_count(&mut file, count).unwrap();
+            drop(file);
+
+            let reader = ArchiveReader::new(&modified).unwrap();
+            let results = reader.entries().collect::<Vec<_>>();
+            // the entries there are, and then an error
+            let read = results.iter().take_while(|x| x.is_ok()).count();
+            assert_eq!(read as u64, count.min(3));
+            assert_eq!(results.len(), read + 1);
+        }
+    }
+
+    #[test]
+    fn authenticated_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("out.arc");
+        build(&archive, true);
+        assert_eq!(entries(&archive).unwrap().len(), 3);
+
+        let changes: [fn(&mut Header); 4] = [
+            |h| h.entry_count = 2,
+            |h| h.content_offset += 1,
+            |h| h.creation_time += 1,
+            |h| h.compression = Compression::None,
+        ];
+        for change in changes {
+            let modified = dir.path().join("modified.arc");
+            fs::copy(&archive, &modified).unwrap();
+            patch_header(&modified, change);
+            assert!(matches!(entries(&modified), Err(Error::Decryption)));
+        }
+    }
+}
@@ -0,0 +1,503 @@
+//! Recovery records
+//!
+//! An archive can carry Reed-Solomon parity over all its bytes (header, content,
+//! entry table and footer), appended after the footer:
+//!
+//! | archive | parity shards | shard checksums | trailer | trailer |
+//! | ------- | ------------- | --------------- | ------- | ------- |
+//!
+//! The archive is split into shards of [`SHARD_SIZE`] bytes, which are
+//! interleaved into groups (shard `i` belongs to group `i % group_count`), so a
+//! run of damaged bytes spreads over many groups. Each group has its own parity
+//! shards, and up to that many damaged shards of a group can be reconstructed.
+//! Damaged shards are located by their CRC32 checksums. The trailer is stored
+//! twice and locates everything else.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use crc_lib::Crc;
+use reed_solomon_erasure::galois_8::ReedSolomon;
+
+use crate::errors::{Error, Result};
+use crate::{FixedStoredSize, ReadFrom, WriteTo, ENTRY_CRC_32};
+
+pub const SHARD_SIZE: u32 = 4096;
+/// GF(2^8) allows 256 shards in total
+const MAX_SHARDS: usize = 256;
+const MAX_DATA_SHARDS: usize = 200;
+pub const RECOVERY_MAGIC: &[u8; 6] = b"Recvry";
+
+#[derive(Debug, Clone, Copy)]
+pub struct RecoveryTrailer {
+    /// size of the archive before the recovery records
+    protected_size: u64,
+    shard_size: u32,
+    /// data shards per group
+    data_shards: u16,
+    /// parity shards per group
+    parity_shards: u16,
+}
+
+impl FixedStoredSize for RecoveryTrailer {
+    const SIZE: usize = 8 + 4 + 2 + 2 + 4 /* checksum */ + RECOVERY_MAGIC.len();
+}
+
+impl RecoveryTrailer {
+    fn fields(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.write_u64::<LittleEndian>(self.protected_size).unwrap();
+        buf.write_u32::<LittleEndian>(self.shard_size).unwrap();
+        buf.write_u16::<LittleEndian>(self.data_shards).unwrap();
+        buf.write_u16::<LittleEndian>(self.parity_shards).unwrap();
+        buf
+    }
+}
+
+impl WriteTo for RecoveryTrailer {
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let fields = self.fields();
+        writer.write_all(&fields)?;
+        writer.write_u32::<LittleEndian>(checksum(&fields))?;
+        writer.write_all(RECOVERY_MAGIC)?;
+        Ok(())
+    }
+}
+
+impl ReadFrom for RecoveryTrailer {
+    type Item = Self;
+
+    fn read_from<R: Read>(reader: &mut R) -> Result<Self::Item> {
+        let trailer = Self {
+            protected_size: reader.read_u64::<LittleEndian>()?,
+            shard_size: reader.read_u32::<LittleEndian>()?,
+            data_shards: reader.read_u16::<LittleEndian>()?,
+            parity_shards: reader.read_u16::<LittleEndian>()?,
+        };
+        let stored_checksum = reader.read_u32::<LittleEndian>()?;
+        let mut magic = [0_u8; RECOVERY_MAGIC.len()];
+        reader.read_exact(&mut magic)?;
+        if &magic != RECOVERY_MAGIC || checksum(&trailer.fields()) != stored_checksum {
+            return Err("Invalid recovery trailer".into());
+        }
+        if trailer.shard_size == 0
+            || trailer.data_shards == 0
+            || trailer.parity_shards == 0
+            || trailer.data_shards as usize + trailer.parity_shards as usize > MAX_SHARDS
+        {
+            return Err("Invalid recovery parameters".into());
+        }
+        Ok(trailer)
+    }
+}
+
+fn checksum(data: &[u8]) -> u32 {
+    Crc::<u32>::new(&ENTRY_CRC_32).checksum(data)
+}
+
+/// Positions of shards and tables derived from a trailer
+struct Layout {
+    trailer: RecoveryTrailer,
+    shard_count: u64,
+    group_count: u64,
+}
+
+impl Layout {
+    fn new(trailer: RecoveryTrailer) -> Self {
+        let shard_count = trailer.protected_size.div_ceil(trailer.shard_size as u64);
+        let group_count = shard_count.div_ceil(trailer.data_shards as u64);
+        Self {
+            trailer,
+            shard_count,
+            group_count,
+        }
+    }
+
+    /// Index of the `n`th data shard in `group`, if it exists
+    fn data_shard(&self, group: u64, n: usize) -> Option<u64> {
+        let index = n as u64 * self.group_count + group;
+        (index < self.shard_count).then_some(index)
+    }
+
+    /// Offset and size of a data shard; the last one can be shorter
+    fn data_range(&self, index: u64) -> (u64, usize) {
+        let offset = index * self.trailer.shard_size as u64;
+        let size = (self.trailer.protected_size - offset).min(self.trailer.shard_size as u64);
+        (offset, size as usize)
+    }
+
+    fn parity_offset(&self, group: u64, n: usize) -> u64 {
+        let index = group * self.trailer.parity_shards as u64 + n as u64;
+        self.trailer.protected_size + index * self.trailer.shard_size as u64
+    }
+
+    fn parity_count(&self) -> u64 {
+        self.group_count * self.trailer.parity_shards as u64
+    }
+
+    /// Checksums of all data shards, and then all parity shards
+    fn checksums_offset(&self) -> u64 {
+        self.trailer.protected_size + self.parity_count() * self.trailer.shard_size as u64
+    }
+
+    fn checksum_index(&self, group: u64, shard: usize) -> u64 {
+        let data_shards = self.trailer.data_shards as usize;
+        if shard < data_shards {
+            self.data_shard(group, shard).unwrap()
+        } else {
+            self.shard_count
+                + group * self.trailer.parity_shards as u64
+                + (shard - data_shards) as u64
+        }
+    }
+
+    fn total_size(&self) -> u64 {
+        self.checksums_offset()
+            + (self.shard_count + self.parity_count()) * 4
+            + 2 * RecoveryTrailer::SIZE as u64
+    }
+}
+
+/// Pick shards per group to get at least `percent` of parity
+fn shard_counts(shard_count: u64, percent: u8) -> (usize, usize) {
+    let mut data_shards = (shard_count as usize).clamp(1, MAX_DATA_SHARDS);
+    loop {
+        let parity_shards = (data_shards * percent as usize).div_ceil(100).max(1);
+        if data_shards + parity_shards <= MAX_SHARDS {
+            return (data_shards, parity_shards);
+        }
+        data_shards -= 1;
+    }
+}
+
+/// Read the recovery trailer, from either copy
+pub fn read_trailer<F: Read + Seek>(file: &mut F) -> Result<Option<RecoveryTrailer>> {
+    let size = file.seek(SeekFrom::End(0))?;
+    for n in 1..=2 {
+        let Some(offset) = size.checked_sub((n * RecoveryTrailer::SIZE) as u64) else {
+            break;
+        };
+        file.seek(SeekFrom::Start(offset))?;
+        if let Ok(trailer) = RecoveryTrailer::read_from(file) {
+            if trailer.protected_size < size && Layout::new(trailer).total_size() == size {
+                return Ok(Some(trailer));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Size of the archive itself, without recovery records
+pub fn archive_size<F: Read + Seek>(file: &mut F) -> Result<u64> {
+    match read_trailer(file)? {
+        Some(t) => Ok(t.protected_size),
+        None => Ok(file.seek(SeekFrom::End(0))?),
+    }
+}
+
+/// Percent of parity in the recovery records, for adding them again after the
+/// archive changes
+pub fn recovery_percent(file: &mut File) -> Result<Option<u8>> {
+    Ok(read_trailer(file)?.map(|t| {
+        let percent = t.parity_shards as usize * 100 / t.data_shards as usize;
+        percent.clamp(1, 100) as u8
+    }))
+}
+
+fn read_at(file: &mut File, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(buf)
+}
+
+fn write_at(file: &mut File, offset: u64, buf: &[u8]) -> io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(buf)
+}
+
+/// Read a data shard, padded with zeros to the shard size
+fn read_data_shard(file: &mut File, layout: &Layout, index: u64) -> io::Result<Vec<u8>> {
+    let mut shard = vec![0_u8; layout.trailer.shard_size as usize];
+    let (offset, size) = layout.data_range(index);
+    read_at(file, offset, &mut shard[..size])?;
+    Ok(shard)
+}
+
+/// Append recovery records with about `percent` of the archive size
+pub fn add_recovery<P: AsRef<Path>>(path: P, percent: u8) -> Result<()> {
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    if read_trailer(&mut file)?.is_some() {
+        return Err("The archive already has recovery records".into());
+    }
+    let protected_size = file.seek(SeekFrom::End(0))?;
+    let (data_shards, parity_shards) =
+        shard_counts(protected_size.div_ceil(SHARD_SIZE as u64), percent);
+    let trailer = RecoveryTrailer {
+        protected_size,
+        shard_size: SHARD_SIZE,
+        data_shards: data_shards as u16,
+        parity_shards: parity_shards as u16,
+    };
+    let layout = Layout::new(trailer);
+    let codec = ReedSolomon::new(data_shards, parity_shards).unwrap();
+
+    let mut checksums = vec![0_u32; (layout.shard_count + layout.parity_count()) as usize];
+    for group in 0..layout.group_count {
+        let mut shards = Vec::with_capacity(data_shards + parity_shards);
+        for n in 0..data_shards {
+            shards.push(match layout.data_shard(group, n) {
+                Some(index) => read_data_shard(&mut file, &layout, index)?,
+                None => vec![0_u8; SHARD_SIZE as usize],
+            });
+        }
+        shards.resize(data_shards + parity_shards, vec![0_u8; SHARD_SIZE as usize]);
+        codec.encode(&mut shards).unwrap();
+
+        for (n, shard) in shards.iter().enumerate() {
+            if n >= data_shards {
+                write_at(
+                    &mut file,
+                    layout.parity_offset(group, n - data_shards),
+                    shard,
+                )?;
+            } else if layout.data_shard(group, n).is_none() {
+                continue;
+            }
+            checksums[layout.checksum_index(group, n) as usize] = checksum(shard);
+        }
+    }
+
+    let mut tail = Vec::new();
+    for c in checksums {
+        tail.write_u32::<LittleEndian>(c)?;
+    }
+    trailer.write_to(&mut tail)?;
+    trailer.write_to(&mut tail)?;
+    write_at(&mut file, layout.checksums_offset(), &tail)?;
+    Ok(())
+}
+
+#[derive(Debug, Default)]
+pub struct RecoveryReport {
+    /// data and parity shards failing their checksums
+    pub damaged_shards: u64,
+    /// groups with more damaged shards than parity shards
+    pub unrecoverable_groups: u64,
+    /// damaged checksums and trailers found
+    pub damaged_metadata: u64,
+}
+
+impl RecoveryReport {
+    pub fn is_intact(&self) -> bool {
+        self.damaged_shards == 0 && self.damaged_metadata == 0
+    }
+
+    pub fn is_repairable(&self) -> bool {
+        self.unrecoverable_groups == 0
+    }
+}
+
+/// Check an archive against its recovery records, and optionally repair it in
+/// place
+///
+/// Returns `None` if the archive has no recovery records.
+pub fn check<P: AsRef<Path>>(path: P, repair: bool) -> Result<Option<RecoveryReport>> {
+    let mut file = OpenOptions::new().read(true).write(repair).open(path)?;
+    let Some(trailer) = read_trailer(&mut file)? else {
+        return Ok(None);
+    };
+    let layout = Layout::new(trailer);
+    let data_shards = trailer.data_shards as usize;
+    let parity_shards = trailer.parity_shards as usize;
+    let codec = ReedSolomon::new(data_shards, parity_shards).unwrap();
+    let mut report = RecoveryReport::default();
+
+    let checksum_count = layout.shard_count + layout.parity_count();
+    let mut checksums = vec![0_u8; checksum_count as usize * 4];
+    read_at(&mut file, layout.checksums_offset(), &mut checksums)?;
+    let checksums = checksums
+        .chunks_exact(4)
+        .map(|x| u32::from_le_bytes(x.try_into().unwrap()))
+        .collect::<Vec<_>>();
+
+    for group in 0..layout.group_count {
+        // shards as read, and those passing the checksums
+        let mut stored = Vec::with_capacity(data_shards + parity_shards);
+        let mut shards = Vec::with_capacity(data_shards + parity_shards);
+        for n in 0..(data_shards + parity_shards) {
+            let shard = if n < data_shards {
+                match layout.data_shard(group, n) {
+                    Some(index) => read_data_shard(&mut file, &layout, index)?,
+                    None => {
+                        // padding
+                        let shard = vec![0_u8; trailer.shard_size as usize];
+                        shards.push(Some(shard.clone()));
+                        stored.push(shard);
+                        continue;
+                    }
+                }
+            } else {
+                let mut shard = vec![0_u8; trailer.shard_size as usize];
+                read_at(
+                    &mut file,
+                    layout.parity_offset(group, n - data_shards),
+                    &mut shard,
+                )?;
+                shard
+            };
+            let stored_checksum = checksums[layout.checksum_index(group, n) as usize];
+            shards.push((checksum(&shard) == stored_checksum).then(|| shard.clone()));
+            stored.push(shard);
+        }
+
+        let damaged = shards.iter().filter(|x| x.is_none()).count();
+        if damaged == 0 {
+            continue;
+        }
+        if damaged > parity_shards {
+            // can't tell damaged shards from damaged checksums
+            report.damaged_shards += damaged as u64;
+            report.unrecoverable_groups += 1;
+            continue;
+        }
+
+        let missing = shards
+            .iter()
+            .enumerate()
+            .filter(|x| x.1.is_none())
+            .map(|x| x.0)
+            .collect::<Vec<_>>();
+        codec
+            .reconstruct(&mut shards)
+            .map_err(|e| Error::Others(format!("Reconstruction failed: {:?}", e)))?;
+        for n in missing {
+            let shard = shards[n].as_ref().unwrap();
+            let checksum_index = layout.checksum_index(group, n);
+            // either the shard or its checksum (or both) can be damaged
+            let shard_damaged = shard != &stored[n];
+            let checksum_damaged = checksum(shard) != checksums[checksum_index as usize];
+            report.damaged_shards += shard_damaged as u64;
+            report.damaged_metadata += checksum_damaged as u64;
+            if !repair {
+                continue;
+            }
+            if shard_damaged {
+                if n < data_shards {
+                    let (offset, size) = layout.data_range(layout.data_shard(group, n).unwrap());
+                    write_at(&mut file, offset, &shard[..size])?;
+                } else {
+                    write_at(
+                        &mut file,
+                        layout.parity_offset(group, n - data_shards),
+                        shard,
+                    )?;
+                }
+            }
+            if checksum_damaged {
+                let offset = layout.checksums_offset() + checksum_index * 4;
+                write_at(&mut file, offset, &checksum(shard).to_le_bytes())?;
+            }
+        }
+    }
+
+    // both trailer copies
+    let trailers_offset = layout.total_size() - 2 * RecoveryTrailer::SIZE as u64;
+    for n in 0..2 {
+        let offset = trailers_offset + (n * RecoveryTrailer::SIZE) as u64;
+        file.seek(SeekFrom::Start(offset))?;
+        if RecoveryTrailer::read_from(&mut file).is_err() {
+            report.damaged_metadata += 1;
+            if repair {
+                let mut buf = Vec::new();
+                trailer.write_to(&mut buf)?;
+                write_at(&mut file, offset, &buf)?;
+            }
+        }
+    }
+
+    Ok(Some(report))
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::path::Path;
+
+    use super::{add_recovery, archive_size, check, SHARD_SIZE};
+    use crate::test_utils::pseudo_random;
+
+    /// Flip bytes at `count` pseudo-random positions within `range`
+    fn corrupt(path: &Path, range: std::ops::Range<usize>, count: usize, seed: u32) {
+        let mut data = fs::read(path).unwrap();
+        let positions = pseudo_random(count * 4, seed);
+        for p in positions.chunks_exact(4) {
+            let p = u32::from_le_bytes(p.try_into().unwrap()) as usize;
+            data[range.start + p % range.len()] ^= 0xff;
+        }
+        fs::write(path, data).unwrap();
+    }
+
+    fn protected(data: &[u8], percent: u8) -> (tempfile::TempDir, std::path::PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("archive");
+        fs::write(&path, data).unwrap();
+        add_recovery(&path, percent).unwrap();
+        (dir, path)
+    }
+
+    #[test]
+    fn repair_random_damage() {
+        let data = pseudo_random(800_000, 1);
+        let (_dir, path) = protected(&data, 10);
+        let with_recovery = fs::read(&path).unwrap();
+        assert_eq!(
+            archive_size(&mut fs::File::open(&path).unwrap()).unwrap(),
+            data.len() as u64
+        );
+
+        // the whole file, including the recovery records, can be damaged
+        corrupt(&path, 0..with_recovery.len(), 15, 2);
+        let report = check(&path, false).unwrap().unwrap();
+        assert!(!report.is_intact());
+        assert!(report.is_repairable());
+
+        let report = check(&path, true).unwrap().unwrap();
+        assert!(report.is_repairable());
+        assert_eq!(fs::read(&path).unwrap(), with_recovery);
+        assert!(check(&path, false).unwrap().unwrap().is_intact());
+    }
+
+    #[test]
+    fn repair_burst() {
+        let data = pseudo_random(1_000_000, 3);
+        let (_dir, path) = protected(&data, 10);
+        let with_recovery = fs::read(&path).unwrap();
+
+        // a run of damaged bytes spreads over the interleaved groups
+        let mut damaged = with_recovery.clone();
+        damaged[100_000..(100_000 + 10 * SHARD_SIZE as usize)].fill(0);
+        fs::write(&path, &damaged).unwrap();
+        check(&path, true).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), with_recovery);
+    }
+
+    #[test]
+    fn too_much_damage() {
+        let data = pseudo_random(200_000, 4);
+        let (_dir, path) = protected(&data, 1);
+        corrupt(&path, 0..data.len(), 1000, 5);
+        let report = check(&path, true).unwrap().unwrap();
+        assert!(!report.is_repairable());
+    }
+
+    #[test]
+    fn small_archive() {
+        let data = b"tiny".to_vec();
+        let (_dir, path) = protected(&data, 1);
+        corrupt(&path, 0..data.len(), 1, 6);
+        assert!(check(&path, true).unwrap().unwrap().is_repairable());
+        assert_eq!(&fs::read(&path).unwrap()[..data.len()], &data[..]);
+    }
+}
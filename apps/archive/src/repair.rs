@@ -0,0 +1,30 @@
+use std::process::exit;
+
+use clap::ArgMatches;
+
+use crate::errors::*;
+use crate::recovery;
+
+pub fn main(matches: &ArgMatches) -> Result<()> {
+    let path = matches.get_one::<String>("archive").unwrap();
+
+    eprintln!("Repairing...");
+    let Some(report) = recovery::check(path, true)? else {
+        return Err("The archive has no recovery records".into());
+    };
+
+    println!("Damaged shards: {}", report.damaged_shards);
+    println!("Damaged recovery metadata: {}", report.damaged_metadata);
+    if !report.is_repairable() {
+        println!(
+            "Repair failed; {} shard groups have too much damage.",
+            report.unrecoverable_groups
+        );
+        exit(1)
+    } else if report.is_intact() {
+        println!("Nothing to repair.");
+    } else {
+        println!("Repair done.");
+    }
+    Ok(())
+}
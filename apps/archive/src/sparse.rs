@@ -0,0 +1,426 @@
+//! Sparse files
+//!
+//! For files with holes (ranges never written, which read as zeros and take no
+//! disk space), only the data ranges are stored, one after another, and the
+//! ranges are recorded as the `sparse` extension: pairs of little-endian u64
+//! offset and length, in ascending order. `original_size` is still the
+//! apparent file size, and the holes are recreated on extraction.
+//!
+//! Data ranges are found with `lseek(2)` `SEEK_DATA`/`SEEK_HOLE`; on
+//! filesystems without them, files are stored as a whole.
+
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::extensions::{self, SPARSE_KEY};
+use crate::{Entry, Extension};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SparseMap {
+    /// the apparent file size
+    size: u64,
+    /// offsets and lengths of the data ranges
+    ranges: Vec<(u64, u64)>,
+}
+
+impl SparseMap {
+    /// Returns the map of a sparse file entry
+    ///
+    /// The ranges read need to be ascending, not overlapping and within the
+    /// file size, or an [`io::ErrorKind::InvalidData`] error is returned.
+    pub fn of(entry: &Entry) -> io::Result<Option<Self>> {
+        let Some(value) = extensions::find(entry, SPARSE_KEY) else {
+            return Ok(None);
+        };
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid sparse map");
+        if value.len() % 16 != 0 {
+            return Err(invalid());
+        }
+        let mut ranges = Vec::with_capacity(value.len() / 16);
+        // end of the last range
+        let mut end = 0_u64;
+        for x in value.chunks_exact(16) {
+            let (offset, length) = x.split_at(8);
+            let offset = u64::from_le_bytes(offset.try_into().unwrap());
+            let length = u64::from_le_bytes(length.try_into().unwrap());
+            if offset < end {
+                return Err(invalid());
+            }
+            end = offset
+                .checked_add(length)
+                .filter(|&x| x <= entry.original_size)
+                .ok_or_else(invalid)?;
+            ranges.push((offset, length));
+        }
+        Ok(Some(Self {
+            size: entry.original_size,
+            ranges,
+        }))
+    }
+
+    pub fn ranges(&self) -> &[(u64, u64)] {
+        &self.ranges
+    }
+
+    /// The size of the stored content
+    pub fn data_size(&self) -> u64 {
+        self.ranges.iter().map(|x| x.1).sum()
+    }
+}
+
+/// Record the data ranges of a sparse file
+pub fn set_sparse_map(entry: &mut Entry, ranges: &[(u64, u64)]) {
+    let mut value = Vec::with_capacity(ranges.len() * 16);
+    for (offset, length) in ranges {
+        value.extend_from_slice(&offset.to_le_bytes());
+        value.extend_from_slice(&length.to_le_bytes());
+    }
+    entry.extensions.retain(|x| x.key() != SPARSE_KEY);
+    entry
+        .extensions
+        .push(Extension::new(SPARSE_KEY.into(), value));
+}
+
+/// Whether the entry has a sparse map, valid or not
+pub fn is_sparse(entry: &Entry) -> bool {
+    extensions::find(entry, SPARSE_KEY).is_some()
+}
+
+/// Returns the data ranges of `file` if it has holes
+#[cfg(unix)]
+pub fn find_data_ranges(file: &File) -> io::Result<Option<Vec<(u64, u64)>>> {
+    use nix::errno::Errno;
+    use nix::unistd::{lseek, Whence};
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::io::AsRawFd;
+
+    let metadata = file.metadata()?;
+    let size = metadata.len();
+    // all blocks allocated; no holes
+    if metadata.blocks() * 512 >= size {
+        return Ok(None);
+    }
+
+    let fd = file.as_raw_fd();
+    let mut ranges = Vec::new();
+    let mut position = 0_u64;
+    while position < size {
+        let start = match lseek(fd, position as i64, Whence::SeekData) {
+            Ok(x) => x as u64,
+            // only holes after
+            Err(Errno::ENXIO) => break,
+            // not supported
+            Err(Errno::EINVAL) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let end = (lseek(fd, start as i64, Whence::SeekHole)? as u64).min(size);
+        if start >= end {
+            break;
+        }
+        ranges.push((start, end - start));
+        position = end;
+    }
+
+    if ranges == [(0, size)] {
+        return Ok(None);
+    }
+    Ok(Some(ranges))
+}
+
+#[cfg(not(unix))]
+pub fn find_data_ranges(_file: &File) -> io::Result<Option<Vec<(u64, u64)>>> {
+    Ok(None)
+}
+
+/// Reads the content of a file to be stored, which is only the data ranges
+/// for sparse files
+pub struct DataReader<R: Read + Seek> {
+    inner: R,
+    /// ranges not read yet, in reverse order
+    ranges: Vec<(u64, u64)>,
+    /// size left of the current range
+    left: u64,
+}
+
+impl<R: Read + Seek> DataReader<R> {
+    pub fn new(inner: R, map: Option<SparseMap>) -> Self {
+        match map {
+            None => Self {
+                inner,
+                ranges: Vec::new(),
+                left: u64::MAX,
+            },
+            Some(mut map) => {
+                map.ranges.reverse();
+                Self {
+                    inner,
+                    ranges: map.ranges,
+                    left: 0,
+                }
+            }
+        }
+    }
+}
+
+impl<R: Read + Seek> Read for DataReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.left == 0 {
+            let Some((offset, length)) = self.ranges.pop() else {
+                return Ok(0);
+            };
+            self.inner.seek(SeekFrom::Start(offset))?;
+            self.left = length;
+        }
+        let size = (buf.len() as u64).min(self.left) as usize;
+        let size = self.inner.read(&mut buf[..size])?;
+        if size == 0 {
+            // the file got truncated
+            self.ranges.clear();
+            self.left = 0;
+        }
+        self.left -= size as u64;
+        Ok(size)
+    }
+}
+
+/// Open the file content to be stored for `entry`
+pub fn open_content(path: &Path, entry: &Entry) -> io::Result<DataReader<BufReader<File>>> {
+    let file = BufReader::new(File::open(path)?);
+    Ok(DataReader::new(file, SparseMap::of(entry)?))
+}
+
+/// A writer which can leave holes
+pub trait Holes: Write {
+    /// Advance by `length` bytes of zeros
+    fn skip(&mut self, length: u64) -> io::Result<()>;
+}
+
+/// Creates real holes, for files written sequentially from the start
+impl Holes for File {
+    fn skip(&mut self, length: u64) -> io::Result<()> {
+        let position = self.seek(SeekFrom::Current(length as i64))?;
+        // the end of the file may be a hole
+        self.set_len(position)
+    }
+}
+
+/// Writes holes out as zeros
+pub struct ZeroFill<W: Write>(pub W);
+
+impl<W: Write> Write for ZeroFill<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<W: Write> Holes for ZeroFill<W> {
+    fn skip(&mut self, length: u64) -> io::Result<()> {
+        io::copy(&mut io::repeat(0).take(length), &mut self.0)?;
+        Ok(())
+    }
+}
+
+/// Turns the stored content back to the apparent file content, by putting the
+/// data ranges at their offsets
+///
+/// Without a sparse map, data are written through as they are.
+pub struct Expand<H: Holes> {
+    inner: H,
+    map: Option<SparseMap>,
+    /// index of the next range
+    next: usize,
+    /// size left of the current range
+    left: u64,
+    /// position in the apparent content
+    position: u64,
+}
+
+impl<H: Holes> Expand<H> {
+    pub fn new(inner: H, map: Option<SparseMap>) -> Self {
+        Self {
+            inner,
+            map,
+            next: 0,
+            left: 0,
+            position: 0,
+        }
+    }
+
+    /// Write the trailing hole, and return the inner writer
+    pub fn finish(mut self) -> io::Result<H> {
+        if let Some(map) = &self.map {
+            if map.size > self.position {
+                self.inner.skip(map.size - self.position)?;
+            }
+        }
+        Ok(self.inner)
+    }
+}
+
+impl<H: Holes> Write for Expand<H> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let Some(map) = &self.map else {
+            return self.inner.write(buf);
+        };
+        while self.left == 0 {
+            let Some(&(offset, length)) = map.ranges.get(self.next) else {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Content beyond the sparse map",
+                ));
+            };
+            self.inner.skip(offset - self.position)?;
+            self.position = offset;
+            self.left = length;
+            self.next += 1;
+        }
+        let size = (buf.len() as u64).min(self.left) as usize;
+        let size = self.inner.write(&buf[..size])?;
+        self.left -= size as u64;
+        self.position += size as u64;
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reads the stored content as the apparent file content, with holes read
+/// as zeros
+///
+/// Without a sparse map, data are read through as they are.
+pub struct ExpandReader<R: Read> {
+    inner: R,
+    map: Option<SparseMap>,
+    /// index of the next range
+    next: usize,
+    /// size left of the current hole
+    hole: u64,
+    /// size left of the data range after the hole
+    left: u64,
+    /// position in the apparent content
+    position: u64,
+}
+
+impl<R: Read> ExpandReader<R> {
+    pub fn new(inner: R, map: Option<SparseMap>) -> Self {
+        Self {
+            inner,
+            map,
+            next: 0,
+            hole: 0,
+            left: 0,
+            position: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for ExpandReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(map) = &self.map else {
+            return self.inner.read(buf);
+        };
+        if self.hole == 0 && self.left == 0 {
+            match map.ranges.get(self.next) {
+                Some(&(offset, length)) => {
+                    self.hole = offset - self.position;
+                    self.left = length;
+                    self.next += 1;
+                }
+                // the trailing hole
+                None => self.hole = map.size.saturating_sub(self.position),
+            }
+        }
+        if self.hole > 0 {
+            let size = (buf.len() as u64).min(self.hole) as usize;
+            buf[..size].fill(0);
+            self.hole -= size as u64;
+            self.position += size as u64;
+            return Ok(size);
+        }
+        if self.left == 0 {
+            return Ok(0);
+        }
+        let size = (buf.len() as u64).min(self.left) as usize;
+        let size = self.inner.read(&mut buf[..size])?;
+        if size == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.left -= size as u64;
+        self.position += size as u64;
+        Ok(size)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Cursor, Read, Write};
+
+    use super::{set_sparse_map, DataReader, Expand, ExpandReader, SparseMap, ZeroFill};
+    use crate::extensions::SPARSE_KEY;
+    use crate::{Entry, Extension, FileType};
+
+    #[test]
+    fn ranges() {
+        let map = SparseMap {
+            size: 20,
+            ranges: vec![(2, 3), (10, 4)],
+        };
+        let content = b"\0\0abc\0\0\0\0\0defg\0\0\0\0\0\0";
+
+        let mut reader = DataReader::new(Cursor::new(content), Some(map.clone()));
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"abcdefg");
+        assert_eq!(map.data_size(), 7);
+
+        let mut expand = Expand::new(ZeroFill(Vec::new()), Some(map.clone()));
+        for x in data.chunks(2) {
+            expand.write_all(x).unwrap();
+        }
+        assert_eq!(expand.finish().unwrap().0, content);
+
+        let mut expanded = Vec::new();
+        ExpandReader::new(&data[..], Some(map.clone()))
+            .read_to_end(&mut expanded)
+            .unwrap();
+        assert_eq!(expanded, content);
+
+        let mut expand = Expand::new(ZeroFill(Vec::new()), Some(map));
+        assert!(expand.write_all(b"abcdefgh").is_err());
+    }
+
+    #[test]
+    fn map_validation() {
+        let map = |ranges: &[(u64, u64)]| {
+            let mut entry = Entry::new(b"a".to_vec(), FileType::Regular);
+            entry.original_size = 20;
+            set_sparse_map(&mut entry, ranges);
+            SparseMap::of(&entry)
+        };
+        let ranges = map(&[(2, 3), (5, 0), (10, 10)]).unwrap().unwrap();
+        assert_eq!(ranges.ranges(), [(2, 3), (5, 0), (10, 10)]);
+        assert!(map(&[]).unwrap().unwrap().ranges().is_empty());
+
+        // overlapping, descending, beyond the size, and overflowing
+        assert!(map(&[(2, 3), (4, 1)]).is_err());
+        assert!(map(&[(10, 1), (2, 3)]).is_err());
+        assert!(map(&[(10, 11)]).is_err());
+        assert!(map(&[(21, 0)]).is_err());
+        assert!(map(&[(1, u64::MAX)]).is_err());
+
+        let mut entry = Entry::new(b"a".to_vec(), FileType::Regular);
+        assert!(SparseMap::of(&entry).unwrap().is_none());
+        entry
+            .extensions
+            .push(Extension::new(SPARSE_KEY.into(), vec![0; 17]));
+        assert!(SparseMap::of(&entry).is_err());
+    }
+}
@@ -1,30 +1,91 @@
 use std::ffi::OsStr;
 use std::io;
+use std::io::{stdout, Read, StdoutLock, Write};
+use std::path::Path;
 use std::process::exit;
 
 use bczhc_lib::str::GenericOsStrExt;
 use clap::ArgMatches;
 use crc_lib::Crc;
+use serde::Serialize;
 
+use crate::checkpoint::{checkpoint_path, PartialArchive};
+use crate::compressors::{
+    create_compressor, entry_decompressor, CodecOptions, CompressionPolicy, Decompress, Level,
+};
 use crate::errors::*;
+use crate::hash::stored_hash;
 use crate::info::print_info;
 use crate::line_progress::LineProgress;
+use crate::output::{Format, RecordWriter};
 use crate::reader::ArchiveReader;
-use crate::{DigestWriter, FileType, OsStrExt, FILE_CRC_64};
+use crate::recovery;
+use crate::recovery::RecoveryReport;
+use crate::sparse::{Expand, SparseMap, ZeroFill};
+use crate::volume;
+use crate::{Chunk, Compression, DigestWriter, Entry, FileType, OsStrExt, FILE_CRC_64};
 
 pub fn main(matches: &ArgMatches) -> Result<()> {
-    let mut has_error = false;
+    let path = matches.get_one::<String>("archive").unwrap();
+    let format = Format::from_matches(matches);
+    let truncate = matches.get_flag("truncate");
 
-    let archive = matches.get_one::<String>("archive").unwrap();
-
-    let mut archive = ArchiveReader::new(archive)?;
-    print_info(&archive.header)?;
-    println!();
+    // volumes never have recovery records
+    let recovery = if volume::is_multi_volume(Path::new(path)) {
+        None
+    } else {
+        recovery::check(path, false)?
+    };
+    let mut archive = match ArchiveReader::new(path) {
+        Ok(a) => a,
+        Err(Error::MissingVolume(volume)) => {
+            let mut reporter = Reporter::new(format, 0);
+            reporter.archive_error(format!("Missing volume: {}", volume.display()))?;
+            reporter.finish(None)?;
+            exit(1)
+        }
+        Err(Error::InvalidFooter) if checkpoint_path(Path::new(path)).exists() => {
+            let partial = PartialArchive::open(Path::new(path))?;
+            if !truncate {
+                let mut reporter = Reporter::new(format, 0);
+                reporter.archive_error(format!(
+                    "Partially written archive: {} entries complete in {} of {} bytes; \
+                     continue it with `create --resume`, or finish it with `test --truncate`",
+                    partial.entries.len(),
+                    partial.position,
+                    partial.size
+                ))?;
+                reporter.finish(None)?;
+                exit(1)
+            }
+            eprintln!("Truncating the partially written archive...");
+            let compression = CompressionPolicy::new(
+                Compression::None,
+                create_compressor(Compression::None, Level::Best, &CodecOptions::default()),
+            );
+            let entry_count = partial.finish(compression)?;
+            eprintln!("Finished with {} entries", entry_count);
+            ArchiveReader::new(path)?
+        }
+        Err(e) => {
+            if let (Some(r), Format::Text) = (&recovery, format) {
+                print_recovery(r);
+            }
+            return Err(e);
+        }
+    };
+    if format == Format::Text {
+        print_info(&archive.header)?;
+        println!();
+    }
 
     eprintln!("Collecting entries...");
     let entries = archive.entries().collect::<Vec<_>>();
     eprintln!("Testing...");
-    let mut line_progress = LineProgress::new(entries.len() as u64);
+    let mut reporter = Reporter::new(format, entries.len() as u64);
+    for problem in archive.volume_problems() {
+        reporter.archive_error(problem.to_string())?;
+    }
 
     for entry in entries.into_iter().enumerate() {
         let progress = entry.0 as u64 + 1;
@@ -36,45 +97,306 @@ pub fn main(matches: &ArgMatches) -> Result<()> {
                 return Err(Error::Io(e));
             }
             Err(Error::Checksum(entry)) => {
-                line_progress.message(&format!("Entry checksum error: {:?}", entry))?;
-                has_error = true;
+                reporter.archive_error(format!("Entry checksum error: {:?}", entry))?;
                 continue;
             }
             Err(e) => {
-                line_progress.message(&format!("Other entry error: {:?}", e))?;
-                has_error = true;
+                reporter.archive_error(format!("Other entry error: {:?}", e))?;
                 continue;
             }
         };
 
         let path_name = OsStr::from_bytes(&entry.path).escape();
-        line_progress.update(progress, path_name.clone())?;
+        reporter.begin(progress, &path_name)?;
+        test_entry(&mut archive, &entry, &mut reporter)?;
+        reporter.end(&entry)?;
+    }
 
-        if entry.file_type != FileType::Regular {
-            continue;
+    let has_error = reporter.finish(recovery.as_ref())?;
+    if format == Format::Text {
+        if has_error {
+            println!("Test done; error occurred.");
+        } else {
+            println!("Test OK!");
         }
+    }
+    if has_error {
+        exit(1)
+    }
+    Ok(())
+}
 
-        let content_checksum = entry.content_checksum;
+fn test_entry(archive: &mut ArchiveReader, entry: &Entry, reporter: &mut Reporter) -> Result<()> {
+    if entry.file_type != FileType::Regular {
+        return Ok(());
+    }
 
-        let crc = Crc::<u64>::new(&FILE_CRC_64);
-        let mut digest = crc.digest();
-        let mut crc_writer = DigestWriter::<u64>::new(&mut digest);
+    let content_checksum = entry.content_checksum;
 
-        let mut content_reader = archive.retrieve_content(entry.offset, entry.stored_size);
-        io::copy(&mut content_reader, &mut crc_writer)?;
+    let crc = Crc::<u64>::new(&FILE_CRC_64);
+    let mut digest = crc.digest();
+    let mut crc_writer = DigestWriter::<u64>::new(&mut digest);
 
-        if content_checksum != digest.finalize() {
-            line_progress.message(&format!("Content checksum error: {}", path_name))?;
-            has_error = true;
+    if entry.chunks.is_empty() {
+        let mut content_reader = archive.content(entry.offset, entry.stored_size);
+        if let Err(e) = io::copy(&mut content_reader, &mut crc_writer) {
+            return content_error(reporter, e);
+        }
+    } else {
+        // the content checksum covers all the chunks in order
+        let decompressor = entry_decompressor(entry.compression, None, archive.dictionary());
+        if decompressor.is_err() {
+            reporter.problem(Status::Unverified, "Chunk hashes not verified", None)?;
+        }
+        for chunk in &entry.chunks {
+            let mut data = Vec::new();
+            if let Err(e) = archive
+                .content(chunk.offset, chunk.stored_size as u64)
+                .read_to_end(&mut data)
+            {
+                content_error(reporter, e)?;
+                continue;
+            }
+            crc_writer.write_all(&data)?;
+
+            if let Ok(d) = &decompressor {
+                if !verify_chunk(&**d, chunk, &data) {
+                    let detail = format!("at offset {}", chunk.offset);
+                    reporter.problem(Status::Error, "Chunk hash error", Some(detail))?;
+                }
+            }
         }
     }
 
-    println!();
-    if has_error {
-        println!("Test done; error occurred.");
-        exit(1)
-    } else {
-        println!("Test OK!");
+    if content_checksum != digest.finalize() {
+        let detail = volume_detail(archive, entry);
+        return reporter.problem(Status::Error, "Content checksum error", detail);
+    }
+
+    let map = match SparseMap::of(entry) {
+        Ok(map) => map,
+        Err(_) => return reporter.problem(Status::Error, "Invalid sparse map", None),
+    };
+    if let Some((function, hash)) = stored_hash(entry) {
+        let Ok(decompressor) = entry_decompressor(entry.compression, None, archive.dictionary())
+        else {
+            return reporter.problem(Status::Unverified, "Content hash not verified", None);
+        };
+        // the size before compression, which excludes the holes
+        let content_size = map.as_ref().map_or(entry.original_size, |x| x.data_size());
+        let mut hasher = Expand::new(ZeroFill(function.hasher()), map);
+        let size = archive.decompress_entry(entry, &*decompressor, &mut hasher);
+        let hash_matched = hasher.finish().is_ok_and(|x| x.0.finalize() == hash);
+        if !size.is_ok_and(|x| x == content_size) || !hash_matched {
+            reporter.problem(Status::Error, "Content hash error", None)?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Serialize, Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+#[serde(rename_all = "lowercase")]
+enum Status {
+    Ok,
+    /// some checks can't be done, like those needing external decompressors
+    Unverified,
+    Error,
+}
+
+/// Result of an entry, or of the archive itself (with no path)
+#[derive(Serialize)]
+struct TestRecord {
+    path: Option<String>,
+    #[serde(rename = "type")]
+    file_type: Option<&'static str>,
+    status: Status,
+    /// problems found, separated by "; "
+    message: Option<String>,
+}
+
+/// Prints problems as progress messages in the text format, or collects them
+/// into a record for each entry
+struct Reporter {
+    output: Output,
+    has_error: bool,
+    path_name: String,
+    status: Status,
+    messages: Vec<String>,
+}
+
+enum Output {
+    Text(LineProgress),
+    Records(RecordWriter<StdoutLock<'static>>),
+}
+
+impl Reporter {
+    fn new(format: Format, entry_count: u64) -> Self {
+        let output = match format {
+            Format::Text => Output::Text(LineProgress::new(entry_count)),
+            Format::Records(f) => Output::Records(RecordWriter::new(f, stdout().lock())),
+        };
+        Self {
+            output,
+            has_error: false,
+            path_name: String::new(),
+            status: Status::Ok,
+            messages: Vec::new(),
+        }
+    }
+
+    fn begin(&mut self, progress: u64, path_name: &str) -> Result<()> {
+        self.path_name = path_name.into();
+        self.status = Status::Ok;
+        self.messages.clear();
+        if let Output::Text(line_progress) = &mut self.output {
+            line_progress.update(progress, path_name.into())?;
+        }
         Ok(())
     }
+
+    fn problem(&mut self, status: Status, message: &str, detail: Option<String>) -> Result<()> {
+        self.has_error |= status == Status::Error;
+        self.status = self.status.max(status);
+        match &mut self.output {
+            Output::Text(line_progress) => {
+                let detail = detail.map(|x| format!(" ({})", x)).unwrap_or_default();
+                line_progress.message(&format!("{}: {}{}", message, self.path_name, detail))?;
+            }
+            Output::Records(_) => {
+                let detail = detail.map(|x| format!(" ({})", x)).unwrap_or_default();
+                self.messages.push(format!("{}{}", message, detail));
+            }
+        }
+        Ok(())
+    }
+
+    fn end(&mut self, entry: &Entry) -> Result<()> {
+        if let Output::Records(records) = &mut self.output {
+            records.write(&TestRecord {
+                path: Some(self.path_name.clone()),
+                file_type: Some(entry.file_type.as_str()),
+                status: self.status,
+                message: (!self.messages.is_empty()).then(|| self.messages.join("; ")),
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Report an error not belonging to any readable entry
+    fn archive_error(&mut self, message: String) -> Result<()> {
+        self.has_error = true;
+        match &mut self.output {
+            Output::Text(line_progress) => line_progress.message(&message)?,
+            Output::Records(records) => records.write(&TestRecord {
+                path: None,
+                file_type: None,
+                status: Status::Error,
+                message: Some(message),
+            })?,
+        }
+        Ok(())
+    }
+
+    /// Report the recovery records if any, and return if any error is found
+    fn finish(mut self, recovery: Option<&RecoveryReport>) -> Result<bool> {
+        if let Some(r) = recovery {
+            self.has_error |= !r.is_intact();
+        }
+        match self.output {
+            Output::Text(_) => {
+                println!();
+                if let Some(r) = recovery {
+                    print_recovery(r);
+                }
+            }
+            Output::Records(mut records) => {
+                if let Some(r) = recovery {
+                    records.write(&TestRecord {
+                        path: None,
+                        file_type: None,
+                        status: if r.is_intact() {
+                            Status::Ok
+                        } else {
+                            Status::Error
+                        },
+                        message: Some(recovery_message(r)),
+                    })?;
+                }
+                records.finish()?;
+            }
+        }
+        Ok(self.has_error)
+    }
+}
+
+fn print_recovery(report: &RecoveryReport) {
+    println!("{}", recovery_message(report));
+}
+
+fn recovery_message(report: &RecoveryReport) -> String {
+    if report.is_intact() {
+        String::from("Recovery records OK")
+    } else if report.is_repairable() {
+        format!(
+            "Damaged shards: {}, damaged recovery metadata: {}; run `archive repair` to repair",
+            report.damaged_shards, report.damaged_metadata
+        )
+    } else {
+        format!(
+            "Damaged shards: {}; too much damage to repair",
+            report.damaged_shards
+        )
+    }
+}
+
+/// Report decryption failures and unreadable volumes, and return other errors
+fn content_error(reporter: &mut Reporter, e: io::Error) -> Result<()> {
+    match e.kind() {
+        io::ErrorKind::InvalidData => {
+            reporter.problem(Status::Error, "Content authentication error", None)
+        }
+        // missing or truncated volumes
+        io::ErrorKind::NotFound | io::ErrorKind::UnexpectedEof => {
+            reporter.problem(Status::Error, "Content unreadable", Some(e.to_string()))
+        }
+        _ => Err(e.into()),
+    }
+}
+
+/// The volumes holding the content of `entry`, for multi-volume archives
+fn volume_detail(archive: &ArchiveReader, entry: &Entry) -> Option<String> {
+    let ranges = if entry.chunks.is_empty() {
+        vec![(entry.offset, entry.stored_size)]
+    } else {
+        entry
+            .chunks
+            .iter()
+            .map(|x| (x.offset, x.stored_size as u64))
+            .collect()
+    };
+    let mut volumes = ranges
+        .into_iter()
+        .flat_map(|(offset, size)| archive.content_volumes(offset, size))
+        .collect::<Vec<_>>();
+    volumes.sort();
+    volumes.dedup();
+    if volumes.is_empty() {
+        return None;
+    }
+    let names = volumes
+        .iter()
+        .map(|x| x.display().to_string())
+        .collect::<Vec<_>>();
+    Some(format!("in {}", names.join(", ")))
+}
+
+/// Check the chunk decompresses to data with the recorded size and hash
+fn verify_chunk(decompressor: &dyn Decompress, chunk: &Chunk, stored: &[u8]) -> bool {
+    let mut hasher = blake3::Hasher::new();
+    match decompressor.decompress_to(&mut &stored[..], &mut hasher) {
+        Ok(size) => {
+            size == chunk.original_size as u64 && hasher.finalize().as_bytes() == &chunk.hash
+        }
+        Err(_) => false,
+    }
 }
@@ -0,0 +1,56 @@
+//! Helpers for tests running subcommands the way `main` does
+
+use std::ffi::OsStr;
+use std::io::Read;
+
+use crate::errors::Result;
+use crate::reader::ArchiveReader;
+use crate::{
+    add, build_cli, compact, convert, create, delete, diff, extract, info, list, test, update,
+};
+
+/// Run `archive <args>`
+pub fn run<S: AsRef<OsStr>>(args: &[S]) -> Result<()> {
+    let args = [OsStr::new("archive")]
+        .into_iter()
+        .chain(args.iter().map(|x| x.as_ref()));
+    let matches = build_cli().try_get_matches_from(args).unwrap();
+    let (name, matches) = matches.subcommand().unwrap();
+    match name {
+        "create" => create::main(matches),
+        "list" => list::main(matches),
+        "extract" => extract::main(matches),
+        "test" => test::main(matches),
+        "info" => info::main(matches),
+        "diff" => diff::main(matches),
+        "add" => add::main(matches),
+        "update" => update::main(matches),
+        "delete" => delete::main(matches),
+        "compact" => compact::main(matches),
+        "convert" => convert::main(matches),
+        _ => unreachable!(),
+    }
+}
+
+/// Pseudo-random, incompressible data, the same for the same `seed`
+pub fn pseudo_random(size: usize, seed: u32) -> Vec<u8> {
+    let mut state = seed;
+    (0..size)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        })
+        .collect()
+}
+
+pub fn read_entry(reader: &ArchiveReader, path: &[u8]) -> Vec<u8> {
+    let mut content = Vec::new();
+    reader
+        .open_entry(path)
+        .unwrap()
+        .read_to_end(&mut content)
+        .unwrap();
+    content
+}
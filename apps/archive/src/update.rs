@@ -0,0 +1,331 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use clap::ArgMatches;
+
+use crate::archive::Archive;
+use crate::checkpoint::checkpoint_path;
+use crate::compressors::CompressionPolicy;
+use crate::create::{add_path, compression_from_matches};
+use crate::errors::*;
+use crate::filter::FileFilter;
+use crate::hash::HashFunction;
+use crate::reader::ArchiveReader;
+use crate::recovery;
+use crate::{Header, ReadFrom, WriteTo, VERSION};
+
+pub fn main(matches: &ArgMatches) -> Result<()> {
+    add_files(matches, true)
+}
+
+/// Add files given on the command line to the archive
+///
+/// See [`Archive::merge_existing`] for `keep_unchanged`.
+pub fn add_files(matches: &ArgMatches, keep_unchanged: bool) -> Result<()> {
+    let archive_path = matches.get_one::<String>("archive").unwrap();
+    let paths = matches.get_many::<String>("path").unwrap();
+    let base_dir = matches.get_one::<String>("base-dir").unwrap();
+    let jobs = *matches.get_one::<usize>("jobs").unwrap();
+    let xattrs = matches.get_flag("xattrs");
+    let chunked = matches.get_flag("chunked");
+    let hash = matches
+        .get_one::<String>("hash")
+        .map(|x| x.parse::<HashFunction>().unwrap());
+    let filter = FileFilter::from_matches(matches, Path::new(base_dir))?;
+
+    let data_filter_cmd = matches
+        .get_many::<String>("data-filter-cmd")
+        .map(|values| values.map(|x| x.to_owned()).collect::<Vec<_>>());
+    let (compression, _) = compression_from_matches(matches, data_filter_cmd.as_ref())?;
+
+    let paths = paths.collect::<Vec<_>>();
+    if paths.iter().any(|x| Path::new(x).is_absolute()) {
+        return Err(Error::AbsolutePath);
+    }
+
+    modify(archive_path, compression, |archive| {
+        archive.set_jobs(jobs);
+        archive.set_xattrs(xattrs);
+        archive.set_chunked(chunked);
+        archive.set_content_hash(hash);
+
+        eprintln!("Indexing...");
+        for path in paths {
+            add_path(archive, &filter, base_dir, path)?;
+        }
+        archive.merge_existing(keep_unchanged);
+        Ok(())
+    })
+}
+
+/// Seconds between checkpoints of archives modified in place
+const CHECKPOINT_INTERVAL: u64 = 60;
+
+/// Modify an archive in place
+///
+/// `edit` gets the archive continued with all its entries. New content and the
+/// entry table are appended after the end of the archive, which is cut back if
+/// writing fails; `compact` reclaims the space of the old entry table. Recovery
+/// records are written again.
+///
+/// The recovery records and the footer are gone while appending, so
+/// checkpoints are recorded like for `create`: an interrupted modification is
+/// finished with `test --truncate`, keeping at least the existing entries.
+pub fn modify<'a, F>(path: &str, compression: CompressionPolicy<'a>, edit: F) -> Result<()>
+where
+    F: FnOnce(&mut Archive<'a, BufWriter<&File>>) -> Result<()>,
+{
+    let reader = ArchiveReader::new(path)?;
+    if reader.is_multi_volume() {
+        return Err("Multi-volume archives can't be modified in place".into());
+    }
+    if reader.header.version != VERSION {
+        return Err(Error::Others(format!(
+            "Archives of version {} can't be modified in place; run `compact` first",
+            reader.header.version
+        )));
+    }
+    eprintln!("Collecting entries...");
+    let entries = reader.entries().collect::<Result<Vec<_>>>()?;
+
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    let recovery = recovery::recovery_percent(&mut file)?;
+    let size = recovery::archive_size(&mut file)?;
+    // recovery records don't cover the changes
+    file.set_len(size)?;
+    let mut file_ref = &file;
+    let journal = checkpoint_path(Path::new(path));
+
+    let result = (|| {
+        file_ref.seek(SeekFrom::Start(size))?;
+        let header = reader.header.clone();
+        let mut archive =
+            Archive::resume(BufWriter::new(&file), compression, header, entries, size)?;
+        edit(&mut archive)?;
+        archive.set_checkpoint(journal.clone(), Duration::from_secs(CHECKPOINT_INTERVAL));
+        eprintln!("Writing files...");
+        archive.write_appended()?;
+        archive.flush()?;
+        let entry_count = archive.entry_count();
+        write_entry_count(&mut file_ref, entry_count)?;
+        archive.remove_checkpoint()?;
+        Ok(())
+    })();
+    if result.is_err() {
+        file.set_len(size)?;
+        // the archive is back as it was
+        if journal.exists() {
+            fs::remove_file(&journal)?;
+        }
+    }
+
+    if let Some(percent) = recovery {
+        eprintln!("Writing recovery records...");
+        recovery::add_recovery(path, percent)?;
+    }
+    result
+}
+
+/// Update the entry count in the header of an archive continued in place
+///
+/// The header stays the same apart from it.
+pub fn write_entry_count<F: Read + Write + Seek>(file: &mut F, entry_count: u64) -> Result<()> {
+    file.seek(SeekFrom::Start(0))?;
+    let mut header = Header::read_from(file)?;
+    header.entry_count = entry_count;
+    file.seek(SeekFrom::Start(0))?;
+    header.write_to(file)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use std::path::Path;
+
+    use super::modify;
+    use crate::checkpoint::checkpoint_path;
+    use crate::compressors::{Compress, CompressionPolicy, NoCompressor};
+    use crate::errors::Result;
+    use crate::reader::ArchiveReader;
+    use crate::test_utils::{read_entry, run};
+    use crate::{recovery, Compression, Entry, FileType};
+
+    /// Stands for the process getting killed while writing
+    struct Killed;
+
+    impl Compress for Killed {
+        fn compress_to(&self, _from: &mut dyn Read, to: &mut dyn Write) -> Result<u64> {
+            to.write_all(&[0; 100])?;
+            panic!("killed");
+        }
+    }
+
+    fn paths(archive: &Path) -> Vec<Vec<u8>> {
+        let reader = ArchiveReader::new(archive).unwrap();
+        let mut paths = reader
+            .entries()
+            .map(|x| x.unwrap().path)
+            .collect::<Vec<_>>();
+        paths.sort();
+        paths
+    }
+
+    fn offset(archive: &Path, path: &[u8]) -> u64 {
+        let reader = ArchiveReader::new(archive).unwrap();
+        reader.find_entry(path).unwrap().unwrap().offset
+    }
+
+    fn assert_recovery_intact(archive: &Path) {
+        let report = recovery::check(archive, false).unwrap().unwrap();
+        assert!(report.is_intact());
+    }
+
+    #[test]
+    fn modify_in_place() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        fs::create_dir_all(src.join("sub")).unwrap();
+        fs::write(src.join("a"), b"a1").unwrap();
+        fs::write(src.join("b"), b"b1").unwrap();
+        fs::write(src.join("sub/c"), b"c1").unwrap();
+        let archive = dir.path().join("out.arc");
+        let archive_str = archive.to_str().unwrap();
+        let src_str = src.to_str().unwrap();
+
+        run(&[
+            "create",
+            archive_str,
+            "-C",
+            src_str,
+            "--recovery",
+            "10",
+            "a",
+            "b",
+            "sub",
+        ])
+        .unwrap();
+        assert_recovery_intact(&archive);
+
+        // add replaces and appends entries
+        fs::write(src.join("a"), b"a2").unwrap();
+        fs::write(src.join("d"), b"d1").unwrap();
+        run(&["add", archive_str, "-C", src_str, "a", "d"]).unwrap();
+        let reader = ArchiveReader::new(&archive).unwrap();
+        assert_eq!(read_entry(&reader, b"a"), b"a2");
+        assert_eq!(read_entry(&reader, b"b"), b"b1");
+        assert_eq!(read_entry(&reader, b"d"), b"d1");
+        assert!(!checkpoint_path(&archive).exists());
+        assert_recovery_intact(&archive);
+
+        // update keeps the entries of unchanged files as they are
+        let a_offset = offset(&archive, b"a");
+        fs::write(src.join("b"), b"b22").unwrap();
+        run(&["update", archive_str, "-C", src_str, "a", "b"]).unwrap();
+        let reader = ArchiveReader::new(&archive).unwrap();
+        assert_eq!(read_entry(&reader, b"a"), b"a2");
+        assert_eq!(read_entry(&reader, b"b"), b"b22");
+        assert_eq!(offset(&archive, b"a"), a_offset);
+        assert_recovery_intact(&archive);
+
+        run(&["delete", archive_str, "sub"]).unwrap();
+        let expected = [&b"a"[..], b"b", b"d"].map(|x| x.to_vec());
+        assert_eq!(paths(&archive), expected);
+        assert_recovery_intact(&archive);
+
+        let size = fs::metadata(&archive).unwrap().len();
+        run(&["compact", archive_str]).unwrap();
+        assert!(fs::metadata(&archive).unwrap().len() < size);
+        assert_eq!(paths(&archive), expected);
+        let reader = ArchiveReader::new(&archive).unwrap();
+        assert_eq!(read_entry(&reader, b"a"), b"a2");
+        assert_eq!(read_entry(&reader, b"b"), b"b22");
+        assert_eq!(read_entry(&reader, b"d"), b"d1");
+        assert_recovery_intact(&archive);
+    }
+
+    #[test]
+    fn failed_modification() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        fs::create_dir(&src).unwrap();
+        fs::write(src.join("a"), b"a1").unwrap();
+        let archive = dir.path().join("out.arc");
+        let archive_str = archive.to_str().unwrap();
+        run(&[
+            "create",
+            archive_str,
+            "-C",
+            src.to_str().unwrap(),
+            "--recovery",
+            "10",
+            "a",
+        ])
+        .unwrap();
+        let original = fs::read(&archive).unwrap();
+
+        let compression = CompressionPolicy::new(Compression::None, Box::new(NoCompressor));
+        let result = modify(archive_str, compression, |archive| {
+            // the first one is written before the second one fails
+            let mut entry = Entry::new(b"b".to_vec(), FileType::Regular);
+            entry.original_size = 2;
+            archive.add_entry(entry, src.join("a"));
+            let mut entry = Entry::new(b"c".to_vec(), FileType::Regular);
+            entry.original_size = 2;
+            archive.add_entry(entry, src.join("missing"));
+            Ok(())
+        });
+        assert!(result.is_err());
+
+        // truncated back, with the recovery records written again
+        assert_eq!(fs::read(&archive).unwrap(), original);
+        assert!(!checkpoint_path(&archive).exists());
+        assert_eq!(paths(&archive), [b"a".to_vec()]);
+        assert_recovery_intact(&archive);
+    }
+
+    #[test]
+    fn interrupted_modification() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        fs::create_dir(&src).unwrap();
+        fs::write(src.join("a"), b"a1").unwrap();
+        fs::write(src.join("b"), b"b1").unwrap();
+        let archive = dir.path().join("out.arc");
+        let archive_str = archive.to_str().unwrap();
+        run(&[
+            "create",
+            archive_str,
+            "-C",
+            src.to_str().unwrap(),
+            "--recovery",
+            "10",
+            "a",
+        ])
+        .unwrap();
+
+        let compression = CompressionPolicy::new(Compression::Zstd, Box::new(Killed));
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            modify(archive_str, compression, |archive| {
+                let mut entry = Entry::new(b"b".to_vec(), FileType::Regular);
+                entry.original_size = 2;
+                archive.add_entry(entry, src.join("b"));
+                Ok(())
+            })
+        }));
+        assert!(result.is_err());
+        // partly appended, and with the existing entries recorded
+        assert!(ArchiveReader::new(&archive).is_err());
+        assert!(checkpoint_path(&archive).exists());
+
+        run(&["test", archive_str, "--truncate"]).unwrap();
+        assert!(!checkpoint_path(&archive).exists());
+        assert_eq!(paths(&archive), [b"a".to_vec()]);
+        let reader = ArchiveReader::new(&archive).unwrap();
+        assert_eq!(read_entry(&reader, b"a"), b"a1");
+    }
+}
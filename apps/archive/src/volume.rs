@@ -0,0 +1,404 @@
+//! Multi-volume archives
+//!
+//! With `--volume-size`, the archive is cut into files `<name>.001`,
+//! `<name>.002`…, each of the volume size except the last one. Volumes are
+//! plain cuts of a single-file archive, so concatenating them gives it back.
+//! When reading, the volume size is taken from the first volume, and every
+//! volume is mapped to its own range; a missing or truncated volume only makes
+//! reads within its range fail.
+//!
+//! Archives are opened either by `<name>` or by `<name>.001`.
+
+use std::ffi::OsString;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::errors::*;
+
+/// The path of volume `number`, counting from 1
+pub fn volume_path(base: &Path, number: usize) -> PathBuf {
+    let mut path = OsString::from(base);
+    path.push(format!(".{:03}", number));
+    path.into()
+}
+
+/// Numbers of the existing volumes of `base`, in ascending order
+fn existing_volumes(base: &Path) -> io::Result<Vec<usize>> {
+    let Some(name) = base.file_name().and_then(|x| x.to_str()) else {
+        return Ok(Vec::new());
+    };
+    let dir = match base.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    let dir_entries = match fs::read_dir(dir) {
+        Ok(x) => x,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut numbers = Vec::new();
+    for dir_entry in dir_entries {
+        let file_name = dir_entry?.file_name();
+        let Some(suffix) = file_name
+            .to_str()
+            .and_then(|x| x.strip_prefix(name))
+            .and_then(|x| x.strip_prefix('.'))
+        else {
+            continue;
+        };
+        if suffix.len() >= 3 && suffix.bytes().all(|x| x.is_ascii_digit()) {
+            match suffix.parse::<usize>() {
+                Ok(n) if n > 0 => numbers.push(n),
+                _ => {}
+            }
+        }
+    }
+    numbers.sort_unstable();
+    Ok(numbers)
+}
+
+/// Whether `path` names a multi-volume archive
+pub fn is_multi_volume(path: &Path) -> bool {
+    find_base(path).is_some()
+}
+
+/// The base path of a multi-volume archive named by `path`
+fn find_base(path: &Path) -> Option<PathBuf> {
+    if let Some(base) = path.to_str().and_then(|x| x.strip_suffix(".001")) {
+        return Some(base.into());
+    }
+    if path.exists() {
+        return None;
+    }
+    existing_volumes(path)
+        .is_ok_and(|x| !x.is_empty())
+        .then(|| path.into())
+}
+
+#[derive(Debug)]
+pub enum VolumeProblem {
+    Missing(PathBuf),
+    /// a volume other than the last with a size different from the first
+    Size {
+        path: PathBuf,
+        size: u64,
+    },
+}
+
+impl Display for VolumeProblem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VolumeProblem::Missing(path) => write!(f, "Missing volume: {}", path.display()),
+            VolumeProblem::Size { path, size } => {
+                write!(f, "Damaged volume: {} (size {})", path.display(), size)
+            }
+        }
+    }
+}
+
+struct Volume {
+    path: PathBuf,
+    /// `None` if missing
+    file: Option<File>,
+    size: u64,
+}
+
+/// Reads the volumes of an archive as one file
+///
+/// A single-file archive is just one volume.
+pub struct VolumeFile {
+    /// the base path of multi-volume archives
+    base: Option<PathBuf>,
+    volumes: Vec<Volume>,
+    /// the size of every volume but the last one
+    volume_size: u64,
+    position: u64,
+}
+
+impl VolumeFile {
+    /// Open an archive, by the path of itself or its volumes
+    pub fn open(path: &Path) -> Result<Self> {
+        let Some(base) = find_base(path) else {
+            let file = File::open(path)?;
+            let size = file.metadata()?.len();
+            return Ok(Self {
+                base: None,
+                volumes: vec![Volume {
+                    path: path.into(),
+                    file: Some(file),
+                    size,
+                }],
+                volume_size: u64::MAX,
+                position: 0,
+            });
+        };
+
+        let last = existing_volumes(&base)?.last().copied().unwrap_or(1);
+        let mut volumes = Vec::with_capacity(last);
+        for number in 1..=last {
+            let path = volume_path(&base, number);
+            let volume = match File::open(&path) {
+                Ok(file) => Volume {
+                    size: file.metadata()?.len(),
+                    file: Some(file),
+                    path,
+                },
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Volume {
+                    path,
+                    file: None,
+                    size: 0,
+                },
+                Err(e) => return Err(e.into()),
+            };
+            volumes.push(volume);
+        }
+        let first = &volumes[0];
+        if first.file.is_none() {
+            return Err(Error::MissingVolume(first.path.clone()));
+        }
+        let volume_size = if volumes.len() == 1 {
+            u64::MAX
+        } else {
+            first.size
+        };
+        if volume_size == 0 {
+            return Err(Error::Others(format!(
+                "Empty volume: {}",
+                first.path.display()
+            )));
+        }
+
+        Ok(Self {
+            base: Some(base),
+            volumes,
+            volume_size,
+            position: 0,
+        })
+    }
+
+    pub fn is_multi_volume(&self) -> bool {
+        self.base.is_some()
+    }
+
+    /// The total size, as if no volume is missing or damaged
+    pub fn size(&self) -> u64 {
+        let last = self.volumes.last().unwrap();
+        (self.volumes.len() as u64 - 1) * self.volume_size + last.size
+    }
+
+    /// Missing volumes, and volumes with wrong sizes
+    pub fn problems(&self) -> Vec<VolumeProblem> {
+        let last = self.volumes.len() - 1;
+        let mut problems = Vec::new();
+        for (i, volume) in self.volumes.iter().enumerate() {
+            if volume.file.is_none() {
+                problems.push(VolumeProblem::Missing(volume.path.clone()));
+            } else if i != last && volume.size != self.volume_size {
+                problems.push(VolumeProblem::Size {
+                    path: volume.path.clone(),
+                    size: volume.size,
+                });
+            }
+        }
+        problems
+    }
+
+    /// The volume following the last one, which may be missing if the last
+    /// one is full
+    pub fn next_volume(&self) -> Option<PathBuf> {
+        let base = self.base.as_ref()?;
+        if self.volumes.last().unwrap().size != self.volume_size {
+            return None;
+        }
+        Some(volume_path(base, self.volumes.len() + 1))
+    }
+
+    /// Paths of the volumes holding the range, for multi-volume archives
+    pub fn volumes_of(&self, offset: u64, size: u64) -> Vec<&Path> {
+        if !self.is_multi_volume() {
+            return Vec::new();
+        }
+        let first = (offset / self.volume_size) as usize;
+        let last = (offset + size.max(1) - 1) / self.volume_size;
+        self.volumes
+            .iter()
+            .take(last as usize + 1)
+            .skip(first)
+            .map(|x| x.path.as_path())
+            .collect()
+    }
+
+    pub fn try_clone(&self) -> io::Result<Self> {
+        let volumes = self
+            .volumes
+            .iter()
+            .map(|x| {
+                Ok(Volume {
+                    path: x.path.clone(),
+                    file: x.file.as_ref().map(|x| x.try_clone()).transpose()?,
+                    size: x.size,
+                })
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(Self {
+            base: self.base.clone(),
+            volumes,
+            volume_size: self.volume_size,
+            position: self.position,
+        })
+    }
+}
+
+impl Read for VolumeFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let index = (self.position / self.volume_size) as usize;
+        let offset = self.position % self.volume_size;
+        let is_last = index + 1 == self.volumes.len();
+        let Some(volume) = self.volumes.get_mut(index) else {
+            return Ok(0);
+        };
+        let Some(file) = &mut volume.file else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Missing volume: {}", volume.path.display()),
+            ));
+        };
+        if offset >= volume.size {
+            if is_last {
+                return Ok(0);
+            }
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("Truncated volume: {}", volume.path.display()),
+            ));
+        }
+
+        let size = (buf.len() as u64).min(volume.size - offset) as usize;
+        file.seek(SeekFrom::Start(offset))?;
+        let size = file.read(&mut buf[..size])?;
+        self.position += size as u64;
+        Ok(size)
+    }
+}
+
+impl Seek for VolumeFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(x) => Some(x),
+            SeekFrom::End(x) => self.size().checked_add_signed(x),
+            SeekFrom::Current(x) => self.position.checked_add_signed(x),
+        };
+        let Some(position) = position else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Seeking before the start",
+            ));
+        };
+        self.position = position;
+        Ok(position)
+    }
+}
+
+/// Writes an archive as volumes of `volume_size` bytes
+pub struct VolumeWriter {
+    base: PathBuf,
+    volume_size: u64,
+    /// number of the current volume
+    number: usize,
+    file: File,
+    written: u64,
+}
+
+impl VolumeWriter {
+    /// Create the first volume, removing the existing volumes of `base`
+    pub fn create(base: &Path, volume_size: u64) -> io::Result<Self> {
+        assert!(volume_size > 0);
+        for number in existing_volumes(base)? {
+            fs::remove_file(volume_path(base, number))?;
+        }
+        Ok(Self {
+            base: base.into(),
+            volume_size,
+            number: 1,
+            file: File::create(volume_path(base, 1))?,
+            written: 0,
+        })
+    }
+}
+
+impl Write for VolumeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.written == self.volume_size {
+            self.file.flush()?;
+            self.number += 1;
+            self.file = File::create(volume_path(&self.base, self.number))?;
+            self.written = 0;
+        }
+        let size = (buf.len() as u64).min(self.volume_size - self.written) as usize;
+        let size = self.file.write(&buf[..size])?;
+        self.written += size as u64;
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    use super::{volume_path, VolumeFile, VolumeProblem, VolumeWriter};
+
+    #[test]
+    fn volumes() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("a.arc");
+        let data = (0..2500_u32).map(|x| x as u8).collect::<Vec<_>>();
+
+        let mut writer = VolumeWriter::create(&base, 1000).unwrap();
+        for x in data.chunks(300) {
+            writer.write_all(x).unwrap();
+        }
+        writer.flush().unwrap();
+        assert!(!volume_path(&base, 4).exists());
+
+        for path in [base.clone(), volume_path(&base, 1)] {
+            let mut file = VolumeFile::open(&path).unwrap();
+            assert!(file.is_multi_volume());
+            assert_eq!(file.size(), 2500);
+            let mut read = Vec::new();
+            file.read_to_end(&mut read).unwrap();
+            assert_eq!(read, data);
+
+            file.seek(SeekFrom::End(-1600)).unwrap();
+            let mut buf = [0_u8; 200];
+            file.read_exact(&mut buf).unwrap();
+            assert_eq!(buf, data[900..1100]);
+            assert_eq!(file.volumes_of(900, 200).len(), 2);
+        }
+
+        fs::remove_file(volume_path(&base, 2)).unwrap();
+        let mut file = VolumeFile::open(&base).unwrap();
+        assert!(matches!(
+            &file.problems()[..],
+            [VolumeProblem::Missing(p)] if p == &volume_path(&base, 2)
+        ));
+        let mut buf = [0_u8; 10];
+        file.seek(SeekFrom::Start(2100)).unwrap();
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, data[2100..2110]);
+        file.seek(SeekFrom::Start(1100)).unwrap();
+        assert!(file.read_exact(&mut buf).is_err());
+    }
+}
@@ -0,0 +1,345 @@
+//! Persistent cache of full file hashes
+//!
+//! Hashing whole files is by far the slowest step, and most files don't change
+//! between runs. Digests are kept in `$XDG_CACHE_HOME/cow-dedupe/hashes`
+//! (`~/.cache/cow-dedupe/hashes` by default), keyed by the device, inode, size,
+//! modification and change times of the file, and the hash function. A file
+//! modified in place gets a new ctime even if its mtime is set back (like by
+//! `cp --preserve` or `touch -r`), and a replaced one a new inode, so stale
+//! digests are never looked up; `cache prune` drops them to keep the file
+//! small.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use std::{env, fs, io};
+
+use serde::{Deserialize, Serialize};
+
+use crate::cli::{CacheArgs, CacheCommands};
+use crate::group::FileEntry;
+
+/// Bumped on format changes; caches of other versions are discarded
+const CACHE_VERSION: u32 = 2;
+/// Save the cache every so often while hashing, so an interrupted run keeps
+/// most of its work
+const SAVE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Serialize, Deserialize, Hash, PartialEq, Eq, Clone, Debug)]
+struct CacheKey {
+    device: u64,
+    inode: u64,
+    size: u64,
+    mtime_ns: i64,
+    ctime_ns: i64,
+    hash_fn: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct CachedHash {
+    /// where the file was seen, for pruning
+    path: PathBuf,
+    digest: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct CacheFile {
+    version: u32,
+    entries: HashMap<CacheKey, CachedHash>,
+}
+
+pub struct HashCache {
+    path: PathBuf,
+    /// name of the hash function looked up
+    hash_fn: String,
+    entries: HashMap<CacheKey, CachedHash>,
+    modified: bool,
+    last_save: Instant,
+}
+
+impl HashCache {
+    /// `$XDG_CACHE_HOME/cow-dedupe/hashes`, or under `~/.cache` if it's not set
+    pub fn default_path() -> Option<PathBuf> {
+        let dir = match env::var_os("XDG_CACHE_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from(env::var_os("HOME")?).join(".cache"),
+        };
+        Some(dir.join("cow-dedupe").join("hashes"))
+    }
+
+    /// Load the cache at `path` for looking up digests of `hash_fn`
+    ///
+    /// A missing cache file gives an empty cache, and so does an unreadable
+    /// one, with a warning.
+    pub fn open(path: &Path, hash_fn: &str) -> io::Result<Self> {
+        let entries = match File::open(path) {
+            Ok(file) => match bincode::deserialize_from::<_, CacheFile>(BufReader::new(file)) {
+                Ok(c) if c.version == CACHE_VERSION => c.entries,
+                Ok(_) => HashMap::new(),
+                Err(e) => {
+                    eprintln!("Hash cache unreadable; starting over ({})", e);
+                    HashMap::new()
+                }
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            path: path.into(),
+            hash_fn: hash_fn.into(),
+            entries,
+            modified: false,
+            last_save: Instant::now(),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn key(&self, entry: &FileEntry) -> Option<CacheKey> {
+        Some(CacheKey {
+            device: entry.device?,
+            inode: entry.inode?,
+            size: entry.size,
+            mtime_ns: entry.mtime_ns?,
+            ctime_ns: entry.ctime_ns?,
+            hash_fn: self.hash_fn.clone(),
+        })
+    }
+
+    pub fn get(&self, entry: &FileEntry) -> Option<&[u8]> {
+        let key = self.key(entry)?;
+        self.entries.get(&key).map(|x| &x.digest[..])
+    }
+
+    /// Record the digest of `entry`, saving the cache if it's time to
+    pub fn insert(&mut self, entry: &FileEntry, digest: &[u8]) -> io::Result<()> {
+        let Some(key) = self.key(entry) else {
+            return Ok(());
+        };
+        let value = CachedHash {
+            path: entry.path.clone(),
+            digest: digest.into(),
+        };
+        self.entries.insert(key, value);
+        self.modified = true;
+        if self.last_save.elapsed() >= SAVE_INTERVAL {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    /// Write the cache if it's changed, replacing the file atomically
+    pub fn save(&mut self) -> io::Result<()> {
+        self.last_save = Instant::now();
+        if !self.modified {
+            return Ok(());
+        }
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut temp_path = self.path.clone().into_os_string();
+        temp_path.push(".tmp");
+
+        let mut writer = BufWriter::new(File::create(&temp_path)?);
+        let cache = CacheFile {
+            version: CACHE_VERSION,
+            entries: std::mem::take(&mut self.entries),
+        };
+        let result = bincode::serialize_into(&mut writer, &cache);
+        self.entries = cache.entries;
+        result.map_err(io::Error::other)?;
+        writer.flush()?;
+        drop(writer);
+        fs::rename(&temp_path, &self.path)?;
+        self.modified = false;
+        Ok(())
+    }
+
+    /// Drop digests of files gone or changed since they were hashed, and
+    /// return the count dropped
+    pub fn prune(&mut self) -> usize {
+        let count = self.entries.len();
+        self.entries
+            .retain(|key, value| file_matches(&value.path, key));
+        let pruned = count - self.entries.len();
+        if pruned > 0 {
+            self.modified = true;
+        }
+        pruned
+    }
+}
+
+#[cfg(unix)]
+fn file_matches(path: &Path, key: &CacheKey) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    let Ok(metadata) = fs::symlink_metadata(path) else {
+        return false;
+    };
+    metadata.dev() == key.device
+        && metadata.ino() == key.inode
+        && metadata.len() == key.size
+        && mtime_ns(&metadata) == key.mtime_ns
+        && ctime_ns(&metadata) == key.ctime_ns
+}
+
+#[cfg(not(unix))]
+fn file_matches(_path: &Path, _key: &CacheKey) -> bool {
+    false
+}
+
+/// The modification time in nanoseconds since the Unix epoch
+#[cfg(unix)]
+pub fn mtime_ns(metadata: &fs::Metadata) -> i64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.mtime() * 1_000_000_000 + metadata.mtime_nsec()
+}
+
+/// The status change time in nanoseconds since the Unix epoch
+#[cfg(unix)]
+pub fn ctime_ns(metadata: &fs::Metadata) -> i64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.ctime() * 1_000_000_000 + metadata.ctime_nsec()
+}
+
+pub fn main(args: CacheArgs) -> anyhow::Result<()> {
+    let Some(path) = HashCache::default_path() else {
+        return Err(anyhow::anyhow!(
+            "Cache directory unknown; neither XDG_CACHE_HOME nor HOME is set"
+        ));
+    };
+    match args.command {
+        CacheCommands::Prune => {
+            let mut cache = HashCache::open(&path, "")?;
+            let count = cache.len();
+            let pruned = cache.prune();
+            cache.save()?;
+            eprintln!("Pruned {} of {} cached hashes", pruned, count);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use std::fs;
+    use std::fs::File;
+    use std::os::unix::fs::MetadataExt;
+    use std::path::Path;
+
+    use super::{ctime_ns, mtime_ns, CacheFile, HashCache, CACHE_VERSION};
+    use crate::group::FileEntry;
+
+    fn file_entry(path: &Path) -> FileEntry {
+        let metadata = fs::metadata(path).unwrap();
+        FileEntry {
+            path: path.into(),
+            size: metadata.len(),
+            inode: Some(metadata.ino()),
+            device: Some(metadata.dev()),
+            mtime_ns: Some(mtime_ns(&metadata)),
+            ctime_ns: Some(ctime_ns(&metadata)),
+        }
+    }
+
+    #[test]
+    fn save_and_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("a");
+        fs::write(&file, b"aaa").unwrap();
+        let path = dir.path().join("cache/hashes");
+
+        let mut cache = HashCache::open(&path, "b3").unwrap();
+        assert!(cache.is_empty());
+        cache.insert(&file_entry(&file), b"digest").unwrap();
+        assert_eq!(cache.get(&file_entry(&file)), Some(&b"digest"[..]));
+        cache.save().unwrap();
+        assert!(!dir.path().join("cache/hashes.tmp").exists());
+
+        let cache = HashCache::open(&path, "b3").unwrap();
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.get(&file_entry(&file)), Some(&b"digest"[..]));
+        let cache = HashCache::open(&path, "sha256").unwrap();
+        assert_eq!(cache.get(&file_entry(&file)), None);
+    }
+
+    #[test]
+    fn changed_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("a");
+        fs::write(&file, b"aaa").unwrap();
+        let entry = file_entry(&file);
+        let mut cache = HashCache::open(&dir.path().join("hashes"), "b3").unwrap();
+        cache.insert(&entry, b"digest").unwrap();
+
+        // the same size, with the mtime set back
+        let modified = fs::metadata(&file).unwrap().modified().unwrap();
+        fs::write(&file, b"bbb").unwrap();
+        File::options()
+            .write(true)
+            .open(&file)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        let changed = file_entry(&file);
+        assert_eq!(changed.mtime_ns, entry.mtime_ns);
+        assert_ne!(changed.ctime_ns, entry.ctime_ns);
+        assert_eq!(cache.get(&changed), None);
+
+        // replaced by another file
+        fs::remove_file(&file).unwrap();
+        fs::write(dir.path().join("b"), b"ccc").unwrap();
+        fs::rename(dir.path().join("b"), &file).unwrap();
+        assert_eq!(cache.get(&file_entry(&file)), None);
+    }
+
+    #[test]
+    fn prune() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("hashes");
+        let files = ["a", "b", "c"].map(|x| dir.path().join(x));
+        let mut cache = HashCache::open(&path, "b3").unwrap();
+        for x in &files {
+            fs::write(x, b"data").unwrap();
+            cache.insert(&file_entry(x), b"digest").unwrap();
+        }
+        cache.save().unwrap();
+
+        fs::remove_file(&files[0]).unwrap();
+        fs::write(&files[1], b"changed").unwrap();
+        let mut cache = HashCache::open(&path, "").unwrap();
+        assert_eq!(cache.prune(), 2);
+        cache.save().unwrap();
+
+        let cache = HashCache::open(&path, "b3").unwrap();
+        assert_eq!(cache.len(), 1);
+        assert!(cache.get(&file_entry(&files[2])).is_some());
+    }
+
+    #[test]
+    fn other_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("a");
+        fs::write(&file, b"aaa").unwrap();
+        let path = dir.path().join("hashes");
+        let mut cache = HashCache::open(&path, "b3").unwrap();
+        cache.insert(&file_entry(&file), b"digest").unwrap();
+        cache.save().unwrap();
+
+        let mut old: CacheFile = bincode::deserialize(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(old.version, CACHE_VERSION);
+        old.version = CACHE_VERSION - 1;
+        fs::write(&path, bincode::serialize(&old).unwrap()).unwrap();
+        assert!(HashCache::open(&path, "b3").unwrap().is_empty());
+
+        fs::write(&path, b"garbage").unwrap();
+        assert!(HashCache::open(&path, "b3").unwrap().is_empty());
+    }
+}
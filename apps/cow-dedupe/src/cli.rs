@@ -14,6 +14,8 @@ pub enum Subcommands {
     /// Do file deduplication
     #[command(alias = "d")]
     Dedupe(DedupeArgs),
+    /// Manage the hash cache
+    Cache(CacheArgs),
 }
 
 #[derive(clap::Args, Debug, Clone)]
@@ -33,12 +35,47 @@ pub struct DedupeArgs {
     /// Don't do anything; just print the size of duplicated files
     #[arg(short, long)]
     pub dry_run: bool,
-    #[arg(long, default_value = "yes")]
-    pub use_cp_cmd: YesNoChoice,
+    /// How to deduplicate files
+    #[arg(long, default_value = "reflink")]
+    pub mode: DedupeMode,
+    /// How to reflink files [default: ioctl on Linux, cp elsewhere]
+    #[arg(short, long)]
+    pub backend: Option<Backend>,
+    /// Deprecated: use `--backend cp` (yes) or `--backend reflink` (no)
+    #[arg(long, hide = true, conflicts_with = "backend")]
+    pub use_cp_cmd: Option<YesNoChoice>,
+    /// With the hardlink and symlink modes, only link files of the same mode
+    /// and owner
+    #[arg(long)]
+    pub match_metadata: bool,
     #[command(flatten)]
     pub common: CommonArgs,
 }
 
+impl DedupeArgs {
+    pub fn backend(&self) -> Backend {
+        match (self.backend, self.use_cp_cmd) {
+            (Some(backend), _) => backend,
+            (None, Some(YesNoChoice::Yes)) => Backend::Cp,
+            (None, Some(YesNoChoice::No)) => Backend::Reflink,
+            (None, None) if cfg!(target_os = "linux") => Backend::Ioctl,
+            (None, None) => Backend::Cp,
+        }
+    }
+}
+
+#[derive(clap::Args, Debug)]
+pub struct CacheArgs {
+    #[command(subcommand)]
+    pub command: CacheCommands,
+}
+
+#[derive(clap::Subcommand, Debug)]
+pub enum CacheCommands {
+    /// Remove hashes of files deleted or changed since
+    Prune,
+}
+
 #[derive(clap::Args, Debug, Clone)]
 pub struct CommonArgs {
     /// Minimum size filter
@@ -53,6 +90,12 @@ pub struct CommonArgs {
     /// JSON or binary input file
     #[arg(short, long)]
     pub input_file: Option<String>,
+    /// Reuse file hashes of previous runs, kept under the XDG cache directory [default]
+    #[arg(long, overrides_with = "no_cache")]
+    pub cache: bool,
+    /// Hash all files from scratch, and don't record the hashes
+    #[arg(long)]
+    pub no_cache: bool,
 }
 
 #[derive(clap::ValueEnum, Debug, Clone, Copy)]
@@ -69,6 +112,14 @@ pub enum HashFn {
     Sha3_512,
 }
 
+impl HashFn {
+    /// The name on the command line
+    pub fn name(&self) -> String {
+        use clap::ValueEnum;
+        self.to_possible_value().unwrap().get_name().into()
+    }
+}
+
 #[derive(clap::ValueEnum, Debug, Clone, Copy)]
 pub enum OutputFormat {
     Default,
@@ -76,6 +127,27 @@ pub enum OutputFormat {
     Binary,
 }
 
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DedupeMode {
+    /// Share the content with reflinks (Btrfs, XFS); files stay independent
+    Reflink,
+    /// Replace duplicates with hard links to the first file
+    Hardlink,
+    /// Replace duplicates with symbolic links to the first file
+    Symlink,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    /// The `FIDEDUPERANGE` ioctl (Linux); the kernel checks the content is
+    /// equal, and the destination file is kept with its metadata
+    Ioctl,
+    /// `cp --reflink -a`, replacing the destination
+    Cp,
+    /// The `reflink` crate, recreating the destination
+    Reflink,
+}
+
 #[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum YesNoChoice {
     Yes,
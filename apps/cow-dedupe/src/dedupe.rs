@@ -1,5 +1,6 @@
 use std::ffi::OsStr;
 use std::fs::remove_file;
+use std::path::Path;
 use std::process::{Command, Stdio};
 
 use anyhow::anyhow;
@@ -7,8 +8,14 @@ use bczhc_lib::str::GenericOsStrExt;
 use colored::Colorize;
 use indicatif::{ProgressBar, ProgressStyle};
 
-use crate::cli::DedupeArgs;
+use crate::cli::{Backend, DedupeArgs, DedupeMode};
+#[cfg(target_os = "linux")]
+use crate::fiemap;
 use crate::group::collect_and_group_files;
+#[cfg(target_os = "linux")]
+use crate::ioctl::{self, Outcome};
+#[cfg(unix)]
+use crate::link;
 use crate::{parse_input_file, print_redundant_size};
 
 macro_rules! os_str {
@@ -18,9 +25,16 @@ macro_rules! os_str {
 }
 
 pub fn main(args: DedupeArgs) -> anyhow::Result<()> {
-    let groups = match args.common.input_file {
+    if args.use_cp_cmd.is_some() {
+        eprintln!(
+            "{}",
+            "--use-cp-cmd is deprecated; use --backend cp or --backend reflink".yellow()
+        );
+    }
+    let backend = args.backend();
+    let groups = match &args.common.input_file {
         None => collect_and_group_files(&args.common)?,
-        Some(f) => parse_input_file(&f)?,
+        Some(f) => parse_input_file(f)?,
     };
 
     print_redundant_size(&groups);
@@ -35,98 +49,276 @@ pub fn main(args: DedupeArgs) -> anyhow::Result<()> {
                 .template("{msg} {bar:50} {pos}/{len}")
                 .unwrap(),
         );
-        pb.set_message("Reflinking".cyan().bold().to_string());
+        let message = match args.mode {
+            DedupeMode::Reflink => "Reflinking",
+            DedupeMode::Hardlink => "Hardlinking",
+            DedupeMode::Symlink => "Symlinking",
+        };
+        pb.set_message(message.cyan().bold().to_string());
         Some(pb)
     };
 
+    let mut stats = Stats::default();
     // TODO: to many messy branches
     for group in groups {
         let files = &group.files;
         let src = &files[0];
+        // dry runs only report, so they don't flush files
+        #[cfg(target_os = "linux")]
+        let src_extents = match args.mode {
+            DedupeMode::Reflink => fiemap::extents(src, !args.dry_run).ok(),
+            _ => None,
+        };
         for dest in files.iter().skip(1) {
             pb.then(|x| x.inc(1));
+            #[cfg(unix)]
+            if let Ok(true) = link::same_inode(src, dest) {
+                stats.already_linked += 1;
+                continue;
+            }
+            #[cfg(target_os = "linux")]
+            if let Some(extents) = &src_extents
+                && matches!(
+                    fiemap::shares_extents(extents, dest, !args.dry_run),
+                    Ok(true)
+                )
+            {
+                stats.already_shared += 1;
+                continue;
+            }
             let result: anyhow::Result<()> = try {
-                if args.use_cp_cmd.yes() {
-                    // use `cp` command
-                    let cmd = [
-                        os_str!("cp"),
-                        os_str!("--reflink"),
-                        // archive mode
-                        os_str!("-a"),
-                        src.as_os_str(),
-                        dest.as_os_str(),
-                    ];
-
-                    if args.dry_run {
-                        println!("{:?}", cmd);
-                    } else {
-                        let child = Command::new(cmd[0])
-                            .args(&cmd[1..])
-                            .stdin(Stdio::null())
-                            .stderr(Stdio::piped())
-                            .stdout(Stdio::piped())
-                            .spawn()?;
-                        let output = child.wait_with_output()?;
-                        if !output.stderr.is_empty() {
-                            pb.then(|x| {
-                                x.println(format!(
-                                    "cmd stderr: {}",
-                                    String::from_utf8_lossy(&output.stderr)
-                                ));
-                            });
+                if args.mode != DedupeMode::Reflink {
+                    link_file(src, dest, &args, &mut stats, &pb)?;
+                } else {
+                    match backend {
+                        Backend::Ioctl if args.dry_run => {
+                            println!("{:?} -> {:?}", src, dest);
                         }
-                        if !output.stdout.is_empty() {
-                            pb.then(|x| {
-                                x.println(format!(
-                                    "cmd stdout: {}",
-                                    String::from_utf8_lossy(&output.stdout)
-                                ))
-                            });
-                        };
-                        if !output.status.success() {
-                            Err(anyhow!(
-                                "Program exited with non-zero status: {}; cmd: {:?}",
-                                output.status,
-                                cmd
-                            ))?;
+                        Backend::Ioctl => {
+                            #[cfg(target_os = "linux")]
+                            {
+                                let (outcome, bytes) = ioctl::dedupe_file(src, dest)?;
+                                stats.deduped_bytes += bytes;
+                                match outcome {
+                                    Outcome::Deduped => {
+                                        stats.deduped += 1;
+                                        pb.then(|x| {
+                                            x.println(format!(
+                                                "Deduplicated: {} ({})",
+                                                dest.escape(),
+                                                bytesize::to_string(bytes, true)
+                                            ))
+                                        });
+                                    }
+                                    Outcome::Differs { offset } => {
+                                        stats.differing += 1;
+                                        pb.then(|x| {
+                                            x.println(
+                                                format!(
+                                                    "Content differs from {} at offset {}: {}",
+                                                    src.escape(),
+                                                    offset,
+                                                    dest.escape()
+                                                )
+                                                .yellow()
+                                                .to_string(),
+                                            )
+                                        });
+                                    }
+                                }
+                            }
+                            #[cfg(not(target_os = "linux"))]
+                            Err(anyhow!("The ioctl backend is only available on Linux"))?;
                         }
-                    }
-                } else {
-                    // use `reflink` crate
-                    // TODO: by this approach I'm not familiar about its internal details
-                    //  and have some trouble preserving the file timestamp, which is
-                    //  important in my use case. So I by default choose using
-                    //  `ls --reflink -a` command.
-                    if args.dry_run {
-                        println!("{:?} -> {:?}", src, dest);
-                    } else {
-                        // first the dest file should be deleted
-                        remove_file(dest)
-                            .map_err(|e| anyhow!("Dest file lost: {}, {:?}", e, dest))?;
-                        reflink::reflink(src, dest)?;
-                        if !dest.exists() {
-                            Err(anyhow!(
-                                "Check failed: destination file doesn't exist: {:?}",
-                                dest
-                            ))?;
+                        Backend::Cp => {
+                            // use `cp` command
+                            let cmd = [
+                                os_str!("cp"),
+                                os_str!("--reflink"),
+                                // archive mode
+                                os_str!("-a"),
+                                src.as_os_str(),
+                                dest.as_os_str(),
+                            ];
+
+                            if args.dry_run {
+                                println!("{:?}", cmd);
+                            } else {
+                                let child = Command::new(cmd[0])
+                                    .args(&cmd[1..])
+                                    .stdin(Stdio::null())
+                                    .stderr(Stdio::piped())
+                                    .stdout(Stdio::piped())
+                                    .spawn()?;
+                                let output = child.wait_with_output()?;
+                                if !output.stderr.is_empty() {
+                                    pb.then(|x| {
+                                        x.println(format!(
+                                            "cmd stderr: {}",
+                                            String::from_utf8_lossy(&output.stderr)
+                                        ));
+                                    });
+                                }
+                                if !output.stdout.is_empty() {
+                                    pb.then(|x| {
+                                        x.println(format!(
+                                            "cmd stdout: {}",
+                                            String::from_utf8_lossy(&output.stdout)
+                                        ))
+                                    });
+                                };
+                                if !output.status.success() {
+                                    Err(anyhow!(
+                                        "Program exited with non-zero status: {}; cmd: {:?}",
+                                        output.status,
+                                        cmd
+                                    ))?;
+                                }
+                                stats.deduped += 1;
+                            }
+                        }
+                        Backend::Reflink => {
+                            // use `reflink` crate
+                            // TODO: by this approach I'm not familiar about its internal details
+                            //  and have some trouble preserving the file timestamp, which is
+                            //  important in my use case. So I by default choose using
+                            //  `ls --reflink -a` command.
+                            if args.dry_run {
+                                println!("{:?} -> {:?}", src, dest);
+                            } else {
+                                // first the dest file should be deleted
+                                remove_file(dest)
+                                    .map_err(|e| anyhow!("Dest file lost: {}, {:?}", e, dest))?;
+                                reflink::reflink(src, dest)?;
+                                if !dest.exists() {
+                                    Err(anyhow!(
+                                        "Check failed: destination file doesn't exist: {:?}",
+                                        dest
+                                    ))?;
+                                }
+                                stats.deduped += 1;
+                            }
                         }
                     }
                 }
             };
-            if let Err(e) = result && let Some(ref b) = pb {
-                b.println(format!(
-                    "Reflinking error: ({} -> {}) {}",
-                    src.escape(),
-                    dest.escape(),
-                    e
-                ));
+            if let Err(e) = result {
+                stats.failed += 1;
+                if let Some(ref b) = pb {
+                    b.println(format!(
+                        "Deduplication error: ({} -> {}) {}",
+                        src.escape(),
+                        dest.escape(),
+                        e
+                    ));
+                }
             }
         }
     }
 
+    if let Some(pb) = pb {
+        pb.finish_and_clear();
+        stats.print(&args);
+    }
+
+    Ok(())
+}
+
+/// Replace `dest` with a hard or symbolic link to `src`
+#[cfg(unix)]
+fn link_file(
+    src: &Path,
+    dest: &Path,
+    args: &DedupeArgs,
+    stats: &mut Stats,
+    pb: &Option<ProgressBar>,
+) -> anyhow::Result<()> {
+    if args.dry_run {
+        println!("{:?} -> {:?}", src, dest);
+        return Ok(());
+    }
+    let message = match link::link_file(src, dest, args.mode, args.match_metadata)? {
+        link::Outcome::Linked => {
+            stats.deduped += 1;
+            return Ok(());
+        }
+        link::Outcome::Differs => {
+            stats.differing += 1;
+            "Content differs"
+        }
+        link::Outcome::MetadataDiffers => {
+            stats.metadata_differing += 1;
+            "Mode or owner differs"
+        }
+    };
+    pb.then(|x| {
+        x.println(
+            format!("{} from {}: {}", message, src.escape(), dest.escape())
+                .yellow()
+                .to_string(),
+        )
+    });
     Ok(())
 }
 
+#[cfg(not(unix))]
+fn link_file(
+    _src: &Path,
+    _dest: &Path,
+    _args: &DedupeArgs,
+    _stats: &mut Stats,
+    _pb: &Option<ProgressBar>,
+) -> anyhow::Result<()> {
+    Err(anyhow!("Linking files is only supported on Unix"))
+}
+
+#[derive(Default)]
+struct Stats {
+    deduped: u64,
+    /// only counted by the ioctl backend
+    deduped_bytes: u64,
+    already_linked: u64,
+    already_shared: u64,
+    differing: u64,
+    metadata_differing: u64,
+    failed: u64,
+}
+
+impl Stats {
+    fn print(&self, args: &DedupeArgs) {
+        let mut summary = format!("Deduplicated files: {}", self.deduped);
+        if args.mode == DedupeMode::Reflink && args.backend() == Backend::Ioctl {
+            summary.push_str(&format!(
+                " ({})",
+                bytesize::to_string(self.deduped_bytes, true)
+            ));
+        }
+        if self.already_linked > 0 {
+            summary.push_str(&format!(
+                "; already the same inode: {}",
+                self.already_linked
+            ));
+        }
+        if self.already_shared > 0 {
+            summary.push_str(&format!(
+                "; already sharing extents: {}",
+                self.already_shared
+            ));
+        }
+        if self.differing > 0 {
+            summary.push_str(&format!("; content differing: {}", self.differing));
+        }
+        if self.metadata_differing > 0 {
+            summary.push_str(&format!(
+                "; mode or owner differing: {}",
+                self.metadata_differing
+            ));
+        }
+        summary.push_str(&format!("; failed: {}", self.failed));
+        eprintln!("{}", summary.cyan());
+    }
+}
+
 trait OptionThen<T> {
     fn then<F>(&self, f: F)
     where
@@ -0,0 +1,203 @@
+//! Physical extents of files, with the Linux `FS_IOC_FIEMAP` ioctl
+//!
+//! Files deduplicated before map their content to the same physical extents,
+//! so duplicates only cost space for the extents they don't share.
+
+use std::fs::File;
+use std::io;
+use std::os::fd::AsRawFd;
+use std::path::Path;
+
+/// `_IOWR('f', 11, struct fiemap)`
+const FS_IOC_FIEMAP: u64 = 0xc020_660b;
+const FIEMAP_FLAG_SYNC: u32 = 0x1;
+const FIEMAP_EXTENT_LAST: u32 = 0x1;
+/// Extents without a physical location of their own:
+/// `UNKNOWN | DELALLOC | DATA_INLINE | DATA_TAIL`
+const FIEMAP_EXTENT_NO_LOCATION: u32 = 0x2 | 0x4 | 0x200 | 0x400;
+/// Extents requested per call
+const EXTENT_COUNT: usize = 256;
+
+/// `struct fiemap` without the extents following it
+#[repr(C)]
+#[derive(Default)]
+struct Fiemap {
+    start: u64,
+    length: u64,
+    flags: u32,
+    mapped_extents: u32,
+    extent_count: u32,
+    reserved: u32,
+}
+
+/// `struct fiemap_extent`
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct FiemapExtent {
+    logical: u64,
+    physical: u64,
+    length: u64,
+    reserved64: [u64; 2],
+    flags: u32,
+    reserved: [u32; 3],
+}
+
+#[repr(C)]
+struct FiemapRequest {
+    header: Fiemap,
+    extents: [FiemapExtent; EXTENT_COUNT],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extent {
+    pub logical: u64,
+    /// `None` if the data has no location of its own yet (delayed allocation,
+    /// inline data)
+    pub physical: Option<u64>,
+    pub length: u64,
+}
+
+/// The extents of the file at `path`, with contiguous ones merged
+///
+/// With `sync`, dirty data is written out first, so it has a location; that
+/// costs a flush of the file, so it's only for deduplicating.
+pub fn extents(path: &Path, sync: bool) -> io::Result<Vec<Extent>> {
+    let file = File::open(path)?;
+    let mut extents: Vec<Extent> = Vec::new();
+    let mut start = 0_u64;
+    loop {
+        let mut request = FiemapRequest {
+            header: Fiemap {
+                start,
+                length: u64::MAX - start,
+                flags: if sync { FIEMAP_FLAG_SYNC } else { 0 },
+                extent_count: EXTENT_COUNT as u32,
+                ..Default::default()
+            },
+            extents: [FiemapExtent::default(); EXTENT_COUNT],
+        };
+        let result = unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_FIEMAP as _, &mut request) };
+        if result == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mapped = &request.extents[..request.header.mapped_extents as usize];
+        for x in mapped {
+            let extent = Extent {
+                logical: x.logical,
+                physical: (x.flags & FIEMAP_EXTENT_NO_LOCATION == 0).then_some(x.physical),
+                length: x.length,
+            };
+            match extents.last_mut() {
+                Some(last) if contiguous(last, &extent) => last.length += extent.length,
+                _ => extents.push(extent),
+            }
+        }
+        match mapped.last() {
+            Some(last) if last.flags & FIEMAP_EXTENT_LAST == 0 => {
+                start = last.logical + last.length;
+            }
+            _ => break,
+        }
+    }
+    Ok(extents)
+}
+
+fn contiguous(a: &Extent, b: &Extent) -> bool {
+    match (a.physical, b.physical) {
+        (Some(pa), Some(pb)) => a.logical + a.length == b.logical && pa + a.length == pb,
+        _ => false,
+    }
+}
+
+/// Whether the file at `path` already has all of its content in `extents`,
+/// the extents of another file
+///
+/// See [`extents`] for `sync`.
+pub fn shares_extents(extents: &[Extent], path: &Path, sync: bool) -> io::Result<bool> {
+    Ok(!extents.is_empty()
+        && extents.iter().all(|x| x.physical.is_some())
+        && extents == self::extents(path, sync)?)
+}
+
+/// Space taken by the files of a group of duplicates, beyond the first file
+///
+/// Extents shared among the files are counted once; extents without a
+/// location are counted for every file.
+pub fn reclaimable_size<P: AsRef<Path>>(files: &[P]) -> io::Result<u64> {
+    let extents = files
+        .iter()
+        .map(|x| extents(x.as_ref(), false))
+        .collect::<io::Result<Vec<_>>>()?;
+    Ok(reclaimable_extent_size(&extents))
+}
+
+/// [`reclaimable_size`] of files with `extents`
+fn reclaimable_extent_size(extents: &[Vec<Extent>]) -> u64 {
+    let first_size = extents
+        .first()
+        .map(|x| x.iter().map(|x| x.length).sum::<u64>())
+        .unwrap_or(0);
+    let mut unlocated = 0_u64;
+    let mut ranges = Vec::new();
+    for x in extents.iter().flatten() {
+        match x.physical {
+            Some(p) => ranges.push((p, p + x.length)),
+            None => unlocated += x.length,
+        }
+    }
+
+    ranges.sort_unstable();
+    let mut used = unlocated;
+    let mut end = 0_u64;
+    for (start, stop) in ranges {
+        let start = start.max(end);
+        if stop > start {
+            used += stop - start;
+            end = stop;
+        }
+    }
+    used.saturating_sub(first_size)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{reclaimable_extent_size, Extent};
+
+    fn extent(logical: u64, physical: Option<u64>, length: u64) -> Extent {
+        Extent {
+            logical,
+            physical,
+            length,
+        }
+    }
+
+    #[test]
+    fn reclaimable() {
+        let a = vec![extent(0, Some(1000), 100), extent(100, Some(5000), 50)];
+        // not shared
+        let b = vec![extent(0, Some(2000), 150)];
+        assert_eq!(reclaimable_extent_size(&[a.clone(), b.clone()]), 150);
+        assert_eq!(
+            reclaimable_extent_size(&[a.clone(), b.clone(), b.clone()]),
+            150
+        );
+
+        // shared
+        assert_eq!(reclaimable_extent_size(&[a.clone(), a.clone()]), 0);
+        assert_eq!(reclaimable_extent_size(std::slice::from_ref(&a)), 0);
+        assert_eq!(reclaimable_extent_size(&[]), 0);
+
+        // partially shared: the first extent, and half of the second
+        let c = vec![extent(0, Some(1000), 100), extent(100, Some(5025), 50)];
+        assert_eq!(reclaimable_extent_size(&[a.clone(), c.clone()]), 25);
+        // overlapping ranges of other files
+        let d = vec![extent(0, Some(950), 150)];
+        assert_eq!(reclaimable_extent_size(&[a.clone(), c, d]), 75);
+
+        // unlocated extents are counted for every file
+        let e = vec![extent(0, None, 100), extent(100, Some(5000), 50)];
+        assert_eq!(reclaimable_extent_size(&[a.clone(), e.clone()]), 100);
+        assert_eq!(reclaimable_extent_size(&[e.clone(), e]), 100);
+    }
+}
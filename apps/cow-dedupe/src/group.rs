@@ -17,12 +17,13 @@ use sha3::{Sha3_256, Sha3_512};
 use bczhc_lib::mutex_lock;
 use bczhc_lib::str::GenericOsStrExt;
 
+use crate::cache::HashCache;
 use crate::cli::{CommonArgs, GroupArgs, HashFn, OutputFormat};
 use crate::hash::{FixedDigest, B3_1024, B3_128, B3_160, B3_2048, B3_256, B3_512};
 use crate::serde::build_output;
 use crate::{
     group_by_hash, group_by_size, parse_input_file, print_redundant_size, unique_by_hardlinks,
-    FileFragmentsHasher, FileFullHasher, Group,
+    FileEdgesHasher, FileFragmentsHasher, FileFullHasher, Group,
 };
 
 static ARGS: Lazy<Mutex<Option<GroupArgs>>> = Lazy::new(|| Mutex::new(None));
@@ -71,27 +72,32 @@ pub fn collect_and_group_files(args: &CommonArgs) -> anyhow::Result<Vec<Group>>
     eprintln!("{}", "Grouping by size...".cyan());
     let mut groups = group_by_size(&mut entries);
     groups.retain(|x| x.len() >= 2);
-    eprintln!(
-        "{}",
-        format!(
-            "File entries: {}",
-            groups.iter().map(|x| x.len()).sum::<usize>()
-        )
-        .cyan()
-    );
+    print_stage_stats("Size", entries.len(), groups.iter().map(|x| x.len()), None);
 
+    let mut cache = if args.no_cache {
+        None
+    } else if let Some(path) = HashCache::default_path() {
+        Some(HashCache::open(&path, &args.hash_fn.name())?)
+    } else {
+        eprintln!("Cache directory unknown; hashing without the cache");
+        None
+    };
+    let cache_ref = cache.as_mut();
     let groups = match args.hash_fn {
-        HashFn::B3_128 => generic_group_files_by_hash::<B3_128>(&groups),
-        HashFn::B3_160 => generic_group_files_by_hash::<B3_160>(&groups),
-        HashFn::B3_256 => generic_group_files_by_hash::<B3_256>(&groups),
-        HashFn::B3_512 => generic_group_files_by_hash::<B3_512>(&groups),
-        HashFn::B3_1024 => generic_group_files_by_hash::<B3_1024>(&groups),
-        HashFn::B3_2048 => generic_group_files_by_hash::<B3_2048>(&groups),
-        HashFn::Sha256 => generic_group_files_by_hash::<Sha256>(&groups),
-        HashFn::Sha512 => generic_group_files_by_hash::<Sha512>(&groups),
-        HashFn::Sha3_256 => generic_group_files_by_hash::<Sha3_256>(&groups),
-        HashFn::Sha3_512 => generic_group_files_by_hash::<Sha3_512>(&groups),
+        HashFn::B3_128 => generic_group_files_by_hash::<B3_128>(&groups, cache_ref),
+        HashFn::B3_160 => generic_group_files_by_hash::<B3_160>(&groups, cache_ref),
+        HashFn::B3_256 => generic_group_files_by_hash::<B3_256>(&groups, cache_ref),
+        HashFn::B3_512 => generic_group_files_by_hash::<B3_512>(&groups, cache_ref),
+        HashFn::B3_1024 => generic_group_files_by_hash::<B3_1024>(&groups, cache_ref),
+        HashFn::B3_2048 => generic_group_files_by_hash::<B3_2048>(&groups, cache_ref),
+        HashFn::Sha256 => generic_group_files_by_hash::<Sha256>(&groups, cache_ref),
+        HashFn::Sha512 => generic_group_files_by_hash::<Sha512>(&groups, cache_ref),
+        HashFn::Sha3_256 => generic_group_files_by_hash::<Sha3_256>(&groups, cache_ref),
+        HashFn::Sha3_512 => generic_group_files_by_hash::<Sha3_512>(&groups, cache_ref),
     }?;
+    if let Some(cache) = &mut cache {
+        cache.save()?;
+    }
 
     Ok(groups
         .into_iter()
@@ -106,19 +112,44 @@ pub fn collect_and_group_files(args: &CommonArgs) -> anyhow::Result<Vec<Group>>
 /// returns a vec of tuples, and each tuple is (hash, duplicated files)
 fn generic_group_files_by_hash<H: FixedDigest>(
     files: &[Vec<FileEntry>],
+    cache: Option<&mut HashCache>,
 ) -> anyhow::Result<Vec<(Vec<u8>, Vec<FileEntry>)>>
 where
     [(); H::OutputSize::USIZE]:,
     [u8; H::OutputSize::USIZE]: From<GenericArray<u8, H::OutputSize>>,
 {
-    eprintln!("{}", "Grouping by file fragments".cyan());
-    let groups =
-        group_by_hash::<H, FileFragmentsHasher, _, _>(|| files.iter().map(|x| x.as_slice()))?;
-    eprintln!("{}", format!("File entries: {}", groups.len()).cyan());
+    // each stage reads more of the files, and only groups still colliding
+    // go on to the next one
+    let file_count = files.iter().map(|x| x.len()).sum::<usize>();
+    eprintln!("{}", "Grouping by first and last 4 KiB...".cyan());
+    let (groups, read) =
+        group_by_hash::<H, FileEdgesHasher, _, _>(|| files.iter().map(|x| x.as_slice()), None)?;
+    let file_count = print_stage_stats(
+        "First and last 4 KiB",
+        file_count,
+        groups.iter().map(|x| x.1.len()),
+        Some(read),
+    );
+    eprintln!("{}", "Grouping by file fragments...".cyan());
+    let (groups, read) = group_by_hash::<H, FileFragmentsHasher, _, _>(
+        || groups.iter().map(|x| x.1.as_slice()),
+        None,
+    )?;
+    let file_count = print_stage_stats(
+        "File fragments",
+        file_count,
+        groups.iter().map(|x| x.1.len()),
+        Some(read),
+    );
     eprintln!("{}", "Grouping by file content...".cyan());
-    let mut groups =
-        group_by_hash::<H, FileFullHasher, _, _>(|| groups.iter().map(|x| x.1.as_slice()))?;
-    eprintln!("{}", format!("Group count: {}", groups.len()).cyan());
+    let (mut groups, read) =
+        group_by_hash::<H, FileFullHasher, _, _>(|| groups.iter().map(|x| x.1.as_slice()), cache)?;
+    print_stage_stats(
+        "File content",
+        file_count,
+        groups.iter().map(|x| x.1.len()),
+        Some(read),
+    );
     let duplicated_file_group_count = groups.iter().filter(|x| x.1.len() >= 2).count();
     eprintln!(
         "{}",
@@ -140,11 +171,37 @@ where
     Ok(r)
 }
 
+/// Print how many of `input_files` a grouping stage kept, and how much it read
+///
+/// Returns the count of files kept.
+fn print_stage_stats<I>(stage: &str, input_files: usize, group_sizes: I, read: Option<u64>) -> usize
+where
+    I: Iterator<Item = usize>,
+{
+    let (mut files, mut groups) = (0_usize, 0_usize);
+    for size in group_sizes {
+        files += size;
+        groups += 1;
+    }
+    let mut stats = format!(
+        "{}: {} -> {} files in {} groups",
+        stage, input_files, files, groups
+    );
+    if let Some(read) = read {
+        stats.push_str(&format!(", {} read", bytesize::to_string(read, true)));
+    }
+    eprintln!("{}", stats.cyan());
+    files
+}
+
 #[derive(Clone, Debug)]
 pub struct FileEntry {
     pub path: PathBuf,
     pub size: u64,
     pub inode: Option<u64>,
+    pub device: Option<u64>,
+    pub mtime_ns: Option<i64>,
+    pub ctime_ns: Option<i64>,
 }
 
 fn collect_file(paths: &Vec<String>, min_size: u64) -> Vec<FileEntry> {
@@ -172,11 +229,17 @@ fn collect_file(paths: &Vec<String>, min_size: u64) -> Vec<FileEntry> {
                         path: entry.path(),
                         size: file_size,
                         inode: None,
+                        device: None,
+                        mtime_ns: None,
+                        ctime_ns: None,
                     };
                     #[cfg(unix)]
                     {
                         use std::os::unix::prelude::MetadataExt;
                         entry.inode = Some(metadata.ino());
+                        entry.device = Some(metadata.dev());
+                        entry.mtime_ns = Some(crate::cache::mtime_ns(&metadata));
+                        entry.ctime_ns = Some(crate::cache::ctime_ns(&metadata));
                     }
                     files_vec.push(entry);
                 }
@@ -217,3 +280,67 @@ fn print_groups(groups: &[Group]) {
         println!()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::collect_and_group_files;
+    use crate::cli::{CommonArgs, HashFn};
+
+    #[test]
+    fn pipeline() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |name: &str, size: usize, changed_at: Option<usize>| {
+            let mut content = (0..size).map(|x| (x % 251) as u8).collect::<Vec<_>>();
+            if let Some(i) = changed_at {
+                content[i] ^= 0xff;
+            }
+            fs::write(dir.path().join(name), content).unwrap();
+        };
+        // passing the edges and the fragments stages
+        write("a", 64 * 1024, None);
+        write("b", 64 * 1024, None);
+        write("c", 64 * 1024, Some(20_000));
+        // passing the edges stage
+        write("d", 64 * 1024, Some(32 * 1024));
+        // small files
+        write("e", 6000, None);
+        write("f", 6000, None);
+        write("g", 6000, Some(3000));
+        write("h", 10, None);
+        write("i", 10, None);
+        write("j", 10, Some(5));
+
+        let args = CommonArgs {
+            min_size: "1B".into(),
+            path: vec![dir.path().to_str().unwrap().into()],
+            hash_fn: HashFn::B3_256,
+            input_file: None,
+            cache: false,
+            no_cache: true,
+        };
+        let mut groups = collect_and_group_files(&args)
+            .unwrap()
+            .into_iter()
+            .map(|g| {
+                let mut names = g
+                    .files
+                    .iter()
+                    .map(|x| x.file_name().unwrap().to_str().unwrap().to_string())
+                    .collect::<Vec<_>>();
+                names.sort();
+                (g.file_size, names)
+            })
+            .collect::<Vec<_>>();
+        groups.sort();
+        assert_eq!(
+            groups,
+            [
+                (10, vec!["h".to_string(), "i".into()]),
+                (6000, vec!["e".into(), "f".into()]),
+                (64 * 1024, vec!["a".into(), "b".into()]),
+            ]
+        );
+    }
+}
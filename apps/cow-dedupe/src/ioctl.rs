@@ -0,0 +1,142 @@
+//! Deduplication with the Linux `FIDEDUPERANGE` ioctl
+//!
+//! The kernel locks both files, compares the ranges byte by byte, and only
+//! shares the extents if they're equal. The destination keeps its inode, so
+//! its metadata is untouched, and a file changed since it was hashed is just
+//! reported as differing.
+//!
+//! Filesystems cap the length of one call (16 MiB on Btrfs), so files are
+//! deduplicated in chunks.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::fd::AsRawFd;
+use std::path::Path;
+
+/// Length of the range deduplicated by each call
+const CHUNK_SIZE: u64 = 16 * 1024 * 1024;
+
+/// `_IOWR(0x94, 54, struct file_dedupe_range)`
+const FIDEDUPERANGE: u64 = 0xc018_9436;
+const FILE_DEDUPE_RANGE_SAME: i32 = 0;
+const FILE_DEDUPE_RANGE_DIFFERS: i32 = 1;
+
+/// `struct file_dedupe_range` with a single `struct file_dedupe_range_info`
+#[repr(C)]
+#[derive(Default)]
+struct FileDedupeRange {
+    src_offset: u64,
+    src_length: u64,
+    dest_count: u16,
+    reserved1: u16,
+    reserved2: u32,
+    // info[0]
+    dest_fd: i64,
+    dest_offset: u64,
+    bytes_deduped: u64,
+    status: i32,
+    reserved: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// All of the file is shared with the source
+    Deduped,
+    /// The content differs from the source, starting in the chunk at `offset`;
+    /// chunks before it are shared
+    Differs { offset: u64 },
+}
+
+/// Share the content of `dest` with `src`, if they're equal
+///
+/// Returns the outcome and the count of bytes deduplicated.
+pub fn dedupe_file(src: &Path, dest: &Path) -> io::Result<(Outcome, u64)> {
+    let src_file = File::open(src)?;
+    // the destination needs to be writable, unless it's owned by us
+    let dest_file = match OpenOptions::new().write(true).open(dest) {
+        Ok(f) => f,
+        Err(_) => File::open(dest)?,
+    };
+    let length = src_file.metadata()?.len();
+    if dest_file.metadata()?.len() != length {
+        return Ok((Outcome::Differs { offset: 0 }, 0));
+    }
+
+    let mut offset = 0_u64;
+    while offset < length {
+        let mut range = FileDedupeRange {
+            src_offset: offset,
+            src_length: CHUNK_SIZE.min(length - offset),
+            dest_count: 1,
+            dest_fd: dest_file.as_raw_fd() as i64,
+            dest_offset: offset,
+            ..Default::default()
+        };
+        let result = unsafe { libc::ioctl(src_file.as_raw_fd(), FIDEDUPERANGE as _, &mut range) };
+        if result == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        match range.status {
+            FILE_DEDUPE_RANGE_SAME => {}
+            FILE_DEDUPE_RANGE_DIFFERS => return Ok((Outcome::Differs { offset }, offset)),
+            errno => return Err(io::Error::from_raw_os_error(-errno)),
+        }
+        if range.bytes_deduped == 0 {
+            return Err(io::Error::other(format!(
+                "No progress deduplicating at offset {}",
+                offset
+            )));
+        }
+        offset += range.bytes_deduped;
+    }
+    Ok((Outcome::Deduped, length))
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::io;
+
+    use super::{dedupe_file, Outcome};
+
+    /// Whether the filesystem of the test directory can't deduplicate, as
+    /// tmpfs and ext4
+    fn unsupported(e: &io::Error) -> bool {
+        matches!(
+            e.raw_os_error(),
+            Some(libc::EOPNOTSUPP | libc::EINVAL | libc::EXDEV | libc::ENOTTY)
+        )
+    }
+
+    #[test]
+    fn outcomes() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        let same = dir.path().join("same");
+        let changed = dir.path().join("changed");
+        let longer = dir.path().join("longer");
+        let content = vec![7_u8; 64 * 1024];
+        fs::write(&src, &content).unwrap();
+        fs::write(&same, &content).unwrap();
+        let mut other = content.clone();
+        other[40_000] = 0;
+        fs::write(&changed, &other).unwrap();
+        other.push(7);
+        fs::write(&longer, &other).unwrap();
+
+        // the length is checked before any ioctl
+        assert_eq!(
+            dedupe_file(&src, &longer).unwrap(),
+            (Outcome::Differs { offset: 0 }, 0)
+        );
+
+        match dedupe_file(&src, &same) {
+            Err(e) if unsupported(&e) => return,
+            result => assert_eq!(result.unwrap(), (Outcome::Deduped, 64 * 1024)),
+        }
+        assert_eq!(fs::read(&same).unwrap(), content);
+        let (outcome, bytes) = dedupe_file(&src, &changed).unwrap();
+        assert_eq!(outcome, Outcome::Differs { offset: 0 });
+        assert_eq!(bytes, 0);
+    }
+}
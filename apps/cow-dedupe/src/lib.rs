@@ -6,7 +6,7 @@
 
 extern crate core;
 
-use std::any::TypeId;
+use std::cell::Cell;
 use std::fs::File;
 use std::io;
 use std::io::{Read, Seek, SeekFrom, Write};
@@ -22,17 +22,25 @@ use digest::Digest;
 use indicatif::{ProgressBar, ProgressStyle};
 use rayon::prelude::ParallelSliceMut;
 
+use crate::cache::HashCache;
 use crate::group::FileEntry;
 use crate::hash::{FixedDigest, HashWriter};
 use crate::serde::Output;
 
 const IO_BUF_SIZE: usize = 4096;
 
+pub mod cache;
 pub mod cli;
 pub mod dedupe;
 pub mod errors;
+#[cfg(target_os = "linux")]
+pub mod fiemap;
 pub mod group;
 pub mod hash;
+#[cfg(target_os = "linux")]
+pub mod ioctl;
+#[cfg(unix)]
+pub mod link;
 pub mod serde;
 
 pub fn group_by_size(entries: &mut Vec<FileEntry>) -> Vec<Vec<FileEntry>> {
@@ -53,10 +61,15 @@ where
         [(); H::OutputSize::USIZE]:,
         [u8; H::OutputSize::USIZE]: From<GenericArray<u8, H::OutputSize>>,
         F: Fn(usize);
+
+    /// Count of bytes read for hashing a file of `file_size`
+    fn read_size(file_size: u64) -> u64;
 }
 
 /// Hash the whole file
 pub struct FileFullHasher;
+/// Hash the first and the last 4 KiB of the file
+pub struct FileEdgesHasher;
 /// 1. some portion from file start
 /// 1. some portion from file end
 /// 1. some portion bytes through file middle
@@ -89,6 +102,39 @@ where
         io_copy_with_progress(&mut file, &mut hasher, progress)?;
         Ok(hasher.0.finalize_fixed().into())
     }
+
+    fn read_size(file_size: u64) -> u64 {
+        file_size
+    }
+}
+
+impl FileEdgesHasher {
+    const EDGE_SIZE: u64 = 4096;
+}
+
+impl<H> FileHash<H> for FileEdgesHasher
+where
+    H: FixedDigest,
+{
+    fn hash<P: AsRef<Path>, F>(p: P, progress: F) -> io::Result<[u8; H::OutputSize::USIZE]>
+    where
+        [(); H::OutputSize::USIZE]:,
+        [u8; H::OutputSize::USIZE]: From<GenericArray<u8, H::OutputSize>>,
+        F: Fn(usize),
+    {
+        let mut file = File::open(p)?;
+        let read_size = Self::EDGE_SIZE.min(file.metadata()?.len());
+
+        let mut hasher = HashWriter(H::new());
+        io_copy_with_progress((&mut file).take(read_size), &mut hasher, &progress)?;
+        file.seek(SeekFrom::End(-(read_size as i64)))?;
+        io_copy_with_progress(file.take(read_size), &mut hasher, &progress)?;
+        Ok(hasher.0.finalize_fixed().into())
+    }
+
+    fn read_size(file_size: u64) -> u64 {
+        2 * Self::EDGE_SIZE.min(file_size)
+    }
 }
 
 impl<H> FileHash<H> for FileFragmentsHasher
@@ -140,11 +186,21 @@ where
 
         Ok(hasher.finalize_fixed().into())
     }
+
+    fn read_size(_file_size: u64) -> u64 {
+        Self::TOTAL_SIZE as u64
+    }
 }
 
-pub fn group_by_hash<'a, H, FH: FileHash<H> + 'static, G, I>(
+/// Group files by the hash of `FH`, keeping groups of two or more
+///
+/// Digests are looked up in and recorded to `cache`, so it's only for
+/// [`FileFullHasher`]. Returns the groups and the count of bytes read.
+#[allow(clippy::type_complexity)]
+pub fn group_by_hash<'a, H, FH: FileHash<H>, G, I>(
     entries_iter_getter: G,
-) -> io::Result<Vec<([u8; H::OutputSize::USIZE], Vec<FileEntry>)>>
+    mut cache: Option<&mut HashCache>,
+) -> io::Result<(Vec<([u8; H::OutputSize::USIZE], Vec<FileEntry>)>, u64)>
 where
     H: FixedDigest,
     [(); H::OutputSize::USIZE]:,
@@ -152,18 +208,12 @@ where
     G: Fn() -> I,
     I: Iterator<Item = &'a [FileEntry]>,
 {
-    let total_file_size = if TypeId::of::<FH>() == TypeId::of::<FileFragmentsHasher>() {
-        entries_iter_getter()
-            .map(|x| x.len() as u64 * FileFragmentsHasher::TOTAL_SIZE as u64)
-            .sum::<u64>()
-    } else {
-        assert_eq!(TypeId::of::<FH>(), TypeId::of::<FileFullHasher>());
-        entries_iter_getter()
-            .map(|x| x.iter().map(|x| x.size).sum::<u64>())
-            .sum::<u64>()
-    };
+    let total_file_size = entries_iter_getter()
+        .map(|x| x.iter().map(|x| FH::read_size(x.size)).sum::<u64>())
+        .sum::<u64>();
 
     let mut groups = Vec::new();
+    let read_size = Cell::new(0_u64);
 
     let progress_bar = ProgressBar::new(total_file_size);
     progress_bar.set_style(
@@ -176,10 +226,22 @@ where
     for g in entries_iter_getter() {
         let mut vec: Vec<(FileEntry, [u8; H::OutputSize::USIZE])> = Vec::new();
         for x in g.iter() {
+            if let Some(c) = &cache
+                && let Some(cached) = c.get(x)
+                && let Ok(digest) = cached.try_into()
+            {
+                progress_bar.inc(FH::read_size(x.size));
+                vec.push((x.clone(), digest));
+                continue;
+            }
             let result: io::Result<()> = try {
                 let digest = FH::hash(&x.path, |s| {
                     progress_bar.inc(s as u64);
+                    read_size.set(read_size.get() + s as u64);
                 })?;
+                if let Some(c) = &mut cache {
+                    c.insert(x, &digest)?;
+                }
                 vec.push((x.clone(), digest));
             };
             if let Err(e) = result {
@@ -195,7 +257,7 @@ where
             }
         }
     }
-    Ok(groups)
+    Ok((groups, read_size.get()))
 }
 
 fn io_copy_with_progress<R, W, F>(mut reader: R, mut writer: W, progress: F) -> io::Result<()>
@@ -247,6 +309,7 @@ const fn max(a: usize, b: usize) -> usize {
     [a, b][(a < b) as usize]
 }
 
+/// Keep one entry of the hard links to each inode
 pub fn unique_by_hardlinks(entries: &[FileEntry]) -> Vec<FileEntry> {
     let mut inode_none = Vec::from_iter(
         entries
@@ -260,8 +323,9 @@ pub fn unique_by_hardlinks(entries: &[FileEntry]) -> Vec<FileEntry> {
             .filter(|x| x.inode.is_some())
             .map(Clone::clone),
     );
-    inode_some.par_sort_by_key(|x| x.inode.unwrap());
-    inode_some.dedup_by_key(|x| x.inode.unwrap());
+    // inode numbers are only unique on their device
+    inode_some.par_sort_by_key(|x| (x.device, x.inode.unwrap()));
+    inode_some.dedup_by_key(|x| (x.device, x.inode.unwrap()));
     inode_none.extend(inode_some);
     inode_none
 }
@@ -273,9 +337,80 @@ pub fn group_redundant_size(groups: &[Group]) -> u64 {
         .sum::<u64>()
 }
 
+/// The space deduplicating `groups` frees: the redundant size, less the
+/// extents the files already share
+pub fn group_reclaimable_size(groups: &[Group]) -> u64 {
+    groups
+        .iter()
+        .map(|x| {
+            let redundant_size = x.file_size * (x.files.len() as u64 - 1);
+            #[cfg(target_os = "linux")]
+            if let Ok(size) = fiemap::reclaimable_size(&x.files) {
+                // extents are whole blocks
+                return size.min(redundant_size);
+            }
+            redundant_size
+        })
+        .sum::<u64>()
+}
+
 pub fn print_redundant_size(groups: &[Group]) {
     eprintln!(
-        "Redundant size: {}",
-        bytesize::to_string(group_redundant_size(groups), true)
+        "Redundant size: {}; reclaimable: {}",
+        bytesize::to_string(group_redundant_size(groups), true),
+        bytesize::to_string(group_reclaimable_size(groups), true)
     );
 }
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::path::Path;
+
+    use sha2::Sha256;
+
+    use crate::{FileEdgesHasher, FileFullHasher, FileHash};
+
+    fn edges_hash(path: &Path) -> [u8; 32] {
+        <FileEdgesHasher as FileHash<Sha256>>::hash(path, |_| {}).unwrap()
+    }
+
+    fn full_hash(path: &Path) -> [u8; 32] {
+        <FileFullHasher as FileHash<Sha256>>::hash(path, |_| {}).unwrap()
+    }
+
+    #[test]
+    fn edges_hasher() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |name: &str, size: usize, changed_at: Option<usize>| {
+            let path = dir.path().join(name);
+            let mut content = (0..size).map(|x| (x % 251) as u8).collect::<Vec<_>>();
+            if let Some(i) = changed_at {
+                content[i] ^= 0xff;
+            }
+            fs::write(&path, content).unwrap();
+            path
+        };
+
+        // only the middle differs
+        let a = write("a", 64 * 1024, None);
+        let b = write("b", 64 * 1024, Some(20_000));
+        assert_eq!(edges_hash(&a), edges_hash(&b));
+        assert_ne!(full_hash(&a), full_hash(&b));
+        let b = write("b", 64 * 1024, Some(64 * 1024 - 1));
+        assert_ne!(edges_hash(&a), edges_hash(&b));
+
+        // the edges of files up to 8 KiB cover all of them
+        for size in [1, 100, 4096, 6000, 8192] {
+            let a = write("a", size, None);
+            let b = write("b", size, None);
+            assert_eq!(edges_hash(&a), edges_hash(&b));
+            let b = write("b", size, Some(size / 2));
+            assert_ne!(edges_hash(&a), edges_hash(&b), "size {}", size);
+        }
+        assert_eq!(
+            <FileEdgesHasher as FileHash<Sha256>>::read_size(6000),
+            2 * 4096
+        );
+    }
+}
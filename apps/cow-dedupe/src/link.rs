@@ -0,0 +1,235 @@
+//! Deduplication with hard links and symbolic links
+//!
+//! For filesystems without reflinks. Unlike reflinked copies, linked files
+//! are no longer independent, so the content is compared byte by byte right
+//! before linking, and the destination is replaced atomically: the link is
+//! made under a temporary name beside it, then renamed over it.
+
+use std::fs::{self, File, Metadata};
+use std::io::{self, Read};
+use std::os::unix::fs::{symlink, MetadataExt};
+use std::path::{Path, PathBuf};
+
+use crate::cli::DedupeMode;
+
+const COMPARE_BUF_SIZE: usize = 65536;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Linked,
+    /// The content differs from the source
+    Differs,
+    /// The mode or owner differs from the source
+    MetadataDiffers,
+}
+
+/// Whether `a` and `b` are the same inode
+pub fn same_inode(a: &Path, b: &Path) -> io::Result<bool> {
+    let (a, b) = (fs::symlink_metadata(a)?, fs::symlink_metadata(b)?);
+    Ok(a.dev() == b.dev() && a.ino() == b.ino())
+}
+
+/// Replace `dest` with a link to `src`, if their content is equal
+///
+/// With `match_metadata`, the mode and owner have to be equal too. Hard links
+/// need both files on the same device.
+pub fn link_file(
+    src: &Path,
+    dest: &Path,
+    mode: DedupeMode,
+    match_metadata: bool,
+) -> io::Result<Outcome> {
+    let src_metadata = fs::symlink_metadata(src)?;
+    let dest_metadata = fs::symlink_metadata(dest)?;
+    if mode == DedupeMode::Hardlink && src_metadata.dev() != dest_metadata.dev() {
+        return Err(io::Error::other("Files are on different devices"));
+    }
+    if match_metadata && !metadata_matches(&src_metadata, &dest_metadata) {
+        return Ok(Outcome::MetadataDiffers);
+    }
+    if src_metadata.len() != dest_metadata.len() || !content_equals(src, dest)? {
+        return Ok(Outcome::Differs);
+    }
+
+    let temp_path = temp_path(dest);
+    // left behind by an interrupted run
+    match fs::remove_file(&temp_path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    match mode {
+        DedupeMode::Hardlink => fs::hard_link(src, &temp_path)?,
+        DedupeMode::Symlink => symlink(fs::canonicalize(src)?, &temp_path)?,
+        DedupeMode::Reflink => unreachable!(),
+    }
+    if let Err(e) = fs::rename(&temp_path, dest) {
+        let _ = fs::remove_file(&temp_path);
+        return Err(e);
+    }
+    Ok(Outcome::Linked)
+}
+
+fn metadata_matches(a: &Metadata, b: &Metadata) -> bool {
+    a.mode() & 0o7777 == b.mode() & 0o7777 && a.uid() == b.uid() && a.gid() == b.gid()
+}
+
+fn content_equals(a: &Path, b: &Path) -> io::Result<bool> {
+    let (mut a, mut b) = (File::open(a)?, File::open(b)?);
+    let mut buf_a = vec![0_u8; COMPARE_BUF_SIZE];
+    let mut buf_b = vec![0_u8; COMPARE_BUF_SIZE];
+    loop {
+        let size = a.read(&mut buf_a)?;
+        if size == 0 {
+            return Ok(b.read(&mut buf_b[..1])? == 0);
+        }
+        match b.read_exact(&mut buf_b[..size]) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+            Err(e) => return Err(e),
+        }
+        if buf_a[..size] != buf_b[..size] {
+            return Ok(false);
+        }
+    }
+}
+
+/// A name beside `path` for the link before it replaces `path`
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = std::ffi::OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(".cow-dedupe-tmp");
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+    use std::path::Path;
+
+    use super::{content_equals, link_file, same_inode, temp_path, Outcome};
+    use crate::cli::DedupeMode;
+
+    #[test]
+    fn outcomes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name);
+        let content = vec![7_u8; 100_000];
+        for name in ["src", "hard", "sym", "mode"] {
+            fs::write(path(name), &content).unwrap();
+        }
+        let mut changed = content.clone();
+        changed[70_000] = 0;
+        fs::write(path("changed"), &changed).unwrap();
+        fs::write(path("shorter"), &content[..99_999]).unwrap();
+        fs::set_permissions(path("mode"), fs::Permissions::from_mode(0o600)).unwrap();
+        fs::set_permissions(path("src"), fs::Permissions::from_mode(0o644)).unwrap();
+
+        let link = |name: &str, mode, match_metadata| {
+            link_file(&path("src"), &path(name), mode, match_metadata).unwrap()
+        };
+        assert_eq!(
+            link("changed", DedupeMode::Hardlink, false),
+            Outcome::Differs
+        );
+        assert_eq!(
+            link("shorter", DedupeMode::Symlink, false),
+            Outcome::Differs
+        );
+        assert_eq!(
+            link("mode", DedupeMode::Hardlink, true),
+            Outcome::MetadataDiffers
+        );
+        assert_eq!(fs::read(path("changed")).unwrap(), changed);
+        assert!(!same_inode(&path("src"), &path("mode")).unwrap());
+
+        assert_eq!(link("hard", DedupeMode::Hardlink, true), Outcome::Linked);
+        assert!(same_inode(&path("src"), &path("hard")).unwrap());
+        assert_eq!(fs::metadata(path("src")).unwrap().nlink(), 2);
+
+        assert_eq!(link("mode", DedupeMode::Symlink, false), Outcome::Linked);
+        assert_eq!(link("sym", DedupeMode::Symlink, false), Outcome::Linked);
+        assert_eq!(
+            fs::read_link(path("sym")).unwrap(),
+            fs::canonicalize(path("src")).unwrap()
+        );
+        assert_eq!(fs::read(path("sym")).unwrap(), content);
+
+        // no temporary links left behind
+        let mut names = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|x| x.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["changed", "hard", "mode", "shorter", "src", "sym"]);
+    }
+
+    #[test]
+    fn rename_over() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dest) = (dir.path().join("src"), dir.path().join("dest"));
+        fs::write(&src, b"content").unwrap();
+        fs::write(&dest, b"content").unwrap();
+        // a stale link from an interrupted run
+        fs::write(temp_path(&dest), b"stale").unwrap();
+
+        assert_eq!(
+            link_file(&src, &dest, DedupeMode::Hardlink, false).unwrap(),
+            Outcome::Linked
+        );
+        assert!(same_inode(&src, &dest).unwrap());
+        assert!(!temp_path(&dest).exists());
+    }
+
+    #[test]
+    fn different_devices() {
+        let shm = Path::new("/dev/shm");
+        let Ok(other) = tempfile::tempdir_in(shm) else {
+            return;
+        };
+        let dir = tempfile::tempdir().unwrap();
+        if fs::metadata(dir.path()).unwrap().dev() == fs::metadata(other.path()).unwrap().dev() {
+            return;
+        }
+        let (src, dest) = (dir.path().join("src"), other.path().join("dest"));
+        fs::write(&src, b"content").unwrap();
+        fs::write(&dest, b"content").unwrap();
+
+        assert!(link_file(&src, &dest, DedupeMode::Hardlink, false).is_err());
+        assert_eq!(
+            link_file(&src, &dest, DedupeMode::Symlink, false).unwrap(),
+            Outcome::Linked
+        );
+    }
+
+    #[test]
+    fn content_comparison() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name);
+        // longer than the compare buffer
+        let content = (0..200_000).map(|x| (x % 251) as u8).collect::<Vec<_>>();
+        fs::write(path("a"), &content).unwrap();
+        fs::write(path("b"), &content).unwrap();
+        fs::write(path("longer"), [&content[..], b"x"].concat()).unwrap();
+        fs::write(path("empty"), b"").unwrap();
+        let mut changed = content.clone();
+        changed[150_000] ^= 1;
+        fs::write(path("changed"), &changed).unwrap();
+
+        assert!(content_equals(&path("a"), &path("b")).unwrap());
+        assert!(!content_equals(&path("a"), &path("changed")).unwrap());
+        assert!(!content_equals(&path("a"), &path("longer")).unwrap());
+        assert!(!content_equals(&path("longer"), &path("a")).unwrap());
+        assert!(!content_equals(&path("empty"), &path("a")).unwrap());
+        assert!(content_equals(&path("empty"), &path("empty")).unwrap());
+    }
+
+    #[test]
+    fn temp_names() {
+        assert_eq!(
+            temp_path(Path::new("/a/b.txt")),
+            Path::new("/a/.b.txt.cow-dedupe-tmp")
+        );
+        assert_eq!(temp_path(Path::new("b")), Path::new(".b.cow-dedupe-tmp"));
+    }
+}
@@ -11,6 +11,9 @@ fn main() -> anyhow::Result<()> {
         Subcommands::Dedupe(args) => {
             cow_dedupe::dedupe::main(args)?;
         }
+        Subcommands::Cache(args) => {
+            cow_dedupe::cache::main(args)?;
+        }
     }
 
     Ok(())